@@ -0,0 +1,362 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Procedural macros that go with `runtime-macros`. They're re-exported from there when its
+//! `macros` feature is enabled.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use {
+    proc_macro2::{Span, TokenStream},
+    std::{collections::HashMap, env},
+    syn::{
+        parse::{Parse, ParseStream},
+        punctuated::Punctuated,
+        spanned::Spanned,
+        Expr, Ident, ItemFn, LitStr, Token,
+    },
+};
+
+/// Splits a procedural macro into the entry point that the compiler calls and an ordinary function
+/// that `runtime-macros` can call at run time.
+///
+/// Only the compiler can call a `#[proc_macro]` function, so the usual way to test one is to write
+/// a stub that converts its arguments to `proc_macro2::TokenStream`s and forwards them to another
+/// function that does the real work. This attribute writes the stub. It goes on the real
+/// function, written with proc_macro2's types, above its `#[proc_macro]`, `#[proc_macro_derive]`,
+/// or `#[proc_macro_attribute]` attribute. The function gets `_internal` added to its name and is
+/// made `pub(crate)`, and an entry point with the original name, the documentation, and the
+/// macro attribute is added that calls it. The function can return anything that converts
+/// into a `proc_macro::TokenStream`.
+///
+/// Since the entry point is needed outside of tests too, this has to come from a normal
+/// dependency, not a dev-dependency. Depending on this crate directly avoids building all of
+/// `runtime-macros` with the macro. In a crate that uses the 2015 edition, the crate root also
+/// needs `extern crate proc_macro;`.
+///
+/// The functions marked with this attribute are also the ones that `cargo runtime-macros` expands
+/// the crate's tests and examples with. It builds the crate with the
+/// `RUNTIME_MACROS_NO_ENTRY_POINTS` environment variable set, which makes this attribute leave
+/// out the entry point and make the function `pub` instead.
+///
+/// # Example
+///
+/// ```ignore
+/// use proc_macro2::TokenStream;
+///
+/// /// Removes the item it's on.
+/// #[runtime_macros_derive::testable]
+/// #[proc_macro_attribute]
+/// pub fn remove(_attr: TokenStream, _item: TokenStream) -> TokenStream {
+///     TokenStream::new()
+/// }
+///
+/// #[cfg(test)]
+/// mod tests {
+///     #[test]
+///     fn code_coverage() {
+///         runtime_macros::Expander::new()
+///             .attribute("remove", super::remove_internal)
+///             .file("tests/tests.rs")
+///             .run()
+///             .unwrap();
+///     }
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn testable(
+    attr: proc_macro::TokenStream,
+    item: proc_macro::TokenStream,
+) -> proc_macro::TokenStream {
+    testable_internal(attr.into(), item.into()).into()
+}
+
+// The attributes that make a function a procedural macro.
+const MACRO_ATTRIBUTES: [&str; 3] = ["proc_macro", "proc_macro_derive", "proc_macro_attribute"];
+
+fn testable_internal(attr: TokenStream, item: TokenStream) -> TokenStream {
+    if !attr.is_empty() {
+        return syn::Error::new(attr.span(), "`#[testable]` doesn't take any arguments")
+            .into_compile_error();
+    }
+    let mut function: ItemFn = match syn::parse2(item) {
+        Ok(function) => function,
+        Err(e) => return e.into_compile_error(),
+    };
+    let kind = function.attrs.iter().position(|attr| {
+        MACRO_ATTRIBUTES
+            .iter()
+            .any(|name| attr.path().is_ident(name))
+    });
+    let macro_attr = match kind {
+        Some(i) => function.attrs.remove(i),
+        None => {
+            return syn::Error::new(
+                function.sig.ident.span(),
+                "`#[testable]` has to go on a function marked with `#[proc_macro]`, \
+                 `#[proc_macro_derive]`, or `#[proc_macro_attribute]`",
+            )
+            .into_compile_error()
+        }
+    };
+    if let Some(receiver) = function.sig.receiver() {
+        return syn::Error::new(receiver.span(), "a procedural macro can't take `self`")
+            .into_compile_error();
+    }
+
+    // The documentation belongs to the macro, so it goes on the entry point.
+    let (docs, attrs) = function
+        .attrs
+        .drain(..)
+        .partition::<Vec<_>, _>(|attr| attr.path().is_ident("doc"));
+    function.attrs = attrs;
+
+    let name = function.sig.ident.clone();
+    let internal = Ident::new(&format!("{}_internal", name), name.span());
+    function.sig.ident = internal.clone();
+
+    // `cargo runtime-macros` builds the crate as an ordinary library, which can't have entry
+    // points, and calls the functions from outside it.
+    if env::var_os("RUNTIME_MACROS_NO_ENTRY_POINTS").is_some() {
+        function.vis = syn::parse_quote!(pub);
+        function.attrs.extend(docs);
+        return quote!(#function);
+    }
+    function.vis = syn::parse_quote!(pub(crate));
+    let args: Vec<Ident> = function
+        .sig
+        .inputs
+        .iter()
+        .enumerate()
+        .map(|(i, arg)| {
+            let span = arg.span().resolved_at(Span::mixed_site());
+            Ident::new(&format!("__arg{}", i), span)
+        })
+        .collect();
+
+    quote! {
+        #(#docs)*
+        #macro_attr
+        pub fn #name(#(#args: ::proc_macro::TokenStream),*) -> ::proc_macro::TokenStream {
+            ::std::convert::From::from(#internal(#(::std::convert::From::from(#args)),*))
+        }
+
+        #function
+    }
+}
+
+/// Writes a test for each macro that expands its invocations in the files that match a glob
+/// pattern, so that a code coverage tool can measure the macro.
+///
+/// Each entry names the kind of macro (`functionlike`, `derive`, or `attribute`), the path it's
+/// invoked by, the function to call for it (usually one written by [`testable`]), and the
+/// pattern, which is relative to the crate's root directory. Entries are separated by `;`. See
+/// `runtime_macros::Expander::glob` for the patterns that can be used.
+///
+/// Each test is named after the kind and the path, like `functionlike_custom_assert`, with `_2`,
+/// `_3`, and so on added if the same macro has more than one entry. It fails if nothing matches
+/// the pattern or the expansion fails, with the error as its message. The crate has to depend on
+/// `runtime-macros`, usually as a dev-dependency.
+///
+/// [`testable`]: attr.testable.html
+///
+/// # Example
+///
+/// ```ignore
+/// #[cfg(test)]
+/// mod tests {
+///     runtime_macros::coverage_tests! {
+///         functionlike custom_assert => super::custom_assert_internal in "tests/**/*.rs";
+///         derive Builder => super::builder_internal in "tests/builder.rs";
+///     }
+/// }
+/// ```
+#[proc_macro]
+pub fn coverage_tests(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    coverage_tests_internal(input.into()).into()
+}
+
+// One macro to write a test for.
+struct CoverageTest {
+    kind: Ident,
+    path: syn::Path,
+    function: Expr,
+    pattern: LitStr,
+}
+
+impl Parse for CoverageTest {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let kind: Ident = input.parse()?;
+        if !["functionlike", "derive", "attribute"]
+            .iter()
+            .any(|k| kind == k)
+        {
+            return Err(syn::Error::new(
+                kind.span(),
+                "expected `functionlike`, `derive`, or `attribute`",
+            ));
+        }
+        let path = input.call(syn::Path::parse_mod_style)?;
+        input.parse::<Token![=>]>()?;
+        let function = input.parse()?;
+        input.parse::<Token![in]>()?;
+        let pattern = input.parse()?;
+        Ok(CoverageTest {
+            kind,
+            path,
+            function,
+            pattern,
+        })
+    }
+}
+
+fn coverage_tests_internal(input: TokenStream) -> TokenStream {
+    let parser = Punctuated::<CoverageTest, Token![;]>::parse_terminated;
+    let tests = match syn::parse::Parser::parse2(parser, input) {
+        Ok(tests) => tests,
+        Err(e) => return e.into_compile_error(),
+    };
+    let mut seen = HashMap::new();
+    tests
+        .iter()
+        .map(|test| {
+            let CoverageTest {
+                ref kind,
+                ref path,
+                ref function,
+                ref pattern,
+            } = *test;
+            let segments: Vec<String> = path
+                .segments
+                .iter()
+                .map(|segment| segment.ident.to_string())
+                .collect();
+            // The same macro can be tested on more than one pattern.
+            let mut name = format!("{}_{}", kind, segments.join("_"));
+            let count = seen.entry(name.clone()).or_insert(0);
+            *count += 1;
+            if *count > 1 {
+                name = format!("{}_{}", name, count);
+            }
+            let name = Ident::new(&name, kind.span());
+            let macro_path = segments.join("::");
+            let pattern = if pattern.value().starts_with('/') {
+                quote!(#pattern)
+            } else {
+                quote!(concat!(env!("CARGO_MANIFEST_DIR"), "/", #pattern))
+            };
+            quote! {
+                #[test]
+                #[allow(non_snake_case)]
+                fn #name() {
+                    let result = runtime_macros::Expander::new()
+                        .#kind(#macro_path, #function)
+                        .glob(#pattern)
+                        .and_then(|expander| expander.run());
+                    if let ::std::result::Result::Err(error) = result {
+                        panic!("{}", error);
+                    }
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_the_function() {
+        let output = testable_internal(
+            TokenStream::new(),
+            quote! {
+                /// Does nothing.
+                #[proc_macro_attribute]
+                #[inline]
+                pub fn nothing(attr: TokenStream, item: TokenStream) -> TokenStream {
+                    item
+                }
+            },
+        );
+        let expected = quote! {
+            /// Does nothing.
+            #[proc_macro_attribute]
+            pub fn nothing(
+                __arg0: ::proc_macro::TokenStream,
+                __arg1: ::proc_macro::TokenStream
+            ) -> ::proc_macro::TokenStream {
+                ::std::convert::From::from(nothing_internal(
+                    ::std::convert::From::from(__arg0),
+                    ::std::convert::From::from(__arg1)
+                ))
+            }
+
+            #[inline]
+            pub(crate) fn nothing_internal(attr: TokenStream, item: TokenStream) -> TokenStream {
+                item
+            }
+        };
+        assert_eq!(output.to_string(), expected.to_string());
+    }
+
+    #[test]
+    fn requires_a_macro_attribute() {
+        let output = testable_internal(
+            TokenStream::new(),
+            quote!(
+                fn f(ts: TokenStream) {}
+            ),
+        );
+        assert!(output.to_string().starts_with(":: core :: compile_error !"));
+    }
+
+    #[test]
+    fn writes_coverage_tests() {
+        let output = coverage_tests_internal(quote! {
+            functionlike custom_assert => custom_assert_internal in "tests/**/*.rs";
+            derive foo::Bar => bar in "/abs.rs";
+        });
+        let expected = quote! {
+            #[test]
+            #[allow(non_snake_case)]
+            fn functionlike_custom_assert() {
+                let result = runtime_macros::Expander::new()
+                    .functionlike("custom_assert", custom_assert_internal)
+                    .glob(concat!(env!("CARGO_MANIFEST_DIR"), "/", "tests/**/*.rs"))
+                    .and_then(|expander| expander.run());
+                if let ::std::result::Result::Err(error) = result {
+                    panic!("{}", error);
+                }
+            }
+            #[test]
+            #[allow(non_snake_case)]
+            fn derive_foo_Bar() {
+                let result = runtime_macros::Expander::new()
+                    .derive("foo::Bar", bar)
+                    .glob("/abs.rs")
+                    .and_then(|expander| expander.run());
+                if let ::std::result::Result::Err(error) = result {
+                    panic!("{}", error);
+                }
+            }
+        };
+        assert_eq!(output.to_string(), expected.to_string());
+
+        let output = coverage_tests_internal(quote!(macro_rules foo => bar in "x.rs"));
+        assert!(output.to_string().contains("expected `functionlike`"));
+    }
+}
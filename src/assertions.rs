@@ -0,0 +1,361 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! The functions behind the assertion macros, such as [`assert_expansion_eq!`]. They're public
+//! only so the macros can call them.
+//!
+//! [`assert_expansion_eq!`]: ../macro.assert_expansion_eq.html
+
+use {
+    proc_macro2::TokenStream,
+    report::{ExpansionPanic, ExpansionRecord, ExpansionReport},
+    tokens, Error,
+};
+
+/// Anything the assertion macros can check: an [`ExpansionReport`], the records returned by the
+/// `*_collect` functions, or a `Result` containing either of them.
+///
+/// [`ExpansionReport`]: ../struct.ExpansionReport.html
+pub trait Expansions {
+    /// Returns every expansion and every recorded panic, or the error that ended the run.
+    fn expansions(&self) -> Result<(Vec<&ExpansionRecord>, Vec<&ExpansionPanic>), &Error>;
+}
+
+impl Expansions for ExpansionReport {
+    fn expansions(&self) -> Result<(Vec<&ExpansionRecord>, Vec<&ExpansionPanic>), &Error> {
+        Ok((
+            self.files
+                .iter()
+                .flat_map(|file| file.records.iter())
+                .collect(),
+            self.files
+                .iter()
+                .flat_map(|file| file.panics.iter())
+                .collect(),
+        ))
+    }
+}
+
+impl Expansions for [ExpansionRecord] {
+    fn expansions(&self) -> Result<(Vec<&ExpansionRecord>, Vec<&ExpansionPanic>), &Error> {
+        Ok((self.iter().collect(), Vec::new()))
+    }
+}
+
+impl Expansions for Vec<ExpansionRecord> {
+    fn expansions(&self) -> Result<(Vec<&ExpansionRecord>, Vec<&ExpansionPanic>), &Error> {
+        self[..].expansions()
+    }
+}
+
+impl<T: Expansions> Expansions for Result<T, Error> {
+    fn expansions(&self) -> Result<(Vec<&ExpansionRecord>, Vec<&ExpansionPanic>), &Error> {
+        match self {
+            Ok(expansions) => expansions.expansions(),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl<T: Expansions + ?Sized> Expansions for &T {
+    fn expansions(&self) -> Result<(Vec<&ExpansionRecord>, Vec<&ExpansionPanic>), &Error> {
+        (**self).expansions()
+    }
+}
+
+// Returns every expansion, or panics if the run failed or any macro function panicked.
+#[track_caller]
+fn succeeded<E: Expansions + ?Sized>(expansions: &E) -> Vec<&ExpansionRecord> {
+    match expansions.expansions() {
+        Ok((records, panics)) => {
+            if let Some(panic) = panics.first() {
+                panic!("expected every expansion to succeed, but {}", panic);
+            }
+            records
+        }
+        Err(error) => panic!("expected every expansion to succeed, but {}", error),
+    }
+}
+
+// Returns every expansion of the given macro.
+#[track_caller]
+fn expansions_of<'e, E: Expansions + ?Sized>(
+    expansions: &'e E,
+    macro_path: &str,
+) -> Vec<&'e ExpansionRecord> {
+    let records: Vec<_> = succeeded(expansions)
+        .into_iter()
+        .filter(|record| record.macro_path == macro_path)
+        .collect();
+    if records.is_empty() {
+        panic!("expected `{}` to be expanded, but it wasn't", macro_path);
+    }
+    records
+}
+
+#[track_caller]
+fn parse(tokens: &str) -> TokenStream {
+    match tokens.parse() {
+        Ok(tokens) => tokens,
+        Err(e) => panic!("`{}` isn't a valid token stream: {}", tokens, e),
+    }
+}
+
+/// See [`assert_expands_ok!`](../macro.assert_expands_ok.html).
+#[track_caller]
+pub fn expands_ok<E: Expansions + ?Sized>(expansions: &E) {
+    succeeded(expansions);
+}
+
+/// See [`assert_expansion_contains!`](../macro.assert_expansion_contains.html).
+#[track_caller]
+pub fn expansion_contains<E: Expansions + ?Sized>(expansions: &E, macro_path: &str, needle: &str) {
+    let needle = parse(needle);
+    let records = expansions_of(expansions, macro_path);
+    if !records
+        .iter()
+        .any(|record| tokens::contains(&record.output, &needle))
+    {
+        let outputs: Vec<String> = records
+            .iter()
+            .map(|record| record.pretty_output())
+            .collect();
+        panic!(
+            "expected an expansion of `{}` to contain `{}`, but the expansions were:\n{}",
+            macro_path,
+            needle,
+            outputs.join("\n")
+        );
+    }
+}
+
+/// See [`assert_expansion_eq!`](../macro.assert_expansion_eq.html).
+#[track_caller]
+pub fn expansion_eq<E: Expansions + ?Sized>(expansions: &E, macro_path: &str, expected: &str) {
+    let expected = parse(expected);
+    let records = expansions_of(expansions, macro_path);
+    if records.len() > 1 {
+        panic!(
+            "expected `{}` to be expanded once, but it was expanded {} times",
+            macro_path,
+            records.len()
+        );
+    }
+    let diff = tokens::diff(&expected, &records[0].output);
+    if !diff.is_empty() {
+        panic!(
+            "the expansion of `{}` doesn't match the expected tokens:\n{}\n\nthe whole expansion:\n{}",
+            macro_path,
+            diff,
+            records[0].pretty_output()
+        );
+    }
+}
+
+/// See [`assert_expansion_panics!`](../macro.assert_expansion_panics.html).
+#[track_caller]
+pub fn expansion_panics<E: Expansions + ?Sized>(expansions: &E, message: Option<&str>) {
+    let panics: Vec<&ExpansionPanic> = match expansions.expansions() {
+        Ok((_, panics)) => panics,
+        Err(Error::ExpansionPanicked { panics, .. }) => panics.iter().collect(),
+        Err(error) => panic!("expected a macro function to panic, but {}", error),
+    };
+    if panics.is_empty() {
+        panic!("expected a macro function to panic, but none did");
+    }
+    if let Some(message) = message {
+        if !panics.iter().any(|panic| panic.message.contains(message)) {
+            let messages: Vec<&str> = panics.iter().map(|panic| panic.message.as_str()).collect();
+            panic!(
+                "expected a macro function to panic with a message containing `{}`, but the messages were: {:?}",
+                message, messages
+            );
+        }
+    }
+}
+
+/// See [`assert_compile_error_matches!`](../macro.assert_compile_error_matches.html).
+#[track_caller]
+pub fn compile_error_matches<E: Expansions + ?Sized>(expansions: &E, pattern: &str) {
+    let messages: Vec<String> = match expansions.expansions() {
+        Ok((records, _)) => records
+            .iter()
+            .flat_map(|record| record.compile_errors())
+            .collect(),
+        Err(Error::MacroFailed { error, .. }) => vec![error.to_string()],
+        Err(error) => panic!("expected a `compile_error!` in an expansion, but {}", error),
+    };
+    if !messages.iter().any(|message| message.contains(pattern)) {
+        panic!(
+            "expected a `compile_error!` containing `{}`, but the errors were: {:?}",
+            pattern, messages
+        );
+    }
+}
+
+/// Asserts that every macro function returned successfully, without panicking or returning an
+/// error. The argument can be an [`ExpansionReport`], the records returned by one of the
+/// `*_collect` functions, or a `Result` containing either of them, as returned by the `emulate_*`
+/// functions.
+///
+/// [`ExpansionReport`]: struct.ExpansionReport.html
+///
+/// # Example
+///
+/// ```
+/// # #[macro_use] extern crate runtime_macros;
+/// # use runtime_macros::{MacroRegistry, ParsedSource, Session};
+/// # fn main() {
+/// let mut session = Session::new();
+/// session.source(ParsedSource::parse("fn main() { double!(1); }".to_owned(), None).unwrap());
+/// let report = session.run(MacroRegistry::new().functionlike("double", |ts| {
+///     quote::quote!(#ts * 2)
+/// }));
+///
+/// assert_expands_ok!(report);
+/// assert_expansion_eq!(report, "double", "1 * 2");
+/// assert_expansion_contains!(report, "double", "* 2");
+/// # }
+/// ```
+#[macro_export]
+macro_rules! assert_expands_ok {
+    ($expansions:expr) => {
+        $crate::assertions::expands_ok(&$expansions)
+    };
+}
+
+/// Asserts that at least one expansion of the given macro contains the given tokens, as described
+/// in [`tokens::contains`]. The expansions can be given in any of the forms that
+/// [`assert_expands_ok!`] accepts, and it's also an assertion failure if any expansion failed.
+///
+/// [`tokens::contains`]: tokens/fn.contains.html
+/// [`assert_expands_ok!`]: macro.assert_expands_ok.html
+#[macro_export]
+macro_rules! assert_expansion_contains {
+    ($expansions:expr, $macro_path:expr, $tokens:expr) => {
+        $crate::assertions::expansion_contains(&$expansions, $macro_path, $tokens)
+    };
+}
+
+/// Asserts that the given macro was expanded exactly once and that its output is the same as the
+/// given tokens, after both are normalized as described in [`tokens::token_eq`]. If they differ,
+/// the failure message shows a [`TokenDiff`]. The expansions can be given in any of the forms that
+/// [`assert_expands_ok!`] accepts, and it's also an assertion failure if any expansion failed.
+///
+/// [`tokens::token_eq`]: tokens/fn.token_eq.html
+/// [`TokenDiff`]: tokens/struct.TokenDiff.html
+/// [`assert_expands_ok!`]: macro.assert_expands_ok.html
+#[macro_export]
+macro_rules! assert_expansion_eq {
+    ($expansions:expr, $macro_path:expr, $tokens:expr) => {
+        $crate::assertions::expansion_eq(&$expansions, $macro_path, $tokens)
+    };
+}
+
+/// Asserts that at least one macro function panicked, optionally with a message containing the
+/// given string. The expansions can be given in any of the forms that [`assert_expands_ok!`]
+/// accepts. The panics can either be recorded in an [`ExpansionReport`], because of the
+/// [`PanicPolicy`], or come from an [`Error::ExpansionPanicked`].
+///
+/// [`assert_expands_ok!`]: macro.assert_expands_ok.html
+/// [`ExpansionReport`]: struct.ExpansionReport.html
+/// [`PanicPolicy`]: enum.PanicPolicy.html
+/// [`Error::ExpansionPanicked`]: enum.Error.html#variant.ExpansionPanicked
+#[macro_export]
+macro_rules! assert_expansion_panics {
+    ($expansions:expr) => {
+        $crate::assertions::expansion_panics(&$expansions, None)
+    };
+    ($expansions:expr, $message:expr) => {
+        $crate::assertions::expansion_panics(&$expansions, Some($message))
+    };
+}
+
+/// Asserts that at least one expansion reported an error containing the given string through
+/// `compile_error!`, as a compile-fail test would. The expansions can be given in any of the forms
+/// that [`assert_expands_ok!`] accepts, such as the result of scanning a fixture file with
+/// [`Expander::run`]. An [`Error::MacroFailed`] from a fallible macro function counts too, since
+/// its error would have become a `compile_error!`.
+///
+/// [`assert_expands_ok!`]: macro.assert_expands_ok.html
+/// [`Expander::run`]: struct.Expander.html#method.run
+/// [`Error::MacroFailed`]: enum.Error.html#variant.MacroFailed
+#[macro_export]
+macro_rules! assert_compile_error_matches {
+    ($expansions:expr, $pattern:expr) => {
+        $crate::assertions::compile_error_matches(&$expansions, $pattern)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        proc_macro2::TokenStream,
+        std::panic::{self, AssertUnwindSafe},
+        MacroRegistry, ParsedSource, Session,
+    };
+
+    fn fails<F: FnOnce()>(f: F) -> bool {
+        panic::catch_unwind(AssertUnwindSafe(f)).is_err()
+    }
+
+    #[test]
+    fn asserts_on_expansions() {
+        let mut session = Session::new();
+        session.source(
+            ParsedSource::parse("fn main() { foo!(1); bar!(2); bar!(3); }".to_owned(), None)
+                .unwrap(),
+        );
+        let report = session.run(
+            MacroRegistry::new()
+                .functionlike("foo", |ts: TokenStream| quote::quote!(vec![#ts]))
+                .functionlike("bar", |ts| ts),
+        );
+
+        assert_expands_ok!(report);
+        assert_expansion_eq!(report, "foo", "vec ! [1]");
+        assert_expansion_contains!(report, "bar", "3");
+        assert!(fails(|| assert_expansion_eq!(report, "foo", "vec![2]")));
+        assert!(fails(|| assert_expansion_eq!(report, "bar", "2")));
+        assert!(fails(|| assert_expansion_contains!(report, "bar", "4")));
+        assert!(fails(|| assert_expansion_eq!(report, "baz", "")));
+        assert!(fails(|| assert_expansion_panics!(report)));
+
+        let panicked = session.run(MacroRegistry::new().functionlike("foo", |_| panic!("no")));
+        assert_expansion_panics!(panicked, "no");
+        assert!(fails(|| assert_expansion_panics!(panicked, "yes")));
+        assert!(fails(|| assert_expands_ok!(panicked)));
+    }
+
+    #[test]
+    fn asserts_on_compile_errors() {
+        let mut session = Session::new();
+        session.source(
+            ParsedSource::parse("fn main() { foo!(1); foo!(2); }".to_owned(), None).unwrap(),
+        );
+        let report = session.run(MacroRegistry::new().functionlike("foo", |ts: TokenStream| {
+            if ts.to_string() == "2" {
+                quote::quote!(compile_error!("two isn't allowed"))
+            } else {
+                ts
+            }
+        }));
+        assert_compile_error_matches!(report, "isn't allowed");
+        assert!(fails(|| assert_compile_error_matches!(report, "one")));
+
+        let failed = session.run(
+            MacroRegistry::new()
+                .functionlike_fallible("foo", |ts| Err(syn::Error::new_spanned(ts, "bad input"))),
+        );
+        assert_compile_error_matches!(failed, "bad input");
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Helpers for benchmarking macro functions with the inputs they're given in real code.
+//!
+//! Scanning a crate's tests with an [`Expander`] records the input of each invocation, and
+//! [`inputs`] and [`attribute_inputs`] turn the report into named inputs for a benchmark, so a
+//! regression in how long a macro takes to expand shows up on the code that actually uses it.
+//! [`measure`] is a small harness for timing a macro function on them without any other crates,
+//! but the inputs work just as well with a benchmarking library. With [criterion], for instance:
+//!
+//! ```ignore
+//! use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+//! use runtime_macros::{bench, Expander, MacroKind};
+//!
+//! fn expansion(c: &mut Criterion) {
+//!     let report = Expander::new()
+//!         .functionlike("custom_assert", custom_assert_internal)
+//!         .file("tests/tests.rs")
+//!         .run()
+//!         .unwrap();
+//!     for (name, input) in bench::inputs(&report, MacroKind::Functionlike, "custom_assert") {
+//!         c.bench_function(&name, |b| {
+//!             b.iter_batched(|| input.clone(), custom_assert_internal, BatchSize::SmallInput)
+//!         });
+//!     }
+//! }
+//!
+//! criterion_group!(benches, expansion);
+//! criterion_main!(benches);
+//! ```
+//!
+//! [`Expander`]: ../struct.Expander.html
+//! [`inputs`]: fn.inputs.html
+//! [`attribute_inputs`]: fn.attribute_inputs.html
+//! [`measure`]: fn.measure.html
+//! [criterion]: https://docs.rs/criterion
+
+use {
+    proc_macro2::TokenStream,
+    report::{ExpansionRecord, ExpansionReport, MacroKind},
+    std::{
+        collections::HashMap,
+        hint,
+        time::{Duration, Instant},
+    },
+};
+
+/// Returns the input of each expansion of the macro of the given kind that was registered as
+/// `macro_path`, named after where it was invoked, like `tests/tests.rs:12:4`. If more than one
+/// expansion has the same location, as nested expansions can, the later ones have `#2`, `#3`,
+/// and so on added to their names, so every name is unique. For attribute-like macros, this is
+/// only the item the attribute was on; see [`attribute_inputs`] for the attribute's own tokens.
+///
+/// Only invocations that expanded are included, since there's no point in timing a panic.
+///
+/// [`attribute_inputs`]: fn.attribute_inputs.html
+///
+/// # Example
+///
+/// ```
+/// # extern crate proc_macro2;
+/// # extern crate runtime_macros;
+/// # use runtime_macros::{bench, Expander, MacroKind};
+/// # fn custom_assert_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+/// let report = Expander::new()
+///     .functionlike("custom_assert", custom_assert_internal)
+///     # .file(concat!(env!("CARGO_MANIFEST_DIR"), "/src/lib.rs"))
+///     # /*
+///     .file("tests/tests.rs")
+///     # */
+///     .run()
+///     .unwrap();
+/// for (name, input) in bench::inputs(&report, MacroKind::Functionlike, "custom_assert") {
+///     println!("{}: {}", name, input);
+/// }
+/// ```
+pub fn inputs<'r>(
+    report: &'r ExpansionReport,
+    kind: MacroKind,
+    macro_path: &'r str,
+) -> impl Iterator<Item = (String, TokenStream)> + 'r {
+    named_records(report, kind, macro_path).map(|(name, record)| (name, record.input.clone()))
+}
+
+/// Returns the attribute and the item of each expansion of the attribute-like macro that was
+/// registered as `macro_path`, named the same way as in [`inputs`].
+///
+/// [`inputs`]: fn.inputs.html
+pub fn attribute_inputs<'r>(
+    report: &'r ExpansionReport,
+    macro_path: &'r str,
+) -> impl Iterator<Item = (String, TokenStream, TokenStream)> + 'r {
+    named_records(report, MacroKind::Attribute, macro_path).map(|(name, record)| {
+        let attr = record.attr.clone().unwrap_or_default();
+        (name, attr, record.input.clone())
+    })
+}
+
+// Returns the records of the given macro's expansions, each with a unique name.
+fn named_records<'r>(
+    report: &'r ExpansionReport,
+    kind: MacroKind,
+    macro_path: &'r str,
+) -> impl Iterator<Item = (String, &'r ExpansionRecord)> + 'r {
+    let mut seen = HashMap::new();
+    report.files.iter().flat_map(move |file| {
+        let path = file.path.display().to_string();
+        let records = file
+            .records
+            .iter()
+            .filter(|record| record.kind == kind && record.macro_path == macro_path);
+        let mut names = Vec::new();
+        for record in records {
+            let location = format!("{}:{}:{}", path, record.start.line, record.start.column);
+            let count = seen.entry(location.clone()).or_insert(0);
+            *count += 1;
+            let name = match *count {
+                1 => location,
+                n => format!("{}#{}", location, n),
+            };
+            names.push((name, record));
+        }
+        names
+    })
+}
+
+/// Calls the function on each input `iterations` times and returns the average time each call
+/// took, along with the input's name. The inputs are cloned before each call, and the clones
+/// aren't timed. The outputs are passed through [`std::hint::black_box`] so the calls can't be
+/// optimized away.
+///
+/// This is a rough measurement for finding the inputs that take the longest or for a quick
+/// comparison between two versions of a macro. A benchmarking library will give more reliable
+/// numbers.
+///
+/// [`std::hint::black_box`]: https://doc.rust-lang.org/std/hint/fn.black_box.html
+///
+/// # Panics
+///
+/// Panics if `iterations` is 0.
+pub fn measure<I, F, R>(inputs: I, iterations: u32, mut f: F) -> Vec<(String, Duration)>
+where
+    I: IntoIterator<Item = (String, TokenStream)>,
+    F: FnMut(TokenStream) -> R,
+{
+    assert!(iterations > 0, "can't measure zero iterations");
+    inputs
+        .into_iter()
+        .map(|(name, input)| {
+            let mut total = Duration::ZERO;
+            for _ in 0..iterations {
+                let input = input.clone();
+                let start = Instant::now();
+                hint::black_box(f(hint::black_box(input)));
+                total += start.elapsed();
+            }
+            (name, total / iterations)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::path::PathBuf, MacroRegistry};
+
+    #[test]
+    fn names_each_input() {
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", |ts| ts)
+            .functionlike("bar", |ts| ts)
+            .attribute("foo", |_, ts| ts);
+        let file = registry
+            .expand_source("foo!(1);\nbar!(2);\n#[foo(3)] fn f() { foo!(4); }", None)
+            .unwrap()
+            .into_report(PathBuf::from("a.rs"));
+        let report = registry.report(vec![file]).unwrap();
+
+        let functionlike: Vec<(String, String)> = inputs(&report, MacroKind::Functionlike, "foo")
+            .map(|(name, input)| (name, input.to_string()))
+            .collect();
+        assert_eq!(
+            functionlike,
+            [
+                ("a.rs:1:0".to_owned(), "1".to_owned()),
+                ("a.rs:3:19".to_owned(), "4".to_owned()),
+            ]
+        );
+        let attributes: Vec<(String, String)> = attribute_inputs(&report, "foo")
+            .map(|(name, attr, _)| (name, attr.to_string()))
+            .collect();
+        assert_eq!(attributes, [("a.rs:3:0".to_owned(), "3".to_owned())]);
+
+        let times = measure(inputs(&report, MacroKind::Functionlike, "bar"), 3, |ts| ts);
+        assert_eq!(times.len(), 1);
+        assert_eq!(times[0].0, "a.rs:2:0");
+    }
+}
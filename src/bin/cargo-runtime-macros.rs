@@ -0,0 +1,22 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Expands the macros in a procedural macro package's tests and examples and prints how many times
+//! each one was invoked. Run it as `cargo runtime-macros --help` for details.
+
+extern crate runtime_macros;
+
+use std::{env, process};
+
+fn main() {
+    process::exit(runtime_macros::cli::cargo_main(env::args().skip(1)));
+}
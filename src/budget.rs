@@ -0,0 +1,116 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Limits on the size of the code that each macro invocation expands to.
+
+use {proc_macro2::TokenStream, tokens};
+
+/// Limits on how much code a macro can generate for each invocation, for
+/// [`MacroRegistry::budget`]. Without any limits, which is the default, every expansion is within
+/// budget.
+///
+/// Sizes are counted in tokens, including the delimiters of each group and the tokens inside it, so
+/// `foo(1, 2)` has six.
+///
+/// [`MacroRegistry::budget`]: struct.MacroRegistry.html#method.budget
+///
+/// # Example
+///
+/// ```
+/// # use runtime_macros::Budget;
+/// // At most 2000 tokens, and no more than 20 for each token of input.
+/// let budget = Budget::new().max_tokens(2000).max_ratio(20.0);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Budget {
+    max_tokens: Option<usize>,
+    max_ratio: Option<f64>,
+    deny: bool,
+}
+
+impl Budget {
+    /// Creates a budget with no limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the most tokens that an expansion can have.
+    pub fn max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Sets the most tokens that an expansion can have for each token of the macro's input: the
+    /// tokens inside the call for a function-like macro, or the annotated item for a derive or an
+    /// attribute-like macro.
+    pub fn max_ratio(mut self, max_ratio: f64) -> Self {
+        self.max_ratio = Some(max_ratio);
+        self
+    }
+
+    /// Sets whether an expansion that's over budget should make the whole run fail, instead of
+    /// only being listed in the report's warnings. This is disabled by default.
+    pub fn deny(mut self, enabled: bool) -> Self {
+        self.deny = enabled;
+        self
+    }
+
+    pub(crate) fn denies(&self) -> bool {
+        self.deny
+    }
+
+    // Returns a message saying how the expansion went over budget, if it did.
+    pub(crate) fn check(&self, input: &TokenStream, output: &TokenStream) -> Option<String> {
+        let output_len = tokens::count(output);
+        if let Some(max_tokens) = self.max_tokens {
+            if output_len > max_tokens {
+                return Some(format!(
+                    "the expansion has {} tokens, which is more than the budget of {}",
+                    output_len, max_tokens
+                ));
+            }
+        }
+        if let Some(max_ratio) = self.max_ratio {
+            // An empty input still gets some room.
+            let input_len = tokens::count(input).max(1);
+            if output_len as f64 > input_len as f64 * max_ratio {
+                return Some(format!(
+                    "the expansion has {} tokens for {} tokens of input, which is more than {} times as many",
+                    output_len, input_len, max_ratio
+                ));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_limits() {
+        let tokens = |s: &str| s.parse::<TokenStream>().unwrap();
+        let (input, output) = (tokens("a"), tokens("fn a() {}"));
+        assert_eq!(Budget::new().check(&input, &output), None);
+        assert_eq!(Budget::new().max_tokens(6).check(&input, &output), None);
+        assert_eq!(
+            Budget::new().max_tokens(5).check(&input, &output).unwrap(),
+            "the expansion has 6 tokens, which is more than the budget of 5"
+        );
+        assert_eq!(
+            Budget::new().max_ratio(2.5).check(&input, &output).unwrap(),
+            "the expansion has 6 tokens for 1 tokens of input, which is more than 2.5 times as many"
+        );
+        assert_eq!(Budget::new().max_ratio(6.0).check(&input, &output), None);
+    }
+}
@@ -0,0 +1,291 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! An on-disk cache of the files whose macros were all expanded successfully, so they can be
+//! skipped on later runs until they or the registered macros change.
+
+use {
+    report::{FileReport, MacroKind},
+    std::{
+        collections::{hash_map::DefaultHasher, BTreeMap},
+        env, fs,
+        hash::{Hash, Hasher},
+        path::{Path, PathBuf},
+    },
+    Error, MacroRegistry,
+};
+
+// A cache directory, along with a fingerprint of the registry whose results it holds.
+pub(crate) struct Cache<'c> {
+    dir: &'c Path,
+    fingerprint: u64,
+}
+
+impl<'c> Cache<'c> {
+    pub(crate) fn new(dir: &'c Path, registry: &MacroRegistry) -> Self {
+        Cache {
+            dir,
+            fingerprint: fingerprint(registry),
+        }
+    }
+
+    // Returns the invocation counts that were stored for a file with the given contents, or `None`
+    // if there's no usable entry. An entry can't be used if any of the modules' files that were
+    // given to its attribute-like macros have changed since.
+    pub(crate) fn load(&self, content: &str) -> Option<BTreeMap<(MacroKind, String), usize>> {
+        let entry = fs::read_to_string(self.entry_path(content)).ok()?;
+        let mut counts = BTreeMap::new();
+        for line in entry.lines() {
+            let mut fields = line.splitn(3, '\t');
+            let kind = match fields.next()? {
+                "functionlike" => MacroKind::Functionlike,
+                "derive" => MacroKind::Derive,
+                "attribute" => MacroKind::Attribute,
+                "module" => {
+                    let hash = fields.next()?;
+                    let module = fs::read_to_string(fields.next()?).ok()?;
+                    if hash != format!("{:016x}", hash_contents(&module)) {
+                        return None;
+                    }
+                    continue;
+                }
+                _ => return None,
+            };
+            let count = fields.next()?.parse().ok()?;
+            let macro_path = fields.next()?;
+            counts.insert((kind, macro_path.to_owned()), count);
+        }
+        Some(counts)
+    }
+
+    // Remembers that a file with the given contents was expanded successfully, along with the paths
+    // and contents of the modules' files that were loaded for it.
+    pub(crate) fn store(
+        &self,
+        content: &str,
+        modules: &[(PathBuf, String)],
+        report: &FileReport,
+    ) -> Result<(), Error> {
+        let path = self.entry_path(content);
+        let mut entry = String::new();
+        for (module, module_content) in modules {
+            let hash = hash_contents(module_content);
+            entry.push_str(&format!("module\t{:016x}\t{}\n", hash, module.display()));
+        }
+        for ((kind, macro_path), count) in report.invocation_counts() {
+            let kind = match kind {
+                MacroKind::Functionlike => "functionlike",
+                MacroKind::Derive => "derive",
+                MacroKind::Attribute => "attribute",
+            };
+            entry.push_str(&format!("{}\t{}\t{}\n", kind, count, macro_path));
+        }
+        fs::create_dir_all(self.dir)
+            .and_then(|()| fs::write(&path, entry))
+            .map_err(|error| Error::IoError {
+                path: Some(path),
+                error,
+            })
+    }
+
+    fn entry_path(&self, content: &str) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        self.fingerprint.hash(&mut hasher);
+        content.hash(&mut hasher);
+        self.dir.join(format!("{:016x}", hasher.finish()))
+    }
+}
+
+fn hash_contents(content: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+// Hashes everything that could change the results of scanning a file, other than the file itself.
+// The macro functions can't be hashed directly, but they're compiled into the running executable,
+// so a rebuild changes its size or modification time.
+fn fingerprint(registry: &MacroRegistry) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+    for (path, _) in registry.functionlike.iter() {
+        (MacroKind::Functionlike, path).hash(&mut hasher);
+    }
+    for (path, _) in registry.derive.iter() {
+        (MacroKind::Derive, path).hash(&mut hasher);
+    }
+    for (path, _) in registry.attribute.iter() {
+        (MacroKind::Attribute, path).hash(&mut hasher);
+    }
+    registry.hooks.len().hash(&mut hasher);
+    for (name, _) in registry.lints.iter() {
+        name.hash(&mut hasher);
+    }
+    for (kind, path, _) in registry.references.iter() {
+        (kind, path).hash(&mut hasher);
+    }
+    format!("{:?}", registry.options).hash(&mut hasher);
+    if let Ok(metadata) = env::current_exe().and_then(fs::metadata) {
+        metadata.len().hash(&mut hasher);
+        metadata.modified().ok().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        proc_macro2::TokenStream,
+        std::{fs, process},
+        Expander,
+    };
+
+    fn identity(ts: TokenStream) -> TokenStream {
+        ts
+    }
+
+    #[test]
+    fn skips_unchanged_files() {
+        let dir = env::temp_dir().join(format!("runtime-macros-cache-test-{}", process::id()));
+        let file = dir.join("source.rs");
+        let cache = dir.join("cache");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&file, "fn main() { foo!(); foo!(); }").unwrap();
+
+        let expander = || {
+            Expander::new()
+                .functionlike("foo", identity)
+                .file(&file)
+                .cache_dir(&cache)
+        };
+        let first = expander().run().unwrap();
+        assert!(!first.files[0].cached);
+        assert_eq!(first.expansions(), 2);
+
+        let second = expander().require_all_invoked(true).run().unwrap();
+        assert!(
+            !second.files[0].cached,
+            "changing the options should invalidate the cache"
+        );
+        let linted = expander().lint("never", |_, _| Ok(())).run().unwrap();
+        assert!(
+            !linted.files[0].cached,
+            "adding a lint should invalidate the cache"
+        );
+        let compared = expander()
+            .compare_functionlike("foo", identity)
+            .run()
+            .unwrap();
+        assert!(
+            !compared.files[0].cached,
+            "adding a reference implementation should invalidate the cache"
+        );
+        let third = expander().run().unwrap();
+        assert!(third.files[0].cached);
+        assert_eq!(third.expansions(), 0);
+        assert_eq!(third.invocations("foo"), 2);
+
+        let forced = expander().refresh_cache(true).run().unwrap();
+        assert!(!forced.files[0].cached);
+        assert_eq!(forced.expansions(), 2);
+
+        fs::write(&file, "fn main() { foo!(); }").unwrap();
+        let changed = expander().run().unwrap();
+        assert!(!changed.files[0].cached);
+        assert_eq!(changed.invocations("foo"), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn notices_changes_to_loaded_modules() {
+        let dir = env::temp_dir().join(format!("runtime-macros-cache-modules-{}", process::id()));
+        let (lib, handlers) = (dir.join("lib.rs"), dir.join("handlers.rs"));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&lib, "#[wrap] mod handlers;").unwrap();
+        fs::write(&handlers, "fn a() {}").unwrap();
+
+        let expander = || {
+            Expander::new()
+                .attribute("wrap", |_, item| item)
+                .file(&lib)
+                .follow_modules(true)
+                .cache_dir(dir.join("cache"))
+        };
+        assert!(!expander().run().unwrap().files[0].cached);
+        assert!(expander().run().unwrap().files[0].cached);
+
+        // Only the module's file changes, but the attribute is given its contents.
+        fs::write(&handlers, "fn b() {}").unwrap();
+        let changed = expander().run().unwrap();
+        assert!(!changed.files[0].cached);
+        assert!(changed.files[0].records[0]
+            .input
+            .to_string()
+            .contains("fn b"));
+        assert!(expander().run().unwrap().files[0].cached);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn doesnt_cache_files_with_warnings() {
+        let dir = env::temp_dir().join(format!("runtime-macros-cache-warnings-{}", process::id()));
+        let file = dir.join("source.rs");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&file, "fn main() { foo!(); }").unwrap();
+
+        let expander = || {
+            Expander::new()
+                .functionlike("foo", identity)
+                .lint("always", |context, _| {
+                    Err(syn::Error::new(context.span, "always warns"))
+                })
+                .file(&file)
+                .cache_dir(dir.join("cache"))
+        };
+        for _ in 0..2 {
+            let report = expander().run().unwrap();
+            assert!(!report.files[0].cached);
+            assert_eq!(report.files[0].warnings.len(), 1);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn doesnt_cache_files_whose_snapshots_changed() {
+        let dir = env::temp_dir().join(format!("runtime-macros-cache-snapshots-{}", process::id()));
+        let file = dir.join("source.rs");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&file, "fn main() { foo!(1); }").unwrap();
+
+        let expander = |output: &'static str| {
+            Expander::new()
+                .functionlike("foo", move |_| output.parse().unwrap())
+                .file(&file)
+                .cache_dir(dir.join("cache"))
+                .snapshots(dir.join("snapshots"))
+        };
+        expander("1").run().unwrap();
+        fs::remove_dir_all(dir.join("cache")).unwrap();
+        for _ in 0..2 {
+            match expander("2").run() {
+                Err(Error::SnapshotMismatch(mismatches)) => assert_eq!(mismatches.len(), 1),
+                result => panic!("expected a snapshot mismatch, got {:?}", result.map(|_| ())),
+            }
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+
+//! Evaluation of `cfg` predicates against a user-supplied set of active configuration options.
+
+use {
+    compat::{self, AttrArgs},
+    std::{borrow::Cow, collections::BTreeSet},
+};
+
+/// A set of configuration options to treat as active when evaluating `#[cfg(...)]` and
+/// `#[cfg_attr(...)]` predicates in the scanned source code.
+///
+/// Options come in two forms, just like in Rust: names (e.g. `unix` or `test`) and key-value pairs
+/// (e.g. `feature = "serde"` or `target_os = "linux"`). Any option that hasn't been added to the set
+/// is considered inactive.
+///
+/// # Example
+///
+/// ```
+/// # use runtime_macros::CfgSet;
+/// let cfgs = CfgSet::new()
+///     .name("test")
+///     .feature("serde")
+///     .key_value("target_os", "linux");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CfgSet {
+    names: BTreeSet<String>,
+    key_values: BTreeSet<(String, String)>,
+}
+
+impl CfgSet {
+    /// Creates an empty set, in which every option is inactive.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Marks a name-only option, like `unix` or `test`, as active.
+    pub fn name(mut self, name: &str) -> Self {
+        self.names.insert(name.to_owned());
+        self
+    }
+
+    /// Marks a key-value option, like `target_os = "linux"`, as active. A key can have several
+    /// active values at once.
+    pub fn key_value(mut self, key: &str, value: &str) -> Self {
+        self.key_values.insert((key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Marks a Cargo feature as active. This is shorthand for `key_value("feature", feature)`.
+    pub fn feature(self, feature: &str) -> Self {
+        self.key_value("feature", feature)
+    }
+
+    /// Evaluates a `cfg` predicate, such as `all(unix, feature = "serde")`, against this set.
+    /// Malformed predicates are considered false.
+    pub fn is_active(&self, predicate: &syn::Meta) -> bool {
+        match *predicate {
+            syn::Meta::Path(ref path) => match path.get_ident() {
+                Some(ident) => self.names.contains(&ident.to_string()),
+                None => false,
+            },
+            syn::Meta::NameValue(ref name_value) => {
+                let key = match name_value.path.get_ident() {
+                    Some(ident) => ident.to_string(),
+                    None => return false,
+                };
+                match compat::meta_str(name_value) {
+                    Some(value) => self.key_values.contains(&(key, value.value())),
+                    None => false,
+                }
+            }
+            syn::Meta::List(ref list) => {
+                let predicates = match compat::meta_list(list) {
+                    Ok(predicates) => predicates,
+                    Err(_) => return false,
+                };
+                match list.path.get_ident() {
+                    Some(ident) if ident == "all" => predicates.iter().all(|p| self.is_active(p)),
+                    Some(ident) if ident == "any" => predicates.iter().any(|p| self.is_active(p)),
+                    Some(ident) if ident == "not" && predicates.len() == 1 => {
+                        !self.is_active(&predicates[0])
+                    }
+                    _ => false,
+                }
+            }
+        }
+    }
+}
+
+// Returns true if any of the given attributes is a `#[cfg(predicate)]` whose predicate is false.
+// Malformed `cfg`s are ignored.
+pub(crate) fn is_configured_out(attrs: &[syn::Attribute], cfgs: &CfgSet) -> bool {
+    attrs.iter().any(|attr| match compat::attr_args(attr) {
+        AttrArgs::List(tokens) if compat::attr_path(attr).is_ident("cfg") => {
+            match syn::parse2::<syn::Meta>(tokens) {
+                Ok(predicate) => !cfgs.is_active(&predicate),
+                Err(_) => false,
+            }
+        }
+        _ => false,
+    })
+}
+
+// Replaces each `#[cfg_attr(predicate, attrs...)]` with the attributes inside it, if its predicate
+// is true according to `cfgs` (or unconditionally if `cfgs` is `None`), or removes it otherwise.
+// Nested `cfg_attr`s are unwrapped too. If there are no `cfg_attr`s, the attributes are returned
+// unchanged.
+pub(crate) fn unwrap_cfg_attrs<'a>(
+    attrs: &'a [syn::Attribute],
+    cfgs: Option<&CfgSet>,
+) -> Cow<'a, [syn::Attribute]> {
+    if !attrs
+        .iter()
+        .any(|attr| compat::attr_path(attr).is_ident("cfg_attr"))
+    {
+        return Cow::Borrowed(attrs);
+    }
+
+    let mut unwrapped = Vec::with_capacity(attrs.len());
+    for attr in attrs {
+        unwrap_cfg_attr(attr.clone(), cfgs, &mut unwrapped);
+    }
+    Cow::Owned(unwrapped)
+}
+
+fn unwrap_cfg_attr(attr: syn::Attribute, cfgs: Option<&CfgSet>, out: &mut Vec<syn::Attribute>) {
+    let (predicate, attrs) = match compat::parse_cfg_attr(&attr) {
+        Some(Ok(parsed)) => parsed,
+        None => {
+            out.push(attr);
+            return;
+        }
+        // The compiler would reject this, so leave it alone.
+        Some(Err(_)) => {
+            out.push(attr);
+            return;
+        }
+    };
+
+    if cfgs.is_none_or(|cfgs| cfgs.is_active(&predicate)) {
+        for attr in attrs {
+            unwrap_cfg_attr(attr, cfgs, out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_active(cfgs: &CfgSet, predicate: &str) -> bool {
+        cfgs.is_active(&syn::parse_str(predicate).unwrap())
+    }
+
+    #[test]
+    fn evaluates_predicates() {
+        let cfgs = CfgSet::new().name("unix").feature("serde");
+        assert!(is_active(&cfgs, "unix"));
+        assert!(!is_active(&cfgs, "windows"));
+        assert!(is_active(&cfgs, "feature = \"serde\""));
+        assert!(!is_active(&cfgs, "feature = \"std\""));
+        assert!(is_active(&cfgs, "all(unix, feature = \"serde\")"));
+        assert!(!is_active(&cfgs, "all(unix, windows)"));
+        assert!(is_active(&cfgs, "any(windows, unix)"));
+        assert!(is_active(&cfgs, "not(windows)"));
+        assert!(!is_active(&cfgs, "not(unix, windows)"));
+    }
+}
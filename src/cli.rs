@@ -0,0 +1,735 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! The `runtime-macros` command, which expands the macros in some files with a function from a Rust
+//! file, for quick experiments without writing a test. This requires the `cli` feature.
+//!
+//! A Rust file can't be loaded without being compiled, so the command writes a small Cargo project
+//! that includes the file as a module and then has Cargo build and run it. The project is kept in
+//! the system's temporary directory, so running the command again with the same function only
+//! rebuilds what changed.
+//!
+//! With the experimental `dylib` feature, the function can also come from a compiled library, which
+//! is loaded directly instead.
+
+#[cfg(feature = "dylib")]
+use MacroLibrary;
+use {
+    cargo_metadata::{Dependency, DependencyKind, MetadataCommand, Package, TargetKind},
+    exports::{self, ExportedMacro},
+    report::ExpansionReport,
+    report::MacroKind,
+    std::{
+        collections::{hash_map::DefaultHasher, BTreeMap},
+        env, fs,
+        hash::{Hash, Hasher},
+        io,
+        path::{Path, PathBuf},
+        process::Command,
+    },
+    ConsoleReporter, Error, Expander, MacroRegistry, MatchMode,
+};
+
+const USAGE: &str = "\
+usage: runtime-macros [--json] <kind> <macro> <function file> <function> <file or glob>...
+
+Expands every invocation of <macro> in the matching files by calling <function>, which is defined in
+<function file>, and prints each expansion and a summary. <kind> is `functionlike`, `attribute`, or
+`derive`. The function has to be visible from outside its file, and it can use `proc-macro2`,
+`quote`, and `syn`. With `--json`, the report is printed as JSON instead.
+
+If the command was built with the experimental `dylib` feature, <function file> can also be a
+compiled library (.so, .dylib, or .dll) that exports <function> the way `MacroLibrary` describes.";
+
+// The parsed command line.
+#[derive(Debug, PartialEq)]
+struct Args {
+    json: bool,
+    kind: MacroKind,
+    macro_path: String,
+    function_file: PathBuf,
+    function: String,
+    patterns: Vec<String>,
+}
+
+impl Args {
+    fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Args, String> {
+        let mut json = false;
+        let mut positional = Vec::new();
+        for arg in args {
+            match arg.as_str() {
+                "--json" => json = true,
+                "-h" | "--help" => return Err(String::new()),
+                flag if flag.starts_with("--") => {
+                    return Err(format!("unknown option `{}`", flag));
+                }
+                _ => positional.push(arg),
+            }
+        }
+        if positional.len() < 5 {
+            return Err("expected a kind, a macro, a function file, a function, and files".into());
+        }
+        let mut positional = positional.into_iter();
+        let kind = match positional.next().unwrap().as_str() {
+            "functionlike" => MacroKind::Functionlike,
+            "attribute" => MacroKind::Attribute,
+            "derive" => MacroKind::Derive,
+            kind => return Err(format!("unknown macro kind `{}`", kind)),
+        };
+        let macro_path = positional.next().unwrap();
+        let function_file = PathBuf::from(positional.next().unwrap());
+        if is_library(&function_file) && !cfg!(feature = "dylib") {
+            return Err(
+                "loading a compiled library requires the `dylib` feature, which this command \
+                 wasn't built with"
+                    .into(),
+            );
+        }
+        let function = positional.next().unwrap();
+        for path in [&macro_path, &function] {
+            if syn::parse_str::<syn::Path>(path).is_err() {
+                return Err(format!("`{}` isn't a valid path", path));
+            }
+        }
+        Ok(Args {
+            json,
+            kind,
+            macro_path,
+            function_file,
+            function,
+            patterns: positional.collect(),
+        })
+    }
+}
+
+/// Runs the command with the given arguments, not including the program's name, and returns its
+/// exit code.
+pub fn main<I: IntoIterator<Item = String>>(args: I) -> i32 {
+    let args = match Args::parse(args) {
+        Ok(args) => args,
+        Err(message) => {
+            if !message.is_empty() {
+                eprintln!("error: {}\n", message);
+            }
+            eprintln!("{}", USAGE);
+            return if message.is_empty() { 0 } else { 2 };
+        }
+    };
+    #[cfg(feature = "dylib")]
+    {
+        if is_library(&args.function_file) {
+            return run_library(args);
+        }
+    }
+    let function_file = match fs::canonicalize(&args.function_file) {
+        Ok(path) => path,
+        Err(error) => return fail(&format!("{}: {}", args.function_file.display(), error)),
+    };
+    let key = (&function_file, &args.kind, &args.macro_path, &args.function);
+    let manifest = match write_project(key, &manifest_source(), &main_source(&args, &function_file))
+    {
+        Ok(manifest) => manifest,
+        Err(error) => return fail(&format!("couldn't write the Cargo project: {}", error)),
+    };
+    let mut project_args = Vec::new();
+    if args.json {
+        project_args.push("--json".to_owned());
+    }
+    project_args.extend(args.patterns);
+    run_project(&manifest, &project_args, false)
+}
+
+// Whether the function file is a compiled library instead of Rust source.
+fn is_library(function_file: &Path) -> bool {
+    matches!(
+        function_file.extension().and_then(|ext| ext.to_str()),
+        Some("so" | "dylib" | "dll")
+    )
+}
+
+// Loads the function from a compiled library and runs it without generating a project.
+#[cfg(feature = "dylib")]
+fn run_library(args: Args) -> i32 {
+    // SAFETY: whoever runs the command vouches for the library, as `MacroLibrary::open` describes.
+    let library = match unsafe { MacroLibrary::open(&args.function_file) } {
+        Ok(library) => library,
+        Err(error) => return fail(&error.to_string()),
+    };
+    let (macro_path, function) = (&args.macro_path, &args.function);
+    let expander = match args.kind {
+        MacroKind::Functionlike => {
+            Expander::new().functionlike_dylib(macro_path, &library, function)
+        }
+        MacroKind::Attribute => Expander::new().attribute_dylib(macro_path, &library, function),
+        MacroKind::Derive => Expander::new().derive_dylib(macro_path, &library, function),
+    };
+    let mut run_args = Vec::new();
+    if args.json {
+        run_args.push("--json".to_owned());
+    }
+    run_args.extend(args.patterns);
+    run(expander, run_args)
+}
+
+fn fail(message: &str) -> i32 {
+    eprintln!("error: {}", message);
+    1
+}
+
+// Writes a generated project and returns the path to its manifest. The project's directory is
+// named after the key, so a later run with the same key reuses what was built.
+fn write_project<K: Hash>(key: K, manifest: &str, main: &str) -> io::Result<PathBuf> {
+    let mut hasher = DefaultHasher::new();
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+    key.hash(&mut hasher);
+    let dir = env::temp_dir()
+        .join("runtime-macros-cli")
+        .join(format!("{:016x}", hasher.finish()));
+    fs::create_dir_all(dir.join("src"))?;
+    let manifest_path = dir.join("Cargo.toml");
+    write_if_changed(&manifest_path, manifest)?;
+    write_if_changed(&dir.join("src/main.rs"), main)?;
+    Ok(manifest_path)
+}
+
+// Builds and runs a generated project with the given arguments and returns its exit code. If the
+// project includes a procedural macro crate as a library, `#[testable]` is told to leave out the
+// entry points, and `proc_macro` is made available the way it is in a procedural macro crate.
+fn run_project(manifest: &Path, args: &[String], library: bool) -> i32 {
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let mut command = Command::new(cargo);
+    command
+        .args(["run", "--quiet", "--manifest-path"])
+        .arg(manifest)
+        .arg("--")
+        .args(args);
+    if library {
+        let mut rustflags = env::var("RUSTFLAGS").unwrap_or_default();
+        rustflags.push_str(" --extern proc_macro");
+        command
+            .env("RUNTIME_MACROS_NO_ENTRY_POINTS", "1")
+            .env("RUSTFLAGS", rustflags.trim_start());
+    }
+    match command.status() {
+        Ok(status) => status.code().unwrap_or(1),
+        Err(error) => fail(&format!("couldn't run Cargo: {}", error)),
+    }
+}
+
+// Avoids touching a file that's already right, so Cargo doesn't rebuild the project.
+fn write_if_changed(path: &Path, contents: &str) -> io::Result<()> {
+    match fs::read_to_string(path) {
+        Ok(ref old) if old == contents => Ok(()),
+        _ => fs::write(path, contents),
+    }
+}
+
+fn manifest_source() -> String {
+    format!(
+        "[package]
+name = \"runtime-macros-cli-run\"
+version = \"0.0.0\"
+edition = \"2021\"
+publish = false
+
+[dependencies]
+proc-macro2 = \"1.0\"
+quote = \"1.0\"
+runtime-macros = {{ path = {:?}, features = [\"cli\"] }}
+syn = {{ version = \"2.0\", features = [\"full\", \"extra-traits\", \"fold\", \"visit\", \"visit-mut\"] }}
+
+[workspace]
+",
+        env!("CARGO_MANIFEST_DIR")
+    )
+}
+
+fn main_source(args: &Args, function_file: &Path) -> String {
+    let method = match args.kind {
+        MacroKind::Functionlike => "functionlike",
+        MacroKind::Attribute => "attribute",
+        MacroKind::Derive => "derive",
+    };
+    format!(
+        "#[path = {:?}]
+mod expansion;
+
+fn main() {{
+    let expander = runtime_macros::Expander::new().{}({:?}, expansion::{});
+    std::process::exit(runtime_macros::cli::run(expander, std::env::args().skip(1)));
+}}
+",
+        function_file.display().to_string(),
+        method,
+        args.macro_path,
+        args.function
+    )
+}
+
+/// Expands the macros in the files that match the patterns in `args` and prints the results,
+/// returning the exit code. Each expansion is printed to standard output, followed by a summary
+/// from a [`ConsoleReporter`] on standard error. If `args` starts with `--json`, the report is
+/// printed as JSON instead of the expansions. This is what the generated project calls.
+///
+/// [`ConsoleReporter`]: ../struct.ConsoleReporter.html
+pub fn run<I: IntoIterator<Item = String>>(mut expander: Expander, args: I) -> i32 {
+    let mut json = false;
+    for arg in args {
+        if arg == "--json" {
+            json = true;
+            continue;
+        }
+        expander = match expander.glob(&arg) {
+            Ok(expander) => expander,
+            Err(error) => return fail(&error.to_string()),
+        };
+    }
+    let result = expander.reporter(ConsoleReporter::new()).run();
+    let report = match result {
+        Ok(ref report) => report,
+        Err(_) => return 1,
+    };
+    if json {
+        println!("{}", report.to_json());
+    } else {
+        for file in report.files.iter() {
+            for record in file.records.iter() {
+                println!(
+                    "{}:{}:{}: `{}` expanded to:\n{}",
+                    file.path.display(),
+                    record.start.line,
+                    record.start.column,
+                    record.macro_path,
+                    record.pretty_output()
+                );
+            }
+        }
+    }
+    i32::from(panicked(report))
+}
+
+fn panicked(report: &ExpansionReport) -> bool {
+    report
+        .files
+        .iter()
+        .any(|file| file.panics.iter().any(|panic| !panic.expected))
+}
+
+const CARGO_USAGE: &str = "\
+usage: cargo runtime-macros [--manifest-path <path>]
+
+Expands the invocations in the package's tests, examples, and benches of each macro that its
+procedural macro crate marks with `#[testable]`, and prints how many times each one was invoked.
+The crate is built as an ordinary library, so every macro in it has to be marked.";
+
+/// Runs `cargo runtime-macros` with the given arguments, which can start with `runtime-macros`
+/// the way Cargo passes them, and returns its exit code.
+pub fn cargo_main<I: IntoIterator<Item = String>>(args: I) -> i32 {
+    let mut args = args.into_iter().peekable();
+    if args.peek().map(String::as_str) == Some("runtime-macros") {
+        args.next();
+    }
+    let mut command = MetadataCommand::new();
+    command.no_deps();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--manifest-path" => match args.next() {
+                Some(path) => {
+                    command.manifest_path(path);
+                }
+                None => return usage_error(CARGO_USAGE, "`--manifest-path` needs a path"),
+            },
+            "-h" | "--help" => {
+                eprintln!("{}", CARGO_USAGE);
+                return 0;
+            }
+            arg => return usage_error(CARGO_USAGE, &format!("unexpected argument `{}`", arg)),
+        }
+    }
+
+    let metadata = match command.exec() {
+        Ok(metadata) => metadata,
+        Err(error) => return fail(&Error::MetadataFailed(error).to_string()),
+    };
+    let package = match metadata.root_package() {
+        Some(package) => package,
+        None => {
+            return fail(
+                "the manifest is a virtual workspace; use `--manifest-path` to pick a package",
+            )
+        }
+    };
+    let target = match package
+        .targets
+        .iter()
+        .find(|target| target.is_kind(TargetKind::ProcMacro))
+    {
+        Some(target) => target,
+        None => {
+            return fail(&format!(
+                "`{}` isn't a procedural macro crate",
+                package.name
+            ))
+        }
+    };
+    let crate_root = target.src_path.as_std_path();
+    let macros = match exports::testable_macros(crate_root) {
+        Ok(macros) => macros,
+        Err(error) => return fail(&error.to_string()),
+    };
+    if macros.is_empty() {
+        return fail(&format!(
+            "none of the macros in {} are marked with `#[testable]`",
+            crate_root.display()
+        ));
+    }
+
+    let lib = target.name.replace('-', "_");
+    let manifest = match write_project(
+        package.manifest_path.as_std_path(),
+        &library_manifest_source(package, &lib, crate_root),
+        &coverage_main_source(&lib, &macros),
+    ) {
+        Ok(manifest) => manifest,
+        Err(error) => return fail(&format!("couldn't write the Cargo project: {}", error)),
+    };
+    run_project(&manifest, &[package.manifest_path.to_string()], true)
+}
+
+fn usage_error(usage: &str, message: &str) -> i32 {
+    eprintln!("error: {}\n\n{}", message, usage);
+    2
+}
+
+// Writes a manifest that builds the procedural macro crate as an ordinary library, with the same
+// dependencies and features.
+fn library_manifest_source(package: &Package, lib: &str, crate_root: &Path) -> String {
+    let mut manifest = format!(
+        "[package]
+name = \"runtime-macros-cargo-run\"
+version = \"0.0.0\"
+edition = \"{}\"
+publish = false
+
+[lib]
+name = \"{}\"
+path = {:?}
+",
+        package.edition.as_str(),
+        lib,
+        crate_root.display().to_string()
+    );
+
+    let mut sections: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut runtime_macros_features = vec!["cli".to_owned()];
+    for dependency in package.dependencies.iter() {
+        if dependency.kind != DependencyKind::Normal {
+            continue;
+        }
+        if dependency.name == "runtime-macros" {
+            runtime_macros_features.extend(dependency.features.iter().cloned());
+            continue;
+        }
+        let section = match dependency.target {
+            Some(ref target) => format!("target.'{}'.dependencies", target),
+            None => "dependencies".to_owned(),
+        };
+        sections
+            .entry(section)
+            .or_default()
+            .push(dependency_source(dependency));
+    }
+    sections
+        .entry("dependencies".to_owned())
+        .or_default()
+        .push(format!(
+            "runtime-macros = {{ path = {:?}, features = {:?} }}",
+            env!("CARGO_MANIFEST_DIR"),
+            runtime_macros_features
+        ));
+    for (section, dependencies) in sections {
+        manifest.push_str(&format!("\n[{}]\n", section));
+        for dependency in dependencies {
+            manifest.push_str(&dependency);
+            manifest.push('\n');
+        }
+    }
+
+    if !package.features.is_empty() {
+        manifest.push_str("\n[features]\n");
+        for (feature, enables) in package.features.iter() {
+            manifest.push_str(&format!("{:?} = {:?}\n", feature, enables));
+        }
+    }
+    manifest.push_str("\n[workspace]\n");
+    manifest
+}
+
+// Writes a line of a `[dependencies]` section that asks for the same dependency.
+fn dependency_source(dependency: &Dependency) -> String {
+    let mut fields = vec![format!("package = {:?}", dependency.name)];
+    if let Some(ref path) = dependency.path {
+        fields.push(format!("path = {:?}", path.as_str()));
+    }
+    // A Git dependency's source looks like `git+https://example.com/repo?branch=main#commit`.
+    if let Some(git) = dependency
+        .source
+        .as_ref()
+        .and_then(|source| source.strip_prefix("git+"))
+    {
+        let git = git.split('#').next().unwrap();
+        let mut parts = git.splitn(2, '?');
+        fields.push(format!("git = {:?}", parts.next().unwrap()));
+        for query in parts.next().unwrap_or("").split('&') {
+            if let Some((key @ ("branch" | "tag" | "rev"), value)) = query.split_once('=') {
+                fields.push(format!("{} = {:?}", key, value));
+            }
+        }
+    }
+    fields.push(format!("version = \"{}\"", dependency.req));
+    if let Some(ref registry) = dependency.registry {
+        fields.push(format!("registry = {:?}", registry));
+    }
+    if !dependency.uses_default_features {
+        fields.push("default-features = false".to_owned());
+    }
+    if !dependency.features.is_empty() {
+        fields.push(format!("features = {:?}", dependency.features));
+    }
+    if dependency.optional {
+        fields.push("optional = true".to_owned());
+    }
+    format!(
+        "{} = {{ {} }}",
+        dependency.rename.as_ref().unwrap_or(&dependency.name),
+        fields.join(", ")
+    )
+}
+
+fn coverage_main_source(lib: &str, macros: &[(ExportedMacro, String)]) -> String {
+    let mut registrations = String::new();
+    for (exported, function) in macros.iter() {
+        let method = match exported.kind {
+            MacroKind::Functionlike => "functionlike",
+            MacroKind::Attribute => "attribute",
+            MacroKind::Derive => "derive",
+        };
+        registrations.push_str(&format!(
+            "\n        .{}({:?}, {}::{})",
+            method, exported.name, lib, function
+        ));
+        if !exported.helpers.is_empty() {
+            registrations.push_str(&format!(
+                "\n        .derive_helpers({:?}, &{:?})",
+                exported.name, exported.helpers
+            ));
+        }
+    }
+    format!(
+        "extern crate runtime_macros;
+extern crate {};
+
+fn main() {{
+    let mut registry = runtime_macros::MacroRegistry::new();
+    registry{};
+    std::process::exit(runtime_macros::cli::coverage(registry, std::env::args().skip(1)));
+}}
+",
+        lib, registrations
+    )
+}
+
+/// Expands the macros in the tests, examples, and benches of the packages whose manifests are in
+/// `args` and prints how many times each registered macro was invoked, and in how many files,
+/// returning the exit code. Since the packages usually invoke the macros with the crate's name,
+/// like `my_macros::foo!()`, each macro matches any path that ends with its name, as with
+/// [`MatchMode::LastSegment`]. Problems are reported by a [`ConsoleReporter`] on standard error.
+/// This is what the project generated by `cargo runtime-macros` calls.
+///
+/// [`MatchMode::LastSegment`]: ../enum.MatchMode.html#variant.LastSegment
+/// [`ConsoleReporter`]: ../struct.ConsoleReporter.html
+pub fn coverage<I: IntoIterator<Item = String>>(mut registry: MacroRegistry, args: I) -> i32 {
+    let macros: Vec<(MacroKind, String)> = registry
+        .registered()
+        .map(|(kind, path)| (kind, path.clone()))
+        .collect();
+    for &(kind, ref path) in macros.iter() {
+        registry.match_mode(kind, path, MatchMode::LastSegment);
+    }
+    let mut expander = Expander::from(registry).follow_modules(true);
+    for manifest in args {
+        expander = match expander.package(manifest) {
+            Ok(expander) => expander,
+            Err(error) => return fail(&error.to_string()),
+        };
+    }
+    let result = expander.reporter(ConsoleReporter::new()).run();
+    let report = match result {
+        Ok(ref report) => report,
+        Err(_) => return 1,
+    };
+    print!("{}", coverage_table(&macros, report));
+    i32::from(panicked(report))
+}
+
+// Lists each macro with its kind, its number of invocations, and the number of files it's invoked
+// in, followed by how many of the macros were invoked at all.
+fn coverage_table(macros: &[(MacroKind, String)], report: &ExpansionReport) -> String {
+    let rows: Vec<(&str, String, usize, usize)> = macros
+        .iter()
+        .map(|&(kind, ref path)| {
+            let counts = report.files.iter().map(|file| {
+                file.invocation_counts()
+                    .get(&(kind, &path[..]))
+                    .cloned()
+                    .unwrap_or(0)
+            });
+            let invocations = counts.clone().sum();
+            let files = counts.filter(|&count| count > 0).count();
+            (&path[..], kind.to_string(), invocations, files)
+        })
+        .collect();
+    let width = rows.iter().map(|row| row.0.len()).max().unwrap_or(0).max(5);
+
+    let mut table = format!(
+        "{:<width$}  {:<13}  {:>11}  files\n",
+        "macro",
+        "kind",
+        "invocations",
+        width = width
+    );
+    for (path, kind, invocations, files) in rows.iter() {
+        table.push_str(&format!(
+            "{:<width$}  {:<13}  {:>11}  {} of {}\n",
+            path,
+            kind,
+            invocations,
+            files,
+            report.files.len(),
+            width = width
+        ));
+    }
+    let invoked = rows.iter().filter(|row| row.2 > 0).count();
+    table.push_str(&format!(
+        "\n{} of {} macros were invoked ({:.0}%)\n",
+        invoked,
+        rows.len(),
+        100.0 * invoked as f64 / rows.len().max(1) as f64
+    ));
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "dylib")]
+    use dylib;
+
+    fn args(line: &str) -> Result<Args, String> {
+        Args::parse(line.split(' ').map(String::from))
+    }
+
+    #[test]
+    fn parses_the_command_line() {
+        assert_eq!(
+            args("--json attribute my::attr src/expand.rs expand_internal a.rs tests/**/*.rs"),
+            Ok(Args {
+                json: true,
+                kind: MacroKind::Attribute,
+                macro_path: "my::attr".into(),
+                function_file: PathBuf::from("src/expand.rs"),
+                function: "expand_internal".into(),
+                patterns: vec!["a.rs".into(), "tests/**/*.rs".into()],
+            })
+        );
+        assert_eq!(args("--help"), Err(String::new()));
+        assert!(args("method m f.rs f a.rs").is_err());
+        assert_eq!(
+            args("derive Foo lib.so f a.rs").is_ok(),
+            cfg!(feature = "dylib")
+        );
+        assert!(args("derive Foo f.rs not-a-path a.rs").is_err());
+        assert!(args("derive Foo f.rs f").is_err());
+
+        let source = main_source(
+            &args("derive Foo f.rs m::f a.rs").unwrap(),
+            Path::new("/f.rs"),
+        );
+        assert!(source.starts_with("#[path = \"/f.rs\"]\nmod expansion;\n"));
+        assert!(source.contains(".derive(\"Foo\", expansion::m::f);"));
+    }
+
+    #[cfg(feature = "dylib")]
+    #[test]
+    fn expands_with_a_compiled_library() {
+        let library = dylib::tests::fixture();
+        let file = env::temp_dir().join(format!("runtime-macros-cli-{}.rs", std::process::id()));
+        fs::write(&file, "fn f() { shout!(a + b); }").unwrap();
+        let command = format!(
+            "functionlike shout {} shout {}",
+            library.path().display(),
+            file.display()
+        );
+        assert_eq!(main(command.split(' ').map(String::from)), 0);
+        fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn prints_coverage_statistics() {
+        let macros = [
+            (MacroKind::Functionlike, "used".to_owned()),
+            (MacroKind::Derive, "Unused".to_owned()),
+        ];
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("used", |ts| ts)
+            .derive("Unused", |ts| ts);
+        let files = ["used!(); used!();", "used!();", ""]
+            .iter()
+            .enumerate()
+            .map(|(i, source)| {
+                registry
+                    .expand_source(source, None)
+                    .unwrap()
+                    .into_report(PathBuf::from(format!("{}.rs", i)))
+            })
+            .collect();
+        let report = registry.report(files).unwrap();
+        assert_eq!(
+            coverage_table(&macros, &report),
+            "\
+macro   kind           invocations  files
+used    function-like            3  2 of 3
+Unused  derive                   0  0 of 3
+
+1 of 2 macros were invoked (50%)
+"
+        );
+
+        let source = coverage_main_source(
+            "my_macros",
+            &[(
+                ExportedMacro {
+                    kind: MacroKind::Derive,
+                    name: "Foo".into(),
+                    helpers: vec!["foo".into()],
+                },
+                "foo_internal".into(),
+            )],
+        );
+        assert!(source.contains(
+            "    registry
+        .derive(\"Foo\", my_macros::foo_internal)
+        .derive_helpers(\"Foo\", &[\"foo\"]);\n"
+        ));
+    }
+}
@@ -0,0 +1,227 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Papering over the parts of syn's API that changed between syn 1 and syn 2, so the rest of the
+//! crate works with whichever one the `syn1` or `syn2` feature picks.
+
+#[cfg(feature = "syn2")]
+use syn::{punctuated::Punctuated, Token};
+use {
+    proc_macro2::TokenStream,
+    syn::{Attribute, LitStr, Meta, MetaList, MetaNameValue, Path},
+};
+#[cfg(not(feature = "syn2"))]
+use {
+    proc_macro2::{Delimiter, TokenTree},
+    syn::{parse::ParseStream, punctuated::Punctuated, NestedMeta, Token},
+};
+
+// The arguments of an attribute.
+pub(crate) enum AttrArgs {
+    // There are none, as in `#[path]`.
+    Empty,
+    // The tokens between the delimiters, as in `#[path(tokens)]`.
+    List(TokenStream),
+    // There's a value, as in `#[path = value]`.
+    Value,
+}
+
+pub(crate) fn attr_path(attr: &Attribute) -> &Path {
+    #[cfg(feature = "syn2")]
+    {
+        attr.path()
+    }
+    #[cfg(not(feature = "syn2"))]
+    {
+        &attr.path
+    }
+}
+
+pub(crate) fn attr_args(attr: &Attribute) -> AttrArgs {
+    #[cfg(feature = "syn2")]
+    {
+        match attr.meta {
+            Meta::Path(_) => AttrArgs::Empty,
+            Meta::List(ref list) => AttrArgs::List(list.tokens.clone()),
+            Meta::NameValue(_) => AttrArgs::Value,
+        }
+    }
+    #[cfg(not(feature = "syn2"))]
+    {
+        let mut tokens = attr.tokens.clone().into_iter();
+        match (tokens.next(), tokens.next()) {
+            (None, _) => AttrArgs::Empty,
+            (Some(TokenTree::Group(group)), None) if group.delimiter() != Delimiter::None => {
+                AttrArgs::List(group.stream())
+            }
+            _ => AttrArgs::Value,
+        }
+    }
+}
+
+// Returns the string in an attribute like `#[doc = "..."]` if the attribute has the given name.
+pub(crate) fn attr_str(attr: &Attribute, name: &str) -> Option<LitStr> {
+    #[cfg(feature = "syn2")]
+    let meta = match attr.meta {
+        Meta::NameValue(ref meta) => meta,
+        _ => return None,
+    };
+    #[cfg(not(feature = "syn2"))]
+    let meta = &match attr.parse_meta() {
+        Ok(Meta::NameValue(meta)) => meta,
+        _ => return None,
+    };
+    if meta.path.is_ident(name) {
+        meta_str(meta).cloned()
+    } else {
+        None
+    }
+}
+
+// Returns the string in a name-value pair like `feature = "..."`.
+pub(crate) fn meta_str(meta: &MetaNameValue) -> Option<&LitStr> {
+    #[cfg(feature = "syn2")]
+    {
+        match meta.value {
+            syn::Expr::Lit(syn::ExprLit {
+                lit: syn::Lit::Str(ref value),
+                ..
+            }) => Some(value),
+            _ => None,
+        }
+    }
+    #[cfg(not(feature = "syn2"))]
+    {
+        match meta.lit {
+            syn::Lit::Str(ref value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+// Returns the items in a list like `all(...)`. It's an error if one of them isn't a `Meta`.
+pub(crate) fn meta_list(list: &MetaList) -> syn::Result<Vec<Meta>> {
+    #[cfg(feature = "syn2")]
+    {
+        list.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)
+            .map(|metas| metas.into_iter().collect())
+    }
+    #[cfg(not(feature = "syn2"))]
+    {
+        list.nested
+            .iter()
+            .map(|nested| match *nested {
+                NestedMeta::Meta(ref meta) => Ok(meta.clone()),
+                NestedMeta::Lit(ref lit) => Err(syn::Error::new_spanned(lit, "expected a path")),
+            })
+            .collect()
+    }
+}
+
+// Splits an attribute like `#[cfg_attr(predicate, attrs...)]` into its predicate and the
+// attributes it adds, which have the same style as the original. Returns `None` if the attribute
+// isn't a `cfg_attr`.
+pub(crate) fn parse_cfg_attr(attr: &Attribute) -> Option<syn::Result<(Meta, Vec<Attribute>)>> {
+    if !attr_path(attr).is_ident("cfg_attr") {
+        return None;
+    }
+    #[cfg(feature = "syn2")]
+    {
+        let list = match attr.meta {
+            Meta::List(ref list) => list,
+            _ => return None,
+        };
+        Some(
+            list.parse_args_with(|input: syn::parse::ParseStream| {
+                let predicate = input.parse::<Meta>()?;
+                input.parse::<Token![,]>()?;
+                let metas = Punctuated::<Meta, Token![,]>::parse_terminated(input)?;
+                Ok((predicate, metas))
+            })
+            .map(|(predicate, metas)| {
+                let attrs = metas
+                    .into_iter()
+                    .map(|meta| Attribute {
+                        pound_token: attr.pound_token,
+                        style: attr.style,
+                        bracket_token: attr.bracket_token,
+                        meta,
+                    })
+                    .collect();
+                (predicate, attrs)
+            }),
+        )
+    }
+    #[cfg(not(feature = "syn2"))]
+    {
+        // syn 1 can only parse attributes whose arguments look like `Meta`s, so each one is split
+        // into its path and the tokens before the next comma by hand.
+        Some(attr.parse_args_with(|input: ParseStream| {
+            let predicate = input.parse::<Meta>()?;
+            input.parse::<Token![,]>()?;
+            let mut attrs = Vec::new();
+            while !input.is_empty() {
+                let path = input.call(Path::parse_mod_style)?;
+                let mut tokens = TokenStream::new();
+                while !input.is_empty() && !input.peek(Token![,]) {
+                    tokens.extend(Some(input.parse::<TokenTree>()?));
+                }
+                attrs.push(Attribute {
+                    pound_token: attr.pound_token,
+                    style: attr.style,
+                    bracket_token: attr.bracket_token,
+                    path,
+                    tokens,
+                });
+                if !input.is_empty() {
+                    input.parse::<Token![,]>()?;
+                }
+            }
+            Ok((predicate, attrs))
+        }))
+    }
+}
+
+// Parses a pattern, such as the output of a macro in a pattern's position. It can have several
+// cases separated by `|`, with or without a leading `|`.
+pub(crate) fn parse_pattern(tokens: TokenStream) -> syn::Result<()> {
+    use syn::parse::Parser;
+    #[cfg(feature = "syn2")]
+    {
+        syn::Pat::parse_multi_with_leading_vert
+            .parse2(tokens)
+            .map(drop)
+    }
+    #[cfg(not(feature = "syn2"))]
+    {
+        (|input: ParseStream| {
+            input.parse::<Option<Token![|]>>()?;
+            Punctuated::<syn::Pat, Token![|]>::parse_separated_nonempty(input)
+        })
+        .parse2(tokens)
+        .map(drop)
+    }
+}
+
+// Returns the attributes of a statement that's a macro invocation, like `foo!();`. syn 1 parses
+// one as an item if it's delimited by braces or as an expression otherwise.
+pub(crate) fn stmt_macro_attrs(stmt: &syn::Stmt) -> Option<&[Attribute]> {
+    match *stmt {
+        #[cfg(feature = "syn2")]
+        syn::Stmt::Macro(ref stmt_macro) => Some(&stmt_macro.attrs),
+        #[cfg(not(feature = "syn2"))]
+        syn::Stmt::Item(syn::Item::Macro(ref item)) if item.ident.is_none() => Some(&item.attrs),
+        #[cfg(not(feature = "syn2"))]
+        syn::Stmt::Semi(syn::Expr::Macro(ref expr), _) => Some(&expr.attrs),
+        _ => None,
+    }
+}
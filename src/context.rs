@@ -0,0 +1,41 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+
+//! Information about each macro invocation, passed to context-aware macro functions and hooks.
+
+use {proc_macro2::Span, report::MacroKind, std::path::Path};
+
+/// Describes the macro invocation that is currently being expanded.
+///
+/// Functions registered with one of the `*_with_context` methods of [`MacroRegistry`] receive one
+/// of these along with their input tokens, as do the hooks registered with
+/// [`MacroRegistry::after_expansion`].
+///
+/// [`MacroRegistry`]: struct.MacroRegistry.html
+/// [`MacroRegistry::after_expansion`]: struct.MacroRegistry.html#method.after_expansion
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub struct ExpansionContext<'c> {
+    /// The path of the file containing the invocation, if it is known. It is `None` when the source
+    /// code was given as an already-opened file.
+    pub source_path: Option<&'c Path>,
+    /// The path of the macro, as it was registered.
+    pub macro_path: &'c str,
+    /// The kind of macro being expanded.
+    pub kind: MacroKind,
+    /// The span of the invocation in the source file.
+    pub span: Span,
+    /// The number of items enclosing the invocation. A macro called at the top level of a file, or
+    /// applied to a top-level item, has a depth of 0.
+    pub depth: usize,
+}
@@ -0,0 +1,69 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Recording the inputs of macro invocations to a corpus directory, so they can be replayed later.
+
+#[cfg(feature = "fs")]
+use {context::ExpansionContext, proc_macro2::TokenStream, report::MacroKind, std::fs, Error};
+use {proc_macro2::LineColumn, std::path::Path};
+
+// Writes the input of one invocation to `<dir>/<kind>/<macro path>/<file>-<line>-<column>`. For
+// attribute-like macros, the attribute's tokens go in a second file with `.attr` added to the
+// name. Characters that can't appear in file names are replaced with `_`.
+#[cfg(feature = "fs")]
+pub(crate) fn record(
+    dir: &Path,
+    context: &ExpansionContext,
+    attr: Option<&TokenStream>,
+    input: &TokenStream,
+) -> Result<(), Error> {
+    let kind = match context.kind {
+        MacroKind::Functionlike => "functionlike",
+        MacroKind::Derive => "derive",
+        MacroKind::Attribute => "attribute",
+    };
+    let dir = dir.join(kind).join(sanitize(context.macro_path));
+    let name = location_name(context.source_path, context.span.start());
+
+    let path = dir.join(&name);
+    let mut result = fs::create_dir_all(&dir).and_then(|()| fs::write(&path, input.to_string()));
+    if let (Ok(()), Some(attr)) = (&result, attr) {
+        result = fs::write(dir.join(name + ".attr"), attr.to_string());
+    }
+    result.map_err(|error| Error::IoError {
+        path: Some(path),
+        error,
+    })
+}
+
+// Names a file after the location of an invocation, as `<file>-<line>-<column>`.
+pub(crate) fn location_name(path: Option<&Path>, start: LineColumn) -> String {
+    format!("{}-{}-{}", file_name(path), start.line, start.column)
+}
+
+// Names a file after the path of a source file, or `source` if the path isn't known.
+pub(crate) fn file_name(path: Option<&Path>) -> String {
+    match path {
+        Some(path) if path != Path::new("") => sanitize(&path.to_string_lossy()),
+        _ => "source".to_owned(),
+    }
+}
+
+// Replaces every character that can't safely appear in a file name with `_`.
+pub(crate) fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
@@ -0,0 +1,98 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+
+//! Rendering of errors with excerpts of the source code they refer to, in the style of rustc's
+//! diagnostics.
+
+use {
+    proc_macro2::Span,
+    std::{fmt::Write, path::Path},
+};
+
+// Renders an error message followed by the file's path, the line that the span starts on, and a
+// row of carets under the span. Returns `None` if the span doesn't point into `source`.
+pub(crate) fn render(
+    source: &str,
+    path: Option<&Path>,
+    message: &str,
+    span: Span,
+) -> Option<String> {
+    let (start, end) = (span.start(), span.end());
+    if start.line == 0 {
+        return None;
+    }
+    let line = source.lines().nth(start.line - 1)?;
+
+    let line_number = start.line.to_string();
+    let gutter = " ".repeat(line_number.len());
+
+    // Keep any tabs in the indentation so the carets line up with the code.
+    let indent: String = line
+        .chars()
+        .take(start.column)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let width = if end.line == start.line {
+        end.column.saturating_sub(start.column)
+    } else {
+        line.chars().count().saturating_sub(start.column)
+    };
+
+    let mut rendered = String::new();
+    writeln!(rendered, "error: {}", message).ok()?;
+    match path {
+        Some(path) => writeln!(
+            rendered,
+            "{}--> {}:{}:{}",
+            gutter,
+            path.display(),
+            start.line,
+            start.column + 1
+        ),
+        None => writeln!(
+            rendered,
+            "{}--> {}:{}",
+            gutter,
+            start.line,
+            start.column + 1
+        ),
+    }
+    .ok()?;
+    writeln!(rendered, "{} |", gutter).ok()?;
+    writeln!(rendered, "{} | {}", line_number, line).ok()?;
+    write!(
+        rendered,
+        "{} | {}{}",
+        gutter,
+        indent,
+        "^".repeat(width.max(1))
+    )
+    .ok()?;
+    Some(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_an_excerpt() {
+        let source = "fn main() {\n    let x = ;\n}\n";
+        let error = syn::parse_file(source).unwrap_err();
+        let rendered = render(source, Some(Path::new("a.rs")), "oops", error.span()).unwrap();
+        assert_eq!(
+            rendered,
+            "error: oops\n --> a.rs:2:13\n  |\n2 |     let x = ;\n  |             ^"
+        );
+    }
+}
@@ -0,0 +1,53 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Comments that tell the scanner what to do with the invocations on the next line, like
+//! `// runtime-macros: skip`.
+
+use std::collections::BTreeMap;
+
+// What the directives before a line say about the invocations on it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Directives {
+    // The invocations shouldn't be expanded.
+    pub(crate) skip: bool,
+    // The macro functions are expected to panic.
+    pub(crate) expect_panic: bool,
+}
+
+// Finds every directive in the source code, by the number of the line it applies to. A directive
+// applies to the next line that isn't another directive, so several can be stacked. Comments that
+// start with `runtime-macros:` but don't name a known directive are ignored.
+pub(crate) fn parse(content: &str) -> BTreeMap<usize, Directives> {
+    let mut directives = BTreeMap::new();
+    let mut pending = Directives::default();
+    for (i, line) in content.lines().enumerate() {
+        let directive = line
+            .trim()
+            .strip_prefix("//")
+            .and_then(|comment| comment.trim().strip_prefix("runtime-macros:"))
+            .map(str::trim);
+        match directive {
+            Some("skip") => pending.skip = true,
+            Some("expect-panic") => pending.expect_panic = true,
+            Some(_) => {}
+            None => {
+                if pending != Directives::default() {
+                    // Line numbers start at 1.
+                    directives.insert(i + 1, pending);
+                    pending = Directives::default();
+                }
+            }
+        }
+    }
+    directives
+}
@@ -0,0 +1,248 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Extracting the Rust code blocks from doc comments and Markdown files, so the macros in them can
+//! be expanded too.
+
+use {
+    compat,
+    session::ParsedSource,
+    syn::{spanned::Spanned, visit::Visit},
+};
+
+// Returns the source code of every Rust code block in the doc comments in the given file. Each
+// one is padded with blank lines so that its code is on the same lines as in the file.
+pub(crate) fn doc_code_blocks(source: &ParsedSource) -> Vec<String> {
+    let mut finder = DocFinder { lines: Vec::new() };
+    finder.visit_file(source.ast());
+
+    // Consecutive doc comments are one block of Markdown.
+    let mut blocks = Vec::new();
+    let mut start = 0;
+    for i in 1..=finder.lines.len() {
+        if i == finder.lines.len() || finder.lines[i].0 != finder.lines[i - 1].0 + 1 {
+            blocks.extend(code_blocks(&finder.lines[start..i]));
+            start = i;
+        }
+    }
+    blocks
+}
+
+// Returns the source code of every Rust code block in the given Markdown, padded like the ones
+// from `doc_code_blocks`.
+#[cfg(feature = "fs")]
+pub(crate) fn markdown_code_blocks(markdown: &str) -> Vec<String> {
+    let lines: Vec<(usize, &str)> = markdown
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line))
+        .collect();
+    code_blocks(&lines)
+}
+
+// Parses a code block, wrapping it in a `main` function first if it isn't a valid file on its
+// own, as rustdoc does. Returns `None` if it still can't be parsed.
+pub(crate) fn parse_block<F, T, E>(block: String, parse: F) -> Option<T>
+where
+    F: Fn(String) -> Result<T, E>,
+{
+    if syn::parse_file(&block).is_ok() {
+        return parse(block).ok();
+    }
+    parse(format!("fn main() {{{}\n}}", block)).ok()
+}
+
+// Finds every line of every doc comment, along with its line number.
+struct DocFinder {
+    lines: Vec<(usize, String)>,
+}
+
+impl<'ast> Visit<'ast> for DocFinder {
+    fn visit_attribute(&mut self, attr: &'ast syn::Attribute) {
+        if let Some(doc) = compat::attr_str(attr, "doc") {
+            let first_line = attr.span().start().line;
+            for (i, line) in doc.value().lines().enumerate() {
+                // Doc comments usually have a space after the `///`.
+                let line = line.strip_prefix(' ').unwrap_or(line);
+                self.lines.push((first_line + i, line.to_owned()));
+            }
+        }
+    }
+}
+
+// Extracts the Rust code blocks from some lines of Markdown, each with its line number.
+fn code_blocks<S: AsRef<str>>(lines: &[(usize, S)]) -> Vec<String> {
+    let mut blocks = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let (fence, info) = match open_fence(lines[i].1.as_ref()) {
+            Some(open) => open,
+            None => {
+                i += 1;
+                continue;
+            }
+        };
+        i += 1;
+        let start = i;
+        while i < lines.len() && !lines[i].1.as_ref().trim().starts_with(fence) {
+            i += 1;
+        }
+        if is_rust(info) && start < lines.len() {
+            let mut block = "\n".repeat(lines[start].0 - 1);
+            for line in lines[start..i].iter() {
+                block.push_str(unhide(line.1.as_ref()));
+                block.push('\n');
+            }
+            blocks.push(block);
+        }
+        i += 1;
+    }
+    blocks
+}
+
+// If the line opens a fenced code block, returns the fence and the info string after it.
+fn open_fence(line: &str) -> Option<(&str, &str)> {
+    let trimmed = line.trim_start();
+    if line.len() - trimmed.len() > 3 {
+        return None;
+    }
+    ["```", "~~~"].iter().find_map(|fence| {
+        let len = trimmed.len() - trimmed.trim_start_matches(fence.chars().next()?).len();
+        if len >= 3 {
+            Some((&trimmed[..len], trimmed[len..].trim()))
+        } else {
+            None
+        }
+    })
+}
+
+// Returns true if a code block with the given info string would be tested by rustdoc as Rust.
+fn is_rust(info: &str) -> bool {
+    info.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|word| !word.is_empty())
+        .all(|word| {
+            [
+                "rust",
+                "should_panic",
+                "no_run",
+                "compile_fail",
+                "test_harness",
+                "standalone_crate",
+            ]
+            .contains(&word)
+                || word.starts_with("ignore")
+                || word.starts_with("edition")
+        })
+}
+
+// Removes the `# ` that rustdoc uses to hide a line of a code block.
+fn unhide(line: &str) -> &str {
+    let trimmed = line.trim_start();
+    if trimmed == "#" {
+        ""
+    } else if let Some(hidden) = trimmed.strip_prefix("# ") {
+        hidden
+    } else if trimmed.starts_with("##") {
+        &trimmed[1..]
+    } else {
+        line
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+    use {Expander, MacroKind};
+
+    #[test]
+    fn extracts_rust_code_blocks() {
+        let markdown = "Some text.
+
+```rust
+# fn hidden() {}
+foo!(1);
+```
+
+```toml
+foo = 2
+```
+
+~~~no_run
+foo!(3);
+~~~
+";
+        assert_eq!(
+            markdown_code_blocks(markdown),
+            [
+                "\n\n\nfn hidden() {}\nfoo!(1);\n",
+                "\n\n\n\n\n\n\n\n\n\n\n\nfoo!(3);\n"
+            ]
+        );
+
+        let source = ParsedSource::parse(
+            "
+/// Does a thing.
+///
+/// ```
+/// foo!(4);
+/// ```
+fn f() {}
+"
+            .to_owned(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(doc_code_blocks(&source), ["\n\n\n\nfoo!(4);\n"]);
+    }
+
+    #[test]
+    fn scans_doc_examples_and_markdown() {
+        let dir = env::temp_dir().join(format!("runtime-macros-docs-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("lib.rs"),
+            "//! ```\n//! foo!(1);\n//! ```\n\n/// ```ignore\n/// #[foo] fn f() {}\n/// ```\nfn g() {}\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("README.md"),
+            "```rust\nfoo!(2);\n```\n\n```text\nfoo!(3);\n```\n",
+        )
+        .unwrap();
+
+        let report = Expander::new()
+            .functionlike("foo", |ts| ts)
+            .attribute("foo", |_, ts| ts)
+            .file(dir.join("lib.rs"))
+            .markdown(dir.join("README.md"))
+            .doc_examples(true)
+            .run()
+            .unwrap();
+        let lines: Vec<(MacroKind, usize)> = report
+            .files
+            .iter()
+            .flat_map(|file| file.call_sites.iter())
+            .map(|site| (site.kind, site.start.line))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                (MacroKind::Functionlike, 2),
+                (MacroKind::Attribute, 6),
+                (MacroKind::Functionlike, 2)
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,328 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! A public way to drive the scanner, for visitors that decide for themselves which parts of a file
+//! to scan or which invocations to expand.
+
+use {
+    context::ExpansionContext,
+    proc_macro2::TokenStream,
+    registry::MacroRegistry,
+    report::ExpansionReport,
+    std::path::{Path, PathBuf},
+    syn::visit::Visit,
+    visitor::MacroVisitor,
+    Error,
+};
+
+/// Decides which macro invocations a [`MacroDriver`] should expand.
+///
+/// Each method is called when the scanner finds the corresponding thing, before anything else is
+/// done with it, and every method does nothing but return `true` by default, so an implementation
+/// only needs the ones it cares about. An invocation whose method returns `false` is skipped: its
+/// macro function isn't called, and it doesn't appear in the report, not even as a [`CallSite`].
+/// Skipping an attribute-like macro when [`chain_attributes`] or [`rescan_attribute_output`] is
+/// enabled leaves the attribute in place, as if it weren't registered.
+///
+/// [`MacroDriver`]: struct.MacroDriver.html
+/// [`CallSite`]: struct.CallSite.html
+/// [`chain_attributes`]: struct.MacroRegistry.html#method.chain_attributes
+/// [`rescan_attribute_output`]: struct.MacroRegistry.html#method.rescan_attribute_output
+///
+/// # Example
+///
+/// ```
+/// # extern crate proc_macro2;
+/// # extern crate runtime_macros;
+/// # extern crate syn;
+/// # use runtime_macros::{ExpansionContext, ExpansionHandler, MacroDriver, MacroRegistry};
+/// # use proc_macro2::TokenStream;
+/// // Only expand the macros inside `#[cfg(test)]` modules.
+/// #[derive(Default)]
+/// struct TestsOnly {
+///     test_mods: Vec<bool>,
+/// }
+///
+/// fn is_test_mod(item: &syn::Item) -> bool {
+///     match *item {
+///         syn::Item::Mod(ref m) => m.attrs.iter().any(|attr| {
+///             attr.path().is_ident("cfg")
+///                 && attr.parse_args::<syn::Ident>().map_or(false, |cfg| cfg == "test")
+///         }),
+///         _ => false,
+///     }
+/// }
+///
+/// impl ExpansionHandler for TestsOnly {
+///     fn enter_item(&mut self, item: &syn::Item) -> bool {
+///         self.test_mods.push(is_test_mod(item));
+///         true
+///     }
+///
+///     fn leave_item(&mut self, _item: &syn::Item) {
+///         self.test_mods.pop();
+///     }
+///
+///     fn on_functionlike(&mut self, _context: &ExpansionContext, _input: &TokenStream) -> bool {
+///         self.test_mods.iter().any(|&test| test)
+///     }
+/// }
+///
+/// let file = syn::parse_file("fn a() { foo!(); } #[cfg(test)] mod tests { fn b() { foo!(); } }")
+///     .unwrap();
+/// let mut registry = MacroRegistry::new();
+/// registry.functionlike("foo", |ts| ts);
+/// let mut driver = MacroDriver::new(&registry, None).unwrap();
+/// driver.handler(TestsOnly::default()).scan_file(&file);
+/// assert_eq!(driver.finish().unwrap().expansions(), 1);
+/// ```
+pub trait ExpansionHandler {
+    /// Called before an item is scanned, including its attributes. If it returns `false`, the
+    /// item is skipped along with everything inside it, and `leave_item` isn't called for it.
+    fn enter_item(&mut self, _item: &syn::Item) -> bool {
+        true
+    }
+
+    /// Called after an item and everything inside it have been scanned.
+    fn leave_item(&mut self, _item: &syn::Item) {}
+
+    /// Called when a registered function-like macro is invoked with the given tokens. Returns
+    /// whether it should be expanded.
+    fn on_functionlike(&mut self, _context: &ExpansionContext, _input: &TokenStream) -> bool {
+        true
+    }
+
+    /// Called when a registered derive macro is applied to the given item. Returns whether it
+    /// should be expanded.
+    fn on_derive(&mut self, _context: &ExpansionContext, _item: &TokenStream) -> bool {
+        true
+    }
+
+    /// Called when a registered attribute-like macro is applied to the given item, with the given
+    /// arguments. Returns whether it should be expanded.
+    fn on_attribute(
+        &mut self,
+        _context: &ExpansionContext,
+        _attr: &TokenStream,
+        _item: &TokenStream,
+    ) -> bool {
+        true
+    }
+}
+
+impl<H: ExpansionHandler + ?Sized> ExpansionHandler for &mut H {
+    fn enter_item(&mut self, item: &syn::Item) -> bool {
+        (**self).enter_item(item)
+    }
+
+    fn leave_item(&mut self, item: &syn::Item) {
+        (**self).leave_item(item)
+    }
+
+    fn on_functionlike(&mut self, context: &ExpansionContext, input: &TokenStream) -> bool {
+        (**self).on_functionlike(context, input)
+    }
+
+    fn on_derive(&mut self, context: &ExpansionContext, item: &TokenStream) -> bool {
+        (**self).on_derive(context, item)
+    }
+
+    fn on_attribute(
+        &mut self,
+        context: &ExpansionContext,
+        attr: &TokenStream,
+        item: &TokenStream,
+    ) -> bool {
+        (**self).on_attribute(context, attr, item)
+    }
+}
+
+/// Expands the registered macros in whichever parts of a file it's given, using the registry's
+/// options, so a custom `syn` visitor can choose what to scan.
+///
+/// Each `scan_*` method expands every invocation in the given syntax tree node, the same way
+/// [`MacroRegistry::emulate_macro_expansion`] would expand them in a whole file, including the
+/// derives and attribute-like macros applied to it. The results of every scan are collected until
+/// [`finish`] is called.
+///
+/// [`MacroRegistry::emulate_macro_expansion`]: struct.MacroRegistry.html#method.emulate_macro_expansion
+/// [`finish`]: #method.finish
+///
+/// # Example
+///
+/// ```
+/// # extern crate runtime_macros;
+/// # extern crate syn;
+/// # use runtime_macros::{MacroDriver, MacroRegistry};
+/// use syn::visit::Visit;
+///
+/// // Only scan the functions whose names start with `test_`.
+/// struct TestFns<'d, 'r: 'd, 'a: 'r>(&'d mut MacroDriver<'r, 'a>);
+///
+/// impl<'d, 'r, 'a, 'ast> Visit<'ast> for TestFns<'d, 'r, 'a> {
+///     fn visit_item_fn(&mut self, item: &'ast syn::ItemFn) {
+///         if item.sig.ident.to_string().starts_with("test_") {
+///             self.0.scan_item(&syn::Item::Fn(item.clone()));
+///         }
+///     }
+/// }
+///
+/// let file = syn::parse_file("fn a() { foo!(); } fn test_a() { foo!(); }").unwrap();
+/// let mut registry = MacroRegistry::new();
+/// registry.functionlike("foo", |ts| ts);
+/// let mut driver = MacroDriver::new(&registry, None).unwrap();
+/// TestFns(&mut driver).visit_file(&file);
+/// assert_eq!(driver.finish().unwrap().expansions(), 1);
+/// ```
+pub struct MacroDriver<'r, 'a: 'r> {
+    registry: &'r MacroRegistry<'a>,
+    visitor: MacroVisitor<'r, 'a>,
+}
+
+impl<'r, 'a> MacroDriver<'r, 'a> {
+    /// Creates a driver that expands the macros in the given registry. `source_path` is the path of
+    /// the file that will be scanned, if it's known; it's passed to context-aware macro functions
+    /// and used in the report.
+    ///
+    /// # Returns
+    ///
+    /// The new driver, or an [`Error::InvalidMacroPath`] if one of the registered paths can't be
+    /// parsed.
+    ///
+    /// [`Error::InvalidMacroPath`]: enum.Error.html#variant.InvalidMacroPath
+    pub fn new(
+        registry: &'r MacroRegistry<'a>,
+        source_path: Option<&'r Path>,
+    ) -> Result<Self, Error> {
+        Ok(MacroDriver {
+            registry,
+            visitor: MacroVisitor::new(registry, source_path)?,
+        })
+    }
+
+    /// Sets the handler that decides which invocations to expand. By default, every invocation of
+    /// a registered macro is expanded.
+    pub fn handler<H: ExpansionHandler + 'r>(&mut self, handler: H) -> &mut Self {
+        self.visitor.handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Expands the macros in a whole file, including the ones in its inner attributes.
+    pub fn scan_file(&mut self, file: &syn::File) -> &mut Self {
+        self.visitor.visit_file(file);
+        self
+    }
+
+    /// Expands the macros in an item, including the derives and attribute-like macros applied to
+    /// it.
+    pub fn scan_item(&mut self, item: &syn::Item) -> &mut Self {
+        self.visitor.visit_item(item);
+        self
+    }
+
+    /// Expands the macros in an expression.
+    pub fn scan_expr(&mut self, expr: &syn::Expr) -> &mut Self {
+        self.visitor.visit_expr(expr);
+        self
+    }
+
+    /// Expands the macros in tokens that haven't been parsed, finding invocations the same way as
+    /// [`MacroRegistry::scan_macro_tokens`]. If `items` is true, the tokens are assumed to be a
+    /// sequence of items, so the derives and attribute-like macros applied to them are expanded
+    /// too.
+    ///
+    /// [`MacroRegistry::scan_macro_tokens`]: struct.MacroRegistry.html#method.scan_macro_tokens
+    pub fn scan_tokens(&mut self, tokens: TokenStream, items: bool) -> &mut Self {
+        self.visitor.scan_tokens(tokens, items);
+        self
+    }
+
+    /// Finishes scanning and returns the results.
+    ///
+    /// # Returns
+    ///
+    /// An [`ExpansionReport`] with a single [`FileReport`] on success, or the first error that
+    /// occurred, just like [`MacroRegistry::emulate_macro_expansion`].
+    ///
+    /// [`ExpansionReport`]: struct.ExpansionReport.html
+    /// [`FileReport`]: struct.FileReport.html
+    /// [`MacroRegistry::emulate_macro_expansion`]: struct.MacroRegistry.html#method.emulate_macro_expansion
+    pub fn finish(self) -> Result<ExpansionReport, Error> {
+        let path = self
+            .visitor
+            .source_path()
+            .map_or_else(PathBuf::new, Path::to_owned);
+        let file = self.visitor.finish()?.into_report(path);
+        self.registry.report(vec![file])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Skips the invocations of macros with the given name and the items with the given name.
+    struct Skip(&'static str);
+
+    impl ExpansionHandler for Skip {
+        fn enter_item(&mut self, item: &syn::Item) -> bool {
+            match *item {
+                syn::Item::Fn(ref f) => f.sig.ident != self.0,
+                _ => true,
+            }
+        }
+
+        fn on_derive(&mut self, context: &ExpansionContext, _item: &TokenStream) -> bool {
+            context.macro_path != self.0
+        }
+
+        fn on_attribute(
+            &mut self,
+            context: &ExpansionContext,
+            _attr: &TokenStream,
+            _item: &TokenStream,
+        ) -> bool {
+            context.macro_path != self.0
+        }
+    }
+
+    #[test]
+    fn skips_what_the_handler_rejects() {
+        let file = syn::parse_file(
+            "
+            #[outer]
+            #[derive(Foo, Bar)]
+            struct A;
+
+            fn skipped() { foo!(); }
+            fn kept() { foo!(); }
+            ",
+        )
+        .unwrap();
+        let mut registry = MacroRegistry::new();
+        registry
+            .attribute("outer", |_, item| item)
+            .derive("Foo", |_| TokenStream::new())
+            .derive("Bar", |_| TokenStream::new())
+            .functionlike("foo", |ts| ts)
+            .rescan_attribute_output(true);
+        let invocations = |skip| {
+            let mut driver = MacroDriver::new(&registry, None).unwrap();
+            driver.handler(Skip(skip)).scan_file(&file);
+            let report = driver.finish().unwrap();
+            ["outer", "Foo", "Bar", "foo"].map(|path| report.invocations(path))
+        };
+        assert_eq!(invocations("Foo"), [1, 0, 1, 2]);
+        assert_eq!(invocations("outer"), [0, 1, 1, 2]);
+        assert_eq!(invocations("skipped"), [1, 1, 1, 1]);
+    }
+}
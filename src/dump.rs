@@ -0,0 +1,125 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Writing every expansion to a directory so it can be browsed after a run.
+
+use {
+    corpus, pretty,
+    report::ExpansionReport,
+    std::{collections::BTreeMap, fmt::Write, fs, path::Path},
+    Error,
+};
+
+// Writes the expansions in the report to `<dir>/<file>/<line>.rs`, with every expansion on the
+// same line in the same file, and lists every invocation in `<dir>/index.txt`.
+pub(crate) fn dump(dir: &Path, report: &ExpansionReport) -> Result<(), Error> {
+    let mut index = String::new();
+    // The contents of each dump file, by its path relative to `dir`.
+    let mut dumps = BTreeMap::new();
+    for file in report.files.iter() {
+        let file_dir = corpus::file_name(Some(&file.path));
+        for record in file.records.iter() {
+            let name = format!("{}/{}.rs", file_dir, record.start.line);
+            let dump = dumps.entry(name).or_insert_with(String::new);
+            if !dump.is_empty() {
+                dump.push('\n');
+            }
+            writeln!(
+                dump,
+                "// {} macro `{}` at {}:{}:{}",
+                record.kind,
+                record.macro_path,
+                file.path.display(),
+                record.start.line,
+                record.start.column
+            )
+            .unwrap();
+            if let Some(ref attr) = record.attr {
+                write!(dump, "\n// Attribute:\n{}", pretty::format(attr)).unwrap();
+            }
+            write!(dump, "\n// Input:\n{}", pretty::format(&record.input)).unwrap();
+            write!(dump, "\n// Output:\n{}", record.pretty_output()).unwrap();
+        }
+
+        for site in file.call_sites.iter() {
+            writeln!(
+                index,
+                "{}:{}:{}\t{}\t{}\t{}/{}.rs\t{}",
+                file.path.display(),
+                site.start.line,
+                site.start.column,
+                site.kind,
+                site.macro_path,
+                file_dir,
+                site.start.line,
+                site.outcome
+            )
+            .unwrap();
+        }
+    }
+
+    let io_error = |path: &Path| {
+        let path = path.to_owned();
+        move |error| Error::IoError {
+            path: Some(path),
+            error,
+        }
+    };
+    for (name, dump) in dumps {
+        let path = dir.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(io_error(parent))?;
+        }
+        fs::write(&path, dump).map_err(io_error(&path))?;
+    }
+    let path = dir.join("index.txt");
+    fs::create_dir_all(dir).map_err(io_error(dir))?;
+    fs::write(&path, index).map_err(io_error(&path))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        proc_macro2::TokenStream,
+        std::{env, path::PathBuf, process},
+        MacroRegistry,
+    };
+
+    #[test]
+    fn dumps_expansions() {
+        let dir = env::temp_dir().join(format!("runtime-macros-dump-{}", process::id()));
+        let mut registry = MacroRegistry::new();
+        registry.functionlike("foo", |ts: TokenStream| quote::quote!(#ts + 1));
+        let scan = registry
+            .expand_source("fn main() {\n    foo!(1); foo!(2);\n}", None)
+            .unwrap();
+        let report = ExpansionReport {
+            files: vec![scan.into_report(PathBuf::from("src/main.rs"))],
+        };
+        dump(&dir, &report).unwrap();
+
+        let dumped = fs::read_to_string(dir.join("src_main.rs").join("2.rs")).unwrap();
+        assert_eq!(
+            dumped,
+            "// function-like macro `foo` at src/main.rs:2:4\n\n// Input:\n1\n\n// Output:\n1 + 1\n\n\
+             // function-like macro `foo` at src/main.rs:2:13\n\n// Input:\n2\n\n// Output:\n2 + 1\n"
+        );
+        let index = fs::read_to_string(dir.join("index.txt")).unwrap();
+        assert_eq!(
+            index.lines().next(),
+            Some("src/main.rs:2:4\tfunction-like\tfoo\tsrc_main.rs/2.rs\texpanded")
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
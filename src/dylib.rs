@@ -0,0 +1,321 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Loading macro functions from a compiled library instead of linking them into the test.
+
+use {
+    foreign,
+    libloading::Library,
+    proc_macro2::{Span, TokenStream},
+    std::{
+        ffi::{CStr, CString},
+        io,
+        os::raw::c_char,
+        path::{Path, PathBuf},
+        sync::Arc,
+    },
+    Error,
+};
+
+// The signatures of the functions a library exports.
+type ExpandFn = unsafe extern "C" fn(*const c_char) -> *mut c_char;
+type ExpandAttributeFn = unsafe extern "C" fn(*const c_char, *const c_char) -> *mut c_char;
+type FreeFn = unsafe extern "C" fn(*mut c_char);
+
+/// The name of the function that a [`MacroLibrary`] has to export for freeing the strings that its
+/// expansion functions return.
+///
+/// [`MacroLibrary`]: struct.MacroLibrary.html
+pub const FREE_SYMBOL: &str = "runtime_macros_free";
+
+/// A compiled library, such as a `cdylib`, that exports macro functions, so macros can be tested
+/// without linking them into the test. This is experimental and requires the `dylib` feature.
+///
+/// A procedural macro crate's own entry points can't be called this way, since they only work
+/// through the compiler's unstable `proc_macro` bridge. Instead, the library exports each
+/// expansion function with the C ABI, working on tokens in their string form:
+///
+/// ```
+/// use std::ffi::{CStr, CString};
+/// use std::os::raw::c_char;
+///
+/// // A function-like or derive macro.
+/// #[no_mangle]
+/// pub unsafe extern "C" fn shout(input: *const c_char) -> *mut c_char {
+///     let input = CStr::from_ptr(input).to_string_lossy();
+///     CString::new(input.to_uppercase()).unwrap().into_raw()
+/// }
+///
+/// // An attribute-like macro.
+/// #[no_mangle]
+/// pub unsafe extern "C" fn wrap(attr: *const c_char, item: *const c_char) -> *mut c_char {
+///     let attr = CStr::from_ptr(attr).to_string_lossy();
+///     let item = CStr::from_ptr(item).to_string_lossy();
+///     CString::new(format!("mod {} {{ {} }}", attr, item)).unwrap().into_raw()
+/// }
+///
+/// // Frees the strings returned by the other functions.
+/// #[no_mangle]
+/// pub unsafe extern "C" fn runtime_macros_free(output: *mut c_char) {
+///     drop(CString::from_raw(output));
+/// }
+/// ```
+///
+/// Such a library can wrap a macro crate's internal functions, as long as they take and return
+/// `proc_macro2` types. It's registered with [`MacroRegistry::functionlike_dylib`],
+/// [`MacroRegistry::derive_dylib`], and [`MacroRegistry::attribute_dylib`]. Like with
+/// [`ForeignTokens`], the spans are lost: the input only has spans at the call site, and the spans
+/// in the output don't point into the scanned file. If a function returns a null pointer, or its
+/// output can't be parsed, the expansion produces a `compile_error!` invocation, and the error is
+/// returned once the rest of the file has been scanned.
+///
+/// A panic can't unwind out of an `extern "C"` function, so a function that panics aborts the
+/// process unless the library catches the panic itself.
+///
+/// [`MacroRegistry::functionlike_dylib`]: struct.MacroRegistry.html#method.functionlike_dylib
+/// [`MacroRegistry::derive_dylib`]: struct.MacroRegistry.html#method.derive_dylib
+/// [`MacroRegistry::attribute_dylib`]: struct.MacroRegistry.html#method.attribute_dylib
+/// [`ForeignTokens`]: trait.ForeignTokens.html
+#[derive(Debug, Clone)]
+pub struct MacroLibrary {
+    path: PathBuf,
+    library: Arc<Library>,
+    free: FreeFn,
+}
+
+impl MacroLibrary {
+    /// Loads the library at the given path. It has to export a function named [`FREE_SYMBOL`].
+    ///
+    /// This returns an [`Error::IoError`] if the library can't be loaded or doesn't export that
+    /// function.
+    ///
+    /// # Safety
+    ///
+    /// Loading a library runs its initialization code, which has to be sound. Every function that's
+    /// registered from it also has to have the signature shown in the [type's documentation], since
+    /// there's no way to check it.
+    ///
+    /// [`FREE_SYMBOL`]: constant.FREE_SYMBOL.html
+    /// [`Error::IoError`]: enum.Error.html#variant.IoError
+    /// [type's documentation]: struct.MacroLibrary.html
+    pub unsafe fn open<P: AsRef<Path>>(path: P) -> Result<MacroLibrary, Error> {
+        let path = path.as_ref().to_owned();
+        let error = |error: libloading::Error| Error::IoError {
+            path: Some(path.clone()),
+            error: io::Error::other(error),
+        };
+        let library = Library::new(&path).map_err(error)?;
+        let free = *library
+            .get::<FreeFn>(FREE_SYMBOL.as_bytes())
+            .map_err(error)?;
+        Ok(MacroLibrary {
+            path,
+            library: Arc::new(library),
+            free,
+        })
+    }
+
+    /// Returns the path the library was loaded from.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Finds an exported function, or describes why it can't be found.
+    fn get<T: Copy>(&self, symbol: &str) -> Result<T, String> {
+        // SAFETY: `open`'s caller promised that every registered function has the right type. The
+        // pointer stays valid after the `Symbol` is dropped because the closures that call it own
+        // a reference to the library.
+        unsafe { self.library.get::<T>(symbol.as_bytes()) }
+            .map(|symbol| *symbol)
+            .map_err(|error| {
+                format!(
+                    "couldn't find `{}` in {}: {}",
+                    symbol,
+                    self.path.display(),
+                    error
+                )
+            })
+    }
+
+    // Returns a function that calls the exported function of a function-like or derive macro.
+    pub(crate) fn expand_fn(
+        &self,
+        symbol: &str,
+    ) -> impl Fn(TokenStream) -> syn::Result<TokenStream> {
+        let library = self.clone();
+        let expand = self.get::<ExpandFn>(symbol);
+        move |ts| {
+            let expand = expand.as_ref().map_err(error)?;
+            let input = c_string(ts)?;
+            // SAFETY: as in `get`.
+            library.output(unsafe { expand(input.as_ptr()) })
+        }
+    }
+
+    // Returns a function that calls the exported function of an attribute-like macro.
+    pub(crate) fn expand_attribute_fn(
+        &self,
+        symbol: &str,
+    ) -> impl Fn(TokenStream, TokenStream) -> syn::Result<TokenStream> {
+        let library = self.clone();
+        let expand = self.get::<ExpandAttributeFn>(symbol);
+        move |attr, item| {
+            let expand = expand.as_ref().map_err(error)?;
+            let (attr, item) = (c_string(attr)?, c_string(item)?);
+            // SAFETY: as in `get`.
+            library.output(unsafe { expand(attr.as_ptr(), item.as_ptr()) })
+        }
+    }
+
+    // Converts a string returned by one of the library's functions to tokens and frees it.
+    fn output(&self, output: *mut c_char) -> syn::Result<TokenStream> {
+        if output.is_null() {
+            return Err(error("the library's function didn't return any tokens"));
+        }
+        // SAFETY: the function returned a string, which stays valid until it's freed.
+        let tokens = unsafe { CStr::from_ptr(output) }
+            .to_str()
+            .map(str::to_owned)
+            .map_err(|_| error("the library's function returned tokens that aren't UTF-8"));
+        // SAFETY: the library's free function takes the strings that its other functions return.
+        unsafe { (self.free)(output) };
+        foreign::output(tokens?)
+    }
+}
+
+// Converts a macro's input to a string the library can read.
+fn c_string(tokens: TokenStream) -> syn::Result<CString> {
+    CString::new(foreign::input::<String>(tokens)?)
+        .map_err(|_| error("couldn't convert the macro's input: it contains a null character"))
+}
+
+fn error<T: std::fmt::Display>(message: T) -> syn::Error {
+    syn::Error::new(Span::call_site(), message)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use {
+        super::MacroLibrary,
+        std::{
+            env, fs,
+            process::{self, Command},
+            sync::OnceLock,
+        },
+        Error, MacroRegistry,
+    };
+
+    const FIXTURE: &str = r#"
+        use std::ffi::{CStr, CString};
+        use std::os::raw::c_char;
+
+        #[no_mangle]
+        pub unsafe extern "C" fn shout(input: *const c_char) -> *mut c_char {
+            let input = CStr::from_ptr(input).to_string_lossy();
+            CString::new(input.to_uppercase()).unwrap().into_raw()
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn wrap(attr: *const c_char, item: *const c_char) -> *mut c_char {
+            let attr = CStr::from_ptr(attr).to_string_lossy();
+            let item = CStr::from_ptr(item).to_string_lossy();
+            CString::new(format!("mod {} {{ {} }}", attr, item)).unwrap().into_raw()
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn nothing(_: *const c_char) -> *mut c_char {
+            std::ptr::null_mut()
+        }
+
+        #[no_mangle]
+        pub unsafe extern "C" fn runtime_macros_free(output: *mut c_char) {
+            drop(CString::from_raw(output));
+        }
+    "#;
+
+    // Compiles the fixture with rustc into a new directory and loads it. It's only compiled once,
+    // since other modules' tests use it too.
+    pub(crate) fn fixture() -> MacroLibrary {
+        static FIXTURE_LIBRARY: OnceLock<MacroLibrary> = OnceLock::new();
+        FIXTURE_LIBRARY
+            .get_or_init(|| {
+                let dir = env::temp_dir().join(format!("runtime-macros-dylib-{}", process::id()));
+                fs::create_dir_all(&dir).unwrap();
+                let source = dir.join("fixture.rs");
+                let library = dir.join(format!(
+                    "{}fixture{}",
+                    env::consts::DLL_PREFIX,
+                    env::consts::DLL_SUFFIX
+                ));
+                fs::write(&source, FIXTURE).unwrap();
+                let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+                let status = Command::new(rustc)
+                    .args(["--crate-type", "cdylib", "--crate-name", "fixture", "-o"])
+                    .arg(&library)
+                    .arg(&source)
+                    .status()
+                    .unwrap();
+                assert!(status.success());
+                // SAFETY: the fixture has no initialization code, and its functions have the
+                // right types.
+                unsafe { MacroLibrary::open(&library) }.unwrap()
+            })
+            .clone()
+    }
+
+    #[test]
+    fn expands_with_a_compiled_library() {
+        let library = fixture();
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike_dylib("shout", &library, "shout")
+            .attribute_dylib("wrap", &library, "wrap")
+            .derive_dylib("Nothing", &library, "nothing")
+            .functionlike_dylib("missing", &library, "missing");
+        let file = registry
+            .expand_source("fn f() { shout!(a + b); }\n#[wrap(m)] fn g() {}", None)
+            .unwrap();
+        let outputs: Vec<String> = file
+            .records
+            .iter()
+            .map(|record| record.output.to_string())
+            .collect();
+        assert_eq!(outputs, ["A + B", "mod m { # [wrap (m)] fn g () { } }"]);
+
+        for (source, expected) in [
+            (
+                "#[derive(Nothing)] struct S;",
+                "the library's function didn't return",
+            ),
+            ("missing!();", "couldn't find `missing` in"),
+        ] {
+            match registry.expand_source(source, None) {
+                Err(Error::MacroFailed { error, .. }) => {
+                    let message = error.to_string();
+                    assert!(message.starts_with(expected), "{}", message);
+                }
+                other => panic!("expected the expansion to fail, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn reports_libraries_that_cant_be_loaded() {
+        let path = env::temp_dir().join("runtime-macros-not-a-library.so");
+        fs::write(&path, "not a library").unwrap();
+        // SAFETY: the file isn't a library, so nothing is loaded.
+        match unsafe { MacroLibrary::open(&path) } {
+            Err(Error::IoError { path: Some(p), .. }) => assert_eq!(p, path),
+            other => panic!("expected the library not to load, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,1189 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+
+//! A builder-style front end for configuring and running macro expansion emulation.
+
+use {
+    budget::Budget,
+    cache::Cache,
+    cfg::CfgSet,
+    context::ExpansionContext,
+    docs, dump,
+    fidelity::Fidelity,
+    foreign::ForeignTokens,
+    glob, isolation,
+    matching::MatchMode,
+    modules, order,
+    panics::PanicPolicy,
+    proc_macro2::TokenStream,
+    progress::{self, Progress, ProgressFn},
+    registry::{AttributeFn, DeriveFn, FunctionlikeFn},
+    report::{ExpansionReport, FileReport, MacroKind},
+    reporter::{self, QuietReporter, Reporter},
+    session,
+    snapshots::Snapshots,
+    std::{
+        cell::RefCell,
+        collections::HashSet,
+        ffi::OsStr,
+        mem,
+        path::{Path, PathBuf},
+        time::Duration,
+    },
+    syn::parse::Parse,
+    Error, MacroRegistry,
+};
+
+#[cfg(feature = "dylib")]
+use dylib::MacroLibrary;
+#[cfg(feature = "verify-compile")]
+use verify::CompileCheck;
+
+/// Configures and runs the emulation of procedural macro expansion over a set of files.
+///
+/// An `Expander` is built up by registering the macros to expand and the files to scan, then
+/// consumed by calling [`run`]. Unlike the `emulate_*_macro_expansion` functions, it returns an
+/// [`ExpansionReport`] describing what it did, and new options can be added to it without changing
+/// any existing signatures.
+///
+/// [`run`]: #method.run
+/// [`ExpansionReport`]: struct.ExpansionReport.html
+///
+/// # Example
+///
+/// ```
+/// # use runtime_macros::Expander;
+/// fn remove_internal(_: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+///     proc_macro2::TokenStream::new()
+/// }
+///
+/// let report = Expander::new()
+///     .functionlike("remove", remove_internal)
+/// # /*
+///     .file("tests/tests.rs")
+/// # */
+/// #   .file(concat!(env!("CARGO_MANIFEST_DIR"), "/src/lib.rs"))
+///     .run()
+///     .unwrap();
+/// assert_eq!(report.files.len(), 1);
+/// ```
+#[derive(Default)]
+pub struct Expander<'a> {
+    registry: MacroRegistry<'a>,
+    files: Vec<PathBuf>,
+    markdown: Vec<PathBuf>,
+    follow_modules: bool,
+    follow_includes: bool,
+    doc_examples: bool,
+    cache_dir: Option<PathBuf>,
+    snapshots: Option<Snapshots>,
+    dump_dir: Option<PathBuf>,
+    isolate: bool,
+    #[cfg(feature = "verify-compile")]
+    compile_check: Option<CompileCheck>,
+    refresh_cache: bool,
+    reporter: Option<RefCell<Box<dyn Reporter + 'a>>>,
+    progress: Option<ProgressFn<'a>>,
+}
+
+impl<'a> Expander<'a> {
+    /// Creates an expander with no registered macros and no files to scan.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a function-like macro, such as `foo!(...)`, with the function that defines how to
+    /// expand it.
+    pub fn functionlike<F>(mut self, macro_path: &str, proc_macro_fn: F) -> Self
+    where
+        F: Fn(TokenStream) -> TokenStream + 'a,
+    {
+        self.registry.functionlike(macro_path, proc_macro_fn);
+        self
+    }
+
+    /// Registers a derive macro, such as `#[derive(Foo)]`, with the function that defines how to
+    /// expand it.
+    pub fn derive<F>(mut self, macro_path: &str, proc_macro_fn: F) -> Self
+    where
+        F: Fn(TokenStream) -> TokenStream + 'a,
+    {
+        self.registry.derive(macro_path, proc_macro_fn);
+        self
+    }
+
+    /// Registers a derive macro with a function that takes the annotated item already parsed. See
+    /// [`MacroRegistry::derive_parsed`] for details.
+    ///
+    /// [`MacroRegistry::derive_parsed`]: struct.MacroRegistry.html#method.derive_parsed
+    pub fn derive_parsed<T, F>(mut self, macro_path: &str, proc_macro_fn: F) -> Self
+    where
+        T: Parse + 'a,
+        F: Fn(T) -> TokenStream + 'a,
+    {
+        self.registry.derive_parsed(macro_path, proc_macro_fn);
+        self
+    }
+
+    /// Registers an attribute-like macro, such as `#[foo(...)]`, with the function that defines how
+    /// to expand it.
+    pub fn attribute<F>(mut self, macro_path: &str, proc_macro_fn: F) -> Self
+    where
+        F: Fn(TokenStream, TokenStream) -> TokenStream + 'a,
+    {
+        self.registry.attribute(macro_path, proc_macro_fn);
+        self
+    }
+
+    /// Registers a reference implementation of a function-like macro to compare its output with.
+    /// See [`MacroRegistry::compare_functionlike`][method] for details.
+    ///
+    /// [method]: struct.MacroRegistry.html#method.compare_functionlike
+    pub fn compare_functionlike<F>(mut self, macro_path: &str, reference_fn: F) -> Self
+    where
+        F: Fn(TokenStream) -> TokenStream + 'a,
+    {
+        self.registry.compare_functionlike(macro_path, reference_fn);
+        self
+    }
+
+    /// Registers a reference implementation of a derive macro to compare its output with. See
+    /// [`MacroRegistry::compare_derive`] for details.
+    ///
+    /// [`MacroRegistry::compare_derive`]: struct.MacroRegistry.html#method.compare_derive
+    pub fn compare_derive<F>(mut self, macro_path: &str, reference_fn: F) -> Self
+    where
+        F: Fn(TokenStream) -> TokenStream + 'a,
+    {
+        self.registry.compare_derive(macro_path, reference_fn);
+        self
+    }
+
+    /// Registers a reference implementation of an attribute-like macro to compare its output
+    /// with. See [`MacroRegistry::compare_attribute`] for details.
+    ///
+    /// [`MacroRegistry::compare_attribute`]: struct.MacroRegistry.html#method.compare_attribute
+    pub fn compare_attribute<F>(mut self, macro_path: &str, reference_fn: F) -> Self
+    where
+        F: Fn(TokenStream, TokenStream) -> TokenStream + 'a,
+    {
+        self.registry.compare_attribute(macro_path, reference_fn);
+        self
+    }
+
+    /// Registers a function-like macro with a function that can fail. See
+    /// [`MacroRegistry::functionlike_fallible`][method] for details.
+    ///
+    /// [method]: struct.MacroRegistry.html#method.functionlike_fallible
+    pub fn functionlike_fallible<F>(mut self, macro_path: &str, proc_macro_fn: F) -> Self
+    where
+        F: Fn(TokenStream) -> syn::Result<TokenStream> + 'a,
+    {
+        self.registry
+            .functionlike_fallible(macro_path, proc_macro_fn);
+        self
+    }
+
+    /// Registers a derive macro with a function that takes the annotated item already parsed and
+    /// can fail. See [`MacroRegistry::derive_fallible`] for details.
+    ///
+    /// [`MacroRegistry::derive_fallible`]: struct.MacroRegistry.html#method.derive_fallible
+    pub fn derive_fallible<T, F>(mut self, macro_path: &str, proc_macro_fn: F) -> Self
+    where
+        T: Parse + 'a,
+        F: Fn(T) -> syn::Result<TokenStream> + 'a,
+    {
+        self.registry.derive_fallible(macro_path, proc_macro_fn);
+        self
+    }
+
+    /// Registers an attribute-like macro with a function that can fail. See
+    /// [`MacroRegistry::attribute_fallible`] for details.
+    ///
+    /// [`MacroRegistry::attribute_fallible`]: struct.MacroRegistry.html#method.attribute_fallible
+    pub fn attribute_fallible<F>(mut self, macro_path: &str, proc_macro_fn: F) -> Self
+    where
+        F: Fn(TokenStream, TokenStream) -> syn::Result<TokenStream> + 'a,
+    {
+        self.registry.attribute_fallible(macro_path, proc_macro_fn);
+        self
+    }
+
+    /// Registers a function-like macro with a function that uses a different type of tokens. See
+    /// [`MacroRegistry::functionlike_foreign`][method] for details.
+    ///
+    /// [method]: struct.MacroRegistry.html#method.functionlike_foreign
+    pub fn functionlike_foreign<T, F>(mut self, macro_path: &str, proc_macro_fn: F) -> Self
+    where
+        T: ForeignTokens,
+        F: Fn(T) -> T + 'a,
+    {
+        self.registry
+            .functionlike_foreign(macro_path, proc_macro_fn);
+        self
+    }
+
+    /// Registers a derive macro with a function that uses a different type of tokens. See
+    /// [`MacroRegistry::derive_foreign`] for details.
+    ///
+    /// [`MacroRegistry::derive_foreign`]: struct.MacroRegistry.html#method.derive_foreign
+    pub fn derive_foreign<T, F>(mut self, macro_path: &str, proc_macro_fn: F) -> Self
+    where
+        T: ForeignTokens,
+        F: Fn(T) -> T + 'a,
+    {
+        self.registry.derive_foreign(macro_path, proc_macro_fn);
+        self
+    }
+
+    /// Registers an attribute-like macro with a function that uses a different type of tokens. See
+    /// [`MacroRegistry::attribute_foreign`] for details.
+    ///
+    /// [`MacroRegistry::attribute_foreign`]: struct.MacroRegistry.html#method.attribute_foreign
+    pub fn attribute_foreign<T, F>(mut self, macro_path: &str, proc_macro_fn: F) -> Self
+    where
+        T: ForeignTokens,
+        F: Fn(T, T) -> T + 'a,
+    {
+        self.registry.attribute_foreign(macro_path, proc_macro_fn);
+        self
+    }
+
+    /// Registers a function-like macro with a function exported by a compiled library. See
+    /// [`MacroRegistry::functionlike_dylib`] for details.
+    ///
+    /// [`MacroRegistry::functionlike_dylib`]: struct.MacroRegistry.html#method.functionlike_dylib
+    #[cfg(feature = "dylib")]
+    pub fn functionlike_dylib(
+        mut self,
+        macro_path: &str,
+        library: &MacroLibrary,
+        symbol: &str,
+    ) -> Self {
+        self.registry
+            .functionlike_dylib(macro_path, library, symbol);
+        self
+    }
+
+    /// Registers a derive macro with a function exported by a compiled library. See
+    /// [`MacroRegistry::derive_dylib`] for details.
+    ///
+    /// [`MacroRegistry::derive_dylib`]: struct.MacroRegistry.html#method.derive_dylib
+    #[cfg(feature = "dylib")]
+    pub fn derive_dylib(mut self, macro_path: &str, library: &MacroLibrary, symbol: &str) -> Self {
+        self.registry.derive_dylib(macro_path, library, symbol);
+        self
+    }
+
+    /// Registers an attribute-like macro with a function exported by a compiled library. See
+    /// [`MacroRegistry::attribute_dylib`] for details.
+    ///
+    /// [`MacroRegistry::attribute_dylib`]: struct.MacroRegistry.html#method.attribute_dylib
+    #[cfg(feature = "dylib")]
+    pub fn attribute_dylib(
+        mut self,
+        macro_path: &str,
+        library: &MacroLibrary,
+        symbol: &str,
+    ) -> Self {
+        self.registry.attribute_dylib(macro_path, library, symbol);
+        self
+    }
+
+    /// Registers a function-like macro with a function that also receives an [`ExpansionContext`]
+    /// describing each invocation it is asked to expand.
+    ///
+    /// [`ExpansionContext`]: struct.ExpansionContext.html
+    pub fn functionlike_with_context<F>(mut self, macro_path: &str, proc_macro_fn: F) -> Self
+    where
+        F: Fn(&ExpansionContext, TokenStream) -> TokenStream + 'a,
+    {
+        self.registry
+            .functionlike_with_context(macro_path, proc_macro_fn);
+        self
+    }
+
+    /// Registers a derive macro with a function that also receives an [`ExpansionContext`]
+    /// describing each invocation it is asked to expand.
+    ///
+    /// [`ExpansionContext`]: struct.ExpansionContext.html
+    pub fn derive_with_context<F>(mut self, macro_path: &str, proc_macro_fn: F) -> Self
+    where
+        F: Fn(&ExpansionContext, TokenStream) -> TokenStream + 'a,
+    {
+        self.registry.derive_with_context(macro_path, proc_macro_fn);
+        self
+    }
+
+    /// Registers an attribute-like macro with a function that also receives an
+    /// [`ExpansionContext`] describing each invocation it is asked to expand.
+    ///
+    /// [`ExpansionContext`]: struct.ExpansionContext.html
+    pub fn attribute_with_context<F>(mut self, macro_path: &str, proc_macro_fn: F) -> Self
+    where
+        F: Fn(&ExpansionContext, TokenStream, TokenStream) -> TokenStream + 'a,
+    {
+        self.registry
+            .attribute_with_context(macro_path, proc_macro_fn);
+        self
+    }
+
+    /// Registers a hook to be called after every expansion. See
+    /// [`MacroRegistry::after_expansion`] for details.
+    ///
+    /// [`MacroRegistry::after_expansion`]: struct.MacroRegistry.html#method.after_expansion
+    pub fn after_expansion<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&ExpansionContext, &TokenStream) + 'a,
+    {
+        self.registry.after_expansion(hook);
+        self
+    }
+
+    /// Registers a lint to run on the output of every successful expansion. See
+    /// [`MacroRegistry::lint`] for details.
+    ///
+    /// [`MacroRegistry::lint`]: struct.MacroRegistry.html#method.lint
+    pub fn lint<F>(mut self, name: &str, lint: F) -> Self
+    where
+        F: Fn(&ExpansionContext, &TokenStream) -> syn::Result<()> + 'a,
+    {
+        self.registry.lint(name, lint);
+        self
+    }
+
+    /// Sets whether attribute-like macros should also be expanded on struct fields and enum
+    /// variants. See [`MacroRegistry::field_attributes`] for details.
+    ///
+    /// [`MacroRegistry::field_attributes`]: struct.MacroRegistry.html#method.field_attributes
+    pub fn field_attributes(mut self, enabled: bool) -> Self {
+        self.registry.field_attributes(enabled);
+        self
+    }
+
+    /// Sets how closely the input passed to each macro function should match what the compiler
+    /// would pass. See [`MacroRegistry::fidelity`] for details.
+    ///
+    /// [`MacroRegistry::fidelity`]: struct.MacroRegistry.html#method.fidelity
+    pub fn fidelity(mut self, fidelity: Fidelity) -> Self {
+        self.registry.fidelity(fidelity);
+        self
+    }
+
+    /// Sets whether `#[cfg_attr(...)]` attributes should be unwrapped. See
+    /// [`MacroRegistry::unwrap_cfg_attr`] for details.
+    ///
+    /// [`MacroRegistry::unwrap_cfg_attr`]: struct.MacroRegistry.html#method.unwrap_cfg_attr
+    pub fn unwrap_cfg_attr(mut self, enabled: bool) -> Self {
+        self.registry.unwrap_cfg_attr(enabled);
+        self
+    }
+
+    /// Declares which configuration options should be considered active. See
+    /// [`MacroRegistry::cfgs`] for details.
+    ///
+    /// [`MacroRegistry::cfgs`]: struct.MacroRegistry.html#method.cfgs
+    pub fn cfgs(mut self, cfgs: CfgSet) -> Self {
+        self.registry.cfgs(cfgs);
+        self
+    }
+
+    /// Sets how derive macro paths are matched. See [`MacroRegistry::derive_match_mode`] for
+    /// details.
+    ///
+    /// [`MacroRegistry::derive_match_mode`]: struct.MacroRegistry.html#method.derive_match_mode
+    pub fn derive_match_mode(mut self, mode: MatchMode) -> Self {
+        self.registry.derive_match_mode(mode);
+        self
+    }
+
+    /// Sets how paths are matched for one registered macro. See [`MacroRegistry::match_mode`] for
+    /// details.
+    ///
+    /// [`MacroRegistry::match_mode`]: struct.MacroRegistry.html#method.match_mode
+    pub fn match_mode(mut self, kind: MacroKind, macro_path: &str, mode: MatchMode) -> Self {
+        self.registry.match_mode(kind, macro_path, mode);
+        self
+    }
+
+    /// Sets whether it's an error for a registered macro never to be invoked in any of the files.
+    /// See [`MacroRegistry::require_all_invoked`] for details.
+    ///
+    /// [`MacroRegistry::require_all_invoked`]: struct.MacroRegistry.html#method.require_all_invoked
+    pub fn require_all_invoked(mut self, enabled: bool) -> Self {
+        self.registry.require_all_invoked(enabled);
+        self
+    }
+
+    /// Sets whether the output of each attribute-like macro should be scanned again. See
+    /// [`MacroRegistry::rescan_attribute_output`][method] for details.
+    ///
+    /// [method]: struct.MacroRegistry.html#method.rescan_attribute_output
+    pub fn rescan_attribute_output(mut self, enabled: bool) -> Self {
+        self.registry.rescan_attribute_output(enabled);
+        self
+    }
+
+    /// Sets whether the output of each function-like macro should be scanned again. See
+    /// [`MacroRegistry::rescan_functionlike_output`][method] for details.
+    ///
+    /// [method]: struct.MacroRegistry.html#method.rescan_functionlike_output
+    pub fn rescan_functionlike_output(mut self, enabled: bool) -> Self {
+        self.registry.rescan_functionlike_output(enabled);
+        self
+    }
+
+    /// Sets whether an item's attribute-like macros should be chained. See
+    /// [`MacroRegistry::chain_attributes`] for details.
+    ///
+    /// [`MacroRegistry::chain_attributes`]: struct.MacroRegistry.html#method.chain_attributes
+    pub fn chain_attributes(mut self, enabled: bool) -> Self {
+        self.registry.chain_attributes(enabled);
+        self
+    }
+
+    /// Sets whether the tokens passed to unregistered macros should be searched for invocations of
+    /// registered function-like macros. See [`MacroRegistry::scan_macro_tokens`] for details.
+    ///
+    /// [`MacroRegistry::scan_macro_tokens`]: struct.MacroRegistry.html#method.scan_macro_tokens
+    pub fn scan_macro_tokens(mut self, enabled: bool) -> Self {
+        self.registry.scan_macro_tokens(enabled);
+        self
+    }
+
+    /// Sets whether the bodies of `macro_rules!` definitions should be searched for invocations of
+    /// registered function-like macros. See [`MacroRegistry::scan_macro_rules`] for details.
+    ///
+    /// [`MacroRegistry::scan_macro_rules`]: struct.MacroRegistry.html#method.scan_macro_rules
+    pub fn scan_macro_rules(mut self, enabled: bool) -> Self {
+        self.registry.scan_macro_rules(enabled);
+        self
+    }
+
+    /// Sets whether files that syn can't parse should be scanned anyway. See
+    /// [`MacroRegistry::lenient_parsing`] for details.
+    ///
+    /// [`MacroRegistry::lenient_parsing`]: struct.MacroRegistry.html#method.lenient_parsing
+    pub fn lenient_parsing(mut self, enabled: bool) -> Self {
+        self.registry.lenient_parsing(enabled);
+        self
+    }
+
+    /// Sets a directory to record the input of every invocation of a registered macro in. See
+    /// [`MacroRegistry::record_corpus`] for details.
+    ///
+    /// [`MacroRegistry::record_corpus`]: struct.MacroRegistry.html#method.record_corpus
+    pub fn record_corpus<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.registry.record_corpus(dir);
+        self
+    }
+
+    /// Sets whether the output of each function-like macro should be parsed in the position where
+    /// the macro was invoked. See [`MacroRegistry::validate_output`] for details.
+    ///
+    /// [`MacroRegistry::validate_output`]: struct.MacroRegistry.html#method.validate_output
+    pub fn validate_output(mut self, enabled: bool) -> Self {
+        self.registry.validate_output(enabled);
+        self
+    }
+
+    /// Sets whether the `#[derive(...)]` attributes should be removed from the item before it's
+    /// passed to a derive macro. See [`MacroRegistry::strip_derive_attributes`][method] for
+    /// details.
+    ///
+    /// [method]: struct.MacroRegistry.html#method.strip_derive_attributes
+    pub fn strip_derive_attributes(mut self, enabled: bool) -> Self {
+        self.registry.strip_derive_attributes(enabled);
+        self
+    }
+
+    /// Sets whether an attribute-like macro's own attribute should be removed from the item before
+    /// it's passed to the macro. See [`MacroRegistry::strip_invoked_attribute`][method] for
+    /// details.
+    ///
+    /// [method]: struct.MacroRegistry.html#method.strip_invoked_attribute
+    pub fn strip_invoked_attribute(mut self, enabled: bool) -> Self {
+        self.registry.strip_invoked_attribute(enabled);
+        self
+    }
+
+    /// Declares the helper attributes of a registered derive macro. See
+    /// [`MacroRegistry::derive_helpers`] for details.
+    ///
+    /// [`MacroRegistry::derive_helpers`]: struct.MacroRegistry.html#method.derive_helpers
+    pub fn derive_helpers(mut self, macro_path: &str, helpers: &[&str]) -> Self {
+        self.registry.derive_helpers(macro_path, helpers);
+        self
+    }
+
+    /// Sets how long a macro function can run for each invocation before it's considered stuck.
+    /// This requires [`isolate`]. See [`MacroRegistry::timeout`] for details.
+    ///
+    /// [`isolate`]: #method.isolate
+    /// [`MacroRegistry::timeout`]: struct.MacroRegistry.html#method.timeout
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.registry.timeout(timeout);
+        self
+    }
+
+    /// Sets the amount of stack space, in bytes, that each call to a macro function is guaranteed.
+    /// See [`MacroRegistry::stack_size`] for details.
+    ///
+    /// [`MacroRegistry::stack_size`]: struct.MacroRegistry.html#method.stack_size
+    #[cfg(feature = "stacker")]
+    pub fn stack_size(mut self, bytes: usize) -> Self {
+        self.registry.stack_size(bytes);
+        self
+    }
+
+    /// Sets whether the panics of invocations marked with `// runtime-macros: expect-panic` should
+    /// be kept out of the standard error stream. See
+    /// [`MacroRegistry::quiet_expected_panics`][method] for details.
+    ///
+    /// [method]: struct.MacroRegistry.html#method.quiet_expected_panics
+    pub fn quiet_expected_panics(mut self, enabled: bool) -> Self {
+        self.registry.quiet_expected_panics(enabled);
+        self
+    }
+
+    /// Sets whether the input of each invocation whose macro function panics unexpectedly should
+    /// be shrunk to a smaller one that still makes it panic. See
+    /// [`MacroRegistry::minimize_panics`] for details.
+    ///
+    /// [`MacroRegistry::minimize_panics`]: struct.MacroRegistry.html#method.minimize_panics
+    pub fn minimize_panics(mut self, enabled: bool) -> Self {
+        self.registry.minimize_panics(enabled);
+        self
+    }
+
+    /// Sets an environment variable for the duration of each call to a macro function. See
+    /// [`MacroRegistry::env`] for details.
+    ///
+    /// [`MacroRegistry::env`]: struct.MacroRegistry.html#method.env
+    pub fn env<K, V>(mut self, key: K, value: V) -> Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.registry.env(key, value);
+        self
+    }
+
+    /// Removes an environment variable for the duration of each call to a macro function. See
+    /// [`MacroRegistry::env_remove`] for details.
+    ///
+    /// [`MacroRegistry::env_remove`]: struct.MacroRegistry.html#method.env_remove
+    pub fn env_remove<K: AsRef<OsStr>>(mut self, key: K) -> Self {
+        self.registry.env_remove(key);
+        self
+    }
+
+    /// Shuffles the order that the files and their items are scanned in, using the given seed. See
+    /// [`MacroRegistry::shuffle`] for details.
+    ///
+    /// [`MacroRegistry::shuffle`]: struct.MacroRegistry.html#method.shuffle
+    pub fn shuffle(mut self, seed: u64) -> Self {
+        self.registry.shuffle(seed);
+        self
+    }
+
+    /// Sets whether each macro function should be called twice for every invocation, to check that
+    /// it gives the same output both times. See [`MacroRegistry::check_determinism`] for details.
+    ///
+    /// [`MacroRegistry::check_determinism`]: struct.MacroRegistry.html#method.check_determinism
+    pub fn check_determinism(mut self, enabled: bool) -> Self {
+        self.registry.check_determinism(enabled);
+        self
+    }
+
+    /// Sets whether each attribute-like macro should also be applied to its own output, to check
+    /// that it can be. See [`MacroRegistry::check_idempotency`] for details.
+    ///
+    /// [`MacroRegistry::check_idempotency`]: struct.MacroRegistry.html#method.check_idempotency
+    pub fn check_idempotency(mut self, enabled: bool) -> Self {
+        self.registry.check_idempotency(enabled);
+        self
+    }
+
+    /// Sets the number of times each macro function should be called again with a mutation of an
+    /// invocation's input. This requires the `fuzz` feature. See [`MacroRegistry::fuzz`] for
+    /// details.
+    ///
+    /// [`MacroRegistry::fuzz`]: struct.MacroRegistry.html#method.fuzz
+    #[cfg(feature = "fuzz")]
+    pub fn fuzz(mut self, mutations: usize, seed: u64) -> Self {
+        self.registry.fuzz(mutations, seed);
+        self
+    }
+
+    /// Sets limits on the size of each expansion of a registered macro. See
+    /// [`MacroRegistry::budget`] for details.
+    ///
+    /// [`MacroRegistry::budget`]: struct.MacroRegistry.html#method.budget
+    pub fn budget(mut self, kind: MacroKind, macro_path: &str, budget: Budget) -> Self {
+        self.registry.budget(kind, macro_path, budget);
+        self
+    }
+
+    /// Sets whether each expansion should be searched for local variables that might capture
+    /// names in the caller's code. See [`MacroRegistry::check_hygiene`] for details.
+    ///
+    /// [`MacroRegistry::check_hygiene`]: struct.MacroRegistry.html#method.check_hygiene
+    pub fn check_hygiene(mut self, enabled: bool) -> Self {
+        self.registry.check_hygiene(enabled);
+        self
+    }
+
+    /// Sets how long an expansion can take before it's considered slow. See
+    /// [`MacroRegistry::slow_threshold`] for details.
+    ///
+    /// [`MacroRegistry::slow_threshold`]: struct.MacroRegistry.html#method.slow_threshold
+    pub fn slow_threshold(mut self, threshold: Duration) -> Self {
+        self.registry.slow_threshold(threshold);
+        self
+    }
+
+    /// Sets whether an expansion that's slower than the threshold is an error. See
+    /// [`MacroRegistry::deny_slow`] for details.
+    ///
+    /// [`MacroRegistry::deny_slow`]: struct.MacroRegistry.html#method.deny_slow
+    pub fn deny_slow(mut self, enabled: bool) -> Self {
+        self.registry.deny_slow(enabled);
+        self
+    }
+
+    /// Sets the working directory for the duration of each call to a macro function. See
+    /// [`MacroRegistry::working_dir`] for details.
+    ///
+    /// [`MacroRegistry::working_dir`]: struct.MacroRegistry.html#method.working_dir
+    pub fn working_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.registry.working_dir(dir);
+        self
+    }
+
+    /// Sets a function that chooses the working directory for the macro functions called while
+    /// scanning each file. See [`MacroRegistry::working_dir_with`] for details.
+    ///
+    /// [`MacroRegistry::working_dir_with`]: struct.MacroRegistry.html#method.working_dir_with
+    pub fn working_dir_with<F>(mut self, working_dir: F) -> Self
+    where
+        F: Fn(Option<&Path>) -> Option<PathBuf> + 'a,
+    {
+        self.registry.working_dir_with(working_dir);
+        self
+    }
+
+    /// Sets what to do when a macro function panics. See [`MacroRegistry::panic_policy`] for
+    /// details.
+    ///
+    /// [`MacroRegistry::panic_policy`]: struct.MacroRegistry.html#method.panic_policy
+    pub fn panic_policy(mut self, policy: PanicPolicy) -> Self {
+        self.registry.panic_policy(policy);
+        self
+    }
+
+    /// Registers several function-like macros at once. See
+    /// [`MacroRegistry::extend_functionlike`] for details.
+    ///
+    /// [`MacroRegistry::extend_functionlike`]: struct.MacroRegistry.html#method.extend_functionlike
+    pub fn extend_functionlike<I, S>(mut self, macro_paths_and_proc_macro_fns: I) -> Self
+    where
+        I: IntoIterator<Item = (S, FunctionlikeFn<'a>)>,
+        S: Into<String>,
+    {
+        self.registry
+            .extend_functionlike(macro_paths_and_proc_macro_fns);
+        self
+    }
+
+    /// Registers several derive macros at once. See [`MacroRegistry::extend_functionlike`] for
+    /// details.
+    ///
+    /// [`MacroRegistry::extend_functionlike`]: struct.MacroRegistry.html#method.extend_functionlike
+    pub fn extend_derive<I, S>(mut self, macro_paths_and_proc_macro_fns: I) -> Self
+    where
+        I: IntoIterator<Item = (S, DeriveFn<'a>)>,
+        S: Into<String>,
+    {
+        self.registry.extend_derive(macro_paths_and_proc_macro_fns);
+        self
+    }
+
+    /// Registers several attribute-like macros at once. See
+    /// [`MacroRegistry::extend_functionlike`] for details.
+    ///
+    /// [`MacroRegistry::extend_functionlike`]: struct.MacroRegistry.html#method.extend_functionlike
+    pub fn extend_attribute<I, S>(mut self, macro_paths_and_proc_macro_fns: I) -> Self
+    where
+        I: IntoIterator<Item = (S, AttributeFn<'a>)>,
+        S: Into<String>,
+    {
+        self.registry
+            .extend_attribute(macro_paths_and_proc_macro_fns);
+        self
+    }
+
+    /// Checks that the registered macros are the same as the ones exported by a procedural macro
+    /// crate. See [`MacroRegistry::check_exports`] for details.
+    ///
+    /// [`MacroRegistry::check_exports`]: struct.MacroRegistry.html#method.check_exports
+    pub fn check_exports<P: AsRef<Path>>(self, crate_root: P) -> Result<Self, Error> {
+        self.registry.check_exports(crate_root)?;
+        Ok(self)
+    }
+
+    /// Adds a Rust source code file to the list of files to scan. Files are scanned in the order
+    /// they are added.
+    pub fn file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.files.push(path.as_ref().to_owned());
+        self
+    }
+
+    /// Adds every Rust source code file that matches a glob pattern, like `tests/**/*.rs`, in the
+    /// order of their paths. The pattern's components are separated by `/`. In each one, `*`
+    /// matches any number of characters and `?` matches one, and a component that's just `**`
+    /// matches any number of directories. Wildcards don't match names that start with `.`, so
+    /// hidden directories are skipped.
+    ///
+    /// A relative pattern is relative to the current directory, which is the package's root
+    /// directory when Cargo runs a test. It's an [`Error::IoError`] if a directory can't be read
+    /// or nothing matches the pattern.
+    ///
+    /// [`Error::IoError`]: enum.Error.html#variant.IoError
+    pub fn glob(mut self, pattern: &str) -> Result<Self, Error> {
+        self.files.extend(glob::matching_files(pattern)?);
+        Ok(self)
+    }
+
+    /// Adds a Markdown file, such as a README, whose Rust code blocks should be scanned. They are
+    /// scanned after all the Rust files. See [`ParsedSource::from_markdown`] for which blocks are
+    /// scanned.
+    ///
+    /// [`ParsedSource::from_markdown`]: struct.ParsedSource.html#method.from_markdown
+    pub fn markdown<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.markdown.push(path.as_ref().to_owned());
+        self
+    }
+
+    /// Adds the root file of every test, example, and bench target of the package with the given
+    /// manifest, or of every member of the workspace if it's a virtual manifest. This asks Cargo
+    /// for the package's metadata, so it requires the `cargo_metadata` feature. Enabling
+    /// [`follow_modules`] is usually needed too, to scan the rest of each target's files.
+    ///
+    /// [`follow_modules`]: #method.follow_modules
+    #[cfg(feature = "cargo_metadata")]
+    pub fn package<P: AsRef<Path>>(mut self, manifest_path: P) -> Result<Self, Error> {
+        self.files
+            .extend(::package::target_roots(manifest_path.as_ref())?);
+        Ok(self)
+    }
+
+    /// Sets whether the files declared as modules with `mod foo;` should be scanned too, along
+    /// with the modules they declare, and so on. This is disabled by default.
+    ///
+    /// Each added file is treated as a crate root, so its modules are looked for next to it, as
+    /// `foo.rs` or `foo/mod.rs`, and `#[path = "..."]` attributes are followed the same way the
+    /// compiler follows them. Module declarations that are configured out by the [`cfgs`] given
+    /// to the registry are skipped. Each file is only scanned once, even if it's reached more than
+    /// once, and it's an error if a declared module's file doesn't exist.
+    ///
+    /// An attribute-like macro applied to a module declaration, like `#[routes] mod handlers;`, is
+    /// given the module as if it were inline, with the contents of its file between the braces,
+    /// since that's what the compiler gives it. The file is still scanned on its own as well.
+    ///
+    /// [`cfgs`]: #method.cfgs
+    pub fn follow_modules(mut self, enabled: bool) -> Self {
+        self.follow_modules = enabled;
+        self
+    }
+
+    /// Sets whether the Rust files pulled in by `include!` and `include_str!` should be scanned
+    /// too. This is disabled by default.
+    ///
+    /// Only calls whose argument is a string literal are followed, and the path is relative to the
+    /// directory of the file containing the call, as it is for the compiler. Files included with
+    /// `include_str!` are only scanned if their names end in `.rs`. An included file is scanned
+    /// on its own, soon after the file that includes it, and each file is only scanned once.
+    pub fn follow_includes(mut self, enabled: bool) -> Self {
+        self.follow_includes = enabled;
+        self
+    }
+
+    /// Sets whether the Rust code blocks in the doc comments in each scanned file should be
+    /// scanned too, like the examples that rustdoc tests. This is disabled by default. Each block
+    /// gets its own [`FileReport`], with the path of the file it's in, right after that file's
+    /// report. See [`ParsedSource::doc_examples`] for which blocks are scanned. The blocks are
+    /// scanned even if the file itself is skipped because of the cache.
+    ///
+    /// [`FileReport`]: struct.FileReport.html
+    /// [`ParsedSource::doc_examples`]: struct.ParsedSource.html#method.doc_examples
+    pub fn doc_examples(mut self, enabled: bool) -> Self {
+        self.doc_examples = enabled;
+        self
+    }
+
+    /// Enables a cache in the given directory, such as `target/runtime-macros/cache`, that
+    /// remembers which files had all their macros expanded successfully, without any warnings. On
+    /// later runs, a file is skipped if neither its contents nor the registered macros have
+    /// changed since then, so only the files that need it are scanned again. The skipped files are
+    /// still listed in the report, marked as [`cached`]. Nothing is remembered from a run that
+    /// fails, such as because an expansion doesn't match its [snapshot], so the files are checked
+    /// again the next time. With [`follow_modules`], a file whose attribute-like macros were given
+    /// the contents of a module's file is also scanned again when that file changes.
+    ///
+    /// Changes to the macro functions themselves are detected by checking whether the running
+    /// executable has been rebuilt. Since skipped files aren't expanded, they don't contribute to
+    /// code coverage, so it's best to leave the cache off for coverage runs.
+    ///
+    /// [`cached`]: struct.FileReport.html#structfield.cached
+    /// [snapshot]: #method.snapshots
+    /// [`follow_modules`]: #method.follow_modules
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::Expander;
+    /// # fn custom_assert_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+    /// # let dir = std::env::temp_dir().join("runtime-macros-cache-doctest");
+    /// let report = Expander::new()
+    ///     .functionlike("custom_assert", custom_assert_internal)
+    /// # /*
+    ///     .file("tests/tests.rs")
+    ///     .cache_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/target/runtime-macros/cache"))
+    /// # */
+    /// #   .file(concat!(env!("CARGO_MANIFEST_DIR"), "/src/lib.rs"))
+    /// #   .cache_dir(&dir)
+    ///     .run()
+    ///     .unwrap();
+    /// # std::fs::remove_dir_all(&dir).ok();
+    /// ```
+    pub fn cache_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.cache_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Sets whether every file should be scanned even if the cache says it can be skipped. The
+    /// cache is still updated with the results. This has no effect unless [`cache_dir`] is set.
+    ///
+    /// [`cache_dir`]: #method.cache_dir
+    pub fn refresh_cache(mut self, enabled: bool) -> Self {
+        self.refresh_cache = enabled;
+        self
+    }
+
+    /// Checks every expansion against a snapshot in the given directory after scanning, and returns
+    /// an error if any of them changed. See [`Snapshots`] for how the snapshots are stored and
+    /// updated.
+    ///
+    /// [`Snapshots`]: struct.Snapshots.html
+    pub fn snapshots<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.snapshots = Some(Snapshots::new(dir));
+        self
+    }
+
+    /// Writes every expansion to the given directory after scanning, such as
+    /// `target/runtime-macros/expansions`, so they can be looked through when debugging a macro.
+    /// Each file's expansions go in a subdirectory named after the file, with every character other
+    /// than letters, digits, `.`, `-`, and `_` replaced with `_`, and the expansions on each line
+    /// go in a file named after the line, like `12.rs`. Each one shows the macro's input and
+    /// output, formatted with [`ExpansionRecord::pretty_output`].
+    ///
+    /// The directory also gets an `index.txt` listing every invocation, one per line, with tabs
+    /// between its location, the kind of macro, its path, the file its expansion is in, and what
+    /// happened to it, as described by [`ExpansionOutcome`]. Invocations that panicked or weren't
+    /// expanded have no expansion, but they're still listed, with the messages of the panics. The
+    /// dump is written even if the run fails because a macro function panicked; old files in the
+    /// directory are overwritten but not removed.
+    ///
+    /// [`ExpansionRecord::pretty_output`]: struct.ExpansionRecord.html#method.pretty_output
+    /// [`ExpansionOutcome`]: enum.ExpansionOutcome.html
+    pub fn dump_expansions<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.dump_dir = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Sets whether to protect the run from macro functions that crash the whole process, for
+    /// instance by calling `std::process::abort`, overflowing the stack, or panicking in a crate
+    /// built with `panic = "abort"`. This is disabled by default.
+    ///
+    /// When it's enabled, the current test is first run again in a child process, which expands
+    /// every invocation and logs each one before calling its macro function. If the child crashes,
+    /// it's run again without the invocation that crashed it, until one finishes. Then the files
+    /// are scanned in this process, without those invocations, and each one is treated like a
+    /// panic, with a message that tells how the process crashed. A successful run expands
+    /// everything in one extra process, or one more for each crash, and the invocations are
+    /// identified by file, line, and column, so the macro functions and the files have to behave
+    /// the same way every time.
+    ///
+    /// The child is found by running the current executable with the current thread's name as the
+    /// name of the test to run, so this only works in a test run by the standard test harness, and
+    /// each test should only run one isolated expander. A coverage tool has to follow child
+    /// processes to count what happens in them, but it also counts what happens when the files
+    /// are scanned again in this process.
+    pub fn isolate(mut self, enabled: bool) -> Self {
+        self.isolate = enabled;
+        self
+    }
+
+    /// Compiles each scanned file with its expansions spliced in after scanning it, and returns an
+    /// error if it doesn't compile. This requires the `verify-compile` feature. See
+    /// [`CompileCheck`] for details. Files skipped because of the cache aren't compiled.
+    ///
+    /// [`CompileCheck`]: struct.CompileCheck.html
+    #[cfg(feature = "verify-compile")]
+    pub fn verify_compile(mut self, check: CompileCheck) -> Self {
+        self.compile_check = Some(check);
+        self
+    }
+
+    /// Sets the [`Reporter`] to tell about each step of the run, such as a [`ConsoleReporter`] to
+    /// print a summary. By default, nothing is reported.
+    ///
+    /// [`Reporter`]: trait.Reporter.html
+    /// [`ConsoleReporter`]: struct.ConsoleReporter.html
+    pub fn reporter<R: Reporter + 'a>(mut self, reporter: R) -> Self {
+        self.reporter = Some(RefCell::new(Box::new(reporter)));
+        self
+    }
+
+    /// Sets a function to call after each file is scanned with how far along the run is, such as to
+    /// drive a progress bar. A file's doc examples are counted as part of it. See [`Progress`] for
+    /// what it's given.
+    ///
+    /// [`Progress`]: struct.Progress.html
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::Expander;
+    /// # fn custom_assert_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+    /// Expander::new()
+    ///     .functionlike("custom_assert", custom_assert_internal)
+    ///     .follow_modules(true)
+    ///     .progress(|progress| {
+    ///         eprintln!(
+    ///             "[{}/{}] {} ({} invocations so far)",
+    ///             progress.files_done,
+    ///             progress.files_total,
+    ///             progress.path.display(),
+    ///             progress.invocations,
+    ///         )
+    ///     })
+    ///     # .file(concat!(env!("CARGO_MANIFEST_DIR"), "/src/lib.rs"))
+    ///     # /*
+    ///     .file("tests/tests.rs")
+    ///     # */
+    ///     .run()
+    ///     .unwrap();
+    /// ```
+    pub fn progress<F: Fn(&Progress) + 'a>(mut self, f: F) -> Self {
+        self.progress = Some(Box::new(f));
+        self
+    }
+
+    /// Scans every file that has been added, expanding each invocation of a registered macro.
+    ///
+    /// # Returns
+    ///
+    /// An [`ExpansionReport`] on success, or an instance of [`Error`] describing the first error
+    /// that occurred. Files after the one that caused the error are not scanned. The exception is
+    /// a panicking macro function: in that case, every file is still scanned, and the error lists
+    /// every invocation that panicked, unless the [`PanicPolicy`] says to record them in the
+    /// report instead.
+    ///
+    /// [`ExpansionReport`]: struct.ExpansionReport.html
+    /// [`PanicPolicy`]: enum.PanicPolicy.html
+    /// [`Error`]: enum.Error.html
+    pub fn run(&self) -> Result<ExpansionReport, Error> {
+        let mut quiet = QuietReporter;
+        let mut borrowed = self.reporter.as_ref().map(RefCell::borrow_mut);
+        let reporter: &mut dyn Reporter = match borrowed {
+            Some(ref mut reporter) => &mut ***reporter,
+            None => &mut quiet,
+        };
+        let result = if self.isolate {
+            isolation::run(|| self.run_here(reporter))
+        } else {
+            self.run_here(reporter)
+        };
+        match (reporter.on_finish(result.as_ref()), result) {
+            (Err(error), Ok(_)) => Err(Error::IoError { path: None, error }),
+            (_, result) => result,
+        }
+    }
+
+    // Does everything that `run` does, in this process.
+    fn run_here(&self, reporter: &mut dyn Reporter) -> Result<ExpansionReport, Error> {
+        let cache = self
+            .cache_dir
+            .as_ref()
+            .map(|dir| Cache::new(dir, &self.registry));
+        let mut files = Vec::new();
+        // The files still to be scanned, last one first, and whether each one is a "mod-rs" file.
+        // The files given to the expander are crate roots, which count.
+        let mut pending: Vec<(PathBuf, bool)> = self
+            .files
+            .iter()
+            .rev()
+            .map(|path| (path.clone(), true))
+            .collect();
+        if let Some(seed) = self.registry.options.shuffle {
+            order::shuffle(&mut pending, seed);
+        }
+        let follow = self.follow_modules || self.follow_includes;
+        let mut seen = HashSet::new();
+        let mut tracker = progress::Tracker::default();
+        // The contents of the files to remember in the cache once the run has succeeded, with the
+        // modules' files that were loaded for them and the indices of their reports.
+        let mut to_cache = Vec::new();
+        while let Some((path, mod_rs)) = pending.pop() {
+            if follow && !seen.insert(modules::normalize(&path)) {
+                continue;
+            }
+
+            reporter.on_file_start(&path);
+            let content = session::read(&path)?;
+            let cached = match cache {
+                Some(ref cache) if !self.refresh_cache => cache.load(&content),
+                _ => None,
+            };
+            let mut source = None;
+            let report = match cached {
+                Some(counts) => {
+                    if follow || self.doc_examples {
+                        source = Some(self.registry.parse(content, Some(&path))?);
+                    }
+                    FileReport {
+                        path: path.clone(),
+                        records: Vec::new(),
+                        nested_items: 0,
+                        near_misses: Vec::new(),
+                        unregistered: Vec::new(),
+                        call_sites: Vec::new(),
+                        panics: Vec::new(),
+                        warnings: Vec::new(),
+                        parse_error: None,
+                        cached: true,
+                        cached_invocations: counts,
+                        duration: Duration::ZERO,
+                    }
+                }
+                None => {
+                    let parsed = self.registry.parse(content, Some(&path))?;
+                    let mut scan = if self.follow_modules {
+                        let cfgs = self.registry.options.cfgs.as_ref();
+                        let module_files = modules::module_files(&parsed, mod_rs, cfgs);
+                        self.registry.scan_with(&parsed, |visitor| {
+                            visitor.module_files = module_files;
+                        })?
+                    } else {
+                        self.registry.scan(&parsed)?
+                    };
+                    let modules = mem::take(&mut scan.loaded_modules);
+                    let report = scan.into_report(path.clone());
+                    #[cfg(feature = "verify-compile")]
+                    {
+                        if let Some(ref check) = self.compile_check {
+                            check.check(parsed.content(), &report)?;
+                        }
+                    }
+                    if cache.is_some() && report.panics.is_empty() && report.warnings.is_empty() {
+                        to_cache.push((parsed.content().to_owned(), modules, files.len()));
+                    }
+                    source = Some(parsed);
+                    report
+                }
+            };
+            reporter::report_file(reporter, &report);
+            files.push(report);
+
+            if let Some(source) = source {
+                if self.doc_examples {
+                    for block in docs::doc_code_blocks(&source) {
+                        files.extend(self.scan_block(block, &path, reporter)?);
+                    }
+                }
+
+                let mut next = Vec::new();
+                if self.follow_modules {
+                    let cfgs = self.registry.options.cfgs.as_ref();
+                    next.extend(modules::declared_modules(&source, mod_rs, cfgs));
+                }
+                if self.follow_includes {
+                    let included = modules::included_files(&source);
+                    next.extend(included.into_iter().map(|path| (path, true)));
+                }
+                pending.extend(next.into_iter().rev());
+            }
+
+            if let Some(ref f) = self.progress {
+                let left = pending.len() + self.markdown.len();
+                tracker.file_done(f, &files, left, &path);
+            }
+        }
+        for (i, path) in self.markdown.iter().enumerate() {
+            for block in docs::markdown_code_blocks(&session::read(path)?) {
+                files.extend(self.scan_block(block, path, reporter)?);
+            }
+
+            if let Some(ref f) = self.progress {
+                tracker.file_done(f, &files, self.markdown.len() - i - 1, path);
+            }
+        }
+        if let Some(ref dir) = self.dump_dir {
+            dump::dump(
+                dir,
+                &ExpansionReport {
+                    files: files.clone(),
+                },
+            )?;
+        }
+        let report = self.registry.report(files)?;
+        if let Some(ref snapshots) = self.snapshots {
+            snapshots.check(&report)?;
+        }
+        if let Some(ref cache) = cache {
+            for (content, modules, i) in to_cache {
+                cache.store(&content, &modules, &report.files[i])?;
+            }
+        }
+        Ok(report)
+    }
+
+    // Scans one code block from a doc comment or a Markdown file, if it can be parsed.
+    fn scan_block(
+        &self,
+        block: String,
+        path: &Path,
+        reporter: &mut dyn Reporter,
+    ) -> Result<Option<FileReport>, Error> {
+        match docs::parse_block(block, |code| self.registry.parse(code, Some(path))) {
+            Some(source) => {
+                reporter.on_file_start(path);
+                let report = self.registry.scan(&source)?.into_report(path.to_owned());
+                reporter::report_file(reporter, &report);
+                Ok(Some(report))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl<'a> From<MacroRegistry<'a>> for Expander<'a> {
+    fn from(registry: MacroRegistry<'a>) -> Self {
+        Self {
+            registry,
+            ..Self::default()
+        }
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Finding the macros that a procedural macro crate exports, by reading its root file.
+
+use {
+    compat,
+    report::MacroKind,
+    session::{self, ParsedSource},
+    std::path::Path,
+    syn::{punctuated::Punctuated, Item, ItemFn, Meta, Token},
+    Error,
+};
+
+/// A procedural macro exported by a crate, as found by [`exported_macros`].
+///
+/// [`exported_macros`]: fn.exported_macros.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ExportedMacro {
+    /// The kind of macro.
+    pub kind: MacroKind,
+    /// The macro's name. For a derive, this is the name in `#[proc_macro_derive(...)]`, not the
+    /// function's name.
+    pub name: String,
+    /// The helper attributes declared by a derive, in the order they're listed. This is empty for
+    /// the other kinds.
+    pub helpers: Vec<String>,
+}
+
+/// Reads the root file of a procedural macro crate, usually `src/lib.rs`, and returns the macros
+/// it exports, in the order they're defined. These are the functions marked with `#[proc_macro]`,
+/// `#[proc_macro_derive]`, or `#[proc_macro_attribute]`, which the compiler only allows in the root
+/// module. [`MacroRegistry::check_exports`] uses this to make sure a registry has every one of
+/// them.
+///
+/// It's an [`Error::IoError`] if the file can't be read and an [`Error::ParseError`] if it can't
+/// be parsed, including if a `#[proc_macro_derive]` attribute doesn't have a name.
+///
+/// [`MacroRegistry::check_exports`]: struct.MacroRegistry.html#method.check_exports
+/// [`Error::IoError`]: enum.Error.html#variant.IoError
+/// [`Error::ParseError`]: enum.Error.html#variant.ParseError
+pub fn exported_macros<P: AsRef<Path>>(crate_root: P) -> Result<Vec<ExportedMacro>, Error> {
+    Ok(exported_functions(crate_root.as_ref())?
+        .into_iter()
+        .map(|(exported, _)| exported)
+        .collect())
+}
+
+// Returns the macros that a crate root exports with `#[testable]`, which may be written as a path
+// like `#[runtime_macros::testable]`, along with the names of the functions it writes.
+#[cfg(feature = "cli")]
+pub(crate) fn testable_macros(crate_root: &Path) -> Result<Vec<(ExportedMacro, String)>, Error> {
+    Ok(exported_functions(crate_root)?
+        .into_iter()
+        .filter(|(_, function)| {
+            function.attrs.iter().any(|attr| {
+                let segments = &compat::attr_path(attr).segments;
+                segments.last().is_some_and(|last| last.ident == "testable")
+            })
+        })
+        .map(|(exported, function)| (exported, format!("{}_internal", function.sig.ident)))
+        .collect())
+}
+
+// Returns the macros that a crate root exports, each with the function that defines it.
+fn exported_functions(path: &Path) -> Result<Vec<(ExportedMacro, ItemFn)>, Error> {
+    let source = ParsedSource::parse(session::read(path)?, Some(path))?;
+    let parse_error = |error| Error::ParseError {
+        path: Some(path.to_owned()),
+        error,
+        snippet: None,
+    };
+
+    let mut exported = Vec::new();
+    for item in source.ast().items.iter() {
+        let function = match *item {
+            Item::Fn(ref function) => function,
+            _ => continue,
+        };
+        for attr in function.attrs.iter() {
+            let path = compat::attr_path(attr);
+            let name = function.sig.ident.to_string();
+            if path.is_ident("proc_macro") {
+                let exported_macro = ExportedMacro {
+                    kind: MacroKind::Functionlike,
+                    name,
+                    helpers: Vec::new(),
+                };
+                exported.push((exported_macro, function.clone()));
+            } else if path.is_ident("proc_macro_attribute") {
+                let exported_macro = ExportedMacro {
+                    kind: MacroKind::Attribute,
+                    name,
+                    helpers: Vec::new(),
+                };
+                exported.push((exported_macro, function.clone()));
+            } else if path.is_ident("proc_macro_derive") {
+                let exported_macro = parse_derive(attr).map_err(parse_error)?;
+                exported.push((exported_macro, function.clone()));
+            }
+        }
+    }
+    Ok(exported)
+}
+
+// Reads the name and helpers from `#[proc_macro_derive(Name, attributes(helper, ...))]`.
+fn parse_derive(attr: &syn::Attribute) -> syn::Result<ExportedMacro> {
+    let args = attr.parse_args_with(Punctuated::<Meta, Token![,]>::parse_terminated)?;
+    let mut args = args.into_iter();
+    let name = match args.next() {
+        Some(Meta::Path(path)) => ident(&path)?,
+        _ => return Err(syn::Error::new_spanned(attr, "expected the derive's name")),
+    };
+    let mut helpers = Vec::new();
+    for arg in args {
+        if let Meta::List(ref list) = arg {
+            if list.path.is_ident("attributes") {
+                for helper in compat::meta_list(list)? {
+                    match helper {
+                        Meta::Path(ref path) => helpers.push(ident(path)?),
+                        _ => return Err(syn::Error::new_spanned(helper, "expected a name")),
+                    }
+                }
+            }
+        }
+    }
+    Ok(ExportedMacro {
+        kind: MacroKind::Derive,
+        name,
+        helpers,
+    })
+}
+
+fn ident(path: &syn::Path) -> syn::Result<String> {
+    match path.get_ident() {
+        Some(ident) => Ok(ident.to_string()),
+        None => Err(syn::Error::new_spanned(path, "expected a name, not a path")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{env, fs, process},
+        MacroRegistry,
+    };
+
+    #[test]
+    fn finds_exported_macros() {
+        let path = env::temp_dir().join(format!("runtime-macros-exports-{}.rs", process::id()));
+        fs::write(
+            &path,
+            "
+            #[proc_macro]
+            pub fn foo(ts: TokenStream) -> TokenStream { ts }
+
+            #[proc_macro_derive(Bar, attributes(bar, baz))]
+            pub fn bar(ts: TokenStream) -> TokenStream { ts }
+
+            #[proc_macro_attribute]
+            pub fn qux(_: TokenStream, ts: TokenStream) -> TokenStream { ts }
+
+            fn helper() {}
+            ",
+        )
+        .unwrap();
+
+        let exported = exported_macros(&path).unwrap();
+        let summary: Vec<(MacroKind, &str, Vec<&str>)> = exported
+            .iter()
+            .map(|m| {
+                let helpers = m.helpers.iter().map(String::as_str).collect();
+                (m.kind, &m.name[..], helpers)
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (MacroKind::Functionlike, "foo", vec![]),
+                (MacroKind::Derive, "Bar", vec!["bar", "baz"]),
+                (MacroKind::Attribute, "qux", vec![]),
+            ]
+        );
+
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", |ts| ts)
+            .derive("Bar", |ts| ts)
+            .derive_helpers("Bar", &["bar"])
+            .functionlike("extra", |ts| ts);
+        match registry.check_exports(&path) {
+            Err(Error::ExportMismatch {
+                unregistered,
+                unexported,
+                helpers,
+                ..
+            }) => {
+                assert_eq!(unregistered, [(MacroKind::Attribute, "qux".to_owned())]);
+                assert_eq!(unexported, [(MacroKind::Functionlike, "extra".to_owned())]);
+                assert_eq!(helpers, [("Bar".to_owned(), "baz".to_owned())]);
+            }
+            other => panic!("expected a mismatch, got {:?}", other),
+        }
+
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", |ts| ts)
+            .derive("Bar", |ts| ts)
+            .derive_helpers("Bar", &["bar", "baz"])
+            .attribute("qux", |_, ts| ts);
+        assert_eq!(registry.check_exports(&path).unwrap(), exported);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,64 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+
+//! Presets for how closely the emulated expansion should follow the compiler's.
+
+use registry::Options;
+
+/// How closely the input passed to each macro function should match what the compiler would pass.
+///
+/// A preset only sets the options listed for it, so any of them can be changed again afterward.
+/// Whichever setting was made last takes effect, so
+/// `registry.fidelity(Fidelity::Rustc).chain_attributes(false)` uses the compiler's behavior for
+/// everything but the chaining of attribute-like macros.
+///
+/// In both presets, attribute-like macros are given their arguments without the delimiters around
+/// them, as the compiler does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum Fidelity {
+    /// Pass every macro the item as it's written in the source code, and expand every invocation
+    /// in the file whether or not the compiler would reach it. This turns off
+    /// [`strip_derive_attributes`], [`strip_invoked_attribute`], [`unwrap_cfg_attr`],
+    /// [`chain_attributes`], [`rescan_attribute_output`], and [`rescan_functionlike_output`]. It's
+    /// the default, since it's the most thorough way to measure coverage.
+    ///
+    /// [`strip_derive_attributes`]: struct.MacroRegistry.html#method.strip_derive_attributes
+    /// [`strip_invoked_attribute`]: struct.MacroRegistry.html#method.strip_invoked_attribute
+    /// [`unwrap_cfg_attr`]: struct.MacroRegistry.html#method.unwrap_cfg_attr
+    /// [`chain_attributes`]: struct.MacroRegistry.html#method.chain_attributes
+    /// [`rescan_attribute_output`]: struct.MacroRegistry.html#method.rescan_attribute_output
+    /// [`rescan_functionlike_output`]: struct.MacroRegistry.html#method.rescan_functionlike_output
+    #[default]
+    Lenient,
+    /// Pass every macro exactly what the compiler would, and expand the invocations in the order
+    /// the compiler would. This turns on all the options that [`Lenient`] turns off, so macros
+    /// that inspect the attributes they're given see the same ones as during compilation, and
+    /// attribute-like macros are expanded one at a time, each in the output of the one before.
+    ///
+    /// [`Lenient`]: #variant.Lenient
+    Rustc,
+}
+
+impl Fidelity {
+    // Sets the options that this preset controls.
+    pub(crate) fn apply(self, options: &mut Options) {
+        let rustc = self == Fidelity::Rustc;
+        options.strip_derive_attributes = rustc;
+        options.strip_invoked_attribute = rustc;
+        options.unwrap_cfg_attr = rustc;
+        options.chain_attributes = rustc;
+        options.rescan_attribute_output = rustc;
+        options.rescan_functionlike_output = rustc;
+    }
+}
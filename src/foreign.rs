@@ -0,0 +1,115 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Converting between this crate's token streams and other types that stand for Rust code, such as
+//! the `TokenStream` of a different version of `proc_macro2`.
+
+use {
+    proc_macro2::{Span, TokenStream},
+    std::{fmt::Display, str::FromStr},
+};
+
+/// A type that stands for a sequence of Rust tokens, which can be used by the functions registered
+/// with [`MacroRegistry::functionlike_foreign`] and the like instead of this crate's
+/// `proc_macro2::TokenStream`.
+///
+/// This is useful if a procedural macro crate uses a version of `proc_macro2` that's incompatible
+/// with the one this crate uses, which is re-exported as [`runtime_macros::proc_macro2`]. It's
+/// implemented for every type that implements `FromStr` and `Display`, which includes the
+/// `TokenStream` of every version of `proc_macro2`, as well as `String`.
+///
+/// The tokens are converted by printing them and parsing the result, so their spans are lost: the
+/// function's input only has spans at the call site, and the spans in its output don't point into
+/// the scanned file.
+///
+/// [`MacroRegistry::functionlike_foreign`]: struct.MacroRegistry.html#method.functionlike_foreign
+/// [`runtime_macros::proc_macro2`]: proc_macro2/index.html
+pub trait ForeignTokens: Sized {
+    /// Converts this crate's tokens to this type.
+    fn from_tokens(tokens: TokenStream) -> Result<Self, String>;
+
+    /// Converts tokens of this type to this crate's tokens.
+    fn into_tokens(self) -> Result<TokenStream, String>;
+}
+
+impl<T> ForeignTokens for T
+where
+    T: FromStr + Display,
+    T::Err: Display,
+{
+    fn from_tokens(tokens: TokenStream) -> Result<Self, String> {
+        tokens
+            .to_string()
+            .parse()
+            .map_err(|error: T::Err| error.to_string())
+    }
+
+    fn into_tokens(self) -> Result<TokenStream, String> {
+        self.to_string()
+            .parse()
+            .map_err(|error: proc_macro2::LexError| error.to_string())
+    }
+}
+
+// Converts a macro's input, reporting a failure as an error, which the expansion turns into a
+// `compile_error!`.
+pub(crate) fn input<T: ForeignTokens>(tokens: TokenStream) -> syn::Result<T> {
+    T::from_tokens(tokens).map_err(|e| {
+        syn::Error::new(
+            Span::call_site(),
+            format!("couldn't convert the macro's input: {}", e),
+        )
+    })
+}
+
+// Converts a macro's output, reporting a failure the same way.
+pub(crate) fn output<T: ForeignTokens>(tokens: T) -> syn::Result<TokenStream> {
+    tokens.into_tokens().map_err(|e| {
+        syn::Error::new(
+            Span::call_site(),
+            format!("couldn't convert the macro's output: {}", e),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use {Error, MacroRegistry};
+
+    #[test]
+    fn converts_through_strings() {
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike_foreign("shout", |ts: String| ts.to_uppercase())
+            .attribute_foreign("wrap", |attr: String, item: String| {
+                format!("mod {} {{ {} }}", attr, item)
+            })
+            .derive_foreign("Unclosed", |_: String| "impl (".to_owned());
+        let file = registry
+            .expand_source("fn f() { shout!(a + b); }\n#[wrap(m)] fn g() {}", None)
+            .unwrap();
+        let outputs: Vec<String> = file
+            .records
+            .iter()
+            .map(|record| record.output.to_string())
+            .collect();
+        assert_eq!(outputs, ["A + B", "mod m { # [wrap (m)] fn g () { } }"]);
+
+        match registry.expand_source("#[derive(Unclosed)] struct S;", None) {
+            Err(Error::MacroFailed { error, .. }) => {
+                let message = error.to_string();
+                assert!(message.starts_with("couldn't convert the macro's output"));
+            }
+            other => panic!("expected the conversion to fail, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Mutating the inputs of real invocations, to check that macro functions cope with inputs that are
+//! almost, but not quite, what they expect.
+
+use {
+    order::SplitMix64,
+    proc_macro2::{Group, LineColumn, Literal, TokenStream, TokenTree},
+    std::fmt,
+};
+
+// One change made to an input.
+pub(crate) enum Mutation {
+    Drop(TokenTree),
+    Duplicate(TokenTree),
+    Swap(TokenTree, TokenTree),
+    Tweak(Literal, Literal),
+}
+
+impl fmt::Display for Mutation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Mutation::Drop(ref tt) => write!(f, "dropped `{}`", tt),
+            Mutation::Duplicate(ref tt) => write!(f, "duplicated `{}`", tt),
+            Mutation::Swap(ref a, ref b) => write!(f, "swapped `{}` and `{}`", a, b),
+            Mutation::Tweak(ref old, ref new) => write!(f, "changed `{}` to `{}`", old, new),
+        }
+    }
+}
+
+// Makes random mutations of one invocation's input. The mutations only depend on the seed, the
+// location of the invocation, and the input, so they're the same in a child process.
+pub(crate) struct Mutator(SplitMix64);
+
+impl Mutator {
+    pub(crate) fn new(seed: u64, start: LineColumn) -> Self {
+        Mutator(SplitMix64(
+            seed ^ ((start.line as u64) << 32 | start.column as u64),
+        ))
+    }
+
+    // Returns a copy of the input with one token tree dropped, duplicated, or swapped with its
+    // neighbor, or one literal changed, along with what was changed. Returns `None` if the input
+    // is empty, since there's nothing to change.
+    pub(crate) fn mutate(&mut self, input: &TokenStream) -> Option<(TokenStream, Mutation)> {
+        let mut trees: Vec<TokenTree> = input.clone().into_iter().collect();
+        let mut locations = Vec::new();
+        locate(&trees, &mut Vec::new(), &mut locations);
+        if locations.is_empty() {
+            return None;
+        }
+        let literals: Vec<&Vec<usize>> = locations
+            .iter()
+            .filter(|location| matches!(at(&trees, location), TokenTree::Literal(_)))
+            .collect();
+
+        let rng = &mut self.0;
+        let choice = rng.below(4);
+        let mutation = if choice == 3 && !literals.is_empty() {
+            let location = literals[rng.below(literals.len() as u64) as usize].clone();
+            let replacement = rng.below(REPLACEMENTS.len() as u64) as usize;
+            edit(&mut trees, &location, &mut |level, i| {
+                let old = match level[i] {
+                    TokenTree::Literal(ref literal) => literal.clone(),
+                    _ => unreachable!(),
+                };
+                let new = tweak(&old, replacement);
+                level[i] = TokenTree::Literal(new.clone());
+                Mutation::Tweak(old, new)
+            })
+        } else {
+            let location = locations[rng.below(locations.len() as u64) as usize].clone();
+            edit(&mut trees, &location, &mut |level, i| match choice {
+                2 if level.len() > 1 => {
+                    let j = if i + 1 < level.len() { i + 1 } else { i - 1 };
+                    level.swap(i, j);
+                    Mutation::Swap(level[j].clone(), level[i].clone())
+                }
+                1 | 2 => {
+                    level.insert(i, level[i].clone());
+                    Mutation::Duplicate(level[i].clone())
+                }
+                _ => Mutation::Drop(level.remove(i)),
+            })
+        };
+        Some((trees.into_iter().collect(), mutation))
+    }
+}
+
+// Adds the location of every token tree, as its index in each group on the way to it.
+fn locate(trees: &[TokenTree], prefix: &mut Vec<usize>, locations: &mut Vec<Vec<usize>>) {
+    for (i, tt) in trees.iter().enumerate() {
+        prefix.push(i);
+        locations.push(prefix.clone());
+        if let TokenTree::Group(ref group) = *tt {
+            let inner: Vec<TokenTree> = group.stream().into_iter().collect();
+            locate(&inner, prefix, locations);
+        }
+        prefix.pop();
+    }
+}
+
+// Returns the token tree at the given location.
+fn at(trees: &[TokenTree], location: &[usize]) -> TokenTree {
+    let tt = trees[location[0]].clone();
+    match tt {
+        TokenTree::Group(ref group) if location.len() > 1 => {
+            let inner: Vec<TokenTree> = group.stream().into_iter().collect();
+            at(&inner, &location[1..])
+        }
+        _ => tt,
+    }
+}
+
+// Calls `f` with the trees in the group that contains the given location and the location's
+// index in it, and then puts the changed trees back in the group.
+fn edit<R>(
+    trees: &mut Vec<TokenTree>,
+    location: &[usize],
+    f: &mut dyn FnMut(&mut Vec<TokenTree>, usize) -> R,
+) -> R {
+    if location.len() == 1 {
+        return f(trees, location[0]);
+    }
+    let group = match trees[location[0]] {
+        TokenTree::Group(ref group) => group.clone(),
+        _ => unreachable!(),
+    };
+    let mut inner: Vec<TokenTree> = group.stream().into_iter().collect();
+    let result = edit(&mut inner, &location[1..], f);
+    let mut new = Group::new(group.delimiter(), inner.into_iter().collect());
+    new.set_span(group.span());
+    trees[location[0]] = TokenTree::Group(new);
+    result
+}
+
+// The literals that others are changed to. Macros that parse literals often don't expect these.
+const REPLACEMENTS: &[fn() -> Literal] = &[
+    || Literal::u8_unsuffixed(0),
+    || Literal::u128_unsuffixed(u128::MAX),
+    || Literal::f64_unsuffixed(0.5),
+    || Literal::string(""),
+    || Literal::character('\0'),
+    || Literal::byte_string(b"\xff"),
+];
+
+// Returns the replacement at the given index, or the next one if that's the same as the literal.
+fn tweak(literal: &Literal, index: usize) -> Literal {
+    let mut new = REPLACEMENTS[index]();
+    if new.to_string() == literal.to_string() {
+        new = REPLACEMENTS[(index + 1) % REPLACEMENTS.len()]();
+    }
+    new.set_span(literal.span());
+    new
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mutations(seed: u64, input: &str, count: usize) -> Vec<(String, String)> {
+        let input: TokenStream = input.parse().unwrap();
+        let mut mutator = Mutator::new(seed, LineColumn { line: 1, column: 0 });
+        (0..count)
+            .map(|_| {
+                let (mutated, mutation) = mutator.mutate(&input).unwrap();
+                (mutated.to_string(), mutation.to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mutates_deterministically() {
+        assert_eq!(mutations(1, "a (b, 1)", 20), mutations(1, "a (b, 1)", 20));
+        assert_ne!(mutations(1, "a (b, 1)", 20), mutations(2, "a (b, 1)", 20));
+        let mut mutator = Mutator::new(1, LineColumn { line: 1, column: 0 });
+        assert!(mutator.mutate(&TokenStream::new()).is_none());
+    }
+
+    #[test]
+    fn makes_every_kind_of_mutation() {
+        let mutations = mutations(3, "a (b, 1)", 100);
+        for (mutated, _) in mutations.iter() {
+            assert_ne!(mutated, "a (b , 1)");
+        }
+        for kind in ["dropped", "duplicated", "swapped", "changed `1` to"] {
+            assert!(
+                mutations
+                    .iter()
+                    .any(|(_, mutation)| mutation.starts_with(kind)),
+                "no mutation starts with {:?}",
+                kind
+            );
+        }
+        assert!(mutations.contains(&("a (b , 0)".to_owned(), "changed `1` to `0`".to_owned())));
+        assert!(mutations.contains(&("a (, b 1)".to_owned(), "swapped `b` and `,`".to_owned())));
+    }
+}
@@ -0,0 +1,183 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Finding the files that match a glob pattern, like `tests/**/*.rs`.
+
+use {
+    std::{
+        fs, io,
+        path::{Path, PathBuf},
+    },
+    Error,
+};
+
+// Returns the files that match the pattern, sorted by their paths. The pattern's components are
+// separated by `/`. In each one, `*` matches any number of characters and `?` matches one, and a
+// component that's just `**` matches any number of directories. Wildcards don't match names that
+// start with `.` unless the component does too. It's an error if nothing matches.
+pub(crate) fn matching_files(pattern: &str) -> Result<Vec<PathBuf>, Error> {
+    let components: Vec<&str> = pattern.split('/').filter(|c| !c.is_empty()).collect();
+    let literal = components.iter().take_while(|c| !has_wildcard(c)).count();
+    let mut base = components[..literal].join("/");
+    if pattern.starts_with('/') {
+        base.insert(0, '/');
+    }
+    let base = PathBuf::from(base);
+
+    let mut files = Vec::new();
+    if literal == components.len() {
+        if base.is_file() {
+            files.push(base);
+        }
+    } else {
+        walk(&base, &components[literal..], &mut files).map_err(|(path, error)| {
+            Error::IoError {
+                path: Some(path),
+                error,
+            }
+        })?;
+    }
+    if files.is_empty() {
+        return Err(Error::IoError {
+            path: Some(PathBuf::from(pattern)),
+            error: io::Error::new(io::ErrorKind::NotFound, "no files match the pattern"),
+        });
+    }
+    files.sort();
+    files.dedup();
+    Ok(files)
+}
+
+// Adds the files in `dir` that match the rest of the pattern.
+fn walk(
+    dir: &Path,
+    pattern: &[&str],
+    files: &mut Vec<PathBuf>,
+) -> Result<(), (PathBuf, io::Error)> {
+    let (&component, rest) = match pattern.split_first() {
+        Some(split) => split,
+        None => return Ok(()),
+    };
+    if component == "**" {
+        walk(dir, rest, files)?;
+    }
+    // The current directory is left out of the paths found in it.
+    let listed = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let entries = match fs::read_dir(listed) {
+        Ok(entries) => entries,
+        // A directory that doesn't exist just doesn't have any matches.
+        Err(ref error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err((dir.to_owned(), error)),
+    };
+    for entry in entries {
+        let entry = entry.map_err(|error| (dir.to_owned(), error))?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        let path = dir.join(&*name);
+        if component == "**" {
+            if !name.starts_with('.') && path.is_dir() {
+                walk(&path, pattern, files)?;
+            }
+        } else if matches(component, &name) {
+            if rest.is_empty() {
+                if path.is_file() {
+                    files.push(path);
+                }
+            } else if path.is_dir() {
+                walk(&path, rest, files)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn has_wildcard(component: &str) -> bool {
+    component.contains(['*', '?'])
+}
+
+// Returns whether a file name matches one component of a pattern.
+fn matches(component: &str, name: &str) -> bool {
+    if name.starts_with('.') && !component.starts_with('.') {
+        return false;
+    }
+    let component: Vec<char> = component.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    matches_chars(&component, &name)
+}
+
+fn matches_chars(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((&'*', rest)) => (0..=name.len()).any(|skip| matches_chars(rest, &name[skip..])),
+        Some((&'?', rest)) => !name.is_empty() && matches_chars(rest, &name[1..]),
+        Some((&c, rest)) => name.first() == Some(&c) && matches_chars(rest, &name[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{env, process},
+    };
+
+    #[test]
+    fn matches_names() {
+        assert!(matches("*.rs", "tests.rs"));
+        assert!(matches("t?sts.rs", "tests.rs"));
+        assert!(!matches("*.rs", "tests.rs.bak"));
+        assert!(!matches("*.rs", ".hidden.rs"));
+        assert!(matches(".*.rs", ".hidden.rs"));
+    }
+
+    #[test]
+    fn finds_matching_files() {
+        let dir = env::temp_dir().join(format!("runtime-macros-glob-{}", process::id()));
+        fs::create_dir_all(dir.join("a/b")).unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        for file in [
+            "top.rs",
+            "a/one.rs",
+            "a/b/two.rs",
+            "a/b/notes.txt",
+            ".git/x.rs",
+        ] {
+            fs::write(dir.join(file), "").unwrap();
+        }
+
+        let root = dir.to_str().unwrap().replace('\\', "/");
+        let found = |pattern: &str| -> Vec<PathBuf> {
+            matching_files(&format!("{}/{}", root, pattern))
+                .unwrap()
+                .into_iter()
+                .map(|path| path.strip_prefix(&dir).unwrap().to_owned())
+                .collect()
+        };
+        let paths = |paths: &[&str]| -> Vec<PathBuf> { paths.iter().map(PathBuf::from).collect() };
+        assert_eq!(
+            found("**/*.rs"),
+            paths(&["a/b/two.rs", "a/one.rs", "top.rs"])
+        );
+        assert_eq!(found("a/*/*"), paths(&["a/b/notes.txt", "a/b/two.rs"]));
+        assert_eq!(found("top.rs"), paths(&["top.rs"]));
+        match matching_files(&format!("{}/**/*.toml", root)) {
+            Err(Error::IoError { error, .. }) => assert_eq!(error.kind(), io::ErrorKind::NotFound),
+            other => panic!("expected an error, got {:?}", other),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
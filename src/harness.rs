@@ -0,0 +1,165 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! A test harness that runs each invocation as its own test, using libtest-mimic.
+
+use {
+    junit,
+    libtest_mimic::{Arguments, Conclusion, Failed, Trial},
+    std::collections::HashMap,
+    Expander, PanicPolicy,
+};
+
+/// A replacement for the standard test harness that shows each invocation of a registered macro
+/// as its own test, so `cargo test` and `cargo nextest` list which ones passed and which failed
+/// instead of hiding them all behind one test. This requires the `libtest-mimic` feature.
+///
+/// The harness runs the [`Expander`] it was made from once, with a [`PanicPolicy::Record`] so
+/// every file is scanned, and then makes a test for each invocation found, named after its
+/// location and the macro's path like the test cases from [`ExpansionReport::to_junit`]. An
+/// invocation fails if its macro function panicked unexpectedly or its expansion contains a
+/// `compile_error!`, and one that wasn't expanded, because it was filtered out or its path didn't
+/// match, is ignored. If the run itself fails, for instance because a file couldn't be parsed,
+/// there's a single failing test named `runtime-macros` with the error instead. The expander is
+/// run again each time the test binary is, even if only some of the tests are selected, and
+/// `cargo nextest` runs the binary once per test, so it's best used on a handful of files.
+///
+/// It goes in a test target of its own with the standard harness turned off:
+///
+/// ```toml
+/// [[test]]
+/// name = "expansions"
+/// harness = false
+/// ```
+///
+/// [`Expander`]: struct.Expander.html
+/// [`PanicPolicy::Record`]: enum.PanicPolicy.html#variant.Record
+/// [`ExpansionReport::to_junit`]: struct.ExpansionReport.html#method.to_junit
+///
+/// # Example
+///
+/// ```no_run
+/// // tests/expansions.rs
+/// # extern crate proc_macro2;
+/// # extern crate runtime_macros;
+/// use runtime_macros::{Expander, Harness};
+/// # fn custom_assert_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+///
+/// fn main() {
+///     Harness::new(
+///         Expander::new()
+///             .functionlike("custom_assert", custom_assert_internal)
+///             .file("tests/tests.rs"),
+///     )
+///     .run()
+///     .exit();
+/// }
+/// ```
+pub struct Harness<'a> {
+    expander: Expander<'a>,
+}
+
+impl<'a> Harness<'a> {
+    /// Creates a harness that runs the given expander.
+    pub fn new(expander: Expander<'a>) -> Self {
+        Harness {
+            expander: expander.panic_policy(PanicPolicy::Record),
+        }
+    }
+
+    /// Runs the expander and returns a test for each invocation, for running with
+    /// `libtest_mimic::run` along with other tests or with arguments of one's own.
+    pub fn trials(&self) -> Vec<Trial> {
+        let report = match self.expander.run() {
+            Ok(report) => report,
+            Err(error) => {
+                let message = error.to_string();
+                return vec![Trial::test("runtime-macros", move || {
+                    Err(Failed::from(message))
+                })];
+            }
+        };
+        let mut seen = HashMap::new();
+        report
+            .files
+            .iter()
+            .flat_map(junit::cases)
+            .map(|case| {
+                // Nested expansions can share a location, but every test needs its own name.
+                let count = seen.entry(case.name.clone()).or_insert(0);
+                *count += 1;
+                let name = match *count {
+                    1 => case.name,
+                    n => format!("{}#{}", case.name, n),
+                };
+                let (failure, ignored) = (case.failure, case.skipped.is_some());
+                Trial::test(name, move || match failure {
+                    None => Ok(()),
+                    Some(("panic", message)) => {
+                        Err(format!("the macro panicked: {}", message).into())
+                    }
+                    Some((_, message)) => {
+                        Err(format!("the expansion contains a compile error: {}", message).into())
+                    }
+                })
+                .with_ignored_flag(ignored)
+            })
+            .collect()
+    }
+
+    /// Runs the expander and then the tests, using the command-line arguments given to the test
+    /// binary. `Conclusion::exit` ends the process with the right status code.
+    pub fn run(&self) -> Conclusion {
+        libtest_mimic::run(&Arguments::from_args(), self.trials())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{env, fs, process},
+    };
+
+    #[test]
+    fn makes_a_test_per_invocation() {
+        let path = env::temp_dir().join(format!("runtime-macros-harness-{}.rs", process::id()));
+        fs::write(&path, "ok!(1);\npanics!();\nok!(2);\n").unwrap();
+
+        let harness = Harness::new(
+            Expander::new()
+                .functionlike("ok", |ts| ts)
+                .functionlike("panics", |_| panic!("oops"))
+                .file(&path),
+        );
+        let trials = harness.trials();
+        let names: Vec<&str> = trials.iter().map(Trial::name).collect();
+        let shown = path.display();
+        assert_eq!(
+            names,
+            [
+                format!("{}:1:0 ok", shown),
+                format!("{}:2:0 panics", shown),
+                format!("{}:3:0 ok", shown),
+            ]
+        );
+        let arguments = Arguments {
+            test_threads: Some(1),
+            quiet: true,
+            ..Arguments::default()
+        };
+        let conclusion = libtest_mimic::run(&arguments, trials);
+        assert_eq!((conclusion.num_passed, conclusion.num_failed), (2, 1));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,109 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! A heuristic search for names that a macro's output binds without hygiene, where they could
+//! capture or shadow names in the caller's code.
+
+use {
+    proc_macro2::{Ident, TokenStream, TokenTree},
+    std::collections::HashSet,
+};
+
+// Returns every local variable that the output binds with `let` or `for` whose name doesn't
+// appear anywhere in the inputs. Since every span looks the same outside a real compiler, even a
+// `Span::mixed_site()` can't be told apart from a call-site span, and names starting with `__`
+// are taken as deliberately hidden instead.
+pub(crate) fn unhygienic_bindings(inputs: &[&TokenStream], output: &TokenStream) -> Vec<Ident> {
+    let mut names = HashSet::new();
+    for input in inputs {
+        collect_names(input, &mut names);
+    }
+    let mut bindings = Vec::new();
+    collect_bindings(output, &names, &mut bindings);
+    bindings
+}
+
+fn collect_names(tokens: &TokenStream, names: &mut HashSet<String>) {
+    for tt in tokens.clone() {
+        match tt {
+            TokenTree::Ident(ident) => {
+                names.insert(ident.to_string());
+            }
+            TokenTree::Group(group) => collect_names(&group.stream(), names),
+            _ => {}
+        }
+    }
+}
+
+fn collect_bindings(tokens: &TokenStream, names: &HashSet<String>, bindings: &mut Vec<Ident>) {
+    let tokens: Vec<TokenTree> = tokens.clone().into_iter().collect();
+    for (i, tt) in tokens.iter().enumerate() {
+        let keyword = match *tt {
+            TokenTree::Group(ref group) => {
+                collect_bindings(&group.stream(), names, bindings);
+                continue;
+            }
+            TokenTree::Ident(ref ident) if ident == "let" || ident == "for" => ident.to_string(),
+            _ => continue,
+        };
+        // Skip `mut` and `ref`, so `let mut x` and `for ref x` are found too.
+        let mut rest = tokens[i + 1..].iter().skip_while(
+            |tt| matches!(tt, TokenTree::Ident(ident) if ident == "mut" || ident == "ref"),
+        );
+        let name = match rest.next() {
+            Some(TokenTree::Ident(name)) => name,
+            _ => continue,
+        };
+        // Only a name on its own is a binding. A path or a tuple struct is a pattern to match.
+        let ends = match (keyword.as_str(), rest.next()) {
+            ("let", Some(TokenTree::Punct(punct))) => matches!(punct.as_char(), '=' | ':' | ';'),
+            ("let", None) => true,
+            ("for", Some(TokenTree::Ident(ident))) => ident == "in",
+            _ => false,
+        };
+        let name_string = name.to_string();
+        if ends
+            && name_string != "_"
+            && !name_string.starts_with("__")
+            && !names.contains(&name_string)
+        {
+            bindings.push(name.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_unhygienic_bindings() {
+        let tokens = |s: &str| s.parse::<TokenStream>().unwrap();
+        let input = tokens("x + 1");
+        let output = tokens(
+            "{
+                let tmp = x + 1;
+                let mut count: u32 = 0;
+                let x = tmp;
+                let __hidden = 2;
+                let Some(y) = None else { return };
+                for i in 0..tmp { let _ = i; }
+                match tmp { value => value }
+            }",
+        );
+        let found: Vec<String> = unhygienic_bindings(&[&input], &output)
+            .iter()
+            .map(Ident::to_string)
+            .collect();
+        assert_eq!(found, ["tmp", "count", "i"]);
+    }
+}
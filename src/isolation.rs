@@ -0,0 +1,427 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Running macro functions that might crash the whole process, by first trying every invocation in
+//! a child process that runs the same test.
+//!
+//! The child is the current test executable, told to run just the current test, with an environment
+//! variable telling the expander inside it to log each invocation before calling its macro
+//! function. If the child crashes, the last invocation in the log is the one that crashed it, so
+//! the child is run again with that one skipped, until a child finishes. Then the invocations are
+//! expanded for real in this process, and the ones that crashed are treated like panics.
+//!
+//! A watchdog thread can also end the process if a macro function runs for too long, leaving a note
+//! in the log that says so.
+
+use {
+    proc_macro2::LineColumn,
+    std::{
+        cell::RefCell,
+        collections::{HashMap, HashSet},
+        fs::OpenOptions,
+        io::{self, Write},
+        path::{Path, PathBuf},
+        process,
+        sync::mpsc::{self, RecvTimeoutError},
+        thread,
+        time::Duration,
+    },
+    Error,
+};
+#[cfg(feature = "fs")]
+use {
+    sandbox,
+    std::{
+        env, fs,
+        process::{Command, Stdio},
+        sync::atomic::{AtomicUsize, Ordering},
+    },
+};
+
+// The file that a child logs its invocations to. Its presence is what makes a process a child.
+#[cfg(feature = "fs")]
+const PROGRESS_VAR: &str = "RUNTIME_MACROS_ISOLATION_PROGRESS";
+// The file listing the invocations that a child should skip.
+#[cfg(feature = "fs")]
+const SKIP_VAR: &str = "RUNTIME_MACROS_ISOLATION_SKIP";
+// The last line of a child's progress log if it didn't crash.
+#[cfg(feature = "fs")]
+const DONE: &str = "done";
+// The start of a line in a child's progress log that explains why it stopped.
+const NOTE: char = '!';
+
+// Only `run` sets a mode, and only the expander calls it, so without the `fs` feature, there's
+// never one.
+#[cfg_attr(not(feature = "fs"), allow(dead_code))]
+enum Mode {
+    // This is a child. It logs each invocation except the ones it's told to skip.
+    Child {
+        progress: PathBuf,
+        skip: HashSet<String>,
+    },
+    // This is the parent. It knows what happened to each invocation that crashed a child.
+    Parent {
+        crashed: HashMap<String, String>,
+    },
+}
+
+thread_local! {
+    static MODE: RefCell<Option<Mode>> = const { RefCell::new(None) };
+}
+
+// Runs a scan with isolation. In a child, this logs the invocations and then exits the process
+// instead of returning.
+#[cfg(feature = "fs")]
+pub(crate) fn run<T, F>(scan: F) -> Result<T, Error>
+where
+    F: FnOnce() -> Result<T, Error>,
+{
+    if let Some(progress) = env::var_os(PROGRESS_VAR) {
+        let progress = PathBuf::from(progress);
+        let skip = match env::var_os(SKIP_VAR) {
+            Some(path) => fs::read_to_string(path)
+                .unwrap_or_default()
+                .lines()
+                .map(str::to_owned)
+                .collect(),
+            None => HashSet::new(),
+        };
+        MODE.with(|mode| {
+            *mode.borrow_mut() = Some(Mode::Child {
+                progress: progress.clone(),
+                skip,
+            })
+        });
+        // An error ends the scan the same way in both processes, so it doesn't matter here.
+        let _ = scan();
+        log(&progress, DONE).ok();
+        process::exit(0);
+    }
+
+    let crashed = probe()?;
+    MODE.with(|mode| *mode.borrow_mut() = Some(Mode::Parent { crashed }));
+    let result = scan();
+    MODE.with(|mode| *mode.borrow_mut() = None);
+    result
+}
+
+// Called before a macro function is called. Returns the message to use as the function's panic
+// message if it shouldn't be called, because it crashed a child or, in a child, because it's being
+// skipped.
+pub(crate) fn before_call(path: Option<&Path>, start: LineColumn) -> Option<String> {
+    before(key(path, start))
+}
+
+// Does the same thing as `before_call`, but for a call with the given mutation of the input.
+#[cfg(feature = "fuzz")]
+pub(crate) fn before_mutation(
+    path: Option<&Path>,
+    start: LineColumn,
+    mutation: usize,
+) -> Option<String> {
+    before(format!("{}\tmutation {}", key(path, start), mutation))
+}
+
+fn before(key: String) -> Option<String> {
+    MODE.with(|mode| match *mode.borrow() {
+        Some(Mode::Child {
+            ref progress,
+            ref skip,
+        }) => {
+            if skip.contains(&key) {
+                Some(String::new())
+            } else {
+                // If this can't be written, the parent finds out from the missing log.
+                log(progress, &key).ok();
+                None
+            }
+        }
+        Some(Mode::Parent { ref crashed }) => crashed.get(&key).cloned(),
+        None => None,
+    })
+}
+
+// Returns an error if there's a timeout but the scan isn't isolated. A macro function can't be
+// stopped or moved to another thread, so the only way to give up on one is to end the process, and
+// without a child to end, that would end the whole test run.
+pub(crate) fn check_timeout(timeout: Option<Duration>) -> Result<(), Error> {
+    let isolated = MODE.with(|mode| mode.borrow().is_some());
+    if timeout.is_none() || isolated {
+        return Ok(());
+    }
+    Err(Error::IoError {
+        path: None,
+        error: io::Error::other("a timeout only works when the run is isolated"),
+    })
+}
+
+// Calls the given function in a child, ending the child if it doesn't return before the timeout,
+// with a note in the log that says why. The parent only calls the functions that finished in time
+// in a child, so it doesn't watch them again: a slower second call, such as under a coverage tool,
+// would otherwise end the whole test run.
+pub(crate) fn with_timeout<F, R>(timeout: Option<Duration>, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let progress = MODE.with(|mode| match *mode.borrow() {
+        Some(Mode::Child { ref progress, .. }) => Some(progress.clone()),
+        _ => None,
+    });
+    let (timeout, progress) = match (timeout, progress) {
+        (Some(timeout), Some(progress)) => (timeout, progress),
+        _ => return f(),
+    };
+
+    let (done, wait) = mpsc::channel::<()>();
+    let watchdog = thread::spawn(move || {
+        if let Err(RecvTimeoutError::Timeout) = wait.recv_timeout(timeout) {
+            let message = format!("the macro function timed out after {:?}", timeout);
+            log(&progress, &format!("{}{}", NOTE, message)).ok();
+            process::exit(1);
+        }
+    });
+    let result = f();
+    drop(done);
+    watchdog.join().ok();
+    result
+}
+
+// Runs children until one finishes, and returns the messages for the invocations that crashed
+// them.
+#[cfg(feature = "fs")]
+fn probe() -> Result<HashMap<String, String>, Error> {
+    let exe = env::current_exe().map_err(|error| Error::IoError { path: None, error })?;
+    let io_error = |error| Error::IoError {
+        path: Some(exe.clone()),
+        error,
+    };
+    // libtest names each test's thread after the test.
+    let test = match thread::current().name() {
+        Some(name) if name != "main" => name.to_owned(),
+        _ => {
+            return Err(io_error(io::Error::other(
+                "isolation only works inside a test run by the standard test harness",
+            )))
+        }
+    };
+
+    static PROBES: AtomicUsize = AtomicUsize::new(0);
+    let dir = env::temp_dir().join(format!(
+        "runtime-macros-isolation-{}-{}",
+        process::id(),
+        PROBES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let (progress, skip) = (dir.join("progress"), dir.join("skip"));
+    let result = (|| {
+        fs::create_dir_all(&dir).map_err(io_error)?;
+        let mut crashed = HashMap::new();
+        loop {
+            fs::write(&progress, "").map_err(io_error)?;
+            let skipped: Vec<&str> = crashed.keys().map(String::as_str).collect();
+            fs::write(&skip, skipped.join("\n")).map_err(io_error)?;
+            // The child gets a copy of the environment, which can't be changing while it's copied.
+            let child = sandbox::locked(|| {
+                Command::new(&exe)
+                    .args([&test[..], "--exact", "--test-threads=1", "--nocapture"])
+                    .env(PROGRESS_VAR, &progress)
+                    .env(SKIP_VAR, &skip)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .spawn()
+            })
+            .map_err(io_error)?;
+            let output = child.wait_with_output().map_err(io_error)?;
+            let log = fs::read_to_string(&progress).map_err(io_error)?;
+            let mut lines = log.lines().rev();
+            let crash = match (lines.next(), lines.next()) {
+                (Some(DONE), _) => return Ok(crashed),
+                (Some(note), Some(key)) if note.starts_with(NOTE) => {
+                    Some((key, note[NOTE.len_utf8()..].to_owned()))
+                }
+                (Some(key), _) if !key.starts_with(NOTE) => Some((
+                    key,
+                    format!("the macro function crashed the process ({})", output.status),
+                )),
+                _ => None,
+            };
+            match crash {
+                // An invocation that crashed is skipped from then on, so it can't crash again.
+                Some((key, message)) if !crashed.contains_key(key) => {
+                    crashed.insert(key.to_owned(), message);
+                }
+                _ => {
+                    return Err(io_error(io::Error::other(format!(
+                        "the isolated test `{}` didn't finish ({}): {}",
+                        test,
+                        output.status,
+                        String::from_utf8_lossy(&output.stderr)
+                    ))))
+                }
+            }
+        }
+    })();
+    fs::remove_dir_all(&dir).ok();
+    result
+}
+
+// Identifies an invocation in a way that's the same in every process.
+fn key(path: Option<&Path>, start: LineColumn) -> String {
+    format!(
+        "{}\t{}\t{}",
+        path.map_or_else(String::new, |path| path.display().to_string()),
+        start.line,
+        start.column
+    )
+}
+
+fn log(progress: &Path, line: &str) -> io::Result<()> {
+    let mut file = OpenOptions::new().append(true).open(progress)?;
+    writeln!(file, "{}", line)
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    use {super::*, Expander, MacroRegistry, PanicPolicy};
+
+    #[test]
+    fn isolates_crashing_invocations() {
+        // The child runs this test too, so the file has to have the same path in both processes.
+        let path = env::temp_dir().join("runtime-macros-isolates-crashing-invocations.rs");
+        fs::write(&path, "fn main() {\n    foo!(abort);\n    foo!(1);\n}\n").unwrap();
+        let report = Expander::new()
+            .functionlike("foo", |ts| {
+                if ts.to_string() == "abort" {
+                    process::abort();
+                }
+                ts
+            })
+            .file(&path)
+            .panic_policy(PanicPolicy::Record)
+            .isolate(true)
+            .run()
+            .unwrap();
+
+        let file = &report.files[0];
+        assert_eq!(file.records.len(), 1);
+        assert_eq!(file.records[0].input.to_string(), "1");
+        assert_eq!(file.panics.len(), 1);
+        assert_eq!(file.panics[0].span.start().line, 2);
+        assert!(file.panics[0]
+            .message
+            .starts_with("the macro function crashed the process"));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "fuzz")]
+    #[test]
+    fn reports_mutations_that_crash() {
+        let path = env::temp_dir().join("runtime-macros-reports-mutations-that-crash.rs");
+        fs::write(&path, "fn main() {\n    foo!(1 2);\n}\n").unwrap();
+        let report = Expander::new()
+            .functionlike("foo", |ts| {
+                if ts.to_string() != "1 2" {
+                    process::abort();
+                }
+                ts
+            })
+            .file(&path)
+            .fuzz(2, 0)
+            .isolate(true)
+            .run()
+            .unwrap();
+
+        let file = &report.files[0];
+        assert_eq!(file.records.len(), 1);
+        assert_eq!(file.warnings.len(), 2);
+        for warning in file.warnings.iter() {
+            assert_eq!(warning.lint, "fuzz");
+            assert!(warning
+                .message
+                .starts_with("the macro function crashed the process"));
+            assert!(warning.message.contains(" when the input was mutated ("));
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refuses_timeouts_without_isolation() {
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", |ts| ts)
+            .timeout(Duration::from_millis(200));
+        match registry.emulate_source("foo!(1);") {
+            Err(Error::IoError { path: None, error }) => assert_eq!(
+                error.to_string(),
+                "a timeout only works when the run is isolated"
+            ),
+            result => panic!("expected an error, got {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn times_out_hanging_invocations() {
+        let path = env::temp_dir().join("runtime-macros-times-out-hanging-invocations.rs");
+        fs::write(&path, "fn main() {\n    foo!(hang);\n    foo!(1);\n}\n").unwrap();
+        let report = Expander::new()
+            .functionlike("foo", |ts| {
+                while ts.to_string() == "hang" {
+                    thread::sleep(Duration::from_millis(10));
+                }
+                ts
+            })
+            .file(&path)
+            .panic_policy(PanicPolicy::Record)
+            .timeout(Duration::from_millis(200))
+            .isolate(true)
+            .run()
+            .unwrap();
+
+        let file = &report.files[0];
+        assert_eq!(file.records.len(), 1);
+        assert_eq!(file.panics.len(), 1);
+        assert_eq!(
+            file.panics[0].message,
+            "the macro function timed out after 200ms"
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn doesnt_time_out_in_the_parent() {
+        let path = env::temp_dir().join("runtime-macros-doesnt-time-out-in-the-parent.rs");
+        fs::write(&path, "fn main() {\n    foo!(1);\n}\n").unwrap();
+        let report = Expander::new()
+            .functionlike("foo", |ts| {
+                // Only the child is quick enough, like a run under a coverage tool.
+                if env::var_os(PROGRESS_VAR).is_none() {
+                    thread::sleep(Duration::from_millis(400));
+                }
+                ts
+            })
+            .file(&path)
+            .panic_policy(PanicPolicy::Record)
+            .timeout(Duration::from_millis(200))
+            .isolate(true)
+            .run()
+            .unwrap();
+
+        let file = &report.files[0];
+        assert_eq!(file.records.len(), 1);
+        assert!(file.panics.is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Writing reports in the JUnit XML format that most CI servers can display.
+
+use {
+    report::{ExpansionOutcome, ExpansionReport, FileReport},
+    std::fmt::Write,
+};
+
+impl ExpansionReport {
+    /// Returns the report as JUnit XML, with one test case for each invocation of a registered
+    /// macro, so a CI server that understands the format can show each expansion as a test.
+    ///
+    /// Each file is a test suite, and each test case is named after the invocation's location and
+    /// the macro's path, like `tests/tests.rs:12:4 custom_assert`. An invocation whose macro
+    /// function panicked unexpectedly is a failure of type `panic`, and one whose expansion
+    /// contains a `compile_error!` is a failure of type `compile_error`. Warnings about an
+    /// expansion are listed in its test case's standard error. An invocation that wasn't expanded,
+    /// because it was filtered out or its path didn't match, is a skipped test case, and cached
+    /// files have no test cases, since their invocations weren't expanded again.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::Expander;
+    /// # fn custom_assert_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+    /// # let dir = std::env::temp_dir();
+    /// let report = Expander::new()
+    ///     .functionlike("custom_assert", custom_assert_internal)
+    ///     # .file(concat!(env!("CARGO_MANIFEST_DIR"), "/src/lib.rs"))
+    ///     # /*
+    ///     .file("tests/tests.rs")
+    ///     # */
+    ///     .run()
+    ///     .unwrap();
+    /// std::fs::write(dir.join("expansions.xml"), report.to_junit()).unwrap();
+    /// ```
+    pub fn to_junit(&self) -> String {
+        let suites: Vec<Suite> = self.files.iter().map(Suite::new).collect();
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        writeln!(
+            xml,
+            "<testsuites name=\"runtime-macros\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.6}\">",
+            suites.iter().map(|suite| suite.cases.len()).sum::<usize>(),
+            suites.iter().map(Suite::failures).sum::<usize>(),
+            suites.iter().map(Suite::skipped).sum::<usize>(),
+            self.files
+                .iter()
+                .map(|file| file.duration.as_secs_f64())
+                .sum::<f64>()
+        )
+        .unwrap();
+        for suite in suites {
+            suite.write(&mut xml);
+        }
+        xml.push_str("</testsuites>\n");
+        xml
+    }
+}
+
+// One file's test cases.
+struct Suite<'f> {
+    file: &'f FileReport,
+    cases: Vec<Case>,
+}
+
+// One invocation's test case, which is also used by the test harness.
+pub(crate) struct Case {
+    pub(crate) name: String,
+    // The type and message of the failure, if it failed.
+    pub(crate) failure: Option<(&'static str, String)>,
+    // Why it was skipped, if it wasn't expanded.
+    pub(crate) skipped: Option<String>,
+    pub(crate) warnings: Vec<String>,
+}
+
+// Returns a test case for each invocation in the file. A panic that was expected isn't a failure.
+pub(crate) fn cases(file: &FileReport) -> Vec<Case> {
+    let path = file.path.display();
+    file.call_sites
+        .iter()
+        .map(|site| {
+            // Invocations that weren't expanded share their orders with the next ones that were.
+            let record = file
+                .records
+                .iter()
+                .find(|record| site.outcome.was_called() && record.order == site.order);
+            let expected = || {
+                file.panics.iter().any(|panic| {
+                    panic.expected
+                        && panic.macro_path == site.macro_path
+                        && panic.span.start() == site.start
+                })
+            };
+            let failure = match (&site.outcome, record) {
+                (ExpansionOutcome::Panicked(_), _) if expected() => None,
+                (ExpansionOutcome::Panicked(message), _) => Some(("panic", message.clone())),
+                (_, Some(record)) => {
+                    let errors = record.compile_errors();
+                    if errors.is_empty() {
+                        None
+                    } else {
+                        Some(("compile_error", errors.join("\n")))
+                    }
+                }
+                _ => None,
+            };
+            let warnings = file
+                .warnings
+                .iter()
+                .filter(|warning| {
+                    warning.macro_path == site.macro_path
+                        && site.start <= warning.start
+                        && warning.end <= site.end
+                })
+                .map(ToString::to_string)
+                .collect();
+            Case {
+                name: format!(
+                    "{}:{}:{} {}",
+                    path, site.start.line, site.start.column, site.macro_path
+                ),
+                failure,
+                skipped: if site.outcome.was_called() {
+                    None
+                } else {
+                    Some(site.outcome.to_string())
+                },
+                warnings,
+            }
+        })
+        .collect()
+}
+
+impl<'f> Suite<'f> {
+    fn new(file: &'f FileReport) -> Self {
+        Suite {
+            file,
+            cases: cases(file),
+        }
+    }
+
+    fn failures(&self) -> usize {
+        self.cases
+            .iter()
+            .filter(|case| case.failure.is_some())
+            .count()
+    }
+
+    fn skipped(&self) -> usize {
+        self.cases
+            .iter()
+            .filter(|case| case.skipped.is_some())
+            .count()
+    }
+
+    fn write(&self, xml: &mut String) {
+        let path = escape(&self.file.path.display().to_string());
+        writeln!(
+            xml,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.6}\">",
+            path,
+            self.cases.len(),
+            self.failures(),
+            self.skipped(),
+            self.file.duration.as_secs_f64()
+        )
+        .unwrap();
+        for case in self.cases.iter() {
+            write!(
+                xml,
+                "    <testcase name=\"{}\" classname=\"{}\"",
+                escape(&case.name),
+                path
+            )
+            .unwrap();
+            if case.failure.is_none() && case.skipped.is_none() && case.warnings.is_empty() {
+                xml.push_str("/>\n");
+                continue;
+            }
+            xml.push_str(">\n");
+            if let Some((kind, ref message)) = case.failure {
+                writeln!(
+                    xml,
+                    "      <failure type=\"{}\" message=\"{}\">{}</failure>",
+                    kind,
+                    escape(message.lines().next().unwrap_or("")),
+                    escape(message)
+                )
+                .unwrap();
+            }
+            if let Some(ref message) = case.skipped {
+                writeln!(xml, "      <skipped message=\"{}\"/>", escape(message)).unwrap();
+            }
+            if !case.warnings.is_empty() {
+                writeln!(
+                    xml,
+                    "      <system-err>{}</system-err>",
+                    escape(&case.warnings.join("\n"))
+                )
+                .unwrap();
+            }
+            xml.push_str("    </testcase>\n");
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+}
+
+// Escapes the text for use in an attribute or an element.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // XML 1.0 doesn't allow most control characters, even escaped.
+            '\n' | '\r' | '\t' => escaped.push(c),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use {std::path::PathBuf, MacroRegistry, PanicPolicy};
+
+    #[test]
+    fn writes_one_test_case_per_invocation() {
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("ok", |ts| ts)
+            .functionlike("panics", |_| panic!("<oops>"))
+            .functionlike("errs", |_| "compile_error!(\"bad\");".parse().unwrap())
+            .panic_policy(PanicPolicy::Record);
+        let file = registry
+            .expand_source(
+                "ok!(1);\npanics!();\nerrs!();\n// runtime-macros: skip\nok!(2);",
+                None,
+            )
+            .unwrap()
+            .into_report(PathBuf::from("a.rs"));
+        let xml = registry.report(vec![file]).unwrap().to_junit();
+        let lines: Vec<&str> = xml.lines().collect();
+        assert!(lines[1].starts_with(
+            "<testsuites name=\"runtime-macros\" tests=\"4\" failures=\"2\" skipped=\"1\""
+        ));
+        assert!(lines[2]
+            .starts_with("  <testsuite name=\"a.rs\" tests=\"4\" failures=\"2\" skipped=\"1\""));
+        assert_eq!(
+            lines[3..],
+            [
+                "    <testcase name=\"a.rs:1:0 ok\" classname=\"a.rs\"/>",
+                "    <testcase name=\"a.rs:2:0 panics\" classname=\"a.rs\">",
+                "      <failure type=\"panic\" message=\"&lt;oops&gt;\">&lt;oops&gt;</failure>",
+                "    </testcase>",
+                "    <testcase name=\"a.rs:3:0 errs\" classname=\"a.rs\">",
+                "      <failure type=\"compile_error\" message=\"bad\">bad</failure>",
+                "    </testcase>",
+                "    <testcase name=\"a.rs:5:0 ok\" classname=\"a.rs\">",
+                "      <skipped message=\"filtered out\"/>",
+                "    </testcase>",
+                "  </testsuite>",
+                "</testsuites>",
+            ]
+        );
+    }
+}
@@ -15,8 +15,8 @@
 //! It is intended for use with code coverage tools like [`tarpaulin`], which can't measure
 //! the code coverage of anything that happens at compile time.
 //!
-//! Currently, `runtime-macros` only works with `functionlike!` procedural macros. Custom
-//! derive may be supported in the future if there's demand.
+//! The `runtime-macros` crate works with `functionlike!`, `#[attributelike]`, and `#[derive(...)]`
+//! procedural macros. There are no plans to support declarative macros.
 //!
 //! [`tarpaulin`]: https://crates.io/crates/cargo-tarpaulin
 //!
@@ -26,22 +26,195 @@
 //! works with your crate's test cases will be able to report on how thoroughly you've tested the
 //! macro.
 //!
+//! If you have macros of several kinds to test, you can register all of them with a
+//! [`MacroRegistry`] so that each file only needs to be parsed once.
+//!
+//! [`MacroRegistry`]: struct.MacroRegistry.html
+//!
+//! For more control, the [`Expander`] builder accepts the same registrations along with a list of
+//! files to scan, and it returns an [`ExpansionReport`] describing what was expanded.
+//!
+//! [`Expander`]: struct.Expander.html
+//! [`ExpansionReport`]: struct.ExpansionReport.html
+//!
+//! If several registries need to scan the same files, a [`Session`] parses each file once and
+//! keeps the syntax trees so every registry can reuse them.
+//!
+//! [`Session`]: struct.Session.html
+//!
+//! Reading files takes the default `fs` feature. Without it, as on `wasm32-unknown-unknown`, source
+//! code is given as a string to [`MacroRegistry::emulate_source`] or [`ParsedSource::parse`].
+//!
+//! [`MacroRegistry::emulate_source`]: struct.MacroRegistry.html#method.emulate_source
+//! [`ParsedSource::parse`]: struct.ParsedSource.html#method.parse
+//!
+//! Comments in the scanned files can also say what to do with the invocations on the next line. A
+//! `// runtime-macros: skip` comment keeps them from being expanded, and a
+//! `// runtime-macros: expect-panic` comment makes their panics expected: they're recorded in the
+//! report as [`ExpansionPanic`]s, whatever the [`PanicPolicy`], and the scan fails with an
+//! [`Error::MacroFailed`] if one of them doesn't panic after all.
+//!
+//! [`ExpansionPanic`]: struct.ExpansionPanic.html
+//! [`PanicPolicy`]: enum.PanicPolicy.html
+//! [`Error::MacroFailed`]: enum.Error.html#variant.MacroFailed
+//!
 //! See the `/examples` directory in the [repository] for working examples.
 //!
 //! [repository]: https://github.com/jeremydavis519/runtime-macros
 
+#[cfg(feature = "cargo_metadata")]
+extern crate cargo_metadata;
+#[cfg(feature = "insta")]
+#[doc(hidden)]
+pub extern crate insta as __insta;
+#[cfg(feature = "dylib")]
+extern crate libloading;
+#[cfg(feature = "libtest-mimic")]
+extern crate libtest_mimic;
+#[cfg(feature = "prettyplease")]
+extern crate prettyplease;
 extern crate proc_macro;
+/// The version of `proc_macro2` whose types this crate uses. A procedural macro crate that uses an
+/// incompatible version can register its functions with [`MacroRegistry::functionlike_foreign`]
+/// and the like instead.
+///
+/// [`MacroRegistry::functionlike_foreign`]: struct.MacroRegistry.html#method.functionlike_foreign
+pub extern crate proc_macro2;
 extern crate quote;
+#[cfg(feature = "rayon")]
+extern crate rayon;
+#[cfg(feature = "macros")]
+extern crate runtime_macros_derive;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+extern crate serde_json;
+#[cfg(feature = "stacker")]
+extern crate stacker;
+#[cfg(feature = "syn2")]
 extern crate syn;
+#[cfg(all(feature = "prettyplease", not(feature = "syn2")))]
+extern crate syn as syn2;
+#[cfg(all(feature = "syn1", not(feature = "syn2")))]
+extern crate syn1 as syn;
 
-use {
-    quote::ToTokens,
-    std::{
-        fs,
-        io::Read,
-        panic::{self, AssertUnwindSafe},
-    },
+#[cfg(all(feature = "syn1", feature = "syn2"))]
+compile_error!("the `syn1` and `syn2` features can't both be enabled");
+#[cfg(not(any(feature = "syn1", feature = "syn2")))]
+compile_error!("either the `syn1` or the `syn2` feature has to be enabled");
+#[cfg(feature = "tracing")]
+extern crate tracing;
+
+#[doc(hidden)]
+pub mod assertions;
+pub mod bench;
+mod budget;
+#[cfg(feature = "fs")]
+mod cache;
+mod cfg;
+#[cfg(feature = "cli")]
+#[doc(hidden)]
+pub mod cli;
+mod compat;
+mod context;
+mod corpus;
+#[cfg(feature = "pretty-errors")]
+mod diagnostic;
+mod directives;
+mod docs;
+mod driver;
+#[cfg(feature = "fs")]
+mod dump;
+#[cfg(feature = "dylib")]
+mod dylib;
+#[cfg(feature = "fs")]
+mod expander;
+#[cfg(feature = "fs")]
+mod exports;
+mod fidelity;
+mod foreign;
+#[cfg(feature = "fuzz")]
+mod fuzz;
+#[cfg(feature = "fs")]
+mod glob;
+#[cfg(feature = "libtest-mimic")]
+mod harness;
+mod hygiene;
+mod isolation;
+mod junit;
+mod matching;
+mod memory;
+mod minimize;
+#[cfg(feature = "fs")]
+mod modules;
+mod order;
+#[cfg(feature = "cargo_metadata")]
+mod package;
+mod panics;
+#[cfg(feature = "rayon")]
+mod parallel;
+mod pretty;
+#[cfg(feature = "fs")]
+mod progress;
+mod registry;
+mod report;
+#[cfg(feature = "fs")]
+mod reporter;
+mod sandbox;
+#[cfg(feature = "serde")]
+mod serialize;
+mod session;
+mod snapshots;
+pub mod tokens;
+#[cfg(feature = "verify-compile")]
+mod verify;
+mod visitor;
+
+pub use budget::Budget;
+pub use cfg::CfgSet;
+pub use context::ExpansionContext;
+pub use driver::{ExpansionHandler, MacroDriver};
+#[cfg(feature = "dylib")]
+pub use dylib::{MacroLibrary, FREE_SYMBOL};
+#[cfg(feature = "fs")]
+pub use expander::Expander;
+#[cfg(feature = "fs")]
+pub use exports::{exported_macros, ExportedMacro};
+pub use fidelity::Fidelity;
+pub use foreign::ForeignTokens;
+#[cfg(feature = "libtest-mimic")]
+pub use harness::Harness;
+pub use matching::{MatchFn, MatchMode};
+#[cfg(feature = "track-memory")]
+pub use memory::TrackingAllocator;
+#[cfg(feature = "cargo_metadata")]
+pub use package::{emulate_for_package, CrateReport, WorkspaceReport, WorkspaceScanner};
+pub use panics::PanicPolicy;
+#[cfg(feature = "rayon")]
+pub use parallel::ParallelExpander;
+#[cfg(feature = "fs")]
+pub use progress::Progress;
+pub use registry::{AttributeFn, DeriveFn, FunctionlikeFn, MacroRegistry};
+pub use report::{
+    CallSite, ExpansionOutcome, ExpansionPanic, ExpansionRecord, ExpansionReport, ExpansionWarning,
+    FileReport, InvocationInfo, MacroKind, MacroPosition, NearMiss, PanicLocation,
 };
+#[cfg(all(feature = "fs", feature = "serde"))]
+pub use reporter::JsonReporter;
+#[cfg(feature = "fs")]
+pub use reporter::{ConsoleReporter, QuietReporter, Reporter};
+#[cfg(feature = "macros")]
+pub use runtime_macros_derive::{coverage_tests, testable};
+pub use session::{ParsedSource, Session};
+#[cfg(feature = "fs")]
+pub use snapshots::Snapshots;
+pub use snapshots::{expansion_snapshots, SnapshotMismatch};
+#[cfg(feature = "verify-compile")]
+pub use verify::CompileCheck;
+
+use std::path::PathBuf;
+#[cfg(feature = "fs")]
+use std::{fs, path::Path};
 
 /// Searches the given Rust source code file for function-like macro calls and calls the functions
 /// that define how to expand them.
@@ -55,18 +228,31 @@ use {
 /// are equivalent to the given one. The paths used to reference the macro must be exactly equal
 /// to the one given in order to be expanded by this function. For example, if `macro_path` is
 /// `"foo"` and the file provided calls the macro using `bar::foo!`, this function will not know
-/// to expand it, and the macro's code coverage will be underestimated.
+/// to expand it, and the macro's code coverage will be underestimated. To match paths more
+/// leniently, use a [`MacroRegistry`] with [`MacroRegistry::match_mode`].
+///
+/// [`MacroRegistry`]: struct.MacroRegistry.html
+/// [`MacroRegistry::match_mode`]: struct.MacroRegistry.html#method.match_mode
 ///
 /// Also, this function uses `proc_macro2::TokenStream`, not the standard `proc_macro::TokenStream`.
 /// The Rust compiler disallows using the `proc_macro` API for anything except defining a procedural
 /// macro (i.e. we can't use it at runtime). You can convert between the two types using their
 /// `into` methods, as shown below.
 ///
+/// All the functions in `macro_paths_and_proc_macro_fns` must have the same type. To mix closures
+/// and function pointers, box them as [`FunctionlikeFn`]s, or use
+/// [`MacroRegistry::extend_functionlike`].
+///
+/// [`FunctionlikeFn`]: type.FunctionlikeFn.html
+/// [`MacroRegistry::extend_functionlike`]: struct.MacroRegistry.html#method.extend_functionlike
+///
 /// # Returns
 ///
-/// `Ok` on success, or an instance of [`Error`] indicating any error that occurred when trying to
-/// read or parse the file.
+/// An [`ExpansionReport`] describing every expansion on success, or an instance of [`Error`]
+/// indicating any error that occurred when trying to read or parse the file. The report can be
+/// used to check how many times each macro was invoked.
 ///
+/// [`ExpansionReport`]: struct.ExpansionReport.html
 /// [`Error`]: enum.Error.html
 ///
 /// # Example
@@ -99,59 +285,38 @@ use {
 /// }
 /// # macro_code_coverage();
 /// ```
-pub fn emulate_functionlike_macro_expansion<'a, F>(
-    mut file: fs::File,
-    macro_paths_and_proc_macro_fns: &[(&'a str, F)],
-) -> Result<(), Error>
+#[cfg(feature = "fs")]
+pub fn emulate_functionlike_macro_expansion<F>(
+    file: fs::File,
+    macro_paths_and_proc_macro_fns: &[(&str, F)],
+) -> Result<ExpansionReport, Error>
 where
     F: Fn(proc_macro2::TokenStream) -> proc_macro2::TokenStream,
 {
-    struct MacroVisitor<'a, F: Fn(proc_macro2::TokenStream) -> proc_macro2::TokenStream> {
-        macro_paths_and_proc_macro_fns: AssertUnwindSafe<Vec<(syn::Path, &'a F)>>,
+    let mut registry = MacroRegistry::new();
+    for (path, proc_macro_fn) in macro_paths_and_proc_macro_fns.iter() {
+        registry.functionlike(path, proc_macro_fn);
     }
-    impl<'a, 'ast, F> syn::visit::Visit<'ast> for MacroVisitor<'a, F>
-    where
-        F: Fn(proc_macro2::TokenStream) -> proc_macro2::TokenStream,
-    {
-        fn visit_macro(&mut self, macro_item: &'ast syn::Macro) {
-            for (path, proc_macro_fn) in self.macro_paths_and_proc_macro_fns.iter() {
-                if macro_item.path == *path {
-                    proc_macro_fn(macro_item.tokens.clone().into());
-                }
-            }
-        }
-    }
-
-    let mut content = String::new();
-    file.read_to_string(&mut content)
-        .map_err(|e| Error::IoError(e))?;
-
-    let ast =
-        AssertUnwindSafe(syn::parse_file(content.as_str()).map_err(|e| Error::ParseError(e))?);
-    let macro_paths_and_proc_macro_fns = AssertUnwindSafe(
-        macro_paths_and_proc_macro_fns
-            .iter()
-            .map(|(s, f)| Ok((syn::parse_str(s)?, f)))
-            .collect::<Result<Vec<(syn::Path, &F)>, _>>()
-            .map_err(|e| Error::ParseError(e))?,
-    );
-
-    panic::catch_unwind(|| {
-        syn::visit::visit_file(
-            &mut MacroVisitor::<F> {
-                macro_paths_and_proc_macro_fns,
-            },
-            &*ast,
-        );
-    })
-    .map_err(|_| {
-        Error::ParseError(syn::parse::Error::new(
-            proc_macro2::Span::call_site().into(),
-            "macro expansion panicked",
-        ))
-    })?;
+    registry.emulate_macro_expansion(file)
+}
 
-    Ok(())
+/// Does the same thing as [`emulate_functionlike_macro_expansion`], but also returns a record of every
+/// expansion, including the tokens that each macro function produced.
+///
+/// [`emulate_functionlike_macro_expansion`]: fn.emulate_functionlike_macro_expansion.html
+#[cfg(feature = "fs")]
+pub fn emulate_functionlike_macro_expansion_collect<F>(
+    file: fs::File,
+    macro_paths_and_proc_macro_fns: &[(&str, F)],
+) -> Result<Vec<ExpansionRecord>, Error>
+where
+    F: Fn(proc_macro2::TokenStream) -> proc_macro2::TokenStream,
+{
+    let mut registry = MacroRegistry::new();
+    for (path, proc_macro_fn) in macro_paths_and_proc_macro_fns.iter() {
+        registry.functionlike(path, proc_macro_fn);
+    }
+    registry.emulate_macro_expansion_collect(file)
 }
 
 /// Searches the given Rust source code file for derive macro calls and calls the functions that
@@ -162,107 +327,129 @@ where
 /// documentation for details and an example of use.
 ///
 /// [`emulate_functionlike_macro_expansion`]: fn.emulate_functionlike_macro_expansion.html
-pub fn emulate_derive_macro_expansion<'a, F>(
-    mut file: fs::File,
-    macro_paths_and_proc_macro_fns: &[(&'a str, F)],
-) -> Result<(), Error>
+#[cfg(feature = "fs")]
+pub fn emulate_derive_macro_expansion<F>(
+    file: fs::File,
+    macro_paths_and_proc_macro_fns: &[(&str, F)],
+) -> Result<ExpansionReport, Error>
 where
     F: Fn(proc_macro2::TokenStream) -> proc_macro2::TokenStream,
 {
-    struct MacroVisitor<'a, F: Fn(proc_macro2::TokenStream) -> proc_macro2::TokenStream> {
-        macro_paths_and_proc_macro_fns: AssertUnwindSafe<Vec<(syn::Path, &'a F)>>,
+    let mut registry = MacroRegistry::new();
+    for (path, proc_macro_fn) in macro_paths_and_proc_macro_fns.iter() {
+        registry.derive(path, proc_macro_fn);
     }
-    impl<'a, 'ast, F> syn::visit::Visit<'ast> for MacroVisitor<'a, F>
-    where
-        F: Fn(proc_macro2::TokenStream) -> proc_macro2::TokenStream,
-    {
-        fn visit_item(&mut self, item: &'ast syn::Item) {
-            macro_rules! visit {
-                ( $($ident:ident),* ) => {
-                    match *item {
-                        $(syn::Item::$ident(ref item) => {
-                            for attr in item.attrs.iter() {
-                                let meta = match &attr.meta {
-                                    syn::Meta::List(list) => list,
-                                    _ => continue
-                                };
-
-                                match meta.path.get_ident() {
-                                    Some(x) => {
-                                        if x != "derive" {
-                                            continue;
-                                        }
-                                    },
-                                    None => continue
-                                }
+    registry.emulate_macro_expansion(file)
+}
 
-                                match meta.parse_nested_meta(|meta| {
-                                    for (path, proc_macro_fn) in self.macro_paths_and_proc_macro_fns.iter() {
-                                        if meta.path == *path {
-                                            proc_macro_fn(/* attributes? */ item.to_token_stream());
-                                        }
-                                    }
-                                    Ok(())
-                                }) {
-                                    Ok(_) => {},
-                                    Err(err) => panic!("Error parsing nested meta: {}", err),
-                                };
-                            }
-                        },)*
-                        _ => {}
-                    }
-                }
-            }
-            visit!(
-                Const,
-                Enum,
-                ExternCrate,
-                Fn,
-                ForeignMod,
-                Impl,
-                Macro,
-                Mod,
-                Static,
-                Struct,
-                Trait,
-                TraitAlias,
-                Type,
-                Union,
-                Use
-            );
-        }
+/// Does the same thing as [`emulate_derive_macro_expansion`], but also returns a record of every
+/// expansion, including the tokens that each macro function produced.
+///
+/// [`emulate_derive_macro_expansion`]: fn.emulate_derive_macro_expansion.html
+#[cfg(feature = "fs")]
+pub fn emulate_derive_macro_expansion_collect<F>(
+    file: fs::File,
+    macro_paths_and_proc_macro_fns: &[(&str, F)],
+) -> Result<Vec<ExpansionRecord>, Error>
+where
+    F: Fn(proc_macro2::TokenStream) -> proc_macro2::TokenStream,
+{
+    let mut registry = MacroRegistry::new();
+    for (path, proc_macro_fn) in macro_paths_and_proc_macro_fns.iter() {
+        registry.derive(path, proc_macro_fn);
     }
+    registry.emulate_macro_expansion_collect(file)
+}
 
-    let mut content = String::new();
-    file.read_to_string(&mut content)
-        .map_err(|e| Error::IoError(e))?;
-
-    let ast =
-        AssertUnwindSafe(syn::parse_file(content.as_str()).map_err(|e| Error::ParseError(e))?);
-    let macro_paths_and_proc_macro_fns = AssertUnwindSafe(
-        macro_paths_and_proc_macro_fns
-            .iter()
-            .map(|(s, f)| Ok((syn::parse_str(s)?, f)))
-            .collect::<Result<Vec<(syn::Path, &F)>, _>>()
-            .map_err(|e| Error::ParseError(e))?,
-    );
+/// Searches the given Rust source code file for derive macro calls and calls the functions that
+/// define how to expand them, giving each function the annotated item already parsed as a `T`.
+///
+/// This function behaves just like [`emulate_derive_macro_expansion`], but it lets the internal
+/// function take any type that implements `syn::parse::Parse`, such as `syn::DeriveInput`, instead
+/// of a raw `TokenStream`. If an annotated item can't be parsed as a `T`, the function isn't
+/// called for it, just as if the `proc_macro_derive` stub had used `syn::parse_macro_input!`.
+///
+/// [`emulate_derive_macro_expansion`]: fn.emulate_derive_macro_expansion.html
+///
+/// # Example
+///
+/// ```
+/// # use runtime_macros::emulate_derive_expansion;
+/// fn hello_world_internal(input: syn::DeriveInput) -> proc_macro2::TokenStream {
+///     let ident = input.ident;
+///     quote::quote!(impl #ident { fn hello_world() {} })
+/// }
+///
+/// # /*
+/// let file = std::fs::File::open("tests/tests.rs").unwrap();
+/// # */
+/// # let file = std::fs::File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/src/lib.rs")).unwrap();
+/// emulate_derive_expansion(file, &[("HelloWorld", hello_world_internal)]).unwrap();
+/// ```
+#[cfg(feature = "fs")]
+pub fn emulate_derive_expansion<T, F>(
+    file: fs::File,
+    macro_paths_and_proc_macro_fns: &[(&str, F)],
+) -> Result<ExpansionReport, Error>
+where
+    T: syn::parse::Parse,
+    F: Fn(T) -> proc_macro2::TokenStream,
+{
+    let mut registry = MacroRegistry::new();
+    for (path, proc_macro_fn) in macro_paths_and_proc_macro_fns.iter() {
+        registry.derive_parsed(path, proc_macro_fn);
+    }
+    registry.emulate_macro_expansion(file)
+}
 
-    panic::catch_unwind(|| {
-        syn::visit::visit_file(
-            &mut MacroVisitor::<F> {
-                macro_paths_and_proc_macro_fns,
-            },
-            &*ast,
-        );
-    })
-    .map_err(|_| {
-        Error::ParseError(syn::parse::Error::new(
-            proc_macro2::Span::call_site().into(),
-            "macro expansion panicked",
-        ))
-    })?;
+/// Searches the given Rust source code file for function-like macro calls and calls the functions
+/// that define how to expand them, which may fail.
+///
+/// This function behaves just like [`emulate_functionlike_macro_expansion`], but the internal
+/// functions return a `syn::Result` instead of converting their errors to `compile_error!`
+/// invocations. The whole file is scanned either way, and then the first error that any function
+/// returned is passed along as an [`Error::MacroFailed`].
+///
+/// [`emulate_functionlike_macro_expansion`]: fn.emulate_functionlike_macro_expansion.html
+/// [`Error::MacroFailed`]: enum.Error.html#variant.MacroFailed
+#[cfg(feature = "fs")]
+pub fn emulate_functionlike_expansion_fallible<F>(
+    file: fs::File,
+    macro_paths_and_proc_macro_fns: &[(&str, F)],
+) -> Result<ExpansionReport, Error>
+where
+    F: Fn(proc_macro2::TokenStream) -> syn::Result<proc_macro2::TokenStream>,
+{
+    let mut registry = MacroRegistry::new();
+    for (path, proc_macro_fn) in macro_paths_and_proc_macro_fns.iter() {
+        registry.functionlike_fallible(path, proc_macro_fn);
+    }
+    registry.emulate_macro_expansion(file)
+}
 
-    Ok(())
+/// Searches the given Rust source code file for derive macro calls and calls the functions that
+/// define how to expand them, which may fail.
+///
+/// This function combines [`emulate_derive_expansion`] and
+/// [`emulate_functionlike_expansion_fallible`]: each internal function takes the annotated item
+/// parsed as a `T`, and an item that can't be parsed counts as an error.
+///
+/// [`emulate_derive_expansion`]: fn.emulate_derive_expansion.html
+/// [`emulate_functionlike_expansion_fallible`]: fn.emulate_functionlike_expansion_fallible.html
+#[cfg(feature = "fs")]
+pub fn emulate_derive_expansion_fallible<T, F>(
+    file: fs::File,
+    macro_paths_and_proc_macro_fns: &[(&str, F)],
+) -> Result<ExpansionReport, Error>
+where
+    T: syn::parse::Parse,
+    F: Fn(T) -> syn::Result<proc_macro2::TokenStream>,
+{
+    let mut registry = MacroRegistry::new();
+    for (path, proc_macro_fn) in macro_paths_and_proc_macro_fns.iter() {
+        registry.derive_fallible(path, proc_macro_fn);
+    }
+    registry.emulate_macro_expansion(file)
 }
 
 /// Searches the given Rust source code file for attribute-like macro calls and calls the functions
@@ -273,110 +460,390 @@ where
 /// documentation for details and an example of use.
 ///
 /// [`emulate_functionlike_macro_expansion`]: fn.emulate_functionlike_macro_expansion.html
-pub fn emulate_attributelike_macro_expansion<'a, F>(
-    mut file: fs::File,
-    macro_paths_and_proc_macro_fns: &[(&'a str, F)],
-) -> Result<(), Error>
+#[cfg(feature = "fs")]
+pub fn emulate_attributelike_macro_expansion<F>(
+    file: fs::File,
+    macro_paths_and_proc_macro_fns: &[(&str, F)],
+) -> Result<ExpansionReport, Error>
 where
     F: Fn(proc_macro2::TokenStream, proc_macro2::TokenStream) -> proc_macro2::TokenStream,
 {
-    struct MacroVisitor<
-        'a,
-        F: Fn(proc_macro2::TokenStream, proc_macro2::TokenStream) -> proc_macro2::TokenStream,
-    > {
-        macro_paths_and_proc_macro_fns: AssertUnwindSafe<Vec<(syn::Path, &'a F)>>,
+    let mut registry = MacroRegistry::new();
+    for (path, proc_macro_fn) in macro_paths_and_proc_macro_fns.iter() {
+        registry.attribute(path, proc_macro_fn);
     }
-    impl<'a, 'ast, F> syn::visit::Visit<'ast> for MacroVisitor<'a, F>
-    where
-        F: Fn(proc_macro2::TokenStream, proc_macro2::TokenStream) -> proc_macro2::TokenStream,
-    {
-        fn visit_item(&mut self, item: &'ast syn::Item) {
-            macro_rules! visit {
-                ( $($ident:ident),* ) => {
-                    match *item {
-                        $(syn::Item::$ident(ref item) => {
-                            for attr in item.attrs.iter() {
-                                let meta = match &attr.meta {
-                                    syn::Meta::List(list) => list,
-                                    _ => continue
-                                };
+    registry.emulate_macro_expansion(file)
+}
 
-                                for (path, proc_macro_fn) in self.macro_paths_and_proc_macro_fns.iter() {
-                                    if meta.path == *path {
-                                        proc_macro_fn(meta.tokens.clone().into(), item.to_token_stream());
-                                    }
-                                }
-                            }
-                        },)*
-                        _ => {}
-                    }
-                }
-            }
-            visit!(
-                Const,
-                Enum,
-                ExternCrate,
-                Fn,
-                ForeignMod,
-                Impl,
-                Macro,
-                Mod,
-                Static,
-                Struct,
-                Trait,
-                TraitAlias,
-                Type,
-                Union,
-                Use
-            );
-        }
+/// Does the same thing as [`emulate_attributelike_macro_expansion`], but also returns a record of every
+/// expansion, including the tokens that each macro function produced.
+///
+/// [`emulate_attributelike_macro_expansion`]: fn.emulate_attributelike_macro_expansion.html
+#[cfg(feature = "fs")]
+pub fn emulate_attributelike_macro_expansion_collect<F>(
+    file: fs::File,
+    macro_paths_and_proc_macro_fns: &[(&str, F)],
+) -> Result<Vec<ExpansionRecord>, Error>
+where
+    F: Fn(proc_macro2::TokenStream, proc_macro2::TokenStream) -> proc_macro2::TokenStream,
+{
+    let mut registry = MacroRegistry::new();
+    for (path, proc_macro_fn) in macro_paths_and_proc_macro_fns.iter() {
+        registry.attribute(path, proc_macro_fn);
     }
+    registry.emulate_macro_expansion_collect(file)
+}
 
-    let mut content = String::new();
-    file.read_to_string(&mut content)
-        .map_err(|e| Error::IoError(e))?;
-
-    let ast =
-        AssertUnwindSafe(syn::parse_file(content.as_str()).map_err(|e| Error::ParseError(e))?);
-    let macro_paths_and_proc_macro_fns = AssertUnwindSafe(
-        macro_paths_and_proc_macro_fns
-            .iter()
-            .map(|(s, f)| Ok((syn::parse_str(s)?, f)))
-            .collect::<Result<Vec<(syn::Path, &F)>, _>>()
-            .map_err(|e| Error::ParseError(e))?,
-    );
+/// Searches the given Rust source code file for attribute-like macro calls and calls the functions
+/// that define how to expand them, which may fail.
+///
+/// This function behaves just like [`emulate_attributelike_macro_expansion`], but with fallible
+/// functions, as described in [`emulate_functionlike_expansion_fallible`].
+///
+/// [`emulate_attributelike_macro_expansion`]: fn.emulate_attributelike_macro_expansion.html
+/// [`emulate_functionlike_expansion_fallible`]: fn.emulate_functionlike_expansion_fallible.html
+#[cfg(feature = "fs")]
+pub fn emulate_attributelike_expansion_fallible<F>(
+    file: fs::File,
+    macro_paths_and_proc_macro_fns: &[(&str, F)],
+) -> Result<ExpansionReport, Error>
+where
+    F: Fn(
+        proc_macro2::TokenStream,
+        proc_macro2::TokenStream,
+    ) -> syn::Result<proc_macro2::TokenStream>,
+{
+    let mut registry = MacroRegistry::new();
+    for (path, proc_macro_fn) in macro_paths_and_proc_macro_fns.iter() {
+        registry.attribute_fallible(path, proc_macro_fn);
+    }
+    registry.emulate_macro_expansion(file)
+}
 
-    panic::catch_unwind(|| {
-        syn::visit::visit_file(
-            &mut MacroVisitor::<F> {
-                macro_paths_and_proc_macro_fns,
-            },
-            &*ast,
-        );
-    })
-    .map_err(|_| {
-        Error::ParseError(syn::parse::Error::new(
-            proc_macro2::Span::call_site().into(),
-            "macro expansion panicked",
-        ))
-    })?;
+/// Reads each file in the given directory as the input to a function-like macro and calls the
+/// function that defines how to expand it.
+///
+/// Unlike the other `emulate_*` functions, this doesn't look for invocations in Rust source code.
+/// Each file holds only the tokens that would go between the macro's delimiters, as in a fuzzing
+/// corpus, and the function is called once per file. The invocations are reported under the macro
+/// path `"corpus"`. See [`MacroRegistry::emulate_from_corpus`] for details.
+///
+/// [`MacroRegistry::emulate_from_corpus`]: struct.MacroRegistry.html#method.emulate_from_corpus
+#[cfg(feature = "fs")]
+pub fn emulate_from_corpus<P, F>(dir: P, proc_macro_fn: F) -> Result<ExpansionReport, Error>
+where
+    P: AsRef<Path>,
+    F: Fn(proc_macro2::TokenStream) -> proc_macro2::TokenStream,
+{
+    MacroRegistry::new()
+        .functionlike("corpus", proc_macro_fn)
+        .emulate_from_corpus(dir)
+}
 
-    Ok(())
+/// Lists every function-like macro invocation, derive, and attribute in the given Rust source code
+/// file, with its path as it's written and its location, without expanding anything.
+///
+/// This can be used to build a custom harness, or to see why a registration isn't matching an
+/// invocation. Attributes that the compiler handles itself, like `#[inline]`, are left out. See
+/// [`MacroRegistry::discover_macro_invocations`] for a version that scans the file the way a
+/// configured registry would.
+///
+/// [`MacroRegistry::discover_macro_invocations`]: struct.MacroRegistry.html#method.discover_macro_invocations
+///
+/// # Example
+///
+/// ```
+/// # use runtime_macros::{discover_macro_invocations, MacroKind};
+/// # /*
+/// let file = std::fs::File::open("tests/tests.rs").unwrap();
+/// # */
+/// # let file = std::fs::File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/src/lib.rs")).unwrap();
+/// for invocation in discover_macro_invocations(file).unwrap() {
+///     println!("{}:{} {}", invocation.start.line, invocation.start.column, invocation);
+/// }
+/// ```
+#[cfg(feature = "fs")]
+pub fn discover_macro_invocations(file: fs::File) -> Result<Vec<InvocationInfo>, Error> {
+    MacroRegistry::new().discover_macro_invocations(file)
 }
 
-/// The error type for `emulate_*_macro_expansion`. If anything goes wrong during the file loading
-/// or macro expansion, this type describes it.
+/// The error type for `emulate_*_macro_expansion` and `discover_macro_invocations`. If anything
+/// goes wrong during the file loading or macro expansion, this type describes it.
+///
+/// Wherever a file is involved, its path is included if it's known. It isn't known when the file
+/// was given as a `fs::File` rather than added to an [`Expander`].
+///
+/// [`Expander`]: struct.Expander.html
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
-    IoError(std::io::Error),
-    ParseError(syn::parse::Error),
+    /// A source file couldn't be read.
+    #[non_exhaustive]
+    IoError {
+        /// The path of the file.
+        path: Option<PathBuf>,
+        /// The underlying error.
+        error: std::io::Error,
+    },
+    /// A source file couldn't be parsed as Rust code. The location of the problem is available
+    /// from `error.span()`.
+    #[non_exhaustive]
+    ParseError {
+        /// The path of the file.
+        path: Option<PathBuf>,
+        /// The underlying error.
+        error: syn::parse::Error,
+        /// The error message along with an excerpt of the source code, if the `pretty-errors`
+        /// feature is enabled. This is what the error displays as.
+        snippet: Option<String>,
+    },
+    /// A macro was registered under a string that isn't a valid path.
+    #[non_exhaustive]
+    InvalidMacroPath {
+        /// The kind of macro it was registered as.
+        kind: MacroKind,
+        /// The string it was registered under.
+        macro_path: String,
+        /// The underlying error.
+        error: syn::parse::Error,
+    },
+    /// A fallible macro function returned an error. The whole file is still expanded, and this
+    /// describes the first error. The location of the invocation is available from
+    /// `error.span()`.
+    #[non_exhaustive]
+    MacroFailed {
+        /// The path of the file.
+        path: Option<PathBuf>,
+        /// The kind of macro that failed.
+        kind: MacroKind,
+        /// The path of the macro, as it was registered.
+        macro_path: String,
+        /// The error that the macro function returned.
+        error: syn::parse::Error,
+        /// The error message along with an excerpt of the source code, if the `pretty-errors`
+        /// feature is enabled. This is what the error displays as.
+        snippet: Option<String>,
+    },
+    /// Some registered macros were never invoked in any of the scanned files. This is only
+    /// returned if [`MacroRegistry::require_all_invoked`] is enabled.
+    ///
+    /// [`MacroRegistry::require_all_invoked`]: struct.MacroRegistry.html#method.require_all_invoked
+    NotInvoked(Vec<(MacroKind, String)>),
+    /// At least one macro function panicked. Every invocation is still expanded, so this lists all
+    /// the ones that panicked, in the order they were found.
+    #[non_exhaustive]
+    ExpansionPanicked {
+        /// The path of the file containing the first invocation that panicked.
+        path: Option<PathBuf>,
+        /// The message of the first panic.
+        message: String,
+        /// Where in the macro's code the first panic happened, if known.
+        location: Option<PanicLocation>,
+        /// The location of the first invocation that panicked.
+        span: proc_macro2::Span,
+        /// Every invocation that panicked, including the first.
+        panics: Vec<ExpansionPanic>,
+    },
+    /// The macros in a registry aren't the same as the ones a procedural macro crate exports. See
+    /// [`MacroRegistry::check_exports`].
+    ///
+    /// [`MacroRegistry::check_exports`]: struct.MacroRegistry.html#method.check_exports
+    #[cfg(feature = "fs")]
+    #[non_exhaustive]
+    ExportMismatch {
+        /// The path of the crate's root file.
+        path: PathBuf,
+        /// The kind and name of each exported macro that isn't registered.
+        unregistered: Vec<(MacroKind, String)>,
+        /// The kind and path of each registered macro that isn't exported.
+        unexported: Vec<(MacroKind, String)>,
+        /// The derive and the name of each helper attribute that's declared by the crate but not
+        /// the registry, or by the registry but not the crate.
+        helpers: Vec<(String, String)>,
+    },
+    /// Some expansions didn't match their snapshots. This lists every one that didn't.
+    SnapshotMismatch(Vec<SnapshotMismatch>),
+    /// A file didn't compile once its expansions were spliced in. See [`CompileCheck`].
+    ///
+    /// [`CompileCheck`]: struct.CompileCheck.html
+    #[cfg(feature = "verify-compile")]
+    #[non_exhaustive]
+    CompileFailed {
+        /// The path of the file.
+        path: Option<PathBuf>,
+        /// What rustc printed.
+        stderr: String,
+    },
+    /// Cargo couldn't describe a package, so its files couldn't be found.
+    #[cfg(feature = "cargo_metadata")]
+    MetadataFailed(cargo_metadata::Error),
+}
+
+impl Error {
+    // Adds an excerpt of the given source code to the error, if the `pretty-errors` feature is
+    // enabled and the error points into the code.
+    #[cfg(feature = "pretty-errors")]
+    pub(crate) fn annotate(mut self, source: &str) -> Error {
+        match self {
+            Error::ParseError {
+                ref path,
+                ref error,
+                ref mut snippet,
+            } => {
+                let message = format!("failed to parse the file: {}", error);
+                *snippet = diagnostic::render(source, path.as_deref(), &message, error.span());
+            }
+            Error::MacroFailed {
+                ref path,
+                kind,
+                ref macro_path,
+                ref error,
+                ref mut snippet,
+            } => {
+                let message = format!("{} macro `{}` failed: {}", kind, macro_path, error);
+                *snippet = diagnostic::render(source, path.as_deref(), &message, error.span());
+            }
+            _ => {}
+        }
+        self
+    }
+
+    #[cfg(not(feature = "pretty-errors"))]
+    pub(crate) fn annotate(self, _source: &str) -> Error {
+        self
+    }
+
+    // Summarizes the given panics, or returns `None` if there weren't any.
+    pub(crate) fn from_panics(panics: Vec<ExpansionPanic>) -> Option<Error> {
+        let (path, message, location, span) = match panics.first() {
+            Some(panic) => (
+                panic.path.clone(),
+                panic.message.clone(),
+                panic.location.clone(),
+                panic.span,
+            ),
+            None => return None,
+        };
+        Some(Error::ExpansionPanicked {
+            path,
+            message,
+            location,
+            span,
+            panics,
+        })
+    }
+}
+
+// Formats the path of a file as the start of an error message.
+fn fmt_path(f: &mut std::fmt::Formatter, path: &Option<PathBuf>) -> std::fmt::Result {
+    match path {
+        Some(path) => write!(f, "{}: ", path.display()),
+        None => Ok(()),
+    }
 }
 
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
-            Error::IoError(e) => e.fmt(f),
-            Error::ParseError(e) => e.fmt(f),
+            Error::IoError { path, error } => {
+                fmt_path(f, path)?;
+                error.fmt(f)
+            }
+            Error::ParseError {
+                snippet: Some(snippet),
+                ..
+            }
+            | Error::MacroFailed {
+                snippet: Some(snippet),
+                ..
+            } => f.write_str(snippet),
+            Error::ParseError { path, error, .. } => {
+                fmt_path(f, path)?;
+                write!(f, "failed to parse the file: {}", error)
+            }
+            Error::InvalidMacroPath {
+                kind,
+                macro_path,
+                error,
+            } => write!(
+                f,
+                "{} macro registered under an invalid path `{}`: {}",
+                kind, macro_path, error
+            ),
+            Error::MacroFailed {
+                path,
+                kind,
+                macro_path,
+                error,
+                ..
+            } => {
+                fmt_path(f, path)?;
+                write!(f, "{} macro `{}` failed: {}", kind, macro_path, error)
+            }
+            Error::NotInvoked(macros) => {
+                f.write_str("these registered macros were never invoked:")?;
+                for (i, (kind, path)) in macros.iter().enumerate() {
+                    let separator = if i == 0 { " " } else { ", " };
+                    write!(f, "{}{} `{}`", separator, kind, path)?;
+                }
+                Ok(())
+            }
+            Error::ExpansionPanicked { panics, .. } => {
+                write!(f, "{} macro invocation(s) panicked", panics.len())?;
+                for panic in panics.iter() {
+                    f.write_str("\n  ")?;
+                    fmt_path(f, &panic.path)?;
+                    panic.fmt(f)?;
+                }
+                Ok(())
+            }
+            #[cfg(feature = "fs")]
+            Error::ExportMismatch {
+                path,
+                unregistered,
+                unexported,
+                helpers,
+            } => {
+                write!(
+                    f,
+                    "the registered macros don't match the ones exported by {}",
+                    path.display()
+                )?;
+                for (kind, name) in unregistered.iter() {
+                    write!(f, "\n  exported but not registered: {} `{}`", kind, name)?;
+                }
+                for (kind, path) in unexported.iter() {
+                    write!(f, "\n  registered but not exported: {} `{}`", kind, path)?;
+                }
+                for (derive, helper) in helpers.iter() {
+                    write!(
+                        f,
+                        "\n  helper attribute `{}` of `{}` isn't declared in both",
+                        helper, derive
+                    )?;
+                }
+                Ok(())
+            }
+            Error::SnapshotMismatch(mismatches) => {
+                write!(
+                    f,
+                    "{} expansion(s) didn't match their snapshots; set UPDATE_EXPANSIONS=1 to update them",
+                    mismatches.len()
+                )?;
+                for mismatch in mismatches.iter() {
+                    write!(f, "\n  {}", mismatch)?;
+                }
+                Ok(())
+            }
+            #[cfg(feature = "verify-compile")]
+            Error::CompileFailed { path, stderr } => {
+                fmt_path(f, path)?;
+                write!(f, "the expansions don't compile:\n{}", stderr)
+            }
+            #[cfg(feature = "cargo_metadata")]
+            Error::MetadataFailed(error) => {
+                write!(f, "failed to get the package metadata: {}", error)
+            }
         }
     }
 }
@@ -384,52 +851,60 @@ impl std::fmt::Display for Error {
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
-            Error::IoError(e) => e.source(),
-            Error::ParseError(e) => e.source(),
+            Error::IoError { error, .. } => Some(error),
+            Error::ParseError { error, .. }
+            | Error::InvalidMacroPath { error, .. }
+            | Error::MacroFailed { error, .. } => Some(error),
+            Error::NotInvoked(_) | Error::ExpansionPanicked { .. } | Error::SnapshotMismatch(_) => {
+                None
+            }
+            #[cfg(feature = "fs")]
+            Error::ExportMismatch { .. } => None,
+            #[cfg(feature = "verify-compile")]
+            Error::CompileFailed { .. } => None,
+            #[cfg(feature = "cargo_metadata")]
+            Error::MetadataFailed(error) => Some(error),
         }
     }
 }
 
-#[cfg(test)]
+// Runs each example's tests under a coverage tool, the way a procedural macro crate would use this
+// one. The tool runs as its own process, so it only has to be installed for these tests.
+#[cfg(all(test, feature = "coverage-self-test"))]
 mod tests {
-    extern crate cargo_tarpaulin;
-    use self::cargo_tarpaulin::config::Config;
-    use self::cargo_tarpaulin::launch_tarpaulin;
-    use std::panic;
-    use std::{env, time};
+    use std::{env, path::Path, process::Command};
+
+    // The command that's run in each example's directory, unless `RUNTIME_MACROS_COVERAGE_COMMAND`
+    // gives another one, like `cargo llvm-cov`.
+    const DEFAULT_COMMAND: &str = "cargo tarpaulin --timeout 60";
 
     #[test]
     fn proc_macro_coverage() {
-        // All the tests are in this one function so they'll run sequentially. Something about how
-        // Tarpaulin works seems to dislike having two instances running in parallel.
+        // All the examples are run in this one function so they'll run sequentially. Something
+        // about how Tarpaulin works seems to dislike having two instances running in parallel.
+        let command = env::var("RUNTIME_MACROS_COVERAGE_COMMAND")
+            .unwrap_or_else(|_| DEFAULT_COMMAND.to_owned());
+        let mut words = command.split_whitespace();
+        let program = words.next().expect("the coverage command is empty");
+        let args: Vec<&str> = words.collect();
 
-        {
-            // Function-like
-            let mut config = Config::default();
-            let test_dir = env::current_dir()
-                .unwrap()
+        // Function-like and attribute-like
+        for example in ["custom_assert", "reference_counting"] {
+            let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
                 .join("examples")
-                .join("custom_assert");
-            config.set_manifest(test_dir.join("Cargo.toml"));
-            config.test_timeout = time::Duration::from_secs(60);
-            let (_trace_map, return_code) = launch_tarpaulin(&config, &None).unwrap();
-            assert_eq!(return_code, 0);
-        }
-
-        {
-            // Attribute-like
-            let mut config = Config::default();
-            let test_dir = env::current_dir()
-                .unwrap()
-                .join("examples")
-                .join("reference_counting");
-            config.set_manifest(test_dir.join("Cargo.toml"));
-            config.test_timeout = time::Duration::from_secs(60);
-            let (_trace_map, return_code) = match launch_tarpaulin(&config, &None) {
-                Ok(ret) => ret,
-                Err(err) => panic!("{}", err),
-            };
-            assert_eq!(return_code, 0);
+                .join(example);
+            let status = Command::new(program)
+                .args(&args)
+                .current_dir(&dir)
+                .status()
+                .unwrap_or_else(|e| panic!("couldn't run `{}`: {}", command, e));
+            assert!(
+                status.success(),
+                "`{}` failed in {} ({})",
+                command,
+                dir.display(),
+                status
+            );
         }
     }
 }
@@ -0,0 +1,245 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+
+//! Rules for deciding whether a macro path in the source code refers to a registered macro.
+
+use {
+    report::MacroKind,
+    std::{fmt, rc::Rc},
+};
+
+/// A shared predicate that decides whether a path in the source code (the second argument) refers
+/// to the registered path (the first argument).
+pub type MatchFn = Rc<dyn Fn(&syn::Path, &syn::Path) -> bool>;
+
+/// How the path of a macro invocation in the source code is compared with the path it was
+/// registered under.
+///
+/// Since this crate only sees Rust's syntax, it can't resolve paths, so it can't know whether
+/// `mycrate::Pod` and `Pod` refer to the same macro. The more lenient modes make it more likely
+/// that every invocation is found, at the risk of expanding an unrelated macro that happens to
+/// have the same name.
+#[derive(Clone, Default)]
+#[non_exhaustive]
+pub enum MatchMode {
+    /// The paths must be exactly equal. With a registration of `"Pod"`, `Pod` matches but
+    /// `mycrate::Pod` doesn't. This is the default.
+    #[default]
+    Exact,
+    /// Only the last segments of the paths must be equal. With a registration of `"Pod"` or
+    /// `"othercrate::Pod"`, both `Pod` and `mycrate::Pod` match.
+    LastSegment,
+    /// The registered path must make up the end of the path in the source code. With a
+    /// registration of `"bytes::Pod"`, both `bytes::Pod` and `mycrate::bytes::Pod` match, but
+    /// `Pod` doesn't. A leading `::` on either path is ignored.
+    Suffix,
+    /// The paths match if the given predicate returns true. Its arguments are the registered path
+    /// and the path in the source code, in that order. See also [`custom`].
+    ///
+    /// [`custom`]: #method.custom
+    Custom(MatchFn),
+}
+
+impl fmt::Debug for MatchMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MatchMode::Exact => f.write_str("Exact"),
+            MatchMode::LastSegment => f.write_str("LastSegment"),
+            MatchMode::Suffix => f.write_str("Suffix"),
+            MatchMode::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+impl MatchMode {
+    /// Creates a [`Custom`] mode from the given predicate.
+    ///
+    /// [`Custom`]: #variant.Custom
+    pub fn custom<F>(predicate: F) -> Self
+    where
+        F: Fn(&syn::Path, &syn::Path) -> bool + 'static,
+    {
+        MatchMode::Custom(Rc::new(predicate))
+    }
+
+    /// Returns true if `found` (a path in the source code) refers to the macro that was registered
+    /// as `registered` under this mode.
+    pub fn matches(&self, registered: &syn::Path, found: &syn::Path) -> bool {
+        match *self {
+            MatchMode::Exact => registered == found,
+            MatchMode::LastSegment => match (registered.segments.last(), found.segments.last()) {
+                (Some(registered), Some(found)) => registered == found,
+                _ => false,
+            },
+            MatchMode::Suffix => {
+                let (registered, found) = (&registered.segments, &found.segments);
+                registered.len() <= found.len()
+                    && registered
+                        .iter()
+                        .rev()
+                        .zip(found.iter().rev())
+                        .all(|(registered, found)| registered == found)
+            }
+            MatchMode::Custom(ref predicate) => predicate(registered, found),
+        }
+    }
+}
+
+// The attributes that the compiler handles itself instead of passing to a procedural macro.
+const BUILT_IN_ATTRIBUTES: &[&str] = &[
+    "allow",
+    "automatically_derived",
+    "bench",
+    "cfg",
+    "cfg_attr",
+    "cold",
+    "collapse_debuginfo",
+    "crate_name",
+    "crate_type",
+    "debugger_visualizer",
+    "deny",
+    "deprecated",
+    "derive",
+    "doc",
+    "expect",
+    "export_name",
+    "forbid",
+    "global_allocator",
+    "ignore",
+    "inline",
+    "instruction_set",
+    "link",
+    "link_name",
+    "link_ordinal",
+    "link_section",
+    "macro_export",
+    "macro_use",
+    "must_use",
+    "no_builtins",
+    "no_implicit_prelude",
+    "no_link",
+    "no_main",
+    "no_mangle",
+    "no_std",
+    "non_exhaustive",
+    "panic_handler",
+    "path",
+    "proc_macro",
+    "proc_macro_attribute",
+    "proc_macro_derive",
+    "recursion_limit",
+    "repr",
+    "should_panic",
+    "target_feature",
+    "test",
+    "track_caller",
+    "type_length_limit",
+    "used",
+    "warn",
+    "windows_subsystem",
+];
+
+// The tools whose attributes, like `#[rustfmt::skip]`, are ignored by the compiler.
+const TOOLS: &[&str] = &[
+    "clippy",
+    "diagnostic",
+    "rust_analyzer",
+    "rustdoc",
+    "rustfmt",
+];
+
+// Returns true if the path refers to something the compiler handles itself, such as `#[inline]`
+// or `macro_rules!`, rather than to a macro that could be registered.
+pub(crate) fn is_built_in(kind: MacroKind, path: &syn::Path) -> bool {
+    let first = match path.segments.first() {
+        Some(segment) if path.leading_colon.is_none() => segment.ident.to_string(),
+        _ => return false,
+    };
+    match kind {
+        MacroKind::Functionlike => path.segments.len() == 1 && first == "macro_rules",
+        MacroKind::Derive => false,
+        MacroKind::Attribute if path.segments.len() == 1 => {
+            BUILT_IN_ATTRIBUTES.contains(&&first[..])
+        }
+        MacroKind::Attribute => TOOLS.contains(&&first[..]),
+    }
+}
+
+// Formats a path the way it would normally be written, without the spaces that `ToTokens` adds.
+pub(crate) fn path_to_string(path: &syn::Path) -> String {
+    let segments = path
+        .segments
+        .iter()
+        .map(|segment| segment.ident.to_string())
+        .collect::<Vec<_>>()
+        .join("::");
+    if path.leading_colon.is_some() {
+        format!("::{}", segments)
+    } else {
+        segments
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(mode: MatchMode, registered: &str, found: &str) -> bool {
+        mode.matches(
+            &syn::parse_str(registered).unwrap(),
+            &syn::parse_str(found).unwrap(),
+        )
+    }
+
+    #[test]
+    fn compares_paths() {
+        assert!(matches(MatchMode::Exact, "Pod", "Pod"));
+        assert!(!matches(MatchMode::Exact, "Pod", "mycrate::Pod"));
+        assert!(!matches(MatchMode::Exact, "Pod", "::Pod"));
+
+        assert!(matches(MatchMode::LastSegment, "Pod", "mycrate::Pod"));
+        assert!(matches(
+            MatchMode::LastSegment,
+            "other::Pod",
+            "mycrate::Pod"
+        ));
+        assert!(!matches(MatchMode::LastSegment, "Pod", "mycrate::Zeroable"));
+
+        assert!(matches(
+            MatchMode::Suffix,
+            "bytes::Pod",
+            "::mycrate::bytes::Pod"
+        ));
+        assert!(matches(MatchMode::Suffix, "::bytes::Pod", "bytes::Pod"));
+        assert!(!matches(MatchMode::Suffix, "bytes::Pod", "Pod"));
+        assert!(!matches(MatchMode::Suffix, "bytes::Pod", "other::Pod"));
+
+        let first_segment = MatchMode::custom(|registered, found| {
+            registered.segments.first() == found.segments.first()
+        });
+        assert!(matches(first_segment.clone(), "foo", "foo::bar"));
+        assert!(!matches(first_segment, "foo", "bar::foo"));
+    }
+
+    #[test]
+    fn recognizes_built_in_paths() {
+        let built_in = |kind, path| is_built_in(kind, &syn::parse_str(path).unwrap());
+        assert!(built_in(MacroKind::Attribute, "inline"));
+        assert!(built_in(MacroKind::Attribute, "rustfmt::skip"));
+        assert!(!built_in(MacroKind::Attribute, "::inline"));
+        assert!(!built_in(MacroKind::Attribute, "tokio::test"));
+        assert!(built_in(MacroKind::Functionlike, "macro_rules"));
+        assert!(!built_in(MacroKind::Functionlike, "println"));
+        assert!(!built_in(MacroKind::Derive, "Debug"));
+    }
+}
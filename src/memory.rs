@@ -0,0 +1,171 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Measuring how much memory each macro function allocates.
+
+#[cfg(feature = "track-memory")]
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// A global allocator that keeps track of how much memory each thread has allocated, so the
+/// peak memory use of each expansion can be recorded in its [`ExpansionRecord::peak_memory`].
+/// This requires the `track-memory` feature.
+///
+/// It wraps another allocator, usually [`System`], and has to be installed as the global
+/// allocator of the test binary that runs the expansions:
+///
+/// ```
+/// # extern crate runtime_macros;
+/// use runtime_macros::TrackingAllocator;
+/// use std::alloc::System;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: TrackingAllocator = TrackingAllocator::new(System);
+/// # fn main() {}
+/// ```
+///
+/// Only the memory allocated on the thread that calls the macro function is counted, so memory
+/// allocated by any threads it starts isn't. Keeping count costs a little time on every
+/// allocation, which also shows up in the [`ExpansionRecord::duration`]s.
+///
+/// [`ExpansionRecord::peak_memory`]: struct.ExpansionRecord.html#structfield.peak_memory
+/// [`ExpansionRecord::duration`]: struct.ExpansionRecord.html#structfield.duration
+/// [`System`]: https://doc.rust-lang.org/std/alloc/struct.System.html
+#[cfg(feature = "track-memory")]
+#[derive(Debug, Default)]
+pub struct TrackingAllocator<A = System> {
+    inner: A,
+}
+
+#[cfg(feature = "track-memory")]
+impl<A> TrackingAllocator<A> {
+    /// Creates an allocator that gets its memory from `inner`.
+    pub const fn new(inner: A) -> Self {
+        TrackingAllocator { inner }
+    }
+}
+
+// Whether a `TrackingAllocator` is the global allocator. It must be if it's ever been called.
+#[cfg(feature = "track-memory")]
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+// The number of bytes allocated on this thread and not yet freed, and the most there have been
+// since the current measurement started. Memory freed on a different thread from the one that
+// allocated it makes these drift, so they can go below zero.
+#[cfg(feature = "track-memory")]
+thread_local! {
+    static CURRENT: Cell<isize> = const { Cell::new(0) };
+    static PEAK: Cell<isize> = const { Cell::new(0) };
+}
+
+// Adds to the count of bytes allocated on this thread. This doesn't allocate, and it does nothing
+// while the thread is being torn down.
+#[cfg(feature = "track-memory")]
+fn count(change: isize) {
+    let _ = CURRENT.try_with(|current| {
+        let now = current.get().wrapping_add(change);
+        current.set(now);
+        let _ = PEAK.try_with(|peak| {
+            if now > peak.get() {
+                peak.set(now);
+            }
+        });
+    });
+}
+
+#[cfg(feature = "track-memory")]
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TrackingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        INSTALLED.store(true, Ordering::Relaxed);
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            count(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        INSTALLED.store(true, Ordering::Relaxed);
+        let ptr = self.inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            count(layout.size() as isize);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        count(-(layout.size() as isize));
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            count(new_size as isize - layout.size() as isize);
+        }
+        new_ptr
+    }
+}
+
+// Calls the function and returns the most memory that it had allocated at once, in bytes, if a
+// `TrackingAllocator` is the global allocator.
+#[cfg(feature = "track-memory")]
+pub(crate) fn track<F: FnOnce() -> R, R>(f: F) -> (R, Option<usize>) {
+    let start = CURRENT.with(Cell::get);
+    // Measurements can be nested, so the outer one's peak has to be kept.
+    let outer_peak = PEAK.with(|peak| peak.replace(start));
+    let result = f();
+    let peak = PEAK.with(|peak| {
+        let inner_peak = peak.get();
+        peak.set(inner_peak.max(outer_peak));
+        inner_peak
+    });
+    if INSTALLED.load(Ordering::Relaxed) {
+        (result, Some(peak.saturating_sub(start).max(0) as usize))
+    } else {
+        (result, None)
+    }
+}
+
+// Without the feature, there's no way to measure anything.
+#[cfg(not(feature = "track-memory"))]
+pub(crate) fn track<F: FnOnce() -> R, R>(f: F) -> (R, Option<usize>) {
+    (f(), None)
+}
+
+#[cfg(all(test, feature = "track-memory"))]
+mod tests {
+    use {super::*, MacroRegistry};
+
+    #[global_allocator]
+    static ALLOCATOR: TrackingAllocator = TrackingAllocator::new(System);
+
+    #[test]
+    fn measures_peak_memory() {
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("small", |ts| ts)
+            .functionlike("big", |ts| {
+                drop(vec![0u8; 1 << 20]);
+                ts
+            });
+        let records = registry
+            .expand_source("small!(); big!();", None)
+            .unwrap()
+            .records;
+        assert!(records[0].peak_memory.unwrap() < 1 << 20);
+        assert!(records[1].peak_memory.unwrap() >= 1 << 20);
+    }
+}
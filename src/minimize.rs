@@ -0,0 +1,141 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Shrinking the input of an invocation that panicked, to find a smaller one that still panics.
+
+use proc_macro2::{Group, TokenStream, TokenTree};
+
+// The most times the macro function is called again while shrinking one input.
+pub(crate) const ATTEMPT_LIMIT: usize = 1000;
+
+// Returns the smallest input found for which `fails` returns true, the way delta debugging does:
+// runs of token trees are removed, shorter runs are tried when none of them can be, and then the
+// contents of each group are shrunk the same way. That's repeated until nothing else can be
+// removed or `fails` has been called `ATTEMPT_LIMIT` times. `input` itself is assumed to fail.
+pub(crate) fn minimize<F>(input: TokenStream, mut fails: F) -> TokenStream
+where
+    F: FnMut(&TokenStream) -> bool,
+{
+    let mut attempts = 0;
+    let mut fails = |candidate: &TokenStream| {
+        if attempts == ATTEMPT_LIMIT {
+            return false;
+        }
+        attempts += 1;
+        fails(candidate)
+    };
+    let mut trees: Vec<TokenTree> = input.into_iter().collect();
+    loop {
+        let before = count(&trees);
+        trees = shrink(trees, &|trees| trees.into_iter().collect(), &mut fails);
+        if count(&trees) == before {
+            return trees.into_iter().collect();
+        }
+    }
+}
+
+// Shrinks one level of the input. `rebuild` turns that level back into the whole input.
+fn shrink(
+    mut trees: Vec<TokenTree>,
+    rebuild: &dyn Fn(Vec<TokenTree>) -> TokenStream,
+    fails: &mut dyn FnMut(&TokenStream) -> bool,
+) -> Vec<TokenTree> {
+    let mut run = trees.len();
+    while run > 0 {
+        let mut removed = false;
+        let mut start = 0;
+        while start < trees.len() {
+            let end = trees.len().min(start + run);
+            let mut candidate = trees.clone();
+            candidate.drain(start..end);
+            if fails(&rebuild(candidate.clone())) {
+                trees = candidate;
+                removed = true;
+            } else {
+                start = end;
+            }
+        }
+        if !removed {
+            run /= 2;
+        }
+    }
+
+    for i in 0..trees.len() {
+        let group = match trees[i] {
+            TokenTree::Group(ref group) => group.clone(),
+            _ => continue,
+        };
+        let outer = trees.clone();
+        let rebuild_group = |inner| {
+            let mut candidate = outer.clone();
+            candidate[i] = with_stream(&group, inner);
+            rebuild(candidate)
+        };
+        let inner = shrink(group.stream().into_iter().collect(), &rebuild_group, fails);
+        trees[i] = with_stream(&group, inner);
+    }
+    trees
+}
+
+// Returns a group like the given one, but with different contents.
+fn with_stream(group: &Group, trees: Vec<TokenTree>) -> TokenTree {
+    let mut new = Group::new(group.delimiter(), trees.into_iter().collect());
+    new.set_span(group.span());
+    TokenTree::Group(new)
+}
+
+// Counts the token trees at every level.
+fn count(trees: &[TokenTree]) -> usize {
+    trees
+        .iter()
+        .map(|tt| match *tt {
+            TokenTree::Group(ref group) => {
+                1 + count(&group.stream().into_iter().collect::<Vec<_>>())
+            }
+            _ => 1,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains_bad(tokens: &TokenStream) -> bool {
+        tokens.clone().into_iter().any(|tt| match tt {
+            TokenTree::Ident(ident) => ident == "bad",
+            TokenTree::Group(group) => contains_bad(&group.stream()),
+            _ => false,
+        })
+    }
+
+    #[test]
+    fn removes_everything_that_doesnt_matter() {
+        let input: TokenStream = "struct S { a: u8, b: [bad; 3], c: () } fn f() {}"
+            .parse()
+            .unwrap();
+        let minimized = minimize(input, contains_bad);
+        assert_eq!(minimized.to_string(), "{ [bad] }");
+    }
+
+    #[test]
+    fn stops_after_the_attempt_limit() {
+        let input: TokenStream = "x ".repeat(2000).parse().unwrap();
+        let mut calls = 0;
+        let minimized = minimize(input.clone(), |_| {
+            calls += 1;
+            false
+        });
+        assert_eq!(minimized.to_string(), input.to_string());
+        assert_eq!(calls, ATTEMPT_LIMIT);
+    }
+}
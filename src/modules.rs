@@ -0,0 +1,370 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Finding the other files that a file pulls in, such as the modules declared with `mod foo;`.
+
+use {
+    cfg::{self, CfgSet},
+    compat,
+    proc_macro2::LineColumn,
+    session::ParsedSource,
+    std::{
+        collections::BTreeMap,
+        path::{Component, Path, PathBuf},
+    },
+    syn::{ext::IdentExt, visit::Visit},
+};
+
+// Returns the path of every file declared as a module in the given file, in the order they're
+// declared, along with whether each one is a "mod-rs" file whose own modules go in the same
+// directory as it (like `mod.rs` or a crate root) rather than in a directory named after it.
+// Modules whose `#[cfg]`s are false according to `cfgs` are left out.
+pub(crate) fn declared_modules(
+    source: &ParsedSource,
+    mod_rs: bool,
+    cfgs: Option<&CfgSet>,
+) -> Vec<(PathBuf, bool)> {
+    declarations(source, mod_rs, cfgs)
+        .into_iter()
+        .map(|(_, path, mod_rs)| (path, mod_rs))
+        .collect()
+}
+
+// Returns the path of the file declared as a module by each `mod foo;` in the given file, by where
+// the module's name starts, as in `declared_modules`.
+pub(crate) fn module_files(
+    source: &ParsedSource,
+    mod_rs: bool,
+    cfgs: Option<&CfgSet>,
+) -> BTreeMap<LineColumn, PathBuf> {
+    declarations(source, mod_rs, cfgs)
+        .into_iter()
+        .map(|(start, path, _)| (start, path))
+        .collect()
+}
+
+// Returns every module declaration in the file, with where the module's name starts, the path of
+// its file, and whether that's a "mod-rs" file.
+fn declarations(
+    source: &ParsedSource,
+    mod_rs: bool,
+    cfgs: Option<&CfgSet>,
+) -> Vec<(LineColumn, PathBuf, bool)> {
+    let path = match source.path() {
+        Some(path) => path,
+        None => return Vec::new(),
+    };
+    let parent = path.parent().unwrap_or_else(|| Path::new(""));
+    let dir = match path.file_stem() {
+        Some(stem) if !mod_rs => parent.join(stem),
+        _ => parent.to_owned(),
+    };
+
+    let mut modules = Vec::new();
+    collect(&source.ast().items, &dir, parent, cfgs, &mut modules);
+    modules
+}
+
+// Finds the module declarations among the given items, including those in inline modules. `dir`
+// is where the files for the declared modules go by default, and `path_base` is what `#[path]`
+// attributes are relative to.
+fn collect(
+    items: &[syn::Item],
+    dir: &Path,
+    path_base: &Path,
+    cfgs: Option<&CfgSet>,
+    modules: &mut Vec<(LineColumn, PathBuf, bool)>,
+) {
+    for item in items.iter() {
+        let item_mod = match *item {
+            syn::Item::Mod(ref item_mod) => item_mod,
+            _ => continue,
+        };
+        if cfgs.is_some_and(|cfgs| cfg::is_configured_out(&item_mod.attrs, cfgs)) {
+            continue;
+        }
+
+        let name = item_mod.ident.unraw().to_string();
+        let start = item_mod.ident.span().start();
+        let path_attr = path_attr(&item_mod.attrs);
+        match item_mod.content {
+            Some((_, ref items)) => {
+                let dir = match path_attr {
+                    Some(path) => path_base.join(path),
+                    None => dir.join(&name),
+                };
+                collect(items, &dir, &dir, cfgs, modules);
+            }
+            None => match path_attr {
+                // A file loaded through a `#[path]` attribute acts like a `mod.rs` file.
+                Some(path) => modules.push((start, normalize(&path_base.join(path)), true)),
+                None => {
+                    let file = dir.join(format!("{}.rs", name));
+                    if file.is_file() {
+                        modules.push((start, file, false));
+                    } else {
+                        modules.push((start, dir.join(&name).join("mod.rs"), true));
+                    }
+                }
+            },
+        }
+    }
+}
+
+// Returns the path of every Rust file pulled in by a call to `include!` or `include_str!` with a
+// string literal in the given file, in the order they appear. Files included with `include_str!`
+// are only returned if their names end in `.rs`, since they might not be Rust code otherwise.
+pub(crate) fn included_files(source: &ParsedSource) -> Vec<PathBuf> {
+    let dir = match source.path() {
+        Some(path) => path.parent().unwrap_or_else(|| Path::new("")),
+        None => return Vec::new(),
+    };
+    let mut finder = IncludeFinder { paths: Vec::new() };
+    finder.visit_file(source.ast());
+    finder
+        .paths
+        .into_iter()
+        .map(|path| normalize(&dir.join(path)))
+        .collect()
+}
+
+struct IncludeFinder {
+    paths: Vec<String>,
+}
+
+impl<'ast> Visit<'ast> for IncludeFinder {
+    fn visit_macro(&mut self, mac: &'ast syn::Macro) {
+        let is_str = if mac.path.is_ident("include") {
+            false
+        } else if mac.path.is_ident("include_str") {
+            true
+        } else {
+            return;
+        };
+        if let Ok(path) = syn::parse2::<syn::LitStr>(mac.tokens.clone()) {
+            let path = path.value();
+            if !is_str || path.ends_with(".rs") {
+                self.paths.push(path);
+            }
+        }
+    }
+}
+
+// Removes the `.` and `..` components from a path without looking at the file system, so that two
+// paths to the same file usually compare equal.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                _ => normalized.push(component),
+            },
+            _ => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+// Returns the value of the `#[path = "..."]` attribute, if there is one.
+fn path_attr(attrs: &[syn::Attribute]) -> Option<String> {
+    attrs
+        .iter()
+        .find_map(|attr| compat::attr_str(attr, "path"))
+        .map(|path| path.value())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        proc_macro2::TokenStream,
+        std::{fs, process},
+        Expander, Session,
+    };
+
+    #[test]
+    fn resolves_module_paths() {
+        let content = "
+            mod a;
+            #[cfg(nope)]
+            mod b;
+            #[path = \"other/c.rs\"]
+            mod c;
+            mod inline {
+                mod d;
+                #[path = \"e.rs\"]
+                mod e;
+            }
+        "
+        .to_owned();
+        let root = ParsedSource::parse(content.clone(), Some(Path::new("tests/main.rs"))).unwrap();
+        let paths = |source: &ParsedSource, mod_rs, cfgs| {
+            declared_modules(source, mod_rs, cfgs)
+                .into_iter()
+                .map(|(path, mod_rs)| (path.to_string_lossy().replace('\\', "/"), mod_rs))
+                .collect::<Vec<_>>()
+        };
+        let cfgs = CfgSet::new();
+        assert_eq!(
+            paths(&root, true, Some(&cfgs)),
+            [
+                ("tests/a/mod.rs".to_owned(), true),
+                ("tests/other/c.rs".to_owned(), true),
+                ("tests/inline/d/mod.rs".to_owned(), true),
+                ("tests/inline/e.rs".to_owned(), true),
+            ]
+        );
+
+        let nested = ParsedSource::parse(content, Some(Path::new("tests/helpers.rs"))).unwrap();
+        assert_eq!(
+            paths(&nested, false, None),
+            [
+                ("tests/helpers/a/mod.rs".to_owned(), true),
+                ("tests/helpers/b/mod.rs".to_owned(), true),
+                ("tests/other/c.rs".to_owned(), true),
+                ("tests/helpers/inline/d/mod.rs".to_owned(), true),
+                ("tests/helpers/inline/e.rs".to_owned(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn follows_module_declarations() {
+        let dir =
+            std::env::temp_dir().join(format!("runtime-macros-modules-test-{}", process::id()));
+        fs::create_dir_all(dir.join("helpers")).unwrap();
+        fs::write(dir.join("main.rs"), "mod helpers; foo!(1);").unwrap();
+        fs::write(dir.join("helpers.rs"), "mod inner; mod main; foo!(2);").unwrap();
+        fs::write(dir.join("helpers/inner.rs"), "foo!(3);").unwrap();
+        fs::write(
+            dir.join("helpers/main.rs"),
+            "#[path = \"../main.rs\"] mod again;",
+        )
+        .unwrap();
+
+        let report = Expander::new()
+            .functionlike("foo", |ts: TokenStream| ts)
+            .file(dir.join("main.rs"))
+            .follow_modules(true)
+            .run()
+            .unwrap();
+        let files: Vec<_> = report
+            .files
+            .iter()
+            .map(|file| file.path.strip_prefix(&dir).unwrap().to_owned())
+            .collect();
+        let expected = [
+            Path::new("main.rs"),
+            Path::new("helpers.rs"),
+            Path::new("helpers/inner.rs"),
+            Path::new("helpers/main.rs"),
+        ];
+        assert_eq!(files, expected);
+        assert_eq!(report.invocations("foo"), 3);
+
+        let mut session = Session::new();
+        session.file_tree(dir.join("main.rs")).unwrap();
+        assert_eq!(session.sources().len(), 4);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gives_module_contents_to_attributes() {
+        let dir =
+            std::env::temp_dir().join(format!("runtime-macros-mod-attr-test-{}", process::id()));
+        fs::create_dir_all(dir.join("handlers")).unwrap();
+        fs::write(dir.join("main.rs"), "#[routes] mod handlers;").unwrap();
+        fs::write(
+            dir.join("handlers.rs"),
+            "#![allow(dead_code)]\nmod users;\nfn index() {}",
+        )
+        .unwrap();
+        fs::write(dir.join("handlers/users.rs"), "#[routes] fn list() {}").unwrap();
+
+        let expander = || {
+            Expander::new()
+                .attribute("routes", |_: TokenStream, item: TokenStream| item)
+                .file(dir.join("main.rs"))
+        };
+        let report = expander().follow_modules(true).run().unwrap();
+        let inputs: Vec<String> = report
+            .files
+            .iter()
+            .flat_map(|file| file.records.iter())
+            .map(|record| record.input.to_string())
+            .collect();
+        assert_eq!(
+            inputs,
+            [
+                "# [routes] mod handlers { # ! [allow (dead_code)] mod users ; fn index () { } }",
+                "# [routes] fn list () { }",
+            ]
+        );
+
+        let report = expander().run().unwrap();
+        assert_eq!(
+            report.files[0].records[0].input.to_string(),
+            "# [routes] mod handlers ;"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn follows_includes() {
+        let dir =
+            std::env::temp_dir().join(format!("runtime-macros-include-test-{}", process::id()));
+        fs::create_dir_all(dir.join("fixtures")).unwrap();
+        let main = "
+            include!(\"fixtures/items.rs\");
+            fn main() {
+                let _ = include_str!(\"fixtures/case.rs\");
+                let _ = include_str!(\"fixtures/data.txt\");
+                foo!(1);
+            }
+        ";
+        fs::write(dir.join("main.rs"), main).unwrap();
+        fs::write(
+            dir.join("fixtures/items.rs"),
+            "include!(\"../main.rs\"); foo!(2);",
+        )
+        .unwrap();
+        fs::write(dir.join("fixtures/case.rs"), "foo!(3);").unwrap();
+        fs::write(dir.join("fixtures/data.txt"), "not rust").unwrap();
+
+        let report = Expander::new()
+            .functionlike("foo", |ts: TokenStream| ts)
+            .file(dir.join("main.rs"))
+            .follow_includes(true)
+            .run()
+            .unwrap();
+        let files: Vec<_> = report
+            .files
+            .iter()
+            .map(|file| file.path.strip_prefix(&dir).unwrap().to_owned())
+            .collect();
+        let expected = [
+            Path::new("main.rs"),
+            Path::new("fixtures/items.rs"),
+            Path::new("fixtures/case.rs"),
+        ];
+        assert_eq!(files, expected);
+        assert_eq!(report.invocations("foo"), 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Shuffling the order that files and items are scanned in, for testing macros that depend on it.
+
+// Shuffles the slice into an order that only depends on its length and the seed.
+pub(crate) fn shuffle<T>(items: &mut [T], seed: u64) {
+    let mut rng = SplitMix64(seed);
+    for i in (1..items.len()).rev() {
+        let j = rng.below(i as u64 + 1) as usize;
+        items.swap(i, j);
+    }
+}
+
+// Takes the elements of a vector out of the given ranges and puts them back in the range order,
+// after the elements before the first range. The ranges must not overlap, and each must start
+// at or after the prefix.
+pub(crate) fn reorder<T, I>(items: Vec<T>, prefix: usize, ranges: I) -> Vec<T>
+where
+    I: IntoIterator<Item = std::ops::Range<usize>>,
+{
+    let mut slots: Vec<Option<T>> = items.into_iter().map(Some).collect();
+    let mut reordered: Vec<T> = slots[..prefix]
+        .iter_mut()
+        .filter_map(Option::take)
+        .collect();
+    for range in ranges {
+        reordered.extend(slots[range].iter_mut().filter_map(Option::take));
+    }
+    reordered
+}
+
+// A small, fast pseudorandom number generator. It's far from cryptographically secure, but it's
+// good enough for shuffling, and it produces the same numbers on every platform.
+pub(crate) struct SplitMix64(pub(crate) u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // Returns a number less than `bound`, which must be positive. The slight bias toward smaller
+    // numbers doesn't matter for this.
+    pub(crate) fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shuffles_deterministically() {
+        let shuffled = |seed| {
+            let mut items: Vec<usize> = (0..20).collect();
+            shuffle(&mut items, seed);
+            items
+        };
+        assert_eq!(shuffled(1), shuffled(1));
+        assert_ne!(shuffled(1), shuffled(2));
+        let mut sorted = shuffled(1);
+        sorted.sort();
+        assert_eq!(sorted, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reorders_ranges() {
+        let items = vec!['a', 'b', 'c', 'd', 'e', 'f'];
+        assert_eq!(
+            reorder(items, 1, vec![4..6, 1..2, 2..4]),
+            vec!['a', 'e', 'f', 'b', 'c', 'd']
+        );
+    }
+}
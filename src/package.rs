@@ -0,0 +1,309 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Finding the files to scan by asking Cargo about a package or workspace.
+
+use {
+    cargo_metadata::{Metadata, MetadataCommand, Package, TargetKind},
+    modules,
+    report::ExpansionReport,
+    session::Session,
+    std::{
+        collections::HashSet,
+        path::{Path, PathBuf},
+    },
+    Error, Expander, MacroRegistry,
+};
+
+/// Scans every test, example, and bench target of the package with the given manifest, using the
+/// given registry, so the paths of those files don't need to be hard-coded. If the manifest is
+/// the root of a virtual workspace, the targets of every member package are scanned instead.
+///
+/// Each target's root file is scanned along with the modules it declares, as with
+/// [`Expander::follow_modules`]. Use [`Expander::package`] to combine this with other options.
+///
+/// [`Expander::follow_modules`]: struct.Expander.html#method.follow_modules
+/// [`Expander::package`]: struct.Expander.html#method.package
+///
+/// # Example
+///
+/// ```
+/// # use runtime_macros::{emulate_for_package, MacroRegistry};
+/// fn custom_assert_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+///     ts
+/// }
+///
+/// let mut registry = MacroRegistry::new();
+/// registry.functionlike("custom_assert", custom_assert_internal);
+/// # /*
+/// let report = emulate_for_package(concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml"), registry)
+/// # */
+/// # let report = emulate_for_package(
+/// #     concat!(env!("CARGO_MANIFEST_DIR"), "/examples/custom_assert/Cargo.toml"),
+/// #     registry,
+/// # )
+///     .unwrap();
+/// assert!(report.invocations("custom_assert") > 0);
+/// ```
+pub fn emulate_for_package<P: AsRef<Path>>(
+    manifest_path: P,
+    registry: MacroRegistry,
+) -> Result<ExpansionReport, Error> {
+    Expander::from(registry)
+        .package(manifest_path)?
+        .follow_modules(true)
+        .run()
+}
+
+// Returns the root files of the test, example, and bench targets of the package with the given
+// manifest, or of every member of the workspace if it's a virtual manifest.
+pub(crate) fn target_roots(manifest_path: &Path) -> Result<Vec<PathBuf>, Error> {
+    let metadata = metadata(manifest_path)?;
+    let packages: Vec<&Package> = match metadata.root_package() {
+        Some(package) => vec![package],
+        None => metadata.workspace_packages(),
+    };
+    Ok(packages
+        .into_iter()
+        .flat_map(|package| package.targets.iter())
+        .filter(|target| {
+            target.is_kind(TargetKind::Test)
+                || target.is_kind(TargetKind::Example)
+                || target.is_kind(TargetKind::Bench)
+        })
+        .map(|target| target.src_path.clone().into_std_path_buf())
+        .collect())
+}
+
+fn metadata(manifest_path: &Path) -> Result<Metadata, Error> {
+    MetadataCommand::new()
+        .manifest_path(manifest_path)
+        .no_deps()
+        .exec()
+        .map_err(Error::MetadataFailed)
+}
+
+/// Scans the source files of every crate in a Cargo workspace, which is useful when most of the
+/// real invocations of a procedural macro are in the crates that depend on it.
+///
+/// Every target of every member is scanned, including libraries and binaries, along with the
+/// modules that each target's root file declares. The results are split up by crate.
+///
+/// # Example
+///
+/// ```
+/// # use runtime_macros::{MacroRegistry, WorkspaceScanner};
+/// fn custom_assert_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+///     ts
+/// }
+///
+/// let mut registry = MacroRegistry::new();
+/// registry.functionlike("custom_assert", custom_assert_internal);
+/// # /*
+/// let report = WorkspaceScanner::new(concat!(env!("CARGO_MANIFEST_DIR"), "/.."))
+///     .exclude("my_macros")
+/// # */
+/// # let report = WorkspaceScanner::new(concat!(env!("CARGO_MANIFEST_DIR"), "/examples/custom_assert"))
+///     .run(&registry)
+///     .unwrap();
+/// for section in report.crates.iter() {
+///     println!("{}: {} expansions", section.name, section.report.expansions());
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct WorkspaceScanner {
+    root: PathBuf,
+    excluded: Vec<String>,
+}
+
+impl WorkspaceScanner {
+    /// Creates a scanner for the workspace whose root directory, containing its `Cargo.toml`, is
+    /// at the given path.
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        WorkspaceScanner {
+            root: root.as_ref().to_owned(),
+            excluded: Vec::new(),
+        }
+    }
+
+    /// Leaves out the member crate with the given name, such as the procedural macro crate
+    /// itself.
+    pub fn exclude(mut self, name: &str) -> Self {
+        self.excluded.push(name.to_owned());
+        self
+    }
+
+    /// Scans every member crate's files with the given registry.
+    ///
+    /// # Returns
+    ///
+    /// A [`WorkspaceReport`] on success, or an instance of [`Error`] describing the first error
+    /// that occurred. The registry's options, like [`MacroRegistry::require_all_invoked`], apply
+    /// to the workspace as a whole rather than to each crate.
+    ///
+    /// [`WorkspaceReport`]: struct.WorkspaceReport.html
+    /// [`Error`]: enum.Error.html
+    /// [`MacroRegistry::require_all_invoked`]: struct.MacroRegistry.html#method.require_all_invoked
+    pub fn run(&self, registry: &MacroRegistry) -> Result<WorkspaceReport, Error> {
+        let metadata = metadata(&self.root.join("Cargo.toml"))?;
+        let mut sections = Vec::new();
+        let mut files = Vec::new();
+        for package in metadata.workspace_packages() {
+            if self.excluded.contains(&package.name) {
+                continue;
+            }
+
+            let mut session = Session::new();
+            let mut seen = HashSet::new();
+            for target in package.targets.iter() {
+                let root = target.src_path.as_std_path();
+                if seen.insert(modules::normalize(root)) {
+                    session.file_tree(root)?;
+                }
+            }
+            let start = files.len();
+            for source in session.sources() {
+                let path = source.path().map(Path::to_owned).unwrap_or_default();
+                files.push(registry.scan(source)?.into_report(path));
+            }
+            sections.push((
+                package.name.clone(),
+                package.manifest_path.clone().into_std_path_buf(),
+                files.len() - start,
+            ));
+        }
+
+        let mut files = registry.report(files)?.files.into_iter();
+        Ok(WorkspaceReport {
+            crates: sections
+                .into_iter()
+                .map(|(name, manifest_path, len)| CrateReport {
+                    name,
+                    manifest_path,
+                    report: ExpansionReport {
+                        files: files.by_ref().take(len).collect(),
+                    },
+                })
+                .collect(),
+        })
+    }
+}
+
+/// The results of scanning a workspace with a [`WorkspaceScanner`].
+///
+/// [`WorkspaceScanner`]: struct.WorkspaceScanner.html
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct WorkspaceReport {
+    /// One entry for each member crate that was scanned.
+    pub crates: Vec<CrateReport>,
+}
+
+impl WorkspaceReport {
+    /// Returns the total number of macro expansions performed across the whole workspace.
+    pub fn expansions(&self) -> usize {
+        self.crates
+            .iter()
+            .map(|section| section.report.expansions())
+            .sum()
+    }
+
+    /// Returns the number of times the macro registered as `macro_path` was expanded across the
+    /// whole workspace.
+    pub fn invocations(&self, macro_path: &str) -> usize {
+        self.crates
+            .iter()
+            .map(|section| section.report.invocations(macro_path))
+            .sum()
+    }
+}
+
+/// The results of scanning one crate in a workspace.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct CrateReport {
+    /// The name of the crate's package.
+    pub name: String,
+    /// The path of the crate's `Cargo.toml`.
+    pub manifest_path: PathBuf,
+    /// The results for the crate's files.
+    pub report: ExpansionReport,
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{env, fs, process},
+    };
+
+    #[test]
+    fn splits_the_workspace_by_crate() {
+        let root = env::temp_dir().join(format!("runtime-macros-workspace-{}", process::id()));
+        let write = |path: &str, content: &str| {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        };
+        write(
+            "Cargo.toml",
+            "[workspace]\nmembers = [\"a\", \"b\", \"macros\"]\nresolver = \"2\"\n",
+        );
+        for name in ["a", "b", "macros"] {
+            write(
+                &format!("{}/Cargo.toml", name),
+                &format!(
+                    "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+                    name
+                ),
+            );
+        }
+        write("a/src/lib.rs", "mod m;\nfoo!(1);\n");
+        write("a/src/m.rs", "foo!(2);\n");
+        write("b/src/main.rs", "fn main() { foo!(3); }\n");
+        write("macros/src/lib.rs", "foo!(4);\n");
+
+        let mut registry = MacroRegistry::new();
+        registry.functionlike("foo", |ts| ts);
+        let report = WorkspaceScanner::new(&root)
+            .exclude("macros")
+            .run(&registry)
+            .unwrap();
+        let mut crates: Vec<(&str, Vec<PathBuf>)> = report
+            .crates
+            .iter()
+            .map(|section| {
+                let files = section
+                    .report
+                    .files
+                    .iter()
+                    .map(|file| file.path.strip_prefix(&root).unwrap().to_owned())
+                    .collect();
+                (&section.name[..], files)
+            })
+            .collect();
+        crates.sort();
+        assert_eq!(
+            crates,
+            [
+                (
+                    "a",
+                    vec![PathBuf::from("a/src/lib.rs"), PathBuf::from("a/src/m.rs")]
+                ),
+                ("b", vec![PathBuf::from("b/src/main.rs")]),
+            ]
+        );
+        assert_eq!(report.invocations("foo"), 3);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
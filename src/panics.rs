@@ -0,0 +1,275 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+
+//! Catching panics in macro functions along with the messages, locations, and backtraces that the
+//! default panic hook would print.
+
+use {
+    report::PanicLocation,
+    std::{
+        any::Any,
+        backtrace::{Backtrace, BacktraceStatus},
+        cell::{Cell, RefCell},
+        panic::{self, AssertUnwindSafe},
+        sync::{Arc, Mutex, PoisonError},
+    },
+};
+
+/// What to do when a macro function panics.
+///
+/// To find out where a panic happened, a panic hook is installed in front of the process's own hook
+/// while a macro function is being called, and the previous hook is put back once the call
+/// returns. A hook that another thread sets during the call is replaced at that point.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum PanicPolicy {
+    /// Keep expanding the other invocations, and then return an [`Error::ExpansionPanicked`]
+    /// listing every invocation that panicked. This is the default.
+    ///
+    /// [`Error::ExpansionPanicked`]: enum.Error.html#variant.ExpansionPanicked
+    #[default]
+    Fail,
+    /// Keep expanding the other invocations, and record each panic in the report instead of
+    /// returning an error. This is useful for compile-fail style inputs, where a test needs to
+    /// check exactly which invocations panicked.
+    Record,
+    /// Don't catch panics at all, so the first one unwinds straight out of the scan and into the
+    /// test harness, with the backtrace that the default panic hook prints. Nothing is reported
+    /// about it, and the rest of the files aren't scanned. This is meant for debugging a failure,
+    /// or for running the expansion under a debugger that stops at panics. Invocations marked
+    /// with `// runtime-macros: expect-panic` still have their panics caught.
+    Propagate,
+}
+
+// Everything known about a panic that was caught.
+pub(crate) struct CaughtPanic {
+    pub(crate) message: String,
+    pub(crate) location: Option<PanicLocation>,
+    pub(crate) backtrace: Option<String>,
+}
+
+// The details that the panic hook saw, before the payload reached `catch_unwind`.
+struct HookInfo {
+    location: Option<PanicLocation>,
+    backtrace: Option<String>,
+}
+
+thread_local! {
+    // Whether the current thread is inside `catch`.
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+    // Whether panics inside `catch` on the current thread should be kept from the previous hook,
+    // so they aren't printed.
+    static QUIET: Cell<bool> = const { Cell::new(false) };
+    // What the hook saw about the last panic on this thread while it was inside `catch`.
+    static LAST_PANIC: RefCell<Option<HookInfo>> = const { RefCell::new(None) };
+}
+
+type Hook = Box<dyn Fn(&panic::PanicHookInfo) + Sync + Send>;
+
+// How many calls to `catch` are running on any thread, and the hook that was installed before ours
+// if there are any.
+struct Installed {
+    calls: usize,
+    previous: Option<Arc<Hook>>,
+}
+
+static INSTALLED: Mutex<Installed> = Mutex::new(Installed {
+    calls: 0,
+    previous: None,
+});
+
+// Installs a panic hook, unless it's already installed, that records the location and backtrace
+// of any panic that happens inside `catch` and then defers to the hook that was installed before
+// it, unless the panic is quiet. Panics on other threads, or outside `catch`, are passed straight
+// through.
+fn install_hook() {
+    let mut installed = INSTALLED.lock().unwrap_or_else(PoisonError::into_inner);
+    installed.calls += 1;
+    if installed.calls > 1 {
+        return;
+    }
+    let previous = Arc::new(panic::take_hook());
+    installed.previous = Some(Arc::clone(&previous));
+    panic::set_hook(Box::new(move |info| {
+        if CATCHING.with(Cell::get) {
+            let location = info.location().map(|location| PanicLocation {
+                file: location.file().to_owned(),
+                line: location.line(),
+                column: location.column(),
+            });
+            let backtrace = Backtrace::capture();
+            let backtrace = match backtrace.status() {
+                BacktraceStatus::Captured => Some(backtrace.to_string()),
+                _ => None,
+            };
+            LAST_PANIC.with(|last| {
+                *last.borrow_mut() = Some(HookInfo {
+                    location,
+                    backtrace,
+                })
+            });
+            if QUIET.with(Cell::get) {
+                return;
+            }
+        }
+        previous(info);
+    }));
+}
+
+// Puts back the hook that was installed before ours once the last call to `catch` has returned, so
+// a hook that's set between scans is the one that's used in the next scan, and nothing is left
+// behind. A hook that another thread sets while a call is running is replaced.
+fn uninstall_hook() {
+    let mut installed = INSTALLED.lock().unwrap_or_else(PoisonError::into_inner);
+    installed.calls -= 1;
+    if installed.calls > 0 {
+        return;
+    }
+    drop(panic::take_hook());
+    if let Some(previous) = installed.previous.take() {
+        match Arc::try_unwrap(previous) {
+            Ok(previous) => panic::set_hook(previous),
+            Err(previous) => panic::set_hook(Box::new(move |info| previous(info))),
+        }
+    }
+}
+
+// Calls the given function, catching any panic. The backtrace is only captured if it's enabled by
+// the `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE` environment variable.
+pub(crate) fn catch<F, R>(f: F) -> Result<R, CaughtPanic>
+where
+    F: FnOnce() -> R,
+{
+    install_hook();
+    let was_catching = CATCHING.with(|catching| catching.replace(true));
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    CATCHING.with(|catching| catching.set(was_catching));
+    uninstall_hook();
+
+    result.map_err(|payload| {
+        let info = LAST_PANIC.with(|last| last.borrow_mut().take());
+        let (location, backtrace) = match info {
+            Some(info) => (info.location, info.backtrace),
+            None => (None, None),
+        };
+        CaughtPanic {
+            message: message(&*payload),
+            location,
+            backtrace,
+        }
+    })
+}
+
+// Does the same thing as `catch`, but the panic isn't printed: the hook that's installed around the
+// call doesn't pass it on to the previous hook, which is put back once the call returns, as usual.
+pub(crate) fn catch_quietly<F, R>(f: F) -> Result<R, CaughtPanic>
+where
+    F: FnOnce() -> R,
+{
+    let was_quiet = QUIET.with(|quiet| quiet.replace(true));
+    let result = catch(f);
+    QUIET.with(|quiet| quiet.set(was_quiet));
+    result
+}
+
+// Extracts the message from a panic's payload, which is usually a string.
+fn message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "Box<dyn Any>".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        sandbox,
+        std::{
+            env,
+            process::Command,
+            sync::atomic::{AtomicUsize, Ordering},
+        },
+    };
+
+    #[test]
+    fn captures_message_and_location() {
+        let line = line!() + 1;
+        let caught = match catch(|| panic!("expected {}", "panic")) {
+            Ok(()) => unreachable!(),
+            Err(caught) => caught,
+        };
+        assert_eq!(caught.message, "expected panic");
+        let location = caught.location.unwrap();
+        assert!(location.file.ends_with("panics.rs"));
+        assert_eq!(location.line, line);
+
+        assert_eq!(catch(|| 5).ok(), Some(5));
+    }
+
+    #[test]
+    fn restores_the_previous_hook() {
+        // The hook is global, so this only runs in a child process where nothing else panics.
+        if env::var_os("RUNTIME_MACROS_HOOK_TEST").is_none() {
+            let output = sandbox::locked(|| {
+                Command::new(env::current_exe().unwrap())
+                    .args(["panics::tests::restores_the_previous_hook", "--exact"])
+                    .env("RUNTIME_MACROS_HOOK_TEST", "1")
+                    .output()
+            })
+            .unwrap();
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stdout)
+            );
+            return;
+        }
+
+        // A hook that's set after a scan is still used in the next one.
+        catch(|| panic!("before")).err().unwrap();
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        panic::set_hook(Box::new(|_| {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+        }));
+        let caught = match catch(|| panic!("inside")) {
+            Ok(()) => unreachable!(),
+            Err(caught) => caught,
+        };
+        assert!(caught.location.is_some());
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+        panic::catch_unwind(|| panic!("outside")).unwrap_err();
+        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+
+        // A quiet panic is kept from the previous hook, but only during the call.
+        catch_quietly(|| panic!("quiet")).err().unwrap();
+        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+        panic::catch_unwind(|| panic!("outside")).unwrap_err();
+        assert_eq!(CALLS.load(Ordering::SeqCst), 3);
+        let _ = panic::take_hook();
+    }
+
+    #[test]
+    fn catches_quietly() {
+        let caught = match catch_quietly(|| panic!("quiet panic")) {
+            Ok(()) => unreachable!(),
+            Err(caught) => caught,
+        };
+        assert_eq!(caught.message, "quiet panic");
+        assert!(caught.location.is_some());
+        assert!(!QUIET.with(Cell::get));
+    }
+}
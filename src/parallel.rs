@@ -0,0 +1,531 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+
+//! Scanning many files in parallel with rayon.
+//!
+//! Most of the types involved, including the registered functions and the token streams in the
+//! report, can't be sent between threads. So each worker thread builds its own registry, and the
+//! results are converted to plain data before they're sent back and reassembled.
+
+use {
+    proc_macro2::{LineColumn, Span, TokenStream},
+    rayon::prelude::*,
+    report::{
+        CallSite, ExpansionOutcome, ExpansionPanic, ExpansionRecord, ExpansionReport,
+        ExpansionWarning, FileReport, InvocationInfo, MacroKind, MacroPosition, NearMiss,
+        PanicLocation,
+    },
+    session,
+    std::{
+        collections::BTreeMap,
+        io,
+        path::{Path, PathBuf},
+        time::Duration,
+    },
+    Error, MacroRegistry,
+};
+
+/// Scans a set of files in parallel, using rayon's global thread pool.
+///
+/// Since the registered macro functions can't be shared between threads, a `ParallelExpander` is
+/// given a function that builds a [`MacroRegistry`], and it calls that function once on each
+/// worker thread. Panics are still caught separately for each invocation, and the resulting
+/// [`ExpansionReport`] lists the files in the order they were added, regardless of the order in
+/// which they were scanned.
+///
+/// The spans in the report can't be sent between threads either, so every `span` in it is
+/// `Span::call_site()`. The `start` and `end` fields still give the locations of the invocations.
+///
+/// This is only available with the `rayon` feature.
+///
+/// [`MacroRegistry`]: struct.MacroRegistry.html
+/// [`ExpansionReport`]: struct.ExpansionReport.html
+///
+/// # Example
+///
+/// ```
+/// # use runtime_macros::{MacroRegistry, ParallelExpander};
+/// fn remove_internal(_: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+///     proc_macro2::TokenStream::new()
+/// }
+///
+/// let report = ParallelExpander::new(|| {
+///     let mut registry = MacroRegistry::new();
+///     registry.functionlike("remove", remove_internal);
+///     registry
+/// })
+/// # /*
+/// .file("tests/a.rs")
+/// .file("tests/b.rs")
+/// # */
+/// # .file(concat!(env!("CARGO_MANIFEST_DIR"), "/src/lib.rs"))
+/// .run()
+/// .unwrap();
+/// ```
+pub struct ParallelExpander<F> {
+    make_registry: F,
+    files: Vec<PathBuf>,
+}
+
+impl<'a, F> ParallelExpander<F>
+where
+    F: Fn() -> MacroRegistry<'a> + Sync,
+{
+    /// Creates a `ParallelExpander` with no files, which will call `make_registry` to get the
+    /// macros to expand.
+    pub fn new(make_registry: F) -> Self {
+        ParallelExpander {
+            make_registry,
+            files: Vec::new(),
+        }
+    }
+
+    /// Adds a Rust source code file to the list of files to scan.
+    pub fn file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.files.push(path.as_ref().to_owned());
+        self
+    }
+
+    /// Scans every file that has been added, in parallel.
+    ///
+    /// # Returns
+    ///
+    /// The same thing that [`Expander::run`] would return for the same files, except for the
+    /// spans. If scanning more than one file fails, the error is the one for the file that was
+    /// added first.
+    ///
+    /// [`Expander::run`]: struct.Expander.html#method.run
+    pub fn run(&self) -> Result<ExpansionReport, Error> {
+        let results: Vec<Result<SentFileReport, SentError>> = self
+            .files
+            .par_iter()
+            .map_init(&self.make_registry, |registry, path| {
+                let source = session::read(path)
+                    .and_then(|content| registry.parse(content, Some(path)))
+                    .map_err(SentError::from)?;
+                let report = registry
+                    .scan(&source)
+                    .map_err(SentError::from)?
+                    .into_report(path.clone());
+                Ok(SentFileReport::from(report))
+            })
+            .collect();
+
+        let mut files = Vec::with_capacity(results.len());
+        for result in results {
+            files.push(result.map_err(Error::from)?.into());
+        }
+        (self.make_registry)().report(files)
+    }
+}
+
+// The errors that scanning a single file can produce, in a form that can be sent between threads.
+enum SentError {
+    Io {
+        path: Option<PathBuf>,
+        error: io::Error,
+    },
+    Parse {
+        path: Option<PathBuf>,
+        error: syn::Error,
+        snippet: Option<String>,
+    },
+    InvalidMacroPath {
+        kind: MacroKind,
+        macro_path: String,
+        error: syn::Error,
+    },
+    MacroFailed {
+        path: Option<PathBuf>,
+        kind: MacroKind,
+        macro_path: String,
+        error: syn::Error,
+        snippet: Option<String>,
+    },
+}
+
+impl From<Error> for SentError {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::IoError { path, error } => SentError::Io { path, error },
+            Error::ParseError {
+                path,
+                error,
+                snippet,
+            } => SentError::Parse {
+                path,
+                error,
+                snippet,
+            },
+            Error::InvalidMacroPath {
+                kind,
+                macro_path,
+                error,
+            } => SentError::InvalidMacroPath {
+                kind,
+                macro_path,
+                error,
+            },
+            Error::MacroFailed {
+                path,
+                kind,
+                macro_path,
+                error,
+                snippet,
+            } => SentError::MacroFailed {
+                path,
+                kind,
+                macro_path,
+                error,
+                snippet,
+            },
+            e => unreachable!("scanning a single file returned {:?}", e),
+        }
+    }
+}
+
+impl From<SentError> for Error {
+    fn from(e: SentError) -> Self {
+        match e {
+            SentError::Io { path, error } => Error::IoError { path, error },
+            SentError::Parse {
+                path,
+                error,
+                snippet,
+            } => Error::ParseError {
+                path,
+                error,
+                snippet,
+            },
+            SentError::InvalidMacroPath {
+                kind,
+                macro_path,
+                error,
+            } => Error::InvalidMacroPath {
+                kind,
+                macro_path,
+                error,
+            },
+            SentError::MacroFailed {
+                path,
+                kind,
+                macro_path,
+                error,
+                snippet,
+            } => Error::MacroFailed {
+                path,
+                kind,
+                macro_path,
+                error,
+                snippet,
+            },
+        }
+    }
+}
+
+// A `FileReport` with its token streams converted to strings and its spans removed.
+struct SentFileReport {
+    path: PathBuf,
+    records: Vec<SentRecord>,
+    nested_items: usize,
+    near_misses: Vec<(MacroKind, String, String)>,
+    unregistered: Vec<SentInvocation>,
+    call_sites: Vec<SentCallSite>,
+    panics: Vec<SentPanic>,
+    warnings: Vec<SentWarning>,
+    parse_error: Option<syn::Error>,
+    cached: bool,
+    cached_invocations: BTreeMap<(MacroKind, String), usize>,
+    duration: Duration,
+}
+
+struct SentRecord {
+    kind: MacroKind,
+    macro_path: String,
+    attr: Option<String>,
+    input: String,
+    output: String,
+    start: LineColumn,
+    end: LineColumn,
+    position: Option<MacroPosition>,
+    order: usize,
+    duration: Duration,
+    peak_memory: Option<usize>,
+}
+
+struct SentCallSite {
+    kind: MacroKind,
+    macro_path: String,
+    start: LineColumn,
+    end: LineColumn,
+    outcome: ExpansionOutcome,
+    position: Option<MacroPosition>,
+    order: usize,
+}
+
+struct SentInvocation {
+    kind: MacroKind,
+    path: String,
+    start: LineColumn,
+    end: LineColumn,
+    position: Option<MacroPosition>,
+}
+
+struct SentPanic {
+    path: Option<PathBuf>,
+    kind: MacroKind,
+    macro_path: String,
+    message: String,
+    location: Option<PanicLocation>,
+    backtrace: Option<String>,
+    minimized_input: Option<String>,
+    expected: bool,
+}
+
+struct SentWarning {
+    kind: MacroKind,
+    macro_path: String,
+    lint: String,
+    message: String,
+    start: LineColumn,
+    end: LineColumn,
+}
+
+impl From<FileReport> for SentFileReport {
+    fn from(report: FileReport) -> Self {
+        SentFileReport {
+            path: report.path,
+            records: report
+                .records
+                .into_iter()
+                .map(|record| SentRecord {
+                    kind: record.kind,
+                    macro_path: record.macro_path,
+                    attr: record.attr.map(|attr| attr.to_string()),
+                    input: record.input.to_string(),
+                    output: record.output.to_string(),
+                    start: record.start,
+                    end: record.end,
+                    position: record.position,
+                    order: record.order,
+                    duration: record.duration,
+                    peak_memory: record.peak_memory,
+                })
+                .collect(),
+            nested_items: report.nested_items,
+            near_misses: report
+                .near_misses
+                .into_iter()
+                .map(|near_miss| {
+                    (
+                        near_miss.kind,
+                        near_miss.found_path,
+                        near_miss.registered_path,
+                    )
+                })
+                .collect(),
+            unregistered: report
+                .unregistered
+                .into_iter()
+                .map(|invocation| SentInvocation {
+                    kind: invocation.kind,
+                    path: invocation.path,
+                    start: invocation.start,
+                    end: invocation.end,
+                    position: invocation.position,
+                })
+                .collect(),
+            call_sites: report
+                .call_sites
+                .into_iter()
+                .map(|site| SentCallSite {
+                    kind: site.kind,
+                    macro_path: site.macro_path,
+                    start: site.start,
+                    end: site.end,
+                    outcome: site.outcome,
+                    position: site.position,
+                    order: site.order,
+                })
+                .collect(),
+            panics: report
+                .panics
+                .into_iter()
+                .map(|panic| SentPanic {
+                    path: panic.path,
+                    kind: panic.kind,
+                    macro_path: panic.macro_path,
+                    message: panic.message,
+                    location: panic.location,
+                    backtrace: panic.backtrace,
+                    minimized_input: panic.minimized_input.as_ref().map(ToString::to_string),
+                    expected: panic.expected,
+                })
+                .collect(),
+            warnings: report
+                .warnings
+                .into_iter()
+                .map(|warning| SentWarning {
+                    kind: warning.kind,
+                    macro_path: warning.macro_path,
+                    lint: warning.lint,
+                    message: warning.message,
+                    start: warning.start,
+                    end: warning.end,
+                })
+                .collect(),
+            parse_error: report.parse_error,
+            cached: report.cached,
+            cached_invocations: report.cached_invocations,
+            duration: report.duration,
+        }
+    }
+}
+
+// Converts a string back into tokens. It was produced by `TokenStream::to_string`, so it always
+// parses.
+fn tokens(s: &str) -> TokenStream {
+    s.parse().unwrap_or_default()
+}
+
+impl From<SentFileReport> for FileReport {
+    fn from(report: SentFileReport) -> Self {
+        FileReport {
+            path: report.path,
+            records: report
+                .records
+                .into_iter()
+                .map(|record| ExpansionRecord {
+                    kind: record.kind,
+                    macro_path: record.macro_path,
+                    attr: record.attr.as_ref().map(|attr| tokens(attr)),
+                    input: tokens(&record.input),
+                    output: tokens(&record.output),
+                    span: Span::call_site(),
+                    start: record.start,
+                    end: record.end,
+                    position: record.position,
+                    order: record.order,
+                    duration: record.duration,
+                    peak_memory: record.peak_memory,
+                })
+                .collect(),
+            nested_items: report.nested_items,
+            near_misses: report
+                .near_misses
+                .into_iter()
+                .map(|(kind, found_path, registered_path)| NearMiss {
+                    kind,
+                    found_path,
+                    registered_path,
+                    span: Span::call_site(),
+                })
+                .collect(),
+            unregistered: report
+                .unregistered
+                .into_iter()
+                .map(|invocation| InvocationInfo {
+                    kind: invocation.kind,
+                    path: invocation.path,
+                    span: Span::call_site(),
+                    start: invocation.start,
+                    end: invocation.end,
+                    position: invocation.position,
+                })
+                .collect(),
+            call_sites: report
+                .call_sites
+                .into_iter()
+                .map(|site| CallSite {
+                    kind: site.kind,
+                    macro_path: site.macro_path,
+                    span: Span::call_site(),
+                    start: site.start,
+                    end: site.end,
+                    outcome: site.outcome,
+                    position: site.position,
+                    order: site.order,
+                })
+                .collect(),
+            panics: report
+                .panics
+                .into_iter()
+                .map(|panic| ExpansionPanic {
+                    path: panic.path,
+                    kind: panic.kind,
+                    macro_path: panic.macro_path,
+                    span: Span::call_site(),
+                    message: panic.message,
+                    location: panic.location,
+                    backtrace: panic.backtrace,
+                    minimized_input: panic.minimized_input.as_ref().map(|input| tokens(input)),
+                    expected: panic.expected,
+                })
+                .collect(),
+            warnings: report
+                .warnings
+                .into_iter()
+                .map(|warning| ExpansionWarning {
+                    kind: warning.kind,
+                    macro_path: warning.macro_path,
+                    lint: warning.lint,
+                    message: warning.message,
+                    span: Span::call_site(),
+                    start: warning.start,
+                    end: warning.end,
+                })
+                .collect(),
+            parse_error: report.parse_error,
+            cached: report.cached,
+            cached_invocations: report.cached_invocations,
+            duration: report.duration,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Expander;
+
+    fn registry<'a>() -> MacroRegistry<'a> {
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("format", |ts| ts)
+            .functionlike("panic", |ts| ts);
+        registry
+    }
+
+    #[test]
+    fn matches_sequential_expansion() {
+        let files = ["src/lib.rs", "src/registry.rs", "src/parallel.rs"];
+        let mut sequential = Expander::from(registry());
+        let mut parallel = ParallelExpander::new(registry);
+        for file in files.iter() {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(file);
+            sequential = sequential.file(&path);
+            parallel = parallel.file(&path);
+        }
+        let (sequential, parallel) = (sequential.run().unwrap(), parallel.run().unwrap());
+
+        assert_eq!(parallel.files.len(), files.len());
+        for (sequential, parallel) in sequential.files.iter().zip(parallel.files.iter()) {
+            assert_eq!(sequential.path, parallel.path);
+            assert_eq!(sequential.invocation_counts(), parallel.invocation_counts());
+            for (sequential, parallel) in sequential.records.iter().zip(parallel.records.iter()) {
+                assert_eq!(sequential.start, parallel.start);
+                assert_eq!(sequential.output.to_string(), parallel.output.to_string());
+            }
+        }
+    }
+}
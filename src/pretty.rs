@@ -0,0 +1,48 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Formatting macro output so people can read it.
+
+use proc_macro2::TokenStream;
+#[cfg(all(feature = "prettyplease", feature = "syn2"))]
+use syn as syn2;
+
+// Formats the tokens as Rust code. With the `prettyplease` feature, tokens that form a valid file,
+// such as the items that derives and attribute-like macros usually produce, are laid out the way
+// rustfmt would. Anything else is printed as a plain token stream.
+pub(crate) fn format(tokens: &TokenStream) -> String {
+    #[cfg(feature = "prettyplease")]
+    {
+        // prettyplease uses syn 2, whichever version the rest of the crate uses.
+        if let Ok(file) = syn2::parse2::<syn2::File>(tokens.clone()) {
+            return prettyplease::unparse(&file);
+        }
+    }
+    format!("{}\n", tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_items() {
+        let tokens: TokenStream = "struct A { x : u32 }".parse().unwrap();
+        if cfg!(feature = "prettyplease") {
+            assert_eq!(format(&tokens), "struct A {\n    x: u32,\n}\n");
+        } else {
+            assert_eq!(format(&tokens), "struct A { x : u32 }\n");
+        }
+        let tokens: TokenStream = "1 + 1".parse().unwrap();
+        assert_eq!(format(&tokens), "1 + 1\n");
+    }
+}
@@ -0,0 +1,124 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Telling the caller how far along a long run is.
+
+use {report::FileReport, std::path::Path};
+
+/// How far along an [`Expander`]'s run is, given to the callback set with [`Expander::progress`]
+/// after each file is scanned.
+///
+/// [`Expander`]: struct.Expander.html
+/// [`Expander::progress`]: struct.Expander.html#method.progress
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub struct Progress<'p> {
+    /// The number of files that have been scanned so far, including this one.
+    pub files_done: usize,
+    /// The number of files to scan in total, as far as is known so far. When modules or included
+    /// files are being followed, this grows as they're found, and a file that's found twice is
+    /// counted until it's skipped.
+    pub files_total: usize,
+    /// The file that was just scanned.
+    pub path: &'p Path,
+    /// The number of invocations of registered macros found so far, in all the files, including
+    /// the ones in code blocks and cached files.
+    pub invocations: usize,
+}
+
+pub(crate) type ProgressFn<'a> = Box<dyn Fn(&Progress) + 'a>;
+
+// Keeps count of the files scanned so far and the invocations found in them.
+#[derive(Default)]
+pub(crate) struct Tracker {
+    files_done: usize,
+    // The number of reports whose invocations have been counted. A file can have more than one,
+    // because its doc examples get their own.
+    reports_counted: usize,
+    invocations: usize,
+}
+
+impl Tracker {
+    // Counts a file that was just scanned, whose reports are at the end of `reports`, and calls
+    // the progress function. `files_left` is the number of files still to be scanned.
+    pub(crate) fn file_done(
+        &mut self,
+        f: &ProgressFn,
+        reports: &[FileReport],
+        files_left: usize,
+        path: &Path,
+    ) {
+        self.files_done += 1;
+        self.invocations += reports[self.reports_counted..]
+            .iter()
+            .map(|file| {
+                let called = file
+                    .call_sites
+                    .iter()
+                    .filter(|site| site.outcome.was_called());
+                called.count() + file.cached_invocations.values().sum::<usize>()
+            })
+            .sum::<usize>();
+        self.reports_counted = reports.len();
+        f(&Progress {
+            files_done: self.files_done,
+            files_total: self.files_done + files_left,
+            path,
+            invocations: self.invocations,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        std::{cell::RefCell, env, fs, process},
+        Expander,
+    };
+
+    #[test]
+    fn reports_progress_after_each_file() {
+        let dir = env::temp_dir().join(format!("runtime-macros-progress-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.rs"), "mod a;\nfoo!(1);\n").unwrap();
+        fs::write(dir.join("a.rs"), "foo!(2);\nfoo!(3);\n").unwrap();
+        fs::write(dir.join("README.md"), "```rust\nfoo!(4);\n```\n").unwrap();
+
+        let steps = RefCell::new(Vec::new());
+        Expander::new()
+            .functionlike("foo", |ts| ts)
+            .file(dir.join("lib.rs"))
+            .markdown(dir.join("README.md"))
+            .follow_modules(true)
+            .progress(|progress| {
+                let name = progress.path.file_name().unwrap().to_string_lossy();
+                steps.borrow_mut().push((
+                    progress.files_done,
+                    progress.files_total,
+                    name.into_owned(),
+                    progress.invocations,
+                ));
+            })
+            .run()
+            .unwrap();
+        assert_eq!(
+            steps.into_inner(),
+            [
+                (1, 3, "lib.rs".to_owned(), 1),
+                (2, 3, "a.rs".to_owned(), 3),
+                (3, 3, "README.md".to_owned(), 4),
+            ]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,3766 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+
+//! A registry that holds procedural macro functions of every kind, so they can all be expanded
+//! in a single pass over each file.
+
+#[cfg(feature = "dylib")]
+use dylib::MacroLibrary;
+use {
+    budget::Budget,
+    cfg::CfgSet,
+    context::ExpansionContext,
+    directives,
+    driver::ExpansionHandler,
+    fidelity::Fidelity,
+    foreign::{self, ForeignTokens},
+    matching::MatchMode,
+    panics::PanicPolicy,
+    proc_macro2::TokenStream,
+    report::{ExpansionPanic, ExpansionReport, FileReport, InvocationInfo, MacroKind},
+    session::ParsedSource,
+    std::{
+        collections::HashSet,
+        ffi::{OsStr, OsString},
+        path::{Path, PathBuf},
+        time::{Duration, Instant},
+    },
+    syn::{parse::Parse, visit::Visit},
+    visitor::{MacroVisitor, Scan},
+    Error,
+};
+#[cfg(feature = "fs")]
+use {
+    exports::{self, ExportedMacro},
+    report::ExpansionRecord,
+    session,
+    std::fs,
+};
+
+/// A boxed function that defines how to expand a function-like macro.
+pub type FunctionlikeFn<'a> = Box<dyn Fn(TokenStream) -> TokenStream + 'a>;
+/// A boxed function that defines how to expand a derive macro.
+pub type DeriveFn<'a> = Box<dyn Fn(TokenStream) -> TokenStream + 'a>;
+/// A boxed function that defines how to expand an attribute-like macro.
+pub type AttributeFn<'a> = Box<dyn Fn(TokenStream, TokenStream) -> TokenStream + 'a>;
+
+// Every registered function is stored in its context-aware, fallible form. Derive macros share
+// this type with function-like macros.
+pub(crate) type ContextFn<'a> =
+    Box<dyn Fn(&ExpansionContext, TokenStream) -> syn::Result<TokenStream> + 'a>;
+pub(crate) type ContextAttributeFn<'a> =
+    Box<dyn Fn(&ExpansionContext, TokenStream, TokenStream) -> syn::Result<TokenStream> + 'a>;
+pub(crate) type HookFn<'a> = Box<dyn Fn(&ExpansionContext, &TokenStream) + 'a>;
+pub(crate) type LintFn<'a> = Box<dyn Fn(&ExpansionContext, &TokenStream) -> syn::Result<()> + 'a>;
+pub(crate) type WorkingDirFn<'a> = Box<dyn Fn(Option<&Path>) -> Option<PathBuf> + 'a>;
+
+/// A collection of function-like, derive, and attribute-like procedural macro functions.
+///
+/// Registering every macro in one `MacroRegistry` lets [`emulate_macro_expansion`] parse each file
+/// only once and find all the invocations in a single visit, instead of calling each of the
+/// `emulate_*_macro_expansion` functions separately.
+///
+/// By default, paths are matched exactly as described in [`emulate_functionlike_macro_expansion`].
+/// This can be relaxed for all derive macros with [`derive_match_mode`] or for individual macros
+/// with [`match_mode`].
+///
+/// [`emulate_macro_expansion`]: struct.MacroRegistry.html#method.emulate_macro_expansion
+/// [`emulate_functionlike_macro_expansion`]: fn.emulate_functionlike_macro_expansion.html
+/// [`derive_match_mode`]: #method.derive_match_mode
+/// [`match_mode`]: #method.match_mode
+///
+/// # Example
+///
+/// ```
+/// # use runtime_macros::MacroRegistry;
+/// fn remove_internal(_: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+///     proc_macro2::TokenStream::new()
+/// }
+///
+/// fn remove_item_internal(
+///     _attr: proc_macro2::TokenStream,
+///     _item: proc_macro2::TokenStream,
+/// ) -> proc_macro2::TokenStream {
+///     proc_macro2::TokenStream::new()
+/// }
+///
+/// # /*
+/// let file = std::fs::File::open("tests/tests.rs").unwrap();
+/// # */
+/// # let file = std::fs::File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/src/lib.rs")).unwrap();
+/// MacroRegistry::new()
+///     .functionlike("remove", remove_internal)
+///     .derive("Remove", remove_internal)
+///     .attribute("remove_item", remove_item_internal)
+///     .emulate_macro_expansion(file)
+///     .unwrap();
+/// ```
+#[derive(Default)]
+pub struct MacroRegistry<'a> {
+    pub(crate) functionlike: Vec<(String, ContextFn<'a>)>,
+    pub(crate) derive: Vec<(String, ContextFn<'a>)>,
+    pub(crate) attribute: Vec<(String, ContextAttributeFn<'a>)>,
+    pub(crate) hooks: Vec<HookFn<'a>>,
+    pub(crate) lints: Vec<(String, LintFn<'a>)>,
+    pub(crate) references: Vec<(MacroKind, String, ContextAttributeFn<'a>)>,
+    pub(crate) working_dir: Option<WorkingDirFn<'a>>,
+    pub(crate) options: Options,
+}
+
+// Settings that change how files are scanned.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Options {
+    pub(crate) field_attributes: bool,
+    pub(crate) unwrap_cfg_attr: bool,
+    pub(crate) cfgs: Option<CfgSet>,
+    pub(crate) derive_match_mode: MatchMode,
+    pub(crate) match_modes: Vec<(MacroKind, String, MatchMode)>,
+    pub(crate) require_all_invoked: bool,
+    pub(crate) panic_policy: PanicPolicy,
+    pub(crate) rescan_attribute_output: bool,
+    pub(crate) rescan_functionlike_output: bool,
+    pub(crate) chain_attributes: bool,
+    pub(crate) scan_macro_tokens: bool,
+    pub(crate) scan_macro_rules: bool,
+    pub(crate) lenient_parsing: bool,
+    #[cfg(feature = "fs")]
+    pub(crate) record_corpus: Option<PathBuf>,
+    pub(crate) validate_output: bool,
+    pub(crate) strip_derive_attributes: bool,
+    pub(crate) strip_invoked_attribute: bool,
+    pub(crate) derive_helpers: Vec<(String, String)>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) quiet_expected_panics: bool,
+    pub(crate) minimize_panics: bool,
+    pub(crate) env: Vec<(OsString, Option<OsString>)>,
+    pub(crate) shuffle: Option<u64>,
+    pub(crate) check_determinism: bool,
+    pub(crate) check_idempotency: bool,
+    #[cfg(feature = "fuzz")]
+    pub(crate) fuzz: Option<(usize, u64)>,
+    pub(crate) budgets: Vec<(MacroKind, String, Budget)>,
+    pub(crate) check_hygiene: bool,
+    pub(crate) slow_threshold: Option<Duration>,
+    pub(crate) deny_slow: bool,
+    #[cfg(feature = "stacker")]
+    pub(crate) stack_size: Option<usize>,
+}
+
+impl Options {
+    // Returns the mode to use when matching the given registration.
+    pub(crate) fn match_mode(&self, kind: MacroKind, macro_path: &str) -> MatchMode {
+        match self
+            .match_modes
+            .iter()
+            .rev()
+            .find(|&&(k, ref path, _)| k == kind && path == macro_path)
+        {
+            Some((_, _, mode)) => mode.clone(),
+            None if kind == MacroKind::Derive => self.derive_match_mode.clone(),
+            None => MatchMode::Exact,
+        }
+    }
+
+    // Returns the budget for the given registration, if it has one.
+    pub(crate) fn budget(&self, kind: MacroKind, macro_path: &str) -> Option<Budget> {
+        self.budgets
+            .iter()
+            .rev()
+            .find(|&&(k, ref path, _)| k == kind && path == macro_path)
+            .map(|&(_, _, budget)| budget)
+    }
+}
+
+impl<'a> MacroRegistry<'a> {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a function-like macro, such as `foo!(...)`, with the function that defines how to
+    /// expand it.
+    pub fn functionlike<F>(&mut self, macro_path: &str, proc_macro_fn: F) -> &mut Self
+    where
+        F: Fn(TokenStream) -> TokenStream + 'a,
+    {
+        self.functionlike_with_context(macro_path, move |_, ts| proc_macro_fn(ts))
+    }
+
+    /// Registers a derive macro, such as `#[derive(Foo)]`, with the function that defines how to
+    /// expand it. Like the compiler, the scan only expands derives on structs, enums, and unions.
+    /// A registered derive anywhere else isn't expanded, and instead it gets an
+    /// [`ExpansionWarning`] in the file's report, with the lint `misplaced_derive`.
+    ///
+    /// [`ExpansionWarning`]: struct.ExpansionWarning.html
+    pub fn derive<F>(&mut self, macro_path: &str, proc_macro_fn: F) -> &mut Self
+    where
+        F: Fn(TokenStream) -> TokenStream + 'a,
+    {
+        self.derive_with_context(macro_path, move |_, ts| proc_macro_fn(ts))
+    }
+
+    /// Registers a derive macro with a function that takes the annotated item already parsed as a
+    /// `T`, such as `syn::DeriveInput` or `syn::ItemStruct`.
+    ///
+    /// If the item can't be parsed as a `T`, the function isn't called, and the expansion produces
+    /// a `compile_error!` invocation instead, just like `syn::parse_macro_input!` would.
+    pub fn derive_parsed<T, F>(&mut self, macro_path: &str, proc_macro_fn: F) -> &mut Self
+    where
+        T: Parse + 'a,
+        F: Fn(T) -> TokenStream + 'a,
+    {
+        self.derive(macro_path, move |ts| match syn::parse2::<T>(ts) {
+            Ok(item) => proc_macro_fn(item),
+            Err(e) => e.into_compile_error(),
+        })
+    }
+
+    /// Registers an attribute-like macro, such as `#[foo(...)]`, with the function that defines how
+    /// to expand it.
+    ///
+    /// Like a `#[proc_macro_attribute]` function, it's given the attribute's arguments without the
+    /// delimiters around them, so `#[foo(a, b)]` passes `a, b`, and `#[foo]` passes no tokens.
+    pub fn attribute<F>(&mut self, macro_path: &str, proc_macro_fn: F) -> &mut Self
+    where
+        F: Fn(TokenStream, TokenStream) -> TokenStream + 'a,
+    {
+        self.attribute_with_context(macro_path, move |_, attr, item| proc_macro_fn(attr, item))
+    }
+
+    /// Registers a function-like macro with a function that also receives an [`ExpansionContext`]
+    /// describing each invocation it is asked to expand.
+    ///
+    /// [`ExpansionContext`]: struct.ExpansionContext.html
+    pub fn functionlike_with_context<F>(&mut self, macro_path: &str, proc_macro_fn: F) -> &mut Self
+    where
+        F: Fn(&ExpansionContext, TokenStream) -> TokenStream + 'a,
+    {
+        self.functionlike.push((
+            macro_path.to_owned(),
+            Box::new(move |context, ts| Ok(proc_macro_fn(context, ts))),
+        ));
+        self
+    }
+
+    /// Registers a derive macro with a function that also receives an [`ExpansionContext`]
+    /// describing each invocation it is asked to expand.
+    ///
+    /// [`ExpansionContext`]: struct.ExpansionContext.html
+    pub fn derive_with_context<F>(&mut self, macro_path: &str, proc_macro_fn: F) -> &mut Self
+    where
+        F: Fn(&ExpansionContext, TokenStream) -> TokenStream + 'a,
+    {
+        self.derive.push((
+            macro_path.to_owned(),
+            Box::new(move |context, ts| Ok(proc_macro_fn(context, ts))),
+        ));
+        self
+    }
+
+    /// Registers an attribute-like macro with a function that also receives an
+    /// [`ExpansionContext`] describing each invocation it is asked to expand.
+    ///
+    /// [`ExpansionContext`]: struct.ExpansionContext.html
+    pub fn attribute_with_context<F>(&mut self, macro_path: &str, proc_macro_fn: F) -> &mut Self
+    where
+        F: Fn(&ExpansionContext, TokenStream, TokenStream) -> TokenStream + 'a,
+    {
+        self.attribute.push((
+            macro_path.to_owned(),
+            Box::new(move |context, attr, item| Ok(proc_macro_fn(context, attr, item))),
+        ));
+        self
+    }
+
+    /// Registers a function-like macro with a function that can fail.
+    ///
+    /// If the function returns an error, the expansion produces a `compile_error!` invocation, and
+    /// the error is returned once the rest of the file has been scanned.
+    pub fn functionlike_fallible<F>(&mut self, macro_path: &str, proc_macro_fn: F) -> &mut Self
+    where
+        F: Fn(TokenStream) -> syn::Result<TokenStream> + 'a,
+    {
+        self.functionlike.push((
+            macro_path.to_owned(),
+            Box::new(move |_, ts| proc_macro_fn(ts)),
+        ));
+        self
+    }
+
+    /// Registers a derive macro with a function that takes the annotated item already parsed as a
+    /// `T` and can fail. `T` can be `proc_macro2::TokenStream` if no parsing is needed.
+    ///
+    /// If the item can't be parsed as a `T` or the function returns an error, the expansion produces
+    /// a `compile_error!` invocation, and the error is returned once the rest of the file has been
+    /// scanned.
+    pub fn derive_fallible<T, F>(&mut self, macro_path: &str, proc_macro_fn: F) -> &mut Self
+    where
+        T: Parse + 'a,
+        F: Fn(T) -> syn::Result<TokenStream> + 'a,
+    {
+        self.derive.push((
+            macro_path.to_owned(),
+            Box::new(move |_, ts| proc_macro_fn(syn::parse2::<T>(ts)?)),
+        ));
+        self
+    }
+
+    /// Registers an attribute-like macro with a function that can fail.
+    ///
+    /// If the function returns an error, the expansion produces a `compile_error!` invocation, and
+    /// the error is returned once the rest of the file has been scanned.
+    pub fn attribute_fallible<F>(&mut self, macro_path: &str, proc_macro_fn: F) -> &mut Self
+    where
+        F: Fn(TokenStream, TokenStream) -> syn::Result<TokenStream> + 'a,
+    {
+        self.attribute.push((
+            macro_path.to_owned(),
+            Box::new(move |_, attr, item| proc_macro_fn(attr, item)),
+        ));
+        self
+    }
+
+    /// Registers a function-like macro with a function that uses a different type of tokens, such
+    /// as the `TokenStream` of another version of `proc_macro2`. See [`ForeignTokens`] for how the
+    /// tokens are converted. If a conversion fails, the expansion produces a `compile_error!`
+    /// invocation, and the error is returned once the rest of the file has been scanned.
+    ///
+    /// [`ForeignTokens`]: trait.ForeignTokens.html
+    pub fn functionlike_foreign<T, F>(&mut self, macro_path: &str, proc_macro_fn: F) -> &mut Self
+    where
+        T: ForeignTokens,
+        F: Fn(T) -> T + 'a,
+    {
+        self.functionlike.push((
+            macro_path.to_owned(),
+            Box::new(move |_, ts| foreign::output(proc_macro_fn(foreign::input(ts)?))),
+        ));
+        self
+    }
+
+    /// Registers a derive macro with a function that uses a different type of tokens. See
+    /// [`functionlike_foreign`] for details.
+    ///
+    /// [`functionlike_foreign`]: #method.functionlike_foreign
+    pub fn derive_foreign<T, F>(&mut self, macro_path: &str, proc_macro_fn: F) -> &mut Self
+    where
+        T: ForeignTokens,
+        F: Fn(T) -> T + 'a,
+    {
+        self.derive.push((
+            macro_path.to_owned(),
+            Box::new(move |_, ts| foreign::output(proc_macro_fn(foreign::input(ts)?))),
+        ));
+        self
+    }
+
+    /// Registers an attribute-like macro with a function that uses a different type of tokens. See
+    /// [`functionlike_foreign`] for details.
+    ///
+    /// [`functionlike_foreign`]: #method.functionlike_foreign
+    pub fn attribute_foreign<T, F>(&mut self, macro_path: &str, proc_macro_fn: F) -> &mut Self
+    where
+        T: ForeignTokens,
+        F: Fn(T, T) -> T + 'a,
+    {
+        self.attribute.push((
+            macro_path.to_owned(),
+            Box::new(move |_, attr, item| {
+                foreign::output(proc_macro_fn(foreign::input(attr)?, foreign::input(item)?))
+            }),
+        ));
+        self
+    }
+
+    /// Registers a function-like macro with a function exported by a compiled library. See
+    /// [`MacroLibrary`] for what the function has to look like. This is experimental and requires
+    /// the `dylib` feature.
+    ///
+    /// If the library doesn't export `symbol`, each expansion produces a `compile_error!`
+    /// invocation, and the error is returned once the rest of the file has been scanned.
+    ///
+    /// [`MacroLibrary`]: struct.MacroLibrary.html
+    #[cfg(feature = "dylib")]
+    pub fn functionlike_dylib(
+        &mut self,
+        macro_path: &str,
+        library: &MacroLibrary,
+        symbol: &str,
+    ) -> &mut Self {
+        self.functionlike_fallible(macro_path, library.expand_fn(symbol))
+    }
+
+    /// Registers a derive macro with a function exported by a compiled library. See
+    /// [`functionlike_dylib`] for details.
+    ///
+    /// [`functionlike_dylib`]: #method.functionlike_dylib
+    #[cfg(feature = "dylib")]
+    pub fn derive_dylib(
+        &mut self,
+        macro_path: &str,
+        library: &MacroLibrary,
+        symbol: &str,
+    ) -> &mut Self {
+        self.derive_fallible::<TokenStream, _>(macro_path, library.expand_fn(symbol))
+    }
+
+    /// Registers an attribute-like macro with a function exported by a compiled library. See
+    /// [`functionlike_dylib`] for details.
+    ///
+    /// [`functionlike_dylib`]: #method.functionlike_dylib
+    #[cfg(feature = "dylib")]
+    pub fn attribute_dylib(
+        &mut self,
+        macro_path: &str,
+        library: &MacroLibrary,
+        symbol: &str,
+    ) -> &mut Self {
+        self.attribute_fallible(macro_path, library.expand_attribute_fn(symbol))
+    }
+
+    /// Registers a reference implementation of a function-like macro that's already registered
+    /// under the same path, such as the old version of a macro that's being refactored. Both
+    /// functions are called for every invocation, and if their outputs differ, or if the reference
+    /// implementation panics, the rest of the file is still scanned, and then an
+    /// [`Error::MacroFailed`] is returned that shows the difference. The outputs are compared after
+    /// they're [normalized], and the diff shows how to get from the reference implementation's
+    /// output to the registered function's.
+    ///
+    /// Only the registered function's output is recorded in the report and passed to the hooks.
+    ///
+    /// [`Error::MacroFailed`]: enum.Error.html#variant.MacroFailed
+    /// [normalized]: tokens/fn.normalize.html
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::MacroRegistry;
+    /// # fn new_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+    /// # fn old_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+    /// # let file = std::fs::File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/src/lib.rs")).unwrap();
+    /// MacroRegistry::new()
+    ///     .functionlike("custom_assert", new_internal)
+    ///     .compare_functionlike("custom_assert", old_internal)
+    ///     .emulate_macro_expansion(file)
+    ///     .unwrap();
+    /// ```
+    pub fn compare_functionlike<F>(&mut self, macro_path: &str, reference_fn: F) -> &mut Self
+    where
+        F: Fn(TokenStream) -> TokenStream + 'a,
+    {
+        self.references.push((
+            MacroKind::Functionlike,
+            macro_path.to_owned(),
+            Box::new(move |_, _, ts| Ok(reference_fn(ts))),
+        ));
+        self
+    }
+
+    /// Registers a reference implementation of a derive macro that's already registered under the
+    /// same path. See [`compare_functionlike`] for details.
+    ///
+    /// [`compare_functionlike`]: #method.compare_functionlike
+    pub fn compare_derive<F>(&mut self, macro_path: &str, reference_fn: F) -> &mut Self
+    where
+        F: Fn(TokenStream) -> TokenStream + 'a,
+    {
+        self.references.push((
+            MacroKind::Derive,
+            macro_path.to_owned(),
+            Box::new(move |_, _, ts| Ok(reference_fn(ts))),
+        ));
+        self
+    }
+
+    /// Registers a reference implementation of an attribute-like macro that's already registered
+    /// under the same path. See [`compare_functionlike`] for details.
+    ///
+    /// [`compare_functionlike`]: #method.compare_functionlike
+    pub fn compare_attribute<F>(&mut self, macro_path: &str, reference_fn: F) -> &mut Self
+    where
+        F: Fn(TokenStream, TokenStream) -> TokenStream + 'a,
+    {
+        self.references.push((
+            MacroKind::Attribute,
+            macro_path.to_owned(),
+            Box::new(move |_, attr, item| Ok(reference_fn(attr, item))),
+        ));
+        self
+    }
+
+    /// Registers a hook to be called after every expansion with the context of the invocation and
+    /// the tokens that the macro function returned. Hooks are called in the order they were
+    /// registered.
+    ///
+    /// Unlike a panic in a macro function, a panic in a hook isn't caught, so hooks can make
+    /// assertions about the output the same way a test would.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::MacroRegistry;
+    /// let mut registry = MacroRegistry::new();
+    /// registry
+    ///     .functionlike("remove", |_| proc_macro2::TokenStream::new())
+    ///     .after_expansion(|context, output| {
+    ///         println!("{} expanded to `{}`", context.macro_path, output);
+    ///     });
+    /// ```
+    pub fn after_expansion<F>(&mut self, hook: F) -> &mut Self
+    where
+        F: Fn(&ExpansionContext, &TokenStream) + 'a,
+    {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Registers a lint, a check that's run on the output of every successful expansion, for
+    /// project-specific rules like "derives mustn't generate `unsafe` code". Lints are run in the
+    /// order they were registered, after any [`Budget`] is checked and before the hooks are called.
+    ///
+    /// The lint returns an error for each problem it finds, combined with [`syn::Error::combine`]
+    /// if there are several. Each one is listed in the [`FileReport::warnings`] with the lint's
+    /// name and the error's message and span. If the span isn't in the file, as with most of the
+    /// spans in a macro's output, the invocation's span is used instead.
+    ///
+    /// [`Budget`]: struct.Budget.html
+    /// [`syn::Error::combine`]: https://docs.rs/syn/2/syn/struct.Error.html#method.combine
+    /// [`FileReport::warnings`]: struct.FileReport.html#structfield.warnings
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::{tokens, MacroKind, MacroRegistry};
+    /// # fn builder_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+    /// let unwrap: proc_macro2::TokenStream = ". unwrap ()".parse().unwrap();
+    /// MacroRegistry::new()
+    ///     .derive("Builder", builder_internal)
+    ///     .lint("no_unwrap", move |context, output| {
+    ///         if context.kind == MacroKind::Derive && tokens::contains(output, &unwrap) {
+    ///             Err(syn::Error::new(context.span, "the expansion calls `unwrap`"))
+    ///         } else {
+    ///             Ok(())
+    ///         }
+    ///     });
+    /// ```
+    pub fn lint<F>(&mut self, name: &str, lint: F) -> &mut Self
+    where
+        F: Fn(&ExpansionContext, &TokenStream) -> syn::Result<()> + 'a,
+    {
+        self.lints.push((name.to_owned(), Box::new(lint)));
+        self
+    }
+
+    /// Sets whether attribute-like macros should also be expanded when they're applied to struct
+    /// fields and enum variants. This is disabled by default.
+    ///
+    /// Rust itself doesn't allow attribute-like macros in those positions, but this can be useful
+    /// for testing the code that handles helper attributes.
+    pub fn field_attributes(&mut self, enabled: bool) -> &mut Self {
+        self.options.field_attributes = enabled;
+        self
+    }
+
+    /// Sets how closely the input passed to each macro function should match what the compiler
+    /// would pass, by setting several of the other options at once. The default is
+    /// [`Fidelity::Lenient`]. Any of those options that are set afterward override the preset.
+    ///
+    /// [`Fidelity::Lenient`]: enum.Fidelity.html#variant.Lenient
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::{Fidelity, MacroRegistry};
+    /// # fn my_derive_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+    /// // Give the derive exactly what the compiler would, but expand attribute-like macros in
+    /// // every item, even if an earlier macro would have removed it.
+    /// MacroRegistry::new()
+    ///     .derive("MyDerive", my_derive_internal)
+    ///     .fidelity(Fidelity::Rustc)
+    ///     .rescan_attribute_output(false)
+    ///     .chain_attributes(false);
+    /// ```
+    pub fn fidelity(&mut self, fidelity: Fidelity) -> &mut Self {
+        fidelity.apply(&mut self.options);
+        self
+    }
+
+    /// Sets whether `#[cfg_attr(predicate, attrs...)]` attributes should be unwrapped so that the
+    /// derive and attribute-like macros inside them are expanded. This is disabled by default.
+    ///
+    /// If a [`CfgSet`] has been given to [`cfgs`], only the `cfg_attr`s whose predicates are true
+    /// are unwrapped, and the rest are ignored, as they would be by the compiler. Otherwise, every
+    /// `cfg_attr` is unwrapped, regardless of its predicate.
+    ///
+    /// [`CfgSet`]: struct.CfgSet.html
+    /// [`cfgs`]: #method.cfgs
+    pub fn unwrap_cfg_attr(&mut self, enabled: bool) -> &mut Self {
+        self.options.unwrap_cfg_attr = enabled;
+        self
+    }
+
+    /// Declares which configuration options should be considered active when evaluating `cfg`
+    /// predicates in the scanned source code.
+    ///
+    /// Once this has been called, any item, field, variant, or statement with a `#[cfg(...)]`
+    /// attribute whose predicate is false is skipped, along with everything inside it, as if it
+    /// had been removed by the compiler. Without a `CfgSet`, `cfg` attributes are ignored.
+    pub fn cfgs(&mut self, cfgs: CfgSet) -> &mut Self {
+        self.options.cfgs = Some(cfgs);
+        self
+    }
+
+    /// Sets how the paths inside `#[derive(...)]` attributes are compared with the paths that
+    /// derive macros were registered under. The default is [`MatchMode::Exact`], so
+    /// `#[derive(mycrate::Pod)]` doesn't match a registration of `"Pod"` unless this is changed.
+    ///
+    /// Modes set with [`match_mode`] take precedence over this one.
+    ///
+    /// [`MatchMode::Exact`]: enum.MatchMode.html#variant.Exact
+    /// [`match_mode`]: #method.match_mode
+    pub fn derive_match_mode(&mut self, mode: MatchMode) -> &mut Self {
+        self.options.derive_match_mode = mode;
+        self
+    }
+
+    /// Sets how paths in the source code are compared with `macro_path` for the macro of the
+    /// given kind that is (or will be) registered under that path. Without this, function-like and
+    /// attribute-like macros use [`MatchMode::Exact`], and derive macros use the mode given to
+    /// [`derive_match_mode`].
+    ///
+    /// [`MatchMode::Exact`]: enum.MatchMode.html#variant.Exact
+    /// [`derive_match_mode`]: #method.derive_match_mode
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::{MacroKind, MacroRegistry, MatchMode};
+    /// # fn foo_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+    /// // Also expand `bar::foo!(...)`, `baz::foo!(...)`, etc.
+    /// MacroRegistry::new()
+    ///     .functionlike("foo", foo_internal)
+    ///     .match_mode(MacroKind::Functionlike, "foo", MatchMode::LastSegment);
+    /// ```
+    pub fn match_mode(&mut self, kind: MacroKind, macro_path: &str, mode: MatchMode) -> &mut Self {
+        self.options
+            .match_modes
+            .push((kind, macro_path.to_owned(), mode));
+        self
+    }
+
+    /// Declares the helper attributes of a registered derive macro, like the ones listed in
+    /// `#[proc_macro_derive(MyDerive, attributes(my_helper))]`.
+    ///
+    /// On an item that derives the macro, including its fields and variants, a helper attribute is
+    /// inert, the way it is to the compiler: it's never expanded as an attribute-like macro, even
+    /// if one is registered with the same name, and it's left in place for the derive to read,
+    /// even with [`strip_derive_attributes`]. Elsewhere, the name is treated like any other
+    /// attribute.
+    ///
+    /// [`strip_derive_attributes`]: #method.strip_derive_attributes
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::MacroRegistry;
+    /// # fn my_derive_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+    /// MacroRegistry::new()
+    ///     .derive("MyDerive", my_derive_internal)
+    ///     .derive_helpers("MyDerive", &["my_helper"]);
+    /// ```
+    pub fn derive_helpers(&mut self, macro_path: &str, helpers: &[&str]) -> &mut Self {
+        self.options.derive_helpers.extend(
+            helpers
+                .iter()
+                .map(|&helper| (macro_path.to_owned(), helper.to_owned())),
+        );
+        self
+    }
+
+    /// Checks that the macros in this registry are the same as the ones exported by a procedural
+    /// macro crate, as found by [`exported_macros`] in its root file, usually `src/lib.rs`. This
+    /// keeps the registrations from falling behind when a macro is added to the crate, renamed, or
+    /// given a new helper attribute.
+    ///
+    /// A registered macro matches an exported one of the same kind if the last segment of its
+    /// path is the exported name. If the derive is registered, its helper attributes have to be
+    /// declared with [`derive_helpers`] too. Any difference is an [`Error::ExportMismatch`] that
+    /// lists all of them, and otherwise the exported macros are returned.
+    ///
+    /// [`exported_macros`]: fn.exported_macros.html
+    /// [`derive_helpers`]: #method.derive_helpers
+    /// [`Error::ExportMismatch`]: enum.Error.html#variant.ExportMismatch
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::MacroRegistry;
+    /// # fn custom_assert_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+    /// # let dir = std::env::temp_dir().join("runtime-macros-check-exports-doctest");
+    /// # std::fs::create_dir_all(dir.join("src")).unwrap();
+    /// # std::fs::write(
+    /// #     dir.join("src/lib.rs"),
+    /// #     "#[proc_macro] pub fn custom_assert(ts: TokenStream) -> TokenStream { ts }",
+    /// # ).unwrap();
+    /// let mut registry = MacroRegistry::new();
+    /// registry.functionlike("custom_assert", custom_assert_internal);
+    /// registry
+    ///     # .check_exports(dir.join("src/lib.rs"))
+    ///     # /*
+    ///     .check_exports("src/lib.rs")
+    ///     # */
+    ///     .unwrap();
+    /// ```
+    #[cfg(feature = "fs")]
+    pub fn check_exports<P: AsRef<Path>>(
+        &self,
+        crate_root: P,
+    ) -> Result<Vec<ExportedMacro>, Error> {
+        let path = crate_root.as_ref();
+        let exported = exports::exported_macros(path)?;
+        let name = |macro_path: &str| {
+            let last = macro_path.rsplit("::").next().unwrap_or(macro_path);
+            last.trim().to_owned()
+        };
+
+        let unregistered: Vec<(MacroKind, String)> = exported
+            .iter()
+            .filter(|m| {
+                !self
+                    .registered()
+                    .any(|(kind, path)| kind == m.kind && name(path) == m.name)
+            })
+            .map(|m| (m.kind, m.name.clone()))
+            .collect();
+        let unexported: Vec<(MacroKind, String)> = self
+            .registered()
+            .filter(|&(kind, path)| {
+                !exported
+                    .iter()
+                    .any(|m| m.kind == kind && m.name == name(path))
+            })
+            .map(|(kind, path)| (kind, path.clone()))
+            .collect();
+        let mut helpers = Vec::new();
+        for (_, path) in self
+            .registered()
+            .filter(|&(kind, _)| kind == MacroKind::Derive)
+        {
+            let derive = match exported
+                .iter()
+                .find(|m| m.kind == MacroKind::Derive && m.name == name(path))
+            {
+                Some(derive) => derive,
+                None => continue,
+            };
+            let declared: Vec<&str> = self
+                .options
+                .derive_helpers
+                .iter()
+                .filter(|(derive_path, _)| derive_path == path)
+                .map(|(_, helper)| &helper[..])
+                .collect();
+            for helper in derive.helpers.iter() {
+                if !declared.contains(&&helper[..]) {
+                    helpers.push((path.clone(), helper.clone()));
+                }
+            }
+            for &helper in declared.iter() {
+                if !derive.helpers.iter().any(|h| h == helper) {
+                    helpers.push((path.clone(), helper.to_owned()));
+                }
+            }
+        }
+
+        if unregistered.is_empty() && unexported.is_empty() && helpers.is_empty() {
+            Ok(exported)
+        } else {
+            Err(Error::ExportMismatch {
+                path: path.to_owned(),
+                unregistered,
+                unexported,
+                helpers,
+            })
+        }
+    }
+
+    /// Sets whether it's an error for a registered macro never to be invoked. This is disabled by
+    /// default.
+    ///
+    /// When enabled, a scan that otherwise succeeds returns [`Error::NotInvoked`], listing every
+    /// registered macro that didn't match any invocation in any of the scanned files. This catches
+    /// typos in registered paths, which would otherwise make a coverage test silently do nothing.
+    ///
+    /// [`Error::NotInvoked`]: enum.Error.html#variant.NotInvoked
+    pub fn require_all_invoked(&mut self, enabled: bool) -> &mut Self {
+        self.options.require_all_invoked = enabled;
+        self
+    }
+
+    /// Sets whether the output of each attribute-like macro should be scanned again, the way the
+    /// compiler does, so that derives, attribute-like macros, and function-like macros that it
+    /// emits are expanded too. This is disabled by default.
+    ///
+    /// When it's enabled, the macro's output replaces the annotated item: the item is passed to
+    /// the macro without the attribute being expanded, and the attributes after that one and the
+    /// item's contents are only scanned if the macro keeps them in its output. An output that
+    /// keeps invoking attribute-like macros is stopped after 128 levels with an error, like the
+    /// compiler's recursion limit.
+    pub fn rescan_attribute_output(&mut self, enabled: bool) -> &mut Self {
+        self.options.rescan_attribute_output = enabled;
+        self
+    }
+
+    /// Sets whether the output of each function-like macro should be scanned again, the way the
+    /// compiler does, so that the derives, attribute-like macros, and function-like macros in the
+    /// items and code that it emits are expanded too. This is disabled by default.
+    ///
+    /// This is useful when a helper macro generates the fixtures that other macros are tested on,
+    /// such as a `make_structs!` whose output derives a registered macro. The output is parsed as
+    /// whatever the invocation's position calls for, or scanned as tokens if the position isn't
+    /// known, and it's an error if it can't be parsed. An output that keeps invoking function-like
+    /// macros is stopped after 128 levels with an error, like the compiler's recursion limit.
+    pub fn rescan_functionlike_output(&mut self, enabled: bool) -> &mut Self {
+        self.options.rescan_functionlike_output = enabled;
+        self
+    }
+
+    /// Sets whether an item's attribute-like macros should be chained, the way the compiler does,
+    /// instead of each being given the original item. This is disabled by default.
+    ///
+    /// When it's enabled, the item's first registered attribute-like macro is given the item
+    /// without that attribute, and each later one is given the output of the one before it, so
+    /// it sees the same input it would see during compilation. Derives and function-like macros
+    /// are still expanded in the original item. To expand them in the output instead, use
+    /// [`rescan_attribute_output`], which also chains attribute-like macros.
+    ///
+    /// [`rescan_attribute_output`]: #method.rescan_attribute_output
+    pub fn chain_attributes(&mut self, enabled: bool) -> &mut Self {
+        self.options.chain_attributes = enabled;
+        self
+    }
+
+    /// Sets whether the tokens passed to unregistered macros should be searched for invocations of
+    /// registered function-like macros. This is disabled by default.
+    ///
+    /// The arguments of a macro like `assert!(foo!(x))` aren't parsed, so `foo!(x)` is normally
+    /// invisible. When this is enabled, anything in those tokens that looks like a path followed
+    /// by `!` and a delimited group is treated as a macro invocation. The bodies of `macro_rules!`
+    /// definitions aren't searched.
+    pub fn scan_macro_tokens(&mut self, enabled: bool) -> &mut Self {
+        self.options.scan_macro_tokens = enabled;
+        self
+    }
+
+    /// Sets whether the bodies of `macro_rules!` definitions should be searched for invocations of
+    /// registered function-like macros. This is disabled by default.
+    ///
+    /// This is useful when a test wraps a procedural macro in a declarative one. Each invocation
+    /// is expanded once, where it's defined, with its tokens exactly as written, so any
+    /// metavariables such as `$e` are passed to the macro function unchanged. That works best
+    /// with macros that accept arbitrary tokens or with a [`PanicPolicy`] that records failures.
+    ///
+    /// [`PanicPolicy`]: enum.PanicPolicy.html
+    pub fn scan_macro_rules(&mut self, enabled: bool) -> &mut Self {
+        self.options.scan_macro_rules = enabled;
+        self
+    }
+
+    /// Sets whether files that syn can't parse should be scanned anyway. This is disabled by
+    /// default, so a file using syntax that syn doesn't understand yet causes an
+    /// [`Error::ParseError`].
+    ///
+    /// When it's enabled, such a file is split into tokens as described in
+    /// [`ParsedSource::parse_lenient`], and anything that looks like an invocation of a
+    /// registered macro is expanded: function-like macros anywhere, and derives and
+    /// attribute-like macros in the outer attributes before something that looks like an item.
+    /// The error from syn is kept in the file's [`FileReport`].
+    ///
+    /// [`Error::ParseError`]: enum.Error.html#variant.ParseError
+    /// [`ParsedSource::parse_lenient`]: struct.ParsedSource.html#method.parse_lenient
+    /// [`FileReport`]: struct.FileReport.html
+    pub fn lenient_parsing(&mut self, enabled: bool) -> &mut Self {
+        self.options.lenient_parsing = enabled;
+        self
+    }
+
+    /// Sets a directory to record the input of every invocation of a registered macro in, as a
+    /// corpus for fuzzing, minimizing, or replaying with [`emulate_from_corpus`]. Nothing is
+    /// recorded by default.
+    ///
+    /// Each input is written to its own file, at `<dir>/<kind>/<macro path>/<file>-<line>-<column>`,
+    /// where the kind is `functionlike`, `derive`, or `attribute`, and the file is the path of the
+    /// file containing the invocation, with every character other than letters, digits, `.`, `-`,
+    /// and `_` replaced with `_`. The input is stored as a token stream, so the original formatting
+    /// is lost. For attribute-like macros, the attribute's own tokens are written to a second file
+    /// with `.attr` added to its name. Inputs are recorded even if the macro function panics, and it's
+    /// an error if a file can't be written.
+    ///
+    /// [`emulate_from_corpus`]: #method.emulate_from_corpus
+    #[cfg(feature = "fs")]
+    pub fn record_corpus<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.options.record_corpus = Some(dir.as_ref().to_owned());
+        self
+    }
+
+    /// Sets whether the output of each function-like macro should be parsed in the position where
+    /// the macro was invoked, such as an expression or a list of items, to catch expansions that
+    /// wouldn't compile there. This is disabled by default.
+    ///
+    /// An expansion that doesn't parse is treated like an error returned by a fallible macro
+    /// function: the rest of the file is still scanned, and then an [`Error::MacroFailed`] is
+    /// returned. The position of each invocation is recorded in its [`CallSite`] either way.
+    /// Invocations found by scanning tokens, as with [`scan_macro_tokens`], have no known position,
+    /// so they aren't checked.
+    ///
+    /// [`Error::MacroFailed`]: enum.Error.html#variant.MacroFailed
+    /// [`CallSite`]: struct.CallSite.html
+    /// [`scan_macro_tokens`]: #method.scan_macro_tokens
+    pub fn validate_output(&mut self, enabled: bool) -> &mut Self {
+        self.options.validate_output = enabled;
+        self
+    }
+
+    /// Sets whether the `#[derive(...)]` attributes should be removed from the item before it's
+    /// passed to a derive macro, the way the compiler does. This is disabled by default, so the
+    /// item is passed with every attribute it had in the source code.
+    ///
+    /// Only the `derive` attributes are removed, including any that name other derives. Other
+    /// attributes, like helper attributes and doc comments, are left in place. If a `cfg_attr`
+    /// was unwrapped into a `derive` because of [`unwrap_cfg_attr`], that's removed too.
+    ///
+    /// [`unwrap_cfg_attr`]: #method.unwrap_cfg_attr
+    pub fn strip_derive_attributes(&mut self, enabled: bool) -> &mut Self {
+        self.options.strip_derive_attributes = enabled;
+        self
+    }
+
+    /// Sets whether an attribute-like macro's own attribute should be removed from the item before
+    /// it's passed to the macro, the way the compiler does. This is disabled by default, so the
+    /// item is passed with every attribute it had in the source code.
+    ///
+    /// Only the attribute that invoked the macro is removed; the item's other attributes are left
+    /// in place, including ones that invoke other attribute-like macros. This is always done when
+    /// [`chain_attributes`] or [`rescan_attribute_output`] is enabled.
+    ///
+    /// [`chain_attributes`]: #method.chain_attributes
+    /// [`rescan_attribute_output`]: #method.rescan_attribute_output
+    pub fn strip_invoked_attribute(&mut self, enabled: bool) -> &mut Self {
+        self.options.strip_invoked_attribute = enabled;
+        self
+    }
+
+    /// Sets how long a macro function can run for each invocation before it's considered stuck.
+    /// There's no limit by default.
+    ///
+    /// A watchdog thread waits for each call to return. Since a macro function can't be stopped
+    /// while it's running, or moved to another thread to be abandoned there, a call that takes too
+    /// long ends the process it's in, so this requires [`Expander::isolate`]. Then only a child
+    /// process ends, and the invocation is treated like a panic with a message that says it timed
+    /// out, so the rest of the files are still expanded. Scanning without isolation returns an
+    /// [`Error::IoError`] instead of starting. The calls that finished in time in the child aren't
+    /// timed again when they're repeated in the test's own process, so a slower second run, such as
+    /// under a coverage tool, doesn't end the test run.
+    ///
+    /// [`Expander::isolate`]: struct.Expander.html#method.isolate
+    /// [`Error::IoError`]: enum.Error.html#variant.IoError
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.options.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the amount of stack space, in bytes, that each call to a macro function is guaranteed.
+    /// This requires the `stacker` feature. By default, macro functions run on the current
+    /// thread's stack, which is usually smaller than the compiler's main thread, where proc macros
+    /// normally run, so a deeply recursive macro can overflow it at run time but not at compile
+    /// time. The compiler's main thread has 8 MiB of stack, unless `RUST_MIN_STACK` says otherwise.
+    ///
+    /// If less than this much stack is left when a macro function is called, it's called on a new
+    /// stack of this size instead, on the same thread, so the function doesn't need to be `Send`.
+    #[cfg(feature = "stacker")]
+    pub fn stack_size(&mut self, bytes: usize) -> &mut Self {
+        self.options.stack_size = Some(bytes);
+        self
+    }
+
+    /// Sets whether the panics of invocations marked with `// runtime-macros: expect-panic` should
+    /// be kept out of the standard error stream. This is disabled by default, so every panic is
+    /// printed by the panic hook, as usual.
+    ///
+    /// When it's enabled, an expected panic is still recorded with its location and backtrace, but
+    /// the quiet panic hook that's installed around the call doesn't pass it on to the hook that
+    /// was installed before, which is put back once the call returns. Other panics, including ones
+    /// on other threads, are printed as usual.
+    pub fn quiet_expected_panics(&mut self, enabled: bool) -> &mut Self {
+        self.options.quiet_expected_panics = enabled;
+        self
+    }
+
+    /// Sets whether the input of each invocation whose macro function panics unexpectedly should
+    /// be shrunk to a smaller one that still makes it panic. This is disabled by default. The
+    /// input that panics in a real file is often much bigger than it needs to be to show the bug,
+    /// and the shrunk one makes a better test case.
+    ///
+    /// The macro function is called again with runs of token trees removed from the input, and
+    /// then with parts of each group's contents removed, and each removal is kept if the function
+    /// still panics with the same message. That stops when nothing else can be removed or after
+    /// 1000 calls, and the smallest input is kept in [`ExpansionPanic::minimized_input`]. For an
+    /// attribute-like macro, only the item is shrunk, not the attribute's arguments. The extra
+    /// calls aren't recorded in the report or passed to the hooks, and their panics aren't
+    /// printed.
+    ///
+    /// [`ExpansionPanic::minimized_input`]: struct.ExpansionPanic.html#structfield.minimized_input
+    pub fn minimize_panics(&mut self, enabled: bool) -> &mut Self {
+        self.options.minimize_panics = enabled;
+        self
+    }
+
+    /// Sets an environment variable for the duration of each call to a macro function, for macros
+    /// that read variables like `CARGO_MANIFEST_DIR` that might be different at run time than
+    /// they would be during compilation. The variable's old value is put back after each call,
+    /// even if the function panics.
+    ///
+    /// Since the environment is shared by the whole process, other threads see the change while a
+    /// macro function is running. That includes other tests, so tests that depend on the same
+    /// variables shouldn't run at the same time as this. The calls that change it are made one at
+    /// a time, though, along with the ones that change the [`working_dir`], so two scans on
+    /// different threads never change it at once.
+    ///
+    /// [`working_dir`]: #method.working_dir
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::MacroRegistry;
+    /// # fn my_macro_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+    /// MacroRegistry::new()
+    ///     .attribute("my_macro", |_, item| my_macro_internal(item))
+    ///     .env("MY_MACRO_CONFIG", "tests/config.toml");
+    /// ```
+    pub fn env<K, V>(&mut self, key: K, value: V) -> &mut Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.options
+            .env
+            .push((key.as_ref().to_owned(), Some(value.as_ref().to_owned())));
+        self
+    }
+
+    /// Removes an environment variable for the duration of each call to a macro function. See
+    /// [`env`] for details.
+    ///
+    /// [`env`]: #method.env
+    pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Self {
+        self.options.env.push((key.as_ref().to_owned(), None));
+        self
+    }
+
+    /// Shuffles the order that the macro functions are called in, for testing macros that keep
+    /// state between invocations, such as a registry of names in a static. The same seed always
+    /// shuffles the same files into the same order, so a failure can be reproduced.
+    ///
+    /// By default, the macro functions are called in source order: the files are scanned in the
+    /// order they were given, each one after the file that declared it as a module, and the
+    /// invocations in each file are expanded in the order they're written. An item's attributes
+    /// are expanded before the invocations inside it, and a macro's output is scanned for other
+    /// invocations right after the macro function returns. With a seed, the top-level items of
+    /// each file are scanned in a random order instead, and so are the files given to an
+    /// [`Expander`], but everything inside an item keeps its order. The [`FileReport`]s still list
+    /// the invocations in source order, and [`CallSite::order`] shows the order they were actually
+    /// expanded in.
+    ///
+    /// Files that are only scanned as tokens, because [`lenient_parsing`] is enabled and syn
+    /// couldn't parse them, aren't shuffled.
+    ///
+    /// [`Expander`]: struct.Expander.html
+    /// [`FileReport`]: struct.FileReport.html
+    /// [`CallSite::order`]: struct.CallSite.html#structfield.order
+    /// [`lenient_parsing`]: #method.lenient_parsing
+    pub fn shuffle(&mut self, seed: u64) -> &mut Self {
+        self.options.shuffle = Some(seed);
+        self
+    }
+
+    /// Sets whether each macro function should be called twice for every invocation, to check that
+    /// it gives the same output both times. This is disabled by default. A macro whose output
+    /// depends on something other than its input, like the iteration order of a `HashMap` or a
+    /// counter in a static, makes builds irreproducible, and this finds the invocations where that
+    /// shows.
+    ///
+    /// The outputs are compared after they're [normalized], and an error that a fallible macro
+    /// function returns is compared as the `compile_error!` it becomes. If they differ, or if the
+    /// second call panics, the rest of the file is still scanned, and then an
+    /// [`Error::MacroFailed`] is returned that shows the difference. Only the first call is
+    /// recorded in the report and passed to the hooks.
+    ///
+    /// [normalized]: tokens/fn.normalize.html
+    /// [`Error::MacroFailed`]: enum.Error.html#variant.MacroFailed
+    pub fn check_determinism(&mut self, enabled: bool) -> &mut Self {
+        self.options.check_determinism = enabled;
+        self
+    }
+
+    /// Sets whether each attribute-like macro should also be applied to its own output, to check
+    /// that it can be. This is disabled by default. An attribute that's applied twice, directly or
+    /// through another macro that passes it along, shouldn't break or keep generating more code,
+    /// and this finds the macros that do.
+    ///
+    /// The macro function is called again with the same arguments and the whole output as the
+    /// item. If that call panics or returns an error, or if its output has more tokens than the
+    /// first output, the rest of the file is still scanned, and then an [`Error::MacroFailed`] is
+    /// returned that says so. The second call isn't recorded in the report or passed to the hooks.
+    ///
+    /// [`Error::MacroFailed`]: enum.Error.html#variant.MacroFailed
+    pub fn check_idempotency(&mut self, enabled: bool) -> &mut Self {
+        self.options.check_idempotency = enabled;
+        self
+    }
+
+    /// Sets the number of times each macro function should be called again with a mutation of an
+    /// invocation's input, to check that it copes with inputs that are almost right. This requires
+    /// the `fuzz` feature. Each mutation drops, duplicates, or swaps a random token tree, or
+    /// changes a literal to one like `0` or `""`, and the mutations are chosen by the seed and the
+    /// invocation's location, so they're the same every time. That includes the inputs replayed
+    /// from a corpus with [`emulate_from_corpus`], which makes the inputs recorded by
+    /// [`record_corpus`] into seeds for fuzzing.
+    ///
+    /// A macro function may return anything, return an error, or panic when it's given a mutated
+    /// input, and none of that is recorded in the report or passed to the hooks. A mutation that
+    /// crashes the whole process, or runs for longer than the [`timeout`], is a bug, and it's
+    /// listed in the [`FileReport::warnings`] with the `fuzz` lint, along with the mutated input.
+    /// That only works if the run is isolated with [`Expander::isolate`]. Otherwise, the crash
+    /// just ends the process, the way a crashing invocation does.
+    ///
+    /// [`emulate_from_corpus`]: #method.emulate_from_corpus
+    /// [`record_corpus`]: #method.record_corpus
+    /// [`timeout`]: #method.timeout
+    /// [`FileReport::warnings`]: struct.FileReport.html#structfield.warnings
+    /// [`Expander::isolate`]: struct.Expander.html#method.isolate
+    #[cfg(feature = "fuzz")]
+    pub fn fuzz(&mut self, mutations: usize, seed: u64) -> &mut Self {
+        self.options.fuzz = Some((mutations, seed));
+        self
+    }
+
+    /// Sets limits on the size of each expansion of the macro of the given kind that is (or will
+    /// be) registered under `macro_path`. Each expansion that's over budget is listed in the
+    /// [`FileReport::warnings`], or, if the budget [denies] it, the rest of the file is still
+    /// scanned, and then an [`Error::MacroFailed`] is returned.
+    ///
+    /// [`FileReport::warnings`]: struct.FileReport.html#structfield.warnings
+    /// [denies]: struct.Budget.html#method.deny
+    /// [`Error::MacroFailed`]: enum.Error.html#variant.MacroFailed
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::{Budget, MacroKind, MacroRegistry};
+    /// # fn builder_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+    /// MacroRegistry::new()
+    ///     .derive("Builder", builder_internal)
+    ///     .budget(MacroKind::Derive, "Builder", Budget::new().max_ratio(10.0).deny(true));
+    /// ```
+    pub fn budget(&mut self, kind: MacroKind, macro_path: &str, budget: Budget) -> &mut Self {
+        self.options
+            .budgets
+            .push((kind, macro_path.to_owned(), budget));
+        self
+    }
+
+    /// Sets whether each expansion should be searched for local variables that might capture or
+    /// shadow names in the caller's code, a common hygiene bug. This is disabled by default.
+    ///
+    /// A variable is suspicious if it's bound with `let` or `for` and its name doesn't appear in
+    /// the macro's input, so it was made up by the macro. If the caller's code, spliced into the
+    /// output, uses or defines a variable with the same name, the two can clash. Giving the name
+    /// [`Span::mixed_site()`] avoids that, but outside the compiler that span can't be told apart
+    /// from any other, so this is only a heuristic: names starting with `__` are assumed to be
+    /// hidden on purpose, and anything else is listed in the [`FileReport::warnings`] under the
+    /// `hygiene` lint for a person to review.
+    ///
+    /// [`Span::mixed_site()`]: https://docs.rs/proc-macro2/1/proc_macro2/struct.Span.html#method.mixed_site
+    /// [`FileReport::warnings`]: struct.FileReport.html#structfield.warnings
+    pub fn check_hygiene(&mut self, enabled: bool) -> &mut Self {
+        self.options.check_hygiene = enabled;
+        self
+    }
+
+    /// Sets how long an expansion can take before it's considered slow. Each expansion that took
+    /// longer is listed in the [`FileReport::warnings`] under the `slow` lint, unless
+    /// [`deny_slow`] is enabled. There's no threshold by default. Every expansion's time is also
+    /// in its [`ExpansionRecord::duration`].
+    ///
+    /// The time is measured on the wall clock, so it's only a rough guide to how long the macro
+    /// will take in the compiler, and it can be thrown off by other tests running at the same
+    /// time. That makes it most useful for finding the call sites that are far slower than the
+    /// rest.
+    ///
+    /// [`FileReport::warnings`]: struct.FileReport.html#structfield.warnings
+    /// [`deny_slow`]: #method.deny_slow
+    /// [`ExpansionRecord::duration`]: struct.ExpansionRecord.html#structfield.duration
+    pub fn slow_threshold(&mut self, threshold: Duration) -> &mut Self {
+        self.options.slow_threshold = Some(threshold);
+        self
+    }
+
+    /// Sets whether an expansion that's slower than the [`slow_threshold`] is an error instead of
+    /// a warning. If it is, the rest of the file is still scanned, and then an
+    /// [`Error::MacroFailed`] is returned. This is disabled by default.
+    ///
+    /// [`slow_threshold`]: #method.slow_threshold
+    /// [`Error::MacroFailed`]: enum.Error.html#variant.MacroFailed
+    pub fn deny_slow(&mut self, enabled: bool) -> &mut Self {
+        self.options.deny_slow = enabled;
+        self
+    }
+
+    /// Sets the working directory for the duration of each call to a macro function, for macros
+    /// that read files by relative paths. During a build, those paths are relative to the
+    /// directory that Cargo runs the compiler in, which is usually the workspace's root, but a test
+    /// might run somewhere else. The directory that was current before is restored after each
+    /// call, even if the function panics.
+    ///
+    /// Like [`env`], this changes the whole process, so other threads, including other tests, see
+    /// the change while a macro function is running. The calls that change it are made one at a
+    /// time, though, so the workers of a `ParallelExpander` that each use a different directory
+    /// don't change it under each other. It's an [`Error::IoError`] if the directory can't be
+    /// used.
+    ///
+    /// [`env`]: #method.env
+    /// [`Error::IoError`]: enum.Error.html#variant.IoError
+    pub fn working_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        let dir = dir.as_ref().to_owned();
+        self.working_dir_with(move |_| Some(dir.clone()))
+    }
+
+    /// Sets a function that chooses the working directory for the macro functions called while
+    /// scanning each file, given the file's path if it's known. If the function returns `None`,
+    /// the working directory isn't changed for that file. See [`working_dir`] for details.
+    ///
+    /// [`working_dir`]: #method.working_dir
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::MacroRegistry;
+    /// # use std::path::Path;
+    /// # fn sql_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+    /// // Each fixture crate in `tests/fixtures` reads its own `queries` directory.
+    /// MacroRegistry::new()
+    ///     .functionlike("sql", sql_internal)
+    ///     .working_dir_with(|path| {
+    ///         path?.ancestors().find(|dir| dir.join("Cargo.toml").is_file()).map(Path::to_owned)
+    ///     });
+    /// ```
+    pub fn working_dir_with<F>(&mut self, working_dir: F) -> &mut Self
+    where
+        F: Fn(Option<&Path>) -> Option<PathBuf> + 'a,
+    {
+        self.working_dir = Some(Box::new(working_dir));
+        self
+    }
+
+    /// Sets what to do when a macro function panics. The default is [`PanicPolicy::Fail`].
+    ///
+    /// [`PanicPolicy::Fail`]: enum.PanicPolicy.html#variant.Fail
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::{ExpansionOutcome, MacroRegistry, PanicPolicy};
+    /// # fn custom_assert_internal(_: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    /// #     panic!("expected an expression")
+    /// # }
+    /// let report = MacroRegistry::new()
+    ///     .functionlike("custom_assert", custom_assert_internal)
+    ///     .panic_policy(PanicPolicy::Record)
+    ///     .emulate_source("fn main() { custom_assert!(1 +); }")
+    ///     .unwrap();
+    /// let outcomes: Vec<_> = report.call_sites().map(|site| site.outcome.clone()).collect();
+    /// assert_eq!(outcomes, [ExpansionOutcome::Panicked("expected an expression".into())]);
+    /// ```
+    pub fn panic_policy(&mut self, policy: PanicPolicy) -> &mut Self {
+        self.options.panic_policy = policy;
+        self
+    }
+
+    /// Registers several function-like macros at once.
+    ///
+    /// Since the functions are boxed, they don't all need to have the same type, so function
+    /// pointers and closures that capture different state can be registered together.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::{FunctionlikeFn, MacroRegistry};
+    /// fn remove_internal(_: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    ///     proc_macro2::TokenStream::new()
+    /// }
+    ///
+    /// let suffix = String::from("_suffix");
+    /// let fns: Vec<(&str, FunctionlikeFn)> = vec![
+    ///     ("remove", Box::new(remove_internal)),
+    ///     ("keep", Box::new(|ts| ts)),
+    ///     ("append", Box::new(move |ts| format!("{} {}", ts, suffix).parse().unwrap())),
+    /// ];
+    /// let mut registry = MacroRegistry::new();
+    /// registry.extend_functionlike(fns);
+    /// ```
+    pub fn extend_functionlike<I, S>(&mut self, macro_paths_and_proc_macro_fns: I) -> &mut Self
+    where
+        I: IntoIterator<Item = (S, FunctionlikeFn<'a>)>,
+        S: Into<String>,
+    {
+        for (path, proc_macro_fn) in macro_paths_and_proc_macro_fns {
+            self.functionlike(&path.into(), proc_macro_fn);
+        }
+        self
+    }
+
+    /// Registers several derive macros at once. See [`extend_functionlike`] for details.
+    ///
+    /// [`extend_functionlike`]: #method.extend_functionlike
+    pub fn extend_derive<I, S>(&mut self, macro_paths_and_proc_macro_fns: I) -> &mut Self
+    where
+        I: IntoIterator<Item = (S, DeriveFn<'a>)>,
+        S: Into<String>,
+    {
+        for (path, proc_macro_fn) in macro_paths_and_proc_macro_fns {
+            self.derive(&path.into(), proc_macro_fn);
+        }
+        self
+    }
+
+    /// Registers several attribute-like macros at once. See [`extend_functionlike`] for details.
+    ///
+    /// [`extend_functionlike`]: #method.extend_functionlike
+    pub fn extend_attribute<I, S>(&mut self, macro_paths_and_proc_macro_fns: I) -> &mut Self
+    where
+        I: IntoIterator<Item = (S, AttributeFn<'a>)>,
+        S: Into<String>,
+    {
+        for (path, proc_macro_fn) in macro_paths_and_proc_macro_fns {
+            self.attribute(&path.into(), proc_macro_fn);
+        }
+        self
+    }
+
+    /// Searches the given Rust source code file for calls to any of the registered macros and calls
+    /// the functions that define how to expand them.
+    ///
+    /// This behaves like calling each of the `emulate_*_macro_expansion` functions in turn, except
+    /// that the file is only read and parsed once.
+    ///
+    /// # Returns
+    ///
+    /// An [`ExpansionReport`] with a single [`FileReport`] on success, or an instance of [`Error`]
+    /// indicating any error that occurred when trying to read or parse the file or the registered
+    /// paths. Since a `File` doesn't know its own path, the `FileReport`'s path is empty.
+    ///
+    /// If a macro function panics, the rest of the file is still expanded, and the resulting
+    /// [`Error::ExpansionPanicked`] lists every invocation that panicked, unless the panics are
+    /// recorded in the report instead because of the [`panic_policy`].
+    ///
+    /// [`ExpansionReport`]: struct.ExpansionReport.html
+    /// [`FileReport`]: struct.FileReport.html
+    /// [`Error`]: enum.Error.html
+    /// [`Error::ExpansionPanicked`]: enum.Error.html#variant.ExpansionPanicked
+    /// [`panic_policy`]: #method.panic_policy
+    #[cfg(feature = "fs")]
+    pub fn emulate_macro_expansion(&self, file: fs::File) -> Result<ExpansionReport, Error> {
+        self.emulate_source(&session::read_file(file)?)
+    }
+
+    /// Does the same thing as [`emulate_macro_expansion`], but with Rust source code that has
+    /// already been read, or that never came from a file, such as the contents of an editor. This
+    /// is available without the `fs` feature.
+    ///
+    /// [`emulate_macro_expansion`]: #method.emulate_macro_expansion
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::MacroRegistry;
+    /// # fn remove_internal(_: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+    /// #     proc_macro2::TokenStream::new()
+    /// # }
+    /// let report = MacroRegistry::new()
+    ///     .functionlike("remove", remove_internal)
+    ///     .emulate_source("fn main() { remove!(1); remove!(2); }")
+    ///     .unwrap();
+    /// assert_eq!(report.invocations("remove"), 2);
+    /// ```
+    pub fn emulate_source(&self, content: &str) -> Result<ExpansionReport, Error> {
+        let file_report = self
+            .expand_source(content, None)?
+            .into_report(PathBuf::new());
+        self.report(vec![file_report])
+    }
+
+    /// Does the same thing as [`emulate_macro_expansion`], but also returns a record of every
+    /// expansion, including the tokens that each macro function produced.
+    ///
+    /// [`emulate_macro_expansion`]: #method.emulate_macro_expansion
+    #[cfg(feature = "fs")]
+    pub fn emulate_macro_expansion_collect(
+        &self,
+        file: fs::File,
+    ) -> Result<Vec<ExpansionRecord>, Error> {
+        Ok(self
+            .emulate_macro_expansion(file)?
+            .files
+            .pop()
+            .map(|file| file.records)
+            .unwrap_or_default())
+    }
+
+    /// Reads each file in the given directory as a bare token stream, rather than a Rust source
+    /// file, and passes it to every registered function-like macro as if the macro had been invoked
+    /// with it. This is meant for corpora of macro inputs, such as the ones that fuzzers keep. The
+    /// files are read in the order of their names, and subdirectories are skipped.
+    ///
+    /// # Returns
+    ///
+    /// An [`ExpansionReport`] with one [`FileReport`] per file on success, or an instance of
+    /// [`Error`] describing the first error that occurred, just like [`emulate_macro_expansion`].
+    /// A file that can't be split into tokens is reported as an [`Error::ParseError`].
+    ///
+    /// [`ExpansionReport`]: struct.ExpansionReport.html
+    /// [`FileReport`]: struct.FileReport.html
+    /// [`Error`]: enum.Error.html
+    /// [`Error::ParseError`]: enum.Error.html#variant.ParseError
+    /// [`emulate_macro_expansion`]: #method.emulate_macro_expansion
+    #[cfg(feature = "fs")]
+    pub fn emulate_from_corpus<P: AsRef<Path>>(&self, dir: P) -> Result<ExpansionReport, Error> {
+        let dir = dir.as_ref();
+        let io_error = |error| Error::IoError {
+            path: Some(dir.to_owned()),
+            error,
+        };
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(dir).map_err(io_error)? {
+            let path = entry.map_err(io_error)?.path();
+            if path.is_file() {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        let mut files = Vec::new();
+        for path in paths {
+            let content = session::read(&path)?;
+            let tokens = match content.parse::<TokenStream>() {
+                Ok(tokens) => tokens,
+                Err(e) => {
+                    return Err(Error::ParseError {
+                        path: Some(path),
+                        error: e.into(),
+                        snippet: None,
+                    }
+                    .annotate(&content))
+                }
+            };
+            let mut visitor = MacroVisitor::new(self, Some(&path))?;
+            visitor.expand_input(tokens);
+            let scan = visitor.finish().map_err(|e| e.annotate(&content))?;
+            files.push(scan.into_report(path.clone()));
+        }
+        self.report(files)
+    }
+
+    // Returns an error if any of the given invocations panicked unexpectedly, unless the panic
+    // policy says to record them instead.
+    pub(crate) fn check_panics<'r, I>(&self, panics: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = &'r ExpansionPanic>,
+    {
+        match self.options.panic_policy {
+            PanicPolicy::Fail => match Error::from_panics(
+                panics
+                    .into_iter()
+                    .filter(|panic| !panic.expected)
+                    .cloned()
+                    .collect(),
+            ) {
+                Some(e) => Err(e),
+                None => Ok(()),
+            },
+            PanicPolicy::Record | PanicPolicy::Propagate => Ok(()),
+        }
+    }
+
+    // Returns the kind and path of every registered macro.
+    pub(crate) fn registered(&self) -> impl Iterator<Item = (MacroKind, &String)> {
+        self.functionlike
+            .iter()
+            .map(|(path, _)| (MacroKind::Functionlike, path))
+            .chain(
+                self.derive
+                    .iter()
+                    .map(|(path, _)| (MacroKind::Derive, path)),
+            )
+            .chain(
+                self.attribute
+                    .iter()
+                    .map(|(path, _)| (MacroKind::Attribute, path)),
+            )
+    }
+
+    // Returns an error if `require_all_invoked` is enabled and some registered macro doesn't
+    // appear among the given invocations.
+    pub(crate) fn check_all_invoked<'r, I>(&self, invoked: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (MacroKind, &'r str)>,
+    {
+        if !self.options.require_all_invoked {
+            return Ok(());
+        }
+
+        let invoked: HashSet<(MacroKind, &str)> = invoked.into_iter().collect();
+        let not_invoked: Vec<(MacroKind, String)> = self
+            .registered()
+            .filter(|&(kind, path)| !invoked.contains(&(kind, &path[..])))
+            .map(|(kind, path)| (kind, path.clone()))
+            .collect();
+        if not_invoked.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::NotInvoked(not_invoked))
+        }
+    }
+
+    // Parses the given source code and expands every registered macro invocation in it.
+    // `source_path` is only used to tell the macro functions where they are.
+    pub(crate) fn expand_source(
+        &self,
+        content: &str,
+        source_path: Option<&Path>,
+    ) -> Result<Scan, Error> {
+        self.scan(&self.parse(content.to_owned(), source_path)?)
+    }
+
+    /// Expands only the invocations of registered macros that start on the given line of the Rust
+    /// source code file, where the first line is 1, so a single failing invocation can be run
+    /// again on its own, such as under a debugger with the [`Propagate`] panic policy.
+    ///
+    /// The other invocations are still listed in the report's [`call_sites`] as
+    /// [`FilteredOut`], so if none of them start on the line, the report has no expansions.
+    /// Registered macros that weren't invoked aren't an error, even with
+    /// [`require_all_invoked`].
+    ///
+    /// [`Propagate`]: enum.PanicPolicy.html#variant.Propagate
+    /// [`call_sites`]: struct.FileReport.html#structfield.call_sites
+    /// [`FilteredOut`]: enum.ExpansionOutcome.html#variant.FilteredOut
+    /// [`require_all_invoked`]: #method.require_all_invoked
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// # use runtime_macros::{MacroRegistry, PanicPolicy};
+    /// # fn custom_assert_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+    /// let file = std::fs::File::open("tests/tests.rs").unwrap();
+    /// MacroRegistry::new()
+    ///     .functionlike("custom_assert", custom_assert_internal)
+    ///     .panic_policy(PanicPolicy::Propagate)
+    ///     .expand_single(file, 42)
+    ///     .unwrap();
+    /// ```
+    #[cfg(feature = "fs")]
+    pub fn expand_single(&self, file: fs::File, line: usize) -> Result<ExpansionReport, Error> {
+        self.expand_single_source(&session::read_file(file)?, line)
+    }
+
+    /// Does the same thing as [`expand_single`], but with Rust source code that has already been
+    /// read. This is available without the `fs` feature.
+    ///
+    /// [`expand_single`]: #method.expand_single
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::MacroRegistry;
+    /// let mut registry = MacroRegistry::new();
+    /// registry.functionlike("foo", |ts| ts);
+    /// let report = registry
+    ///     .expand_single_source("fn main() {\n    foo!(1);\n    foo!(2);\n}", 3)
+    ///     .unwrap();
+    /// assert_eq!(report.files[0].records[0].input.to_string(), "2");
+    /// assert_eq!(report.call_sites().count(), 2);
+    /// ```
+    pub fn expand_single_source(
+        &self,
+        content: &str,
+        line: usize,
+    ) -> Result<ExpansionReport, Error> {
+        let source = self.parse(content.to_owned(), None)?;
+        let file = self
+            .scan_with(&source, |visitor| {
+                visitor.handler = Some(Box::new(OnLine(line)));
+            })?
+            .into_report(PathBuf::new());
+        self.check_panics(file.panics.iter())?;
+        Ok(ExpansionReport { files: vec![file] })
+    }
+
+    /// Lists every function-like macro invocation, derive, and attribute in the given Rust source
+    /// code file without expanding anything, not even the registered macros.
+    ///
+    /// The file is parsed and scanned the way this registry's options say, so, for example,
+    /// invocations inside other macros' tokens are only found with [`scan_macro_tokens`], and items
+    /// that are configured out with [`cfgs`] are skipped. Since no macro is expanded, the tokens of
+    /// every function-like macro invocation are scanned, including those of registered macros.
+    /// Each invocation's path is given as it's written in the file, so comparing it with a
+    /// registration's path shows why they didn't match. Attributes that the compiler handles
+    /// itself, like `#[inline]` and `#[rustfmt::skip]`, are left out.
+    ///
+    /// [`scan_macro_tokens`]: #method.scan_macro_tokens
+    /// [`cfgs`]: #method.cfgs
+    #[cfg(feature = "fs")]
+    pub fn discover_macro_invocations(&self, file: fs::File) -> Result<Vec<InvocationInfo>, Error> {
+        self.discover_source(&session::read_file(file)?)
+    }
+
+    /// Does the same thing as [`discover_macro_invocations`], but with Rust source code that has
+    /// already been read. This is available without the `fs` feature.
+    ///
+    /// [`discover_macro_invocations`]: #method.discover_macro_invocations
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::{MacroKind, MacroRegistry};
+    /// let invocations = MacroRegistry::new()
+    ///     .discover_source("#[derive(Debug, mycrate::Pod)] struct A(u8);")
+    ///     .unwrap();
+    /// let paths: Vec<(MacroKind, &str)> = invocations
+    ///     .iter()
+    ///     .map(|invocation| (invocation.kind, &invocation.path[..]))
+    ///     .collect();
+    /// assert_eq!(
+    ///     paths,
+    ///     [(MacroKind::Derive, "Debug"), (MacroKind::Derive, "mycrate::Pod")]
+    /// );
+    /// ```
+    pub fn discover_source(&self, content: &str) -> Result<Vec<InvocationInfo>, Error> {
+        let source = self.parse(content.to_owned(), None)?;
+        let mut visitor = MacroVisitor::new(self, None)?.without_macros();
+        walk(&source, &mut visitor);
+        Ok(visitor
+            .finish()
+            .map_err(|e| e.annotate(source.content()))?
+            .unregistered)
+    }
+
+    // Parses a file, leniently if this registry is configured that way.
+    pub(crate) fn parse(
+        &self,
+        content: String,
+        path: Option<&Path>,
+    ) -> Result<ParsedSource, Error> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("parse", path = ?path).entered();
+        let result = if self.options.lenient_parsing {
+            ParsedSource::parse_lenient(content, path)
+        } else {
+            ParsedSource::parse(content, path)
+        };
+        #[cfg(feature = "tracing")]
+        {
+            if let Err(ref error) = result {
+                tracing::error!(%error, "the file couldn't be parsed");
+            }
+        }
+        result
+    }
+
+    // Expands every registered macro invocation in an already parsed file.
+    pub(crate) fn scan(&self, source: &ParsedSource) -> Result<Scan, Error> {
+        self.scan_with(source, |_| {})
+    }
+
+    // Does the same thing as `scan`, but lets the caller set up the visitor first, such as by
+    // giving it a handler.
+    pub(crate) fn scan_with<'r, F>(
+        &'r self,
+        source: &'r ParsedSource,
+        setup: F,
+    ) -> Result<Scan, Error>
+    where
+        F: FnOnce(&mut MacroVisitor<'r, 'a>),
+    {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!("scan", path = ?source.path()).entered();
+        let start = Instant::now();
+        let mut visitor = MacroVisitor::new(self, source.path())?;
+        setup(&mut visitor);
+        walk(source, &mut visitor);
+        let mut scan = visitor.finish().map_err(|e| e.annotate(source.content()))?;
+        scan.parse_error = source.parse_error().cloned();
+        scan.duration = start.elapsed();
+        #[cfg(feature = "tracing")]
+        tracing::info!(
+            expansions = scan.records.len(),
+            panics = scan.panics.len(),
+            warnings = scan.warnings.len(),
+            duration = ?scan.duration,
+            "scanned the file",
+        );
+        Ok(scan)
+    }
+
+    // Combines the results of scanning each file into one report, or returns an error if the
+    // results as a whole should be considered a failure.
+    pub(crate) fn report(&self, files: Vec<FileReport>) -> Result<ExpansionReport, Error> {
+        self.check_panics(files.iter().flat_map(|file| file.panics.iter()))?;
+        self.check_all_invoked(
+            files
+                .iter()
+                .flat_map(|file| file.invocation_counts().into_keys()),
+        )?;
+        Ok(ExpansionReport { files })
+    }
+}
+
+// Allows only the invocations that start on a certain line.
+struct OnLine(usize);
+
+impl ExpansionHandler for OnLine {
+    fn on_functionlike(&mut self, context: &ExpansionContext, _input: &TokenStream) -> bool {
+        context.span.start().line == self.0
+    }
+
+    fn on_derive(&mut self, context: &ExpansionContext, _item: &TokenStream) -> bool {
+        context.span.start().line == self.0
+    }
+
+    fn on_attribute(
+        &mut self,
+        context: &ExpansionContext,
+        _attr: &TokenStream,
+        _item: &TokenStream,
+    ) -> bool {
+        context.span.start().line == self.0
+    }
+}
+
+// Passes every invocation in an already parsed file to the visitor.
+fn walk(source: &ParsedSource, visitor: &mut MacroVisitor) {
+    visitor.directives = directives::parse(source.content());
+    match source.tokens() {
+        Some(tokens) => visitor.scan_tokens(tokens.clone(), true),
+        None => visitor.visit_file(source.ast()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proc_macro2::{Span, TokenTree};
+    use report::{ExpansionOutcome, MacroPosition};
+    use sandbox;
+    use session::Session;
+    use std::{fs, process};
+
+    fn identity(ts: TokenStream) -> TokenStream {
+        ts
+    }
+
+    #[test]
+    fn collects_every_kind_in_one_pass() {
+        let source = "
+            #[derive(Debug, Foo)]
+            struct A;
+
+            #[bar(baz)]
+            fn main() {
+                foo!(1 + 1);
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", |_| "2".parse().unwrap())
+            .derive("Foo", identity)
+            .attribute("bar", |_, item| item);
+        let records = registry.expand_source(source, None).unwrap().records;
+
+        let kinds: Vec<MacroKind> = records.iter().map(|record| record.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                MacroKind::Derive,
+                MacroKind::Attribute,
+                MacroKind::Functionlike
+            ]
+        );
+        assert_eq!(records[1].attr.as_ref().unwrap().to_string(), "baz");
+        assert_eq!(records[2].input.to_string(), "1 + 1");
+        assert_eq!(records[2].output.to_string(), "2");
+    }
+
+    #[test]
+    fn passes_context_to_functions_and_hooks() {
+        use std::cell::RefCell;
+
+        let source = "
+            foo!();
+            fn main() {
+                foo!(inner);
+            }
+        ";
+        let depths = RefCell::new(Vec::new());
+        let outputs = RefCell::new(Vec::new());
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike_with_context("foo", |context, ts| {
+                assert_eq!(context.macro_path, "foo");
+                depths.borrow_mut().push(context.depth);
+                ts
+            })
+            .after_expansion(|_, output| outputs.borrow_mut().push(output.to_string()));
+        registry.expand_source(source, None).unwrap();
+
+        assert_eq!(*depths.borrow(), [0, 1]);
+        assert_eq!(*outputs.borrow(), ["", "inner"]);
+    }
+
+    #[test]
+    fn fallible_errors_are_returned_after_the_whole_file() {
+        use std::cell::Cell;
+
+        let source = "
+            fn main() {
+                foo!(bad);
+                foo!(good);
+            }
+        ";
+        let calls = Cell::new(0);
+        let mut registry = MacroRegistry::new();
+        registry.functionlike_fallible("foo", |ts| {
+            calls.set(calls.get() + 1);
+            match ts.to_string().as_str() {
+                "bad" => Err(syn::Error::new(Span::call_site(), "bad input")),
+                _ => Ok(ts),
+            }
+        });
+
+        match registry.expand_source(source, None) {
+            Err(Error::MacroFailed {
+                kind,
+                macro_path,
+                error,
+                ..
+            }) => {
+                assert_eq!(kind, MacroKind::Functionlike);
+                assert_eq!(macro_path, "foo");
+                assert_eq!(error.to_string(), "bad input");
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn expands_nested_items() {
+        let source = "
+            mod outer {
+                mod inner {
+                    #[derive(Foo)]
+                    struct A;
+                }
+            }
+            fn main() {
+                #[derive(Foo)]
+                struct B;
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry.derive("Foo", identity);
+        let scan = registry.expand_source(source, None).unwrap();
+
+        let inputs: Vec<String> = scan
+            .records
+            .iter()
+            .map(|record| record.input.to_string())
+            .collect();
+        assert_eq!(
+            inputs,
+            ["# [derive (Foo)] struct A ;", "# [derive (Foo)] struct B ;"]
+        );
+        assert_eq!(scan.nested_items, 3);
+    }
+
+    #[test]
+    fn expands_attributes_on_associated_and_foreign_items() {
+        let source = "
+            impl A {
+                #[bar]
+                fn a() {}
+            }
+            trait B {
+                #[bar(x)]
+                const B: u8;
+            }
+            extern \"C\" {
+                #[bar]
+                static C: u8;
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry.attribute("bar", |_, item| item);
+        let records = registry.expand_source(source, None).unwrap().records;
+
+        let inputs: Vec<String> = records
+            .iter()
+            .map(|record| record.input.to_string())
+            .collect();
+        assert_eq!(
+            inputs,
+            [
+                "# [bar] fn a () { }",
+                "# [bar (x)] const B : u8 ;",
+                "# [bar] static C : u8 ;"
+            ]
+        );
+        assert_eq!(records[1].attr.as_ref().unwrap().to_string(), "x");
+    }
+
+    #[test]
+    fn expands_field_attributes_only_when_enabled() {
+        let source = "
+            struct A {
+                #[bar]
+                a: u8,
+            }
+            enum B {
+                #[bar]
+                B(#[bar] u8),
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry.attribute("bar", |_, item| item);
+        assert_eq!(
+            registry.expand_source(source, None).unwrap().records.len(),
+            0
+        );
+
+        registry.field_attributes(true);
+        let records = registry.expand_source(source, None).unwrap().records;
+        let inputs: Vec<String> = records
+            .iter()
+            .map(|record| record.input.to_string())
+            .collect();
+        assert_eq!(
+            inputs,
+            ["# [bar] a : u8", "# [bar] B (# [bar] u8)", "# [bar] u8"]
+        );
+    }
+
+    #[test]
+    fn expands_inner_attributes() {
+        let source = "
+            #![bar]
+            mod m {
+                #![bar(x)]
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry.attribute("bar", |_, item| item);
+        let records = registry.expand_source(source, None).unwrap().records;
+
+        let inputs: Vec<String> = records
+            .iter()
+            .map(|record| record.input.to_string())
+            .collect();
+        assert_eq!(
+            inputs,
+            [
+                "# ! [bar] mod m { # ! [bar (x)] }",
+                "mod m { # ! [bar (x)] }"
+            ]
+        );
+    }
+
+    #[test]
+    fn unwraps_cfg_attr() {
+        let source = "
+            #[cfg_attr(feature = \"foo\", derive(Foo))]
+            #[cfg_attr(unix, cfg_attr(test, bar))]
+            struct A;
+        ";
+        let mut registry = MacroRegistry::new();
+        registry
+            .derive("Foo", identity)
+            .attribute("bar", |_, item| item);
+        assert_eq!(
+            registry.expand_source(source, None).unwrap().records.len(),
+            0
+        );
+
+        registry.unwrap_cfg_attr(true);
+        let records = registry.expand_source(source, None).unwrap().records;
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            records[0].input.to_string(),
+            "# [derive (Foo)] # [bar] struct A ;"
+        );
+
+        registry.cfgs(CfgSet::new().feature("foo"));
+        let records = registry.expand_source(source, None).unwrap().records;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].input.to_string(), "# [derive (Foo)] struct A ;");
+    }
+
+    #[test]
+    fn skips_configured_out_code() {
+        let source = "
+            #[cfg(feature = \"foo\")]
+            mod a {
+                foo!(a);
+            }
+            fn main() {
+                #[cfg(not(feature = \"foo\"))]
+                foo!(b);
+                foo!(c);
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry.functionlike("foo", identity);
+        assert_eq!(
+            registry.expand_source(source, None).unwrap().records.len(),
+            3
+        );
+
+        registry.cfgs(CfgSet::new());
+        let records = registry.expand_source(source, None).unwrap().records;
+        let inputs: Vec<String> = records
+            .iter()
+            .map(|record| record.input.to_string())
+            .collect();
+        assert_eq!(inputs, ["b", "c"]);
+
+        registry.cfgs(CfgSet::new().feature("foo"));
+        let records = registry.expand_source(source, None).unwrap().records;
+        let inputs: Vec<String> = records
+            .iter()
+            .map(|record| record.input.to_string())
+            .collect();
+        assert_eq!(inputs, ["a", "c"]);
+    }
+
+    #[test]
+    fn matches_qualified_derive_paths() {
+        let source = "
+            #[derive(Pod, bytes::Pod, mycrate::bytes::Pod)]
+            struct A;
+        ";
+        let count = |mode| {
+            let mut registry = MacroRegistry::new();
+            registry
+                .derive("bytes::Pod", identity)
+                .derive_match_mode(mode);
+            registry.expand_source(source, None).unwrap().records.len()
+        };
+        assert_eq!(count(MatchMode::Exact), 1);
+        assert_eq!(count(MatchMode::Suffix), 2);
+        assert_eq!(count(MatchMode::LastSegment), 3);
+    }
+
+    #[test]
+    fn uses_per_registration_match_modes() {
+        let source = "
+            #[a::bar]
+            #[a::baz]
+            fn main() {
+                a::foo!();
+                a::b::foo!();
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("b::foo", identity)
+            .attribute("bar", |_, item| item)
+            .attribute("baz", |_, item| item)
+            .match_mode(MacroKind::Functionlike, "b::foo", MatchMode::Suffix)
+            .match_mode(MacroKind::Attribute, "bar", MatchMode::LastSegment);
+        let records = registry.expand_source(source, None).unwrap().records;
+        let paths: Vec<&str> = records
+            .iter()
+            .map(|record| &record.macro_path[..])
+            .collect();
+        assert_eq!(paths, ["bar", "b::foo"]);
+    }
+
+    #[test]
+    fn reports_near_misses() {
+        let source = "
+            #[derive(mycrate::Foo)]
+            #[other::bar]
+            struct A;
+            fn main() {
+                mycrate::foo!();
+                foo!();
+                baz!();
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", identity)
+            .derive("Foo", identity)
+            .attribute("bar", |_, item| item);
+        let scan = registry.expand_source(source, None).unwrap();
+        assert_eq!(scan.records.len(), 1);
+        let near_misses: Vec<(MacroKind, &str, &str)> = scan
+            .near_misses
+            .iter()
+            .map(|near_miss| {
+                (
+                    near_miss.kind,
+                    &near_miss.found_path[..],
+                    &near_miss.registered_path[..],
+                )
+            })
+            .collect();
+        assert_eq!(
+            near_misses,
+            [
+                (MacroKind::Derive, "mycrate::Foo", "Foo"),
+                (MacroKind::Attribute, "other::bar", "bar"),
+                (MacroKind::Functionlike, "mycrate::foo", "foo"),
+            ]
+        );
+        assert_eq!(
+            scan.near_misses[2].to_string(),
+            "`mycrate::foo` was not expanded because it doesn't match any registered function-like \
+             macro; did you mean `foo`?"
+        );
+    }
+
+    #[test]
+    fn lists_unregistered_invocations() {
+        let source = "
+            #[derive(Debug, Foo, Helped)]
+            #[inline]
+            #[rustfmt::skip]
+            #[other::bar]
+            #[helper]
+            struct A;
+            macro_rules! m {
+                () => {};
+            }
+            fn main() {
+                foo!(println!());
+                mycrate::foo!();
+                let x: baz!() = 1;
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", identity)
+            .derive("Foo", identity)
+            .derive("Helped", identity)
+            .derive_helpers("Helped", &["helper"])
+            .attribute("bar", |_, item| item);
+        let scan = registry.expand_source(source, None).unwrap();
+        let unregistered: Vec<(MacroKind, &str, usize, Option<MacroPosition>)> = scan
+            .unregistered
+            .iter()
+            .map(|invocation| {
+                (
+                    invocation.kind,
+                    &invocation.path[..],
+                    invocation.start.line,
+                    invocation.position,
+                )
+            })
+            .collect();
+        assert_eq!(
+            unregistered,
+            [
+                (MacroKind::Derive, "Debug", 2, None),
+                (MacroKind::Attribute, "other::bar", 5, None),
+                (
+                    MacroKind::Functionlike,
+                    "mycrate::foo",
+                    13,
+                    Some(MacroPosition::Statement)
+                ),
+                (
+                    MacroKind::Functionlike,
+                    "baz",
+                    14,
+                    Some(MacroPosition::Type)
+                ),
+            ]
+        );
+        assert_eq!(scan.unregistered[3].to_string(), "`baz!`");
+        assert_eq!(scan.unregistered[3].start.column, 23);
+    }
+
+    #[test]
+    fn expands_a_single_line() {
+        let source = "
+            #[derive(Foo)] #[bar]
+            struct A;
+            fn main() {
+                foo!(1);
+                foo!(2); foo!(3);
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", |ts| {
+                assert_ne!(ts.to_string(), "1");
+                ts
+            })
+            .derive("Foo", |_| panic!("expanded"))
+            .attribute("bar", |_, item| item)
+            .require_all_invoked(true);
+        let report = registry.expand_single_source(source, 6).unwrap();
+        let inputs: Vec<String> = report.files[0]
+            .records
+            .iter()
+            .map(|record| record.input.to_string())
+            .collect();
+        assert_eq!(inputs, ["2", "3"]);
+        let outcomes: Vec<(usize, ExpansionOutcome)> = report
+            .call_sites()
+            .map(|site| (site.start.line, site.outcome.clone()))
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                (2, ExpansionOutcome::FilteredOut),
+                (2, ExpansionOutcome::FilteredOut),
+                (5, ExpansionOutcome::FilteredOut),
+                (6, ExpansionOutcome::Expanded),
+                (6, ExpansionOutcome::Expanded),
+            ]
+        );
+
+        let error = registry.expand_single_source(source, 2).unwrap_err();
+        assert!(matches!(error, Error::ExpansionPanicked { .. }));
+    }
+
+    #[test]
+    fn discovers_invocations_without_expanding() {
+        let source = "
+            #[derive(Foo)]
+            #[bar]
+            struct A;
+            #[cfg(feature = \"foo\")]
+            #[bar]
+            struct B;
+            fn main() {
+                foo!(mycrate::foo!());
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", |_| panic!("expanded"))
+            .derive("Foo", |_| panic!("expanded"))
+            .attribute("bar", |_, _| panic!("expanded"))
+            .derive_helpers("Foo", &["bar"])
+            .scan_macro_tokens(true)
+            .cfgs(CfgSet::new());
+        let invocations: Vec<(MacroKind, String, usize)> = registry
+            .discover_source(source)
+            .unwrap()
+            .into_iter()
+            .map(|invocation| (invocation.kind, invocation.path, invocation.start.line))
+            .collect();
+        assert_eq!(
+            invocations,
+            [
+                (MacroKind::Derive, "Foo".to_owned(), 2),
+                (MacroKind::Attribute, "bar".to_owned(), 3),
+                (MacroKind::Functionlike, "foo".to_owned(), 9),
+                (MacroKind::Functionlike, "mycrate::foo".to_owned(), 9),
+            ]
+        );
+    }
+
+    #[test]
+    fn requires_all_macros_to_be_invoked() {
+        let source = "
+            fn main() {
+                foo!();
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", identity)
+            .functionlike("fooo", identity)
+            .derive("Foo", identity);
+        let report = registry
+            .expand_source(source, None)
+            .unwrap()
+            .into_report(PathBuf::new());
+        registry
+            .check_all_invoked(report.invocation_counts().into_keys())
+            .unwrap();
+
+        registry.require_all_invoked(true);
+        match registry.check_all_invoked(report.invocation_counts().into_keys()) {
+            Err(e @ Error::NotInvoked(_)) => assert_eq!(
+                e.to_string(),
+                "these registered macros were never invoked: function-like `fooo`, derive `Foo`"
+            ),
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn counts_invocations() {
+        let source = "
+            #[derive(Foo)]
+            struct A;
+            fn main() {
+                foo!();
+                foo!();
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", identity)
+            .derive("Foo", identity)
+            .functionlike("bar", identity);
+        let report = ExpansionReport {
+            files: vec![registry
+                .expand_source(source, None)
+                .unwrap()
+                .into_report(PathBuf::from("a.rs"))],
+        };
+        assert_eq!(report.expansions(), 3);
+        assert_eq!(report.invocations("foo"), 2);
+        assert_eq!(report.invocations("bar"), 0);
+        assert_eq!(
+            report.invocation_counts().into_iter().collect::<Vec<_>>(),
+            [
+                ((MacroKind::Functionlike, "foo"), 2),
+                ((MacroKind::Derive, "Foo"), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_expanding_after_a_panic() {
+        let source = "
+            fn main() {
+                foo!(1);
+                foo!(panic);
+                foo!(2);
+                foo!(panic);
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry.functionlike("foo", |ts: TokenStream| {
+            if ts.to_string() == "panic" {
+                panic!("told to panic");
+            }
+            ts
+        });
+        let scan = registry.expand_source(source, None).unwrap();
+        assert_eq!(scan.records.len(), 2);
+        assert_eq!(scan.panics.len(), 2);
+        match Error::from_panics(scan.panics.clone()) {
+            Some(Error::ExpansionPanicked {
+                message,
+                location,
+                panics,
+                ..
+            }) => {
+                assert_eq!(message, "told to panic");
+                assert!(location.unwrap().file.ends_with("registry.rs"));
+                assert_eq!(panics.len(), 2);
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn minimizes_panicking_inputs() {
+        fn contains_bad(tokens: TokenStream) -> bool {
+            tokens.into_iter().any(|tt| match tt {
+                TokenTree::Ident(ident) => ident == "bad",
+                TokenTree::Group(group) => contains_bad(group.stream()),
+                _ => false,
+            })
+        }
+        let source = "
+            #[derive(Foo)]
+            struct S { a: u8, b: Vec<bad>, c: [u8; 4] }
+            // runtime-macros: expect-panic
+            #[derive(Foo)]
+            struct T(bad);
+        ";
+        let mut registry = MacroRegistry::new();
+        registry
+            .derive("Foo", |ts: TokenStream| {
+                if contains_bad(ts) {
+                    panic!("found `bad`");
+                }
+                panic!("unexpected input");
+            })
+            .panic_policy(PanicPolicy::Record)
+            .quiet_expected_panics(true)
+            .minimize_panics(true);
+        let scan = registry.expand_source(source, None).unwrap();
+        assert_eq!(scan.panics.len(), 2);
+        let minimized = scan.panics[0].minimized_input.as_ref().unwrap();
+        assert_eq!(minimized.to_string(), "{ bad }");
+        assert!(scan.panics[0]
+            .to_string()
+            .ends_with("found `bad` (minimized input: `{ bad }`)"));
+        assert!(scan.panics[1].minimized_input.is_none());
+    }
+
+    #[test]
+    fn distinguishes_errors() {
+        let mut registry = MacroRegistry::new();
+        registry.derive("Foo", identity);
+        match registry.expand_source("struct", Some(Path::new("a.rs"))) {
+            Err(Error::ParseError { path, .. }) => assert_eq!(path.unwrap(), Path::new("a.rs")),
+            result => panic!("unexpected result: {:?}", result),
+        }
+        match registry.expand_source("#[derive(Foo = 1)] struct A;", None) {
+            Err(Error::ParseError { path: None, .. }) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        registry.functionlike("not a path", identity);
+        match registry.expand_source("", None) {
+            Err(Error::InvalidMacroPath {
+                kind, macro_path, ..
+            }) => {
+                assert_eq!(kind, MacroKind::Functionlike);
+                assert_eq!(macro_path, "not a path");
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn records_outcomes_per_call_site() {
+        let source = "
+            fn main() {
+                foo!(1);
+                foo!(panic);
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry.functionlike("foo", |ts: TokenStream| {
+            if ts.to_string() == "panic" {
+                panic!("told to panic");
+            }
+            ts
+        });
+        let report = ExpansionReport {
+            files: vec![registry
+                .expand_source(source, None)
+                .unwrap()
+                .into_report(PathBuf::new())],
+        };
+        assert!(registry
+            .check_panics(report.files[0].panics.iter())
+            .is_err());
+
+        registry.panic_policy(PanicPolicy::Record);
+        registry
+            .check_panics(report.files[0].panics.iter())
+            .unwrap();
+        let outcomes: Vec<ExpansionOutcome> = report
+            .call_sites()
+            .map(|site| site.outcome.clone())
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                ExpansionOutcome::Expanded,
+                ExpansionOutcome::Panicked("told to panic".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn records_invocations_that_werent_expanded() {
+        let source = "
+            fn main() {
+                foo!(1);
+                // runtime-macros: skip
+                foo!(2);
+                other::foo!(3);
+                foo!(4);
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry.functionlike("foo", identity);
+        let scan = registry.expand_source(source, None).unwrap();
+        let sites: Vec<(usize, ExpansionOutcome, usize)> = scan
+            .call_sites
+            .iter()
+            .map(|site| (site.start.line, site.outcome.clone(), site.order))
+            .collect();
+        assert_eq!(
+            sites,
+            [
+                (3, ExpansionOutcome::Expanded, 0),
+                (5, ExpansionOutcome::FilteredOut, 1),
+                (6, ExpansionOutcome::SkippedUnmatched, 1),
+                (7, ExpansionOutcome::Expanded, 1),
+            ]
+        );
+        assert_eq!(scan.records.len(), 2);
+        assert_eq!(scan.near_misses.len(), 1);
+    }
+
+    #[test]
+    fn records_line_and_column_locations() {
+        let source = "fn main() {\n    foo!(1);\n}\n\n#[derive(Foo)]\nstruct A;\n";
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", identity)
+            .derive("Foo", identity);
+        let scan = registry.expand_source(source, None).unwrap();
+        let locations: Vec<_> = scan
+            .records
+            .iter()
+            .map(|record| {
+                (
+                    (record.start.line, record.start.column),
+                    (record.end.line, record.end.column),
+                )
+            })
+            .collect();
+        assert_eq!(locations, [((2, 4), (2, 11)), ((5, 9), (5, 12))]);
+        assert_eq!(scan.call_sites[1].start, scan.records[1].start);
+    }
+
+    #[test]
+    fn runs_several_registries_over_one_session() {
+        use std::cell::Cell;
+
+        let calls = Cell::new(0);
+        let mut session = Session::new();
+        session.source(
+            ParsedSource::parse("#[derive(Foo)] struct A; foo!();".to_owned(), None).unwrap(),
+        );
+
+        let mut functionlike = MacroRegistry::new();
+        functionlike.functionlike("foo", |ts| {
+            calls.set(calls.get() + 1);
+            ts
+        });
+        let mut derive = MacroRegistry::new();
+        derive.derive("Foo", |ts| {
+            calls.set(calls.get() + 1);
+            ts
+        });
+        assert_eq!(session.run(&functionlike).unwrap().invocations("foo"), 1);
+        assert_eq!(session.run(&derive).unwrap().invocations("Foo"), 1);
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn rescans_attribute_output() {
+        let source = "
+            #[outer]
+            #[derive(Foo)]
+            struct A;
+        ";
+        let mut registry = MacroRegistry::new();
+        registry
+            .attribute("outer", |_, item: TokenStream| {
+                format!("#[inner] {} fn generated() {{ foo!(); }}", item)
+                    .parse()
+                    .unwrap()
+            })
+            .attribute("inner", |_, item| item)
+            .derive("Foo", identity)
+            .functionlike("foo", identity);
+        let counts = |registry: &MacroRegistry| {
+            let report = registry
+                .expand_source(source, None)
+                .unwrap()
+                .into_report(PathBuf::new());
+            ["outer", "inner", "Foo", "foo"]
+                .iter()
+                .map(|path| report.invocations(path))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(counts(&registry), [1, 0, 1, 0]);
+
+        registry.rescan_attribute_output(true);
+        assert_eq!(counts(&registry), [1, 1, 1, 1]);
+        let records = registry.expand_source(source, None).unwrap().records;
+        assert_eq!(records[0].input.to_string(), "# [derive (Foo)] struct A ;");
+
+        registry.attribute("forever", |_, item: TokenStream| {
+            format!("#[forever] {}", item).parse().unwrap()
+        });
+        match registry.expand_source("#[forever] struct B;", None) {
+            Err(Error::MacroFailed {
+                macro_path, error, ..
+            }) => {
+                assert_eq!(macro_path, "forever");
+                assert_eq!(
+                    error.to_string(),
+                    "recursion limit reached while expanding `#[forever]`"
+                );
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn rescans_functionlike_output() {
+        let source = "
+            fixtures!(A, B);
+            fn main() {
+                fixtures!(C);
+                assert!(fixtures!(D));
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("fixtures", |ts: TokenStream| {
+                let names: Vec<String> = ts
+                    .into_iter()
+                    .filter_map(|tt| match tt {
+                        TokenTree::Ident(ident) => Some(ident.to_string()),
+                        _ => None,
+                    })
+                    .collect();
+                names
+                    .iter()
+                    .map(|name| format!("#[derive(Foo)] #[bar] struct {}; foo!();", name))
+                    .collect::<String>()
+                    .parse()
+                    .unwrap()
+            })
+            .functionlike("foo", identity)
+            .derive("Foo", identity)
+            .attribute("bar", |_, item| item)
+            .scan_macro_tokens(true);
+        let counts = |registry: &MacroRegistry| {
+            let report = registry
+                .expand_source(source, None)
+                .unwrap()
+                .into_report(PathBuf::new());
+            ["fixtures", "Foo", "bar", "foo"]
+                .iter()
+                .map(|path| report.invocations(path))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(counts(&registry), [3, 0, 0, 0]);
+
+        registry.rescan_functionlike_output(true);
+        assert_eq!(counts(&registry), [3, 4, 4, 4]);
+
+        registry.functionlike("forever", |_| "forever!()".parse().unwrap());
+        match registry.expand_source("fn main() { forever!() }", None) {
+            Err(Error::MacroFailed {
+                macro_path, error, ..
+            }) => {
+                assert_eq!(macro_path, "forever");
+                assert_eq!(
+                    error.to_string(),
+                    "recursion limit reached while expanding `forever!`"
+                );
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn chains_attribute_macros() {
+        let source = "
+            #[first]
+            #[second]
+            #[derive(Foo)]
+            struct A;
+        ";
+        let mut registry = MacroRegistry::new();
+        registry
+            .attribute("first", |_, item: TokenStream| {
+                format!("{} struct B;", item).parse().unwrap()
+            })
+            .attribute("second", |_, item| item)
+            .derive("Foo", identity);
+        let inputs = |registry: &MacroRegistry| {
+            registry
+                .expand_source(source, None)
+                .unwrap()
+                .records
+                .into_iter()
+                .map(|record| (record.macro_path, record.input.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let original = "# [first] # [second] # [derive (Foo)] struct A ;";
+        assert_eq!(
+            inputs(&registry),
+            [
+                ("first".to_owned(), original.to_owned()),
+                ("second".to_owned(), original.to_owned()),
+                ("Foo".to_owned(), original.to_owned()),
+            ]
+        );
+
+        registry.chain_attributes(true);
+        assert_eq!(
+            inputs(&registry),
+            [
+                (
+                    "first".to_owned(),
+                    "# [second] # [derive (Foo)] struct A ;".to_owned()
+                ),
+                (
+                    "second".to_owned(),
+                    "# [derive (Foo)] struct A ;".to_owned()
+                ),
+                ("Foo".to_owned(), original.to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn scans_tokens_of_unregistered_macros() {
+        let source = "
+            macro_rules! m {
+                () => { foo!(0) };
+            }
+
+            fn main() {
+                assert!(foo!(1) != bar::foo!(2));
+                println!(\"{:?}\", vec![foo!(3)]);
+                foo!(foo!(4));
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry.functionlike("foo", identity);
+        let scan = registry.expand_source(source, None).unwrap();
+        assert_eq!(scan.records.len(), 1);
+
+        registry.scan_macro_tokens(true);
+        let scan = registry.expand_source(source, None).unwrap();
+        let inputs: Vec<String> = scan
+            .records
+            .iter()
+            .map(|record| record.input.to_string())
+            .collect();
+        assert_eq!(inputs, ["1", "3", "foo ! (4)"]);
+        assert_eq!(scan.records[0].start.column, 24);
+        assert_eq!(scan.near_misses.len(), 1);
+        assert_eq!(scan.near_misses[0].found_path, "bar::foo");
+    }
+
+    #[test]
+    fn scans_macro_rules_bodies() {
+        let source = "
+            macro_rules! check {
+                (foo!($e:expr)) => {};
+                ($e:expr) => { foo!($e) };
+                ($($e:expr),*) => { $(foo!($e);)* };
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry.functionlike("foo", identity);
+        assert!(registry
+            .expand_source(source, None)
+            .unwrap()
+            .records
+            .is_empty());
+
+        registry.scan_macro_rules(true);
+        let inputs: Vec<String> = registry
+            .expand_source(source, None)
+            .unwrap()
+            .records
+            .iter()
+            .map(|record| record.input.to_string())
+            .collect();
+        assert_eq!(inputs, ["$ e", "$ e"]);
+    }
+
+    #[test]
+    fn scans_unparseable_files_leniently() {
+        let source = "
+            #[derive(Foo)]
+            struct A;
+
+            #[cfg(nope)]
+            #[derive(Foo)]
+            struct B;
+
+            fn main() {
+                let x = 1 +;
+                foo!(x);
+            }
+
+            #[bar(1)]
+            impl Trait for Type {
+                fn f() { foo!(y); }
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry
+            .derive("Foo", identity)
+            .functionlike("foo", identity)
+            .attribute("bar", |_, item| item)
+            .cfgs(CfgSet::new());
+        match registry.expand_source(source, None) {
+            Err(Error::ParseError { .. }) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        registry.lenient_parsing(true);
+        let scan = registry.expand_source(source, None).unwrap();
+        assert!(scan.parse_error.is_some());
+        let inputs: Vec<(&str, String)> = scan
+            .records
+            .iter()
+            .map(|record| (&record.macro_path[..], record.input.to_string()))
+            .collect();
+        assert_eq!(
+            inputs,
+            [
+                ("Foo", "# [derive (Foo)] struct A ;".to_owned()),
+                ("foo", "x".to_owned()),
+                (
+                    "bar",
+                    "# [bar (1)] impl Trait for Type { fn f () { foo ! (y) ; } }".to_owned()
+                ),
+                ("foo", "y".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_derives_structs_enums_and_unions() {
+        let source = "
+            #[derive(Foo)]
+            struct A;
+            #[derive(Foo)]
+            enum B {}
+            #[derive(Foo)]
+            union C { x: u8 }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry.derive("Foo", identity);
+        assert_eq!(
+            registry.expand_source(source, None).unwrap().records.len(),
+            3
+        );
+
+        // Anywhere else, the derive is reported, and the rest of the file is still expanded.
+        for source in [
+            "#[derive(Foo)] impl A {} #[derive(Foo)] struct S;",
+            "#[derive(Foo)] fn f(); #[derive(Foo)] struct S;",
+        ] {
+            let scan = registry.expand_source(source, None).unwrap();
+            assert_eq!(scan.records.len(), 1);
+            assert_eq!(scan.warnings.len(), 1);
+            let warning = &scan.warnings[0];
+            assert_eq!(
+                (warning.kind, &warning.macro_path[..], &warning.lint[..]),
+                (MacroKind::Derive, "Foo", "misplaced_derive")
+            );
+            assert_eq!(
+                warning.message,
+                "`derive` may only be applied to `struct`s, `enum`s and `union`s"
+            );
+        }
+
+        // Nothing can be derived without any registered derives.
+        let scan = MacroRegistry::new()
+            .expand_source("#[derive(Foo)] impl A {}", None)
+            .unwrap();
+        assert!(scan.records.is_empty());
+    }
+
+    #[test]
+    fn scans_verbatim_items() {
+        let source = "
+            #[bar]
+            fn f();
+
+            #[bar]
+            impl const Trait for Type {
+                fn g() { foo!(); }
+            }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", identity)
+            .attribute("bar", |_, item| item);
+        let inputs: Vec<(String, String)> = registry
+            .expand_source(source, None)
+            .unwrap()
+            .records
+            .into_iter()
+            .map(|record| (record.macro_path, record.input.to_string()))
+            .collect();
+        assert_eq!(
+            inputs,
+            [
+                ("bar".to_owned(), "# [bar] fn f () ;".to_owned()),
+                (
+                    "bar".to_owned(),
+                    "# [bar] impl const Trait for Type { fn g () { foo ! () ; } }".to_owned()
+                ),
+                ("foo".to_owned(), "".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn expands_source_code() {
+        let report = MacroRegistry::new()
+            .functionlike("foo", |ts| ts)
+            .emulate_source("fn main() { foo!(1); }\nfoo!(2);")
+            .unwrap();
+        assert_eq!(report.invocations("foo"), 2);
+        assert_eq!(report.files[0].path, PathBuf::new());
+        assert!(matches!(
+            MacroRegistry::new().emulate_source("fn"),
+            Err(Error::ParseError { path: None, .. })
+        ));
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn expands_corpus_files() {
+        let dir = std::env::temp_dir().join(format!("runtime-macros-corpus-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a"), "1 + 1").unwrap();
+        fs::write(dir.join("b"), "panic").unwrap();
+        fs::write(dir.join("c"), "").unwrap();
+
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", |ts| {
+                if ts.to_string() == "panic" {
+                    panic!("bad input");
+                }
+                ts
+            })
+            .panic_policy(PanicPolicy::Record);
+        let report = registry.emulate_from_corpus(&dir).unwrap();
+        let outcomes: Vec<(PathBuf, ExpansionOutcome)> = report
+            .files
+            .iter()
+            .map(|file| (file.path.clone(), file.call_sites[0].outcome.clone()))
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                (dir.join("a"), ExpansionOutcome::Expanded),
+                (
+                    dir.join("b"),
+                    ExpansionOutcome::Panicked("bad input".to_owned())
+                ),
+                (dir.join("c"), ExpansionOutcome::Expanded),
+            ]
+        );
+
+        fs::write(dir.join("d"), "(").unwrap();
+        match registry.emulate_from_corpus(&dir) {
+            Err(Error::ParseError { path, .. }) => assert_eq!(path, Some(dir.join("d"))),
+            other => panic!("expected a parse error, got {:?}", other.map(|_| ())),
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "fs")]
+    #[test]
+    fn records_inputs_to_a_corpus() {
+        let dir = std::env::temp_dir().join(format!("runtime-macros-record-{}", process::id()));
+        let source = "
+            #[bar(x)]
+            fn f() { foo!(1 + 1); }
+        ";
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", identity)
+            .attribute("bar", |_, item| item)
+            .record_corpus(&dir);
+        registry
+            .expand_source(source, Some(Path::new("src/lib.rs")))
+            .unwrap();
+
+        let foo = dir.join("functionlike").join("foo");
+        assert_eq!(
+            fs::read_to_string(foo.join("src_lib.rs-3-21")).unwrap(),
+            "1 + 1"
+        );
+        let bar = dir.join("attribute").join("bar");
+        assert_eq!(
+            fs::read_to_string(bar.join("src_lib.rs-2-12.attr")).unwrap(),
+            "x"
+        );
+        assert!(fs::read_to_string(bar.join("src_lib.rs-2-12"))
+            .unwrap()
+            .contains("fn f"));
+
+        let replayed = MacroRegistry::new()
+            .functionlike("foo", identity)
+            .emulate_from_corpus(&foo)
+            .unwrap();
+        assert_eq!(replayed.expansions(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn validates_output_by_position() {
+        let source = "
+            foo!(item);
+            fn main() {
+                let x: foo!(ty) = foo!(expr);
+                let foo!(pat) = x;
+                foo!(stmt);
+            }
+        ";
+        let output = |ts: TokenStream| -> TokenStream {
+            match ts.to_string().as_str() {
+                "item" => "fn f() {}",
+                "ty" => "u32",
+                "expr" => "1 + 1",
+                "pat" => "_",
+                _ => "let y = 2; y",
+            }
+            .parse()
+            .unwrap()
+        };
+        let mut registry = MacroRegistry::new();
+        registry.functionlike("foo", output).validate_output(true);
+        let positions: Vec<Option<MacroPosition>> = registry
+            .expand_source(source, None)
+            .unwrap()
+            .call_sites
+            .into_iter()
+            .map(|site| site.position)
+            .collect();
+        assert_eq!(
+            positions,
+            [
+                Some(MacroPosition::Item),
+                Some(MacroPosition::Type),
+                Some(MacroPosition::Expression),
+                Some(MacroPosition::Pattern),
+                Some(MacroPosition::Statement),
+            ]
+        );
+
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", |_| "fn f() {}".parse().unwrap())
+            .validate_output(true);
+        match registry.expand_source("fn main() { let x = foo!(); }", None) {
+            Err(Error::MacroFailed { error, .. }) => assert!(error
+                .to_string()
+                .starts_with("the expansion isn't valid as an expression")),
+            other => panic!("expected an invalid expansion, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn strips_derive_attributes() {
+        let source = "
+            #[derive(Debug, Foo)]
+            #[doc = \"A\"]
+            #[derive(Clone)]
+            struct A;
+        ";
+        let mut registry = MacroRegistry::new();
+        registry.derive("Foo", identity);
+        let input = |registry: &MacroRegistry| {
+            registry.expand_source(source, None).unwrap().records[0]
+                .input
+                .to_string()
+        };
+        assert!(input(&registry).contains("derive"));
+
+        registry.strip_derive_attributes(true);
+        assert_eq!(input(&registry), "# [doc = \"A\"] struct A ;");
+    }
+
+    #[test]
+    fn strips_invoked_attribute() {
+        let source = "
+            #[foo(a)]
+            #[bar]
+            #[foo(b)]
+            struct A;
+        ";
+        let mut registry = MacroRegistry::new();
+        registry.attribute("foo", |_, item| item);
+        let inputs = |registry: &MacroRegistry| {
+            registry
+                .expand_source(source, None)
+                .unwrap()
+                .records
+                .into_iter()
+                .map(|record| record.input.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            inputs(&registry)[0],
+            "# [foo (a)] # [bar] # [foo (b)] struct A ;"
+        );
+
+        registry.strip_invoked_attribute(true);
+        assert_eq!(
+            inputs(&registry),
+            [
+                "# [bar] # [foo (b)] struct A ;",
+                "# [foo (a)] # [bar] struct A ;",
+            ]
+        );
+    }
+
+    #[test]
+    fn passes_attribute_arguments_without_delimiters() {
+        let source = "
+            #[foo(a, b)]
+            #[foo[c]]
+            #[foo { d }]
+            #[foo]
+            struct A;
+        ";
+        use std::cell::RefCell;
+
+        let args = RefCell::new(Vec::new());
+        let mut registry = MacroRegistry::new();
+        registry.attribute("foo", |attr: TokenStream, item| {
+            args.borrow_mut().push(attr.to_string());
+            item
+        });
+        registry.expand_source(source, None).unwrap();
+        drop(registry);
+        assert_eq!(args.into_inner(), ["a , b", "c", "d", ""]);
+    }
+
+    #[test]
+    fn ignores_derive_helpers() {
+        let source = "
+            #[derive(Foo)]
+            #[helper]
+            struct A {
+                #[helper]
+                x: u32,
+            }
+
+            #[helper]
+            fn f() {}
+        ";
+        let mut registry = MacroRegistry::new();
+        registry
+            .derive("Foo", identity)
+            .attribute("helper", |_, item| item)
+            .field_attributes(true);
+        let invocations = |registry: &MacroRegistry| {
+            registry
+                .expand_source(source, None)
+                .unwrap()
+                .into_report(PathBuf::new())
+                .invocations("helper")
+        };
+        assert_eq!(invocations(&registry), 3);
+
+        registry.derive_helpers("Foo", &["helper"]);
+        assert_eq!(invocations(&registry), 1);
+    }
+
+    #[test]
+    fn sets_options_by_fidelity() {
+        let source = "
+            #[outer(x)]
+            #[cfg_attr(all(), derive(Foo))]
+            #[derive(Foo)]
+            struct A;
+        ";
+        let mut registry = MacroRegistry::new();
+        registry
+            .derive("Foo", identity)
+            .attribute("outer", |_, item| item);
+        let inputs = |registry: &MacroRegistry| {
+            registry
+                .expand_source(source, None)
+                .unwrap()
+                .records
+                .into_iter()
+                .map(|record| record.input.to_string())
+                .collect::<Vec<_>>()
+        };
+        let written =
+            "# [outer (x)] # [cfg_attr (all () , derive (Foo))] # [derive (Foo)] struct A ;";
+        assert_eq!(inputs(&registry), [written, written]);
+
+        registry.fidelity(Fidelity::Rustc);
+        assert_eq!(
+            inputs(&registry),
+            [
+                "# [derive (Foo)] # [derive (Foo)] struct A ;",
+                "struct A ;",
+                "struct A ;",
+            ]
+        );
+
+        registry.fidelity(Fidelity::Lenient);
+        assert_eq!(inputs(&registry), [written, written]);
+    }
+
+    #[test]
+    fn follows_directive_comments() {
+        let source = "
+            fn main() {
+                // runtime-macros: skip
+                foo!(1);
+                // runtime-macros: expect-panic
+                foo!(panic);
+                foo!(2);
+            }
+        ";
+        let output = |ts: TokenStream| -> TokenStream {
+            if ts.to_string() == "panic" {
+                panic!("expected");
+            }
+            ts
+        };
+        let mut registry = MacroRegistry::new();
+        registry.functionlike("foo", output);
+        let scan = registry.expand_source(source, None).unwrap();
+        let report = registry
+            .report(vec![scan.into_report(PathBuf::new())])
+            .unwrap();
+        let file = &report.files[0];
+        assert_eq!(file.records.len(), 1);
+        assert_eq!(file.records[0].input.to_string(), "2");
+        assert_eq!(file.panics.len(), 1);
+        assert!(file.panics[0].expected);
+
+        let source = "
+            // runtime-macros: expect-panic
+            foo!(1);
+        ";
+        match registry.expand_source(source, None) {
+            Err(Error::MacroFailed { error, .. }) => {
+                assert_eq!(
+                    error.to_string(),
+                    "the macro was expected to panic, but it didn't"
+                )
+            }
+            other => panic!("expected a missing panic, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[cfg(feature = "stacker")]
+    #[test]
+    fn grows_the_stack() {
+        // Uses at least 8 MiB of stack, which is more than a test thread has.
+        fn recurse(depth: usize) -> usize {
+            let frame = std::hint::black_box([depth as u8; 64 * 1024]);
+            match depth {
+                0 => frame[0] as usize,
+                _ => recurse(depth - 1) + frame[1] as usize,
+            }
+        }
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("deep", |_| recurse(128).to_string().parse().unwrap())
+            .stack_size(64 * 1024 * 1024);
+        let records = registry.expand_source("deep!();", None).unwrap().records;
+        assert_eq!(records[0].output.to_string(), "8256");
+    }
+
+    #[test]
+    fn propagates_panics() {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", |_| panic!("told to panic"))
+            .panic_policy(PanicPolicy::Propagate);
+        let payload = panic::catch_unwind(AssertUnwindSafe(|| {
+            registry.expand_source("foo!(); foo!();", None).map(|_| ())
+        }))
+        .unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"told to panic"));
+
+        // Expected panics are still caught.
+        let scan = registry
+            .expand_source("// runtime-macros: expect-panic\nfoo!();", None)
+            .unwrap();
+        assert!(scan.panics[0].expected);
+    }
+
+    #[test]
+    fn sets_environment_variables() {
+        use std::env;
+
+        // Other threads could be reading the environment while it changes.
+        sandbox::locked(|| {
+            let (set, removed) = (
+                "RUNTIME_MACROS_TEST_SET_VAR",
+                "RUNTIME_MACROS_TEST_REMOVED_VAR",
+            );
+            env::set_var(removed, "before");
+            let mut registry = MacroRegistry::new();
+            registry
+                .functionlike("foo", move |_| {
+                    let value = |key| env::var(key).unwrap_or_else(|_| "unset".to_owned());
+                    format!("{:?} {:?}", value(set), value(removed))
+                        .parse()
+                        .unwrap()
+                })
+                .env(set, "during")
+                .env_remove(removed);
+            let records = registry.expand_source("foo!();", None).unwrap().records;
+            assert_eq!(records[0].output.to_string(), "\"during\" \"unset\"");
+            assert!(env::var_os(set).is_none());
+            assert_eq!(env::var(removed).unwrap(), "before");
+            env::remove_var(removed);
+        });
+    }
+
+    #[test]
+    fn changes_the_working_directory() {
+        use std::env;
+
+        // Other tests could change the working directory while this one checks it.
+        sandbox::locked(|| {
+            let dir = env::temp_dir().join(format!("runtime-macros-working-dir-{}", process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("greeting.txt"), "\"hello\"").unwrap();
+            let before = env::current_dir().unwrap();
+            let mut registry = MacroRegistry::new();
+            registry
+                .functionlike("read", |_| {
+                    fs::read_to_string("greeting.txt").unwrap().parse().unwrap()
+                })
+                .working_dir_with(|path| path.and_then(Path::parent).map(Path::to_owned));
+            let records = registry
+                .expand_source("read!();", Some(&dir.join("lib.rs")))
+                .unwrap()
+                .records;
+            assert_eq!(records[0].output.to_string(), "\"hello\"");
+            assert_eq!(env::current_dir().unwrap(), before);
+
+            registry.working_dir(dir.join("missing"));
+            match registry.expand_source("read!();", None) {
+                Err(Error::IoError { path, .. }) => assert_eq!(path, Some(dir.join("missing"))),
+                other => panic!(
+                    "expected an I/O error, got {:?}",
+                    other.map(|scan| scan.records)
+                ),
+            }
+            fs::remove_dir_all(&dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn shuffles_items_with_a_seed() {
+        let source = "
+            foo!(0);
+            mod a { foo!(1); foo!(2); }
+            foo!(3);
+            #[bar]
+            fn f() { foo!(5); }
+            foo!(6);
+            foo!(7);
+        ";
+        let orders = |seed: Option<u64>| {
+            let mut registry = MacroRegistry::new();
+            registry
+                .functionlike("foo", identity)
+                .attribute("bar", |_, item| item);
+            if let Some(seed) = seed {
+                registry.shuffle(seed);
+            }
+            let scan = registry.expand_source(source, None).unwrap();
+            let inputs: Vec<String> = scan
+                .records
+                .iter()
+                .filter(|record| record.kind == MacroKind::Functionlike)
+                .map(|record| record.input.to_string())
+                .collect();
+            assert_eq!(inputs, ["0", "1", "2", "3", "5", "6", "7"]);
+            assert!(scan
+                .records
+                .iter()
+                .zip(scan.call_sites.iter())
+                .all(|(record, site)| record.order == site.order));
+            scan.call_sites
+                .iter()
+                .map(|site| site.order)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(orders(None), (0..8).collect::<Vec<_>>());
+        let shuffled = orders(Some(3));
+        assert_ne!(shuffled, orders(None));
+        assert_eq!(shuffled, orders(Some(3)));
+        // Everything inside an item keeps its order.
+        assert_eq!(shuffled[1] + 1, shuffled[2]);
+        assert_eq!(shuffled[4] + 1, shuffled[5]);
+        let mut sorted = shuffled;
+        sorted.sort();
+        assert_eq!(sorted, (0..8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn checks_determinism() {
+        use std::cell::Cell;
+
+        let calls = Cell::new(0);
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("same", identity)
+            .functionlike("counter", |_| {
+                calls.set(calls.get() + 1);
+                format!("let x = {};", calls.get()).parse().unwrap()
+            })
+            .check_determinism(true);
+        let scan = registry.expand_source("same!(1); same!(2);", None).unwrap();
+        assert_eq!(scan.records.len(), 2);
+
+        match registry.expand_source("counter!();", None) {
+            Err(Error::MacroFailed {
+                macro_path, error, ..
+            }) => {
+                assert_eq!(macro_path, "counter");
+                assert!(error.to_string().ends_with(": let x = [-1-]{+2+} ;"));
+            }
+            other => panic!(
+                "expected a failure, got {:?}",
+                other.map(|scan| scan.records)
+            ),
+        }
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[cfg(feature = "fuzz")]
+    #[test]
+    fn fuzzes_inputs() {
+        use std::cell::RefCell;
+
+        let inputs = RefCell::new(Vec::new());
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", |ts: TokenStream| {
+                inputs.borrow_mut().push(ts.to_string());
+                if ts.to_string() != "1 , x" {
+                    panic!("unexpected input");
+                }
+                ts
+            })
+            .fuzz(10, 1);
+        let scan = registry.expand_source("foo!(1, x);", None).unwrap();
+        assert_eq!(scan.records.len(), 1);
+        assert!(scan.panics.is_empty());
+        assert!(scan.warnings.is_empty());
+        let inputs = inputs.borrow();
+        assert_eq!(inputs.len(), 11);
+        assert!(inputs[1..].iter().all(|input| input != "1 , x"));
+    }
+
+    #[test]
+    fn checks_idempotency() {
+        let mut registry = MacroRegistry::new();
+        registry
+            .attribute("inline_all", |_, item| item)
+            .attribute("with_getter", |_, item| {
+                let mut output = item;
+                output.extend(
+                    "impl A { fn get(&self) {} }"
+                        .parse::<TokenStream>()
+                        .unwrap(),
+                );
+                output
+            })
+            .attribute("struct_only", |_, item| {
+                let item: syn::ItemStruct = syn::parse2(item).unwrap();
+                quote::quote!(#item impl Clone for A { fn clone(&self) -> Self { A } })
+            })
+            .check_idempotency(true);
+        let scan = registry
+            .expand_source("#[inline_all] fn f() {}", None)
+            .unwrap();
+        assert_eq!(scan.records.len(), 1);
+
+        let failure = |source| match registry.expand_source(source, None) {
+            Err(Error::MacroFailed {
+                macro_path, error, ..
+            }) => (macro_path, error.to_string()),
+            other => panic!(
+                "expected a failure, got {:?}",
+                other.map(|scan| scan.records)
+            ),
+        };
+        let (macro_path, message) = failure("#[with_getter] struct A;");
+        assert_eq!(macro_path, "with_getter");
+        assert_eq!(
+            message,
+            "the macro's output grew from 19 to 31 tokens when it was applied to its own output"
+        );
+        let (macro_path, message) = failure("#[struct_only] struct A;");
+        assert_eq!(macro_path, "struct_only");
+        assert!(message.starts_with("the macro panicked when it was applied to its own output"));
+    }
+
+    #[test]
+    fn compares_with_reference_implementations() {
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("same", identity)
+            .compare_functionlike("same", |ts| ts)
+            .derive("Size", |_| "const SIZE: usize = 2;".parse().unwrap())
+            .compare_derive("Size", |_| "const SIZE: usize = 1;".parse().unwrap())
+            .attribute("keep", |_, item| item)
+            .compare_attribute("keep", |attr, item| {
+                assert!(attr.is_empty());
+                item
+            });
+        let scan = registry
+            .expand_source("same!(1); #[keep] fn f() {}", None)
+            .unwrap();
+        assert_eq!(scan.records.len(), 2);
+
+        match registry.expand_source("#[derive(Size)] struct A;", None) {
+            Err(Error::MacroFailed {
+                macro_path, error, ..
+            }) => {
+                assert_eq!(macro_path, "Size");
+                assert_eq!(
+                    error.to_string(),
+                    "the macro's output differs from the reference implementation's: \
+                     ... : usize = [-1-]{+2+} ;"
+                );
+            }
+            other => panic!(
+                "expected a failure, got {:?}",
+                other.map(|scan| scan.records)
+            ),
+        }
+    }
+
+    #[test]
+    fn checks_budgets() {
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("small", identity)
+            .functionlike("big", |_| "fn a() {} fn b() {}".parse().unwrap())
+            .budget(
+                MacroKind::Functionlike,
+                "small",
+                Budget::new().max_tokens(3),
+            )
+            .budget(MacroKind::Functionlike, "big", Budget::new().max_ratio(5.0));
+        let scan = registry
+            .expand_source("small!(1); big!(x); small!(1 + 1 + 1);", None)
+            .unwrap();
+        let warnings: Vec<(&str, usize)> = scan
+            .warnings
+            .iter()
+            .map(|warning| (&warning.macro_path[..], warning.start.column))
+            .collect();
+        assert_eq!(warnings, [("big", 11), ("small", 20)]);
+        assert_eq!(
+            scan.warnings[1].to_string(),
+            "`small`: the expansion has 5 tokens, which is more than the budget of 3 (budget)"
+        );
+
+        registry.budget(
+            MacroKind::Functionlike,
+            "small",
+            Budget::new().max_tokens(3).deny(true),
+        );
+        match registry.expand_source("small!(1); small!(1 + 1 + 1);", None) {
+            Err(Error::MacroFailed { macro_path, .. }) => assert_eq!(macro_path, "small"),
+            other => panic!(
+                "expected a failure, got {:?}",
+                other.map(|scan| scan.records)
+            ),
+        }
+    }
+
+    #[test]
+    fn runs_lints() {
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", identity)
+            .lint("no_unsafe", |_, output| {
+                let mut errors = output
+                    .clone()
+                    .into_iter()
+                    .filter(|tt| tt.to_string() == "unsafe")
+                    .map(|tt| syn::Error::new(tt.span(), "the expansion uses `unsafe`"));
+                match errors.next() {
+                    Some(mut first) => {
+                        first.extend(errors);
+                        Err(first)
+                    }
+                    None => Ok(()),
+                }
+            })
+            .lint("never_empty", |context, output| {
+                if output.is_empty() {
+                    Err(syn::Error::new(Span::call_site(), context.macro_path))
+                } else {
+                    Ok(())
+                }
+            });
+        let scan = registry
+            .expand_source("foo!(a); foo!(unsafe {} unsafe {}); foo!();", None)
+            .unwrap();
+        let warnings: Vec<(&str, String, usize)> = scan
+            .warnings
+            .iter()
+            .map(|warning| {
+                (
+                    &warning.lint[..],
+                    warning.message.clone(),
+                    warning.start.column,
+                )
+            })
+            .collect();
+        assert_eq!(
+            warnings,
+            [
+                ("no_unsafe", "the expansion uses `unsafe`".to_owned(), 14),
+                ("no_unsafe", "the expansion uses `unsafe`".to_owned(), 24),
+                ("never_empty", "foo".to_owned(), 36),
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_hygiene() {
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike(
+                "square",
+                |input| quote::quote!({ let value = #input; value * value }),
+            )
+            .check_hygiene(true);
+        let scan = registry
+            .expand_source(
+                "fn f(value: u32) -> u32 { square!(value) + square!(2) }",
+                None,
+            )
+            .unwrap();
+        assert_eq!(scan.warnings.len(), 1);
+        assert_eq!(scan.warnings[0].lint, "hygiene");
+        assert_eq!(scan.warnings[0].start.column, 43);
+        assert!(scan.warnings[0]
+            .message
+            .starts_with("the expansion binds `value`"));
+    }
+
+    #[test]
+    fn flags_slow_expansions() {
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("fast", identity)
+            .functionlike("slow", |ts| {
+                std::thread::sleep(Duration::from_millis(200));
+                ts
+            })
+            .slow_threshold(Duration::from_millis(100));
+        let scan = registry.expand_source("fast!(); slow!();", None).unwrap();
+        assert!(scan.records[1].duration >= Duration::from_millis(200));
+        assert_eq!(scan.warnings.len(), 1);
+        assert_eq!(scan.warnings[0].macro_path, "slow");
+        assert_eq!(scan.warnings[0].lint, "slow");
+
+        registry.deny_slow(true);
+        match registry.expand_source("fast!(); slow!();", None) {
+            Err(Error::MacroFailed { macro_path, .. }) => assert_eq!(macro_path, "slow"),
+            other => panic!(
+                "expected a failure, got {:?}",
+                other.map(|scan| scan.records)
+            ),
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn emits_tracing_spans_and_events() {
+        use std::sync::{
+            atomic::{AtomicU64, Ordering},
+            Mutex,
+        };
+        use tracing::{field, span, Event, Id, Metadata, Subscriber};
+
+        // Writes down the name of each span and the message of each event.
+        #[derive(Default)]
+        struct Log {
+            next_id: AtomicU64,
+            lines: Mutex<Vec<String>>,
+        }
+
+        struct Message<'m>(&'m mut String);
+
+        impl<'m> field::Visit for Message<'m> {
+            fn record_debug(&mut self, field: &field::Field, value: &dyn std::fmt::Debug) {
+                if field.name() == "message" {
+                    *self.0 = format!("{:?}", value);
+                }
+            }
+        }
+
+        impl Subscriber for &'static Log {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+            fn new_span(&self, span: &span::Attributes) -> Id {
+                let name = span.metadata().name();
+                self.lines.lock().unwrap().push(format!("span {}", name));
+                Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+            }
+            fn record(&self, _: &Id, _: &span::Record) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, event: &Event) {
+                let mut message = String::new();
+                event.record(&mut Message(&mut message));
+                self.lines.lock().unwrap().push(message);
+            }
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        let log: &'static Log = Box::leak(Box::default());
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("ok", identity)
+            .functionlike("panics", |_| panic!("oops"))
+            .panic_policy(PanicPolicy::Record);
+        tracing::subscriber::with_default(log, || {
+            registry.expand_source("ok!(1);\npanics!();", None).unwrap();
+        });
+        assert_eq!(
+            *log.lines.lock().unwrap(),
+            [
+                "span parse",
+                "span scan",
+                "span expand",
+                "the macro expanded",
+                "span expand",
+                "the macro panicked",
+                "scanned the file",
+            ]
+        );
+    }
+}
@@ -0,0 +1,601 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+
+//! The structured results returned by an `Expander` run.
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+#[cfg(feature = "serde")]
+use serialize;
+use {
+    pretty,
+    proc_macro2::{LineColumn, Span, TokenStream},
+    std::{collections::BTreeMap, fmt, path::PathBuf, time::Duration},
+    tokens,
+};
+
+/// A summary of everything an [`Expander`] did during a run.
+///
+/// [`Expander`]: struct.Expander.html
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct ExpansionReport {
+    /// One entry for each file that was scanned, in the order they were given to the expander.
+    pub files: Vec<FileReport>,
+}
+
+impl ExpansionReport {
+    /// Returns the total number of macro expansions performed across all the scanned files.
+    pub fn expansions(&self) -> usize {
+        self.files.iter().map(|file| file.records.len()).sum()
+    }
+
+    /// Returns the number of times the macro registered as `macro_path` was expanded across all
+    /// the scanned files.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::emulate_functionlike_macro_expansion;
+    /// # fn custom_assert_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+    /// # /*
+    /// let file = std::fs::File::open("tests/tests.rs").unwrap();
+    /// # */
+    /// # let file = std::fs::File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/src/lib.rs")).unwrap();
+    /// let report =
+    ///     emulate_functionlike_macro_expansion(file, &[("custom_assert", custom_assert_internal)])
+    ///         .unwrap();
+    /// # /*
+    /// assert!(report.invocations("custom_assert") >= 5);
+    /// # */
+    /// ```
+    pub fn invocations(&self, macro_path: &str) -> usize {
+        self.files
+            .iter()
+            .map(|file| file.invocations(macro_path))
+            .sum()
+    }
+
+    /// Returns the number of times each registered macro was expanded across all the scanned files.
+    /// Macros that were never expanded are left out.
+    pub fn invocation_counts(&self) -> BTreeMap<(MacroKind, &str), usize> {
+        let mut counts = BTreeMap::new();
+        for file in self.files.iter() {
+            for (key, count) in file.invocation_counts() {
+                *counts.entry(key).or_insert(0) += count;
+            }
+        }
+        counts
+    }
+
+    /// Returns every call site of a registered macro in any of the scanned files, along with what
+    /// happened to it.
+    pub fn call_sites(&self) -> impl Iterator<Item = &CallSite> {
+        self.files.iter().flat_map(|file| file.call_sites.iter())
+    }
+
+    /// Returns every near miss found in any of the scanned files.
+    pub fn near_misses(&self) -> impl Iterator<Item = &NearMiss> {
+        self.files.iter().flat_map(|file| file.near_misses.iter())
+    }
+
+    /// Returns every invocation in any of the scanned files that didn't match a registered macro.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::{MacroKind, MacroRegistry};
+    /// # fn custom_assert_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+    /// let mut registry = MacroRegistry::new();
+    /// registry.functionlike("custom_assert", custom_assert_internal);
+    /// let report = registry
+    ///     .emulate_source("fn f() { custom_assert!(true); cutsom_assert!(false); }")
+    ///     .unwrap();
+    /// let missed: Vec<&str> = report
+    ///     .unregistered()
+    ///     .filter(|invocation| invocation.kind == MacroKind::Functionlike)
+    ///     .map(|invocation| &invocation.path[..])
+    ///     .collect();
+    /// assert_eq!(missed, ["cutsom_assert"]);
+    /// ```
+    pub fn unregistered(&self) -> impl Iterator<Item = &InvocationInfo> {
+        self.files.iter().flat_map(|file| file.unregistered.iter())
+    }
+
+    /// Returns every warning about an expansion in any of the scanned files.
+    pub fn warnings(&self) -> impl Iterator<Item = &ExpansionWarning> {
+        self.files.iter().flat_map(|file| file.warnings.iter())
+    }
+}
+
+/// The results of scanning a single file.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub struct FileReport {
+    /// The path of the file, as it was given to the expander.
+    pub path: PathBuf,
+    /// A record of each macro invocation found in the file that matched a registered macro and was
+    /// expanded, in source order. See [`MacroRegistry::shuffle`] for what that means.
+    ///
+    /// [`MacroRegistry::shuffle`]: struct.MacroRegistry.html#method.shuffle
+    pub records: Vec<ExpansionRecord>,
+    /// The number of items nested inside other items, such as inline modules and function bodies,
+    /// that were checked for derive and attribute-like macros.
+    pub nested_items: usize,
+    /// Every macro invocation in the file that wasn't expanded even though the last segment of its
+    /// path is the same as that of a registered macro. Each of these probably means the macro's
+    /// coverage is being underestimated.
+    pub near_misses: Vec<NearMiss>,
+    /// Every function-like macro invocation, derive, and attribute in the file that didn't match
+    /// any registered macro, in source order, which can be compared with the macros a crate
+    /// exports to find the ones that aren't being tested. Attributes that the compiler handles
+    /// itself, like `#[inline]` and `#[rustfmt::skip]`, and helper attributes of registered derives
+    /// are left out, but macros from other crates, like `println!` and `#[derive(Debug)]`, aren't.
+    /// Near misses are listed here too.
+    pub unregistered: Vec<InvocationInfo>,
+    /// Every invocation of a registered macro in the file, in source order, along with the outcome
+    /// of each one. This includes the invocations that weren't expanded because they were filtered
+    /// out or were near misses.
+    pub call_sites: Vec<CallSite>,
+    /// Every invocation in the file whose macro function panicked. This can only be non-empty if
+    /// the [`PanicPolicy`] is [`Record`], since otherwise the panics are returned as an error.
+    ///
+    /// [`PanicPolicy`]: enum.PanicPolicy.html
+    /// [`Record`]: enum.PanicPolicy.html#variant.Record
+    pub panics: Vec<ExpansionPanic>,
+    /// Every problem that a check found in one of the file's expansions without making the run
+    /// fail, like an expansion that's over its [`Budget`], in source order.
+    ///
+    /// [`Budget`]: struct.Budget.html
+    pub warnings: Vec<ExpansionWarning>,
+    /// The error syn reported if the file couldn't be fully parsed and its tokens were scanned
+    /// instead, because [`MacroRegistry::lenient_parsing`] was enabled.
+    ///
+    /// [`MacroRegistry::lenient_parsing`]: struct.MacroRegistry.html#method.lenient_parsing
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize::option_error"))]
+    pub parse_error: Option<syn::Error>,
+    /// Whether the file was skipped because the expander's cache showed that it was expanded
+    /// successfully before, with the same contents and the same registrations. If so, `records`,
+    /// `call_sites`, and the other per-invocation lists are empty.
+    pub cached: bool,
+    /// For a cached file, the number of times each registered macro was expanded when the file was
+    /// last scanned. This is empty for files that were actually scanned.
+    #[cfg_attr(
+        feature = "serde",
+        serde(serialize_with = "serialize::invocation_counts")
+    )]
+    pub cached_invocations: BTreeMap<(MacroKind, String), usize>,
+    /// How long it took to parse and scan the file, including the time spent in the macro
+    /// functions. This is zero for a cached file.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize::duration"))]
+    pub duration: Duration,
+}
+
+impl FileReport {
+    /// Returns the number of macro expansions performed in this file.
+    pub fn expansions(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Returns the number of times the macro registered as `macro_path` was expanded in this file.
+    /// If the file was cached, this is the number from when it was last scanned.
+    pub fn invocations(&self, macro_path: &str) -> usize {
+        self.records
+            .iter()
+            .filter(|record| record.macro_path == macro_path)
+            .count()
+            + self
+                .cached_invocations
+                .iter()
+                .filter(|((_, path), _)| path == macro_path)
+                .map(|(_, count)| count)
+                .sum::<usize>()
+    }
+
+    /// Returns the number of times each registered macro was expanded in this file. Macros that
+    /// were never expanded are left out. If the file was cached, these are the numbers from when
+    /// it was last scanned.
+    pub fn invocation_counts(&self) -> BTreeMap<(MacroKind, &str), usize> {
+        let mut counts: BTreeMap<(MacroKind, &str), usize> = self
+            .cached_invocations
+            .iter()
+            .map(|(&(kind, ref path), &count)| ((kind, &path[..]), count))
+            .collect();
+        for record in self.records.iter() {
+            *counts
+                .entry((record.kind, &record.macro_path[..]))
+                .or_insert(0) += 1;
+        }
+        counts
+    }
+}
+
+/// A record of a single macro expansion.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub struct ExpansionRecord {
+    /// The kind of macro that was expanded.
+    pub kind: MacroKind,
+    /// The path of the macro, as it was registered.
+    pub macro_path: String,
+    /// The tokens inside the attribute, for attribute-like macros. This is the first argument that
+    /// was passed to the macro function.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize::option_tokens"))]
+    pub attr: Option<TokenStream>,
+    /// The tokens that the macro function was given to expand: the tokens inside the macro call for
+    /// function-like macros, or the annotated item for derive and attribute-like macros.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize::tokens"))]
+    pub input: TokenStream,
+    /// The tokens that the macro function returned.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize::tokens"))]
+    pub output: TokenStream,
+    /// The location of the invocation: the macro call for function-like macros, the path inside
+    /// `#[derive(...)]` for derive macros, or the attribute for attribute-like macros.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub span: Span,
+    /// The line and column in the file where the invocation starts. Lines start at 1, and columns
+    /// start at 0.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize::line_column"))]
+    pub start: LineColumn,
+    /// The line and column in the file where the invocation ends.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize::line_column"))]
+    pub end: LineColumn,
+    /// Where in the grammar a function-like macro was invoked, as in [`CallSite::position`].
+    ///
+    /// [`CallSite::position`]: struct.CallSite.html#structfield.position
+    pub position: Option<MacroPosition>,
+    /// The order of the invocation among all the file's invocations, as in [`CallSite::order`].
+    ///
+    /// [`CallSite::order`]: struct.CallSite.html#structfield.order
+    pub order: usize,
+    /// How long the macro function took, not counting any extra calls made by checks like
+    /// [`MacroRegistry::check_determinism`].
+    ///
+    /// [`MacroRegistry::check_determinism`]: struct.MacroRegistry.html#method.check_determinism
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize::duration"))]
+    pub duration: Duration,
+    /// The most memory that the macro function had allocated at once, in bytes, not counting
+    /// what was allocated before it was called. This is only known with the `track-memory`
+    /// feature, when a [`TrackingAllocator`] is the global allocator.
+    ///
+    /// [`TrackingAllocator`]: struct.TrackingAllocator.html
+    pub peak_memory: Option<usize>,
+}
+
+impl ExpansionRecord {
+    /// Returns the output of the macro function as Rust code, for failure messages, snapshots, and
+    /// the like. If the `prettyplease` feature is enabled and the output is a valid file, it's
+    /// formatted the way rustfmt would format it. Otherwise, it's printed as a token stream. The
+    /// result always ends with a newline.
+    pub fn pretty_output(&self) -> String {
+        pretty::format(&self.output)
+    }
+
+    /// Returns the message of every `compile_error!` invocation in the output, as described in
+    /// [`tokens::compile_errors`]. This includes the error returned by a fallible macro function,
+    /// since it's recorded as the `compile_error!` that it would become.
+    ///
+    /// [`tokens::compile_errors`]: tokens/fn.compile_errors.html
+    pub fn compile_errors(&self) -> Vec<String> {
+        tokens::compile_errors(&self.output)
+    }
+}
+
+/// A single invocation of a registered macro in the source code, or one that would be if its path
+/// matched.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub struct CallSite {
+    /// The kind of macro that was invoked.
+    pub kind: MacroKind,
+    /// The path of the macro, as it was registered.
+    pub macro_path: String,
+    /// The location of the invocation.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub span: Span,
+    /// The line and column in the file where the invocation starts. Lines start at 1, and columns
+    /// start at 0.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize::line_column"))]
+    pub start: LineColumn,
+    /// The line and column in the file where the invocation ends.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize::line_column"))]
+    pub end: LineColumn,
+    /// What happened to the invocation.
+    pub outcome: ExpansionOutcome,
+    /// Where in the grammar a function-like macro was invoked. This is `None` for derives and
+    /// attribute-like macros, and for invocations that were found by scanning tokens rather than
+    /// a syntax tree.
+    pub position: Option<MacroPosition>,
+    /// How many of the file's invocations had their macro functions called before this one's. For
+    /// an invocation whose function was called, this is its index among those in
+    /// [`FileReport::call_sites`] unless the order was shuffled with [`MacroRegistry::shuffle`].
+    ///
+    /// [`FileReport::call_sites`]: struct.FileReport.html#structfield.call_sites
+    /// [`MacroRegistry::shuffle`]: struct.MacroRegistry.html#method.shuffle
+    pub order: usize,
+}
+
+/// The grammatical position of a function-like macro invocation, which determines what its
+/// expansion has to parse as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub enum MacroPosition {
+    /// An expression, like `let x = foo!();`.
+    Expression,
+    /// A statement inside a block, like the `foo!();` in `{ foo!(); }`. The expansion can be any
+    /// number of statements, optionally followed by an expression.
+    Statement,
+    /// An item in a module, like `foo!();` at the top level of a file. The expansion can be any
+    /// number of items.
+    Item,
+    /// An item in an `impl` block. The expansion can be any number of associated items.
+    ImplItem,
+    /// An item in a trait definition. The expansion can be any number of trait items.
+    TraitItem,
+    /// An item in an `extern` block. The expansion can be any number of foreign items.
+    ForeignItem,
+    /// A type, like `let x: foo!();`.
+    Type,
+    /// A pattern, like `let foo!() = x;`.
+    Pattern,
+}
+
+impl fmt::Display for MacroPosition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            MacroPosition::Expression => "an expression",
+            MacroPosition::Statement => "statements",
+            MacroPosition::Item => "items",
+            MacroPosition::ImplItem => "associated items",
+            MacroPosition::TraitItem => "trait items",
+            MacroPosition::ForeignItem => "foreign items",
+            MacroPosition::Type => "a type",
+            MacroPosition::Pattern => "a pattern",
+        })
+    }
+}
+
+/// What happened to a macro invocation that was found in a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub enum ExpansionOutcome {
+    /// The macro function returned, whether with tokens or an error.
+    Expanded,
+    /// The macro function panicked with the given message.
+    Panicked(String),
+    /// The invocation wasn't expanded because its path doesn't match the registered macro's,
+    /// even though the last segments are the same. It's also listed as a [`NearMiss`].
+    ///
+    /// [`NearMiss`]: struct.NearMiss.html
+    SkippedUnmatched,
+    /// The invocation matched a registered macro but wasn't expanded, because a
+    /// `// runtime-macros: skip` comment or the [`ExpansionHandler`] said not to.
+    ///
+    /// [`ExpansionHandler`]: trait.ExpansionHandler.html
+    FilteredOut,
+}
+
+impl ExpansionOutcome {
+    /// Returns whether the macro function was called, which means it either returned or panicked.
+    pub fn was_called(&self) -> bool {
+        match *self {
+            ExpansionOutcome::Expanded | ExpansionOutcome::Panicked(_) => true,
+            ExpansionOutcome::SkippedUnmatched | ExpansionOutcome::FilteredOut => false,
+        }
+    }
+}
+
+impl fmt::Display for ExpansionOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExpansionOutcome::Expanded => f.write_str("expanded"),
+            ExpansionOutcome::Panicked(ref message) => write!(f, "panicked: {}", message),
+            ExpansionOutcome::SkippedUnmatched => {
+                f.write_str("skipped because its path doesn't match")
+            }
+            ExpansionOutcome::FilteredOut => f.write_str("filtered out"),
+        }
+    }
+}
+
+/// A warning about a macro invocation that almost matched a registered macro but wasn't expanded,
+/// such as `mycrate::custom_assert!(...)` when only `"custom_assert"` is registered.
+///
+/// This usually means the registration needs a more lenient [`MatchMode`].
+///
+/// [`MatchMode`]: enum.MatchMode.html
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub struct NearMiss {
+    /// The kind of macro that was invoked.
+    pub kind: MacroKind,
+    /// The path of the macro, as it appears in the source code.
+    pub found_path: String,
+    /// The path of the registered macro that it almost matched.
+    pub registered_path: String,
+    /// The location of the invocation.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub span: Span,
+}
+
+impl fmt::Display for NearMiss {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "`{}` was not expanded because it doesn't match any registered {} macro; did you mean `{}`?",
+            self.found_path, self.kind, self.registered_path
+        )
+    }
+}
+
+/// A macro invocation found in a file, identified by its path as it appears in the source code.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub struct InvocationInfo {
+    /// The kind of macro that was invoked.
+    pub kind: MacroKind,
+    /// The path of the macro, as it appears in the source code.
+    pub path: String,
+    /// The location of the invocation.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub span: Span,
+    /// The line and column in the file where the invocation starts. Lines start at 1, and columns
+    /// start at 0.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize::line_column"))]
+    pub start: LineColumn,
+    /// The line and column in the file where the invocation ends.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize::line_column"))]
+    pub end: LineColumn,
+    /// Where in the grammar a function-like macro was invoked, as in [`CallSite::position`].
+    ///
+    /// [`CallSite::position`]: struct.CallSite.html#structfield.position
+    pub position: Option<MacroPosition>,
+}
+
+impl fmt::Display for InvocationInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            MacroKind::Functionlike => write!(f, "`{}!`", self.path),
+            MacroKind::Derive => write!(f, "`#[derive({})]`", self.path),
+            MacroKind::Attribute => write!(f, "`#[{}]`", self.path),
+        }
+    }
+}
+
+/// A problem with one expansion that a check found, such as an expansion that's over its
+/// [`Budget`]. Unlike an error, it doesn't make the run fail.
+///
+/// [`Budget`]: struct.Budget.html
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub struct ExpansionWarning {
+    /// The kind of macro that was expanded.
+    pub kind: MacroKind,
+    /// The path of the macro, as it was registered.
+    pub macro_path: String,
+    /// The name of the check that found the problem, like `budget`.
+    pub lint: String,
+    /// A description of the problem.
+    pub message: String,
+    /// The location of the invocation, or of the problem inside it if that's known.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub span: Span,
+    /// The line and column in the file where the location starts. Lines start at 1, and columns
+    /// start at 0.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize::line_column"))]
+    pub start: LineColumn,
+    /// The line and column in the file where the location ends.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize::line_column"))]
+    pub end: LineColumn,
+}
+
+impl fmt::Display for ExpansionWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}`: {} ({})", self.macro_path, self.message, self.lint)
+    }
+}
+
+/// A macro invocation whose function panicked instead of returning.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub struct ExpansionPanic {
+    /// The path of the file containing the invocation, if it's known.
+    pub path: Option<PathBuf>,
+    /// The kind of macro that was invoked.
+    pub kind: MacroKind,
+    /// The path of the macro, as it was registered.
+    pub macro_path: String,
+    /// The location of the invocation.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub span: Span,
+    /// The panic message, if the payload was a string.
+    pub message: String,
+    /// Where in the macro's code the panic happened, if known.
+    pub location: Option<PanicLocation>,
+    /// A backtrace of the panic, if backtraces were enabled with the `RUST_BACKTRACE` or
+    /// `RUST_LIB_BACKTRACE` environment variable.
+    pub backtrace: Option<String>,
+    /// A smaller input that still made the macro function panic with the same message, if
+    /// [`MacroRegistry::minimize_panics`] was enabled and the panic wasn't expected.
+    ///
+    /// [`MacroRegistry::minimize_panics`]: struct.MacroRegistry.html#method.minimize_panics
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize::option_tokens"))]
+    pub minimized_input: Option<TokenStream>,
+    /// Whether the invocation was marked with a `// runtime-macros: expect-panic` comment. Expected
+    /// panics are always recorded in the report instead of being returned as an error.
+    pub expected: bool,
+}
+
+impl fmt::Display for ExpansionPanic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} macro `{}` panicked", self.kind, self.macro_path)?;
+        if let Some(ref location) = self.location {
+            write!(f, " at {}", location)?;
+        }
+        write!(f, ": {}", self.message)?;
+        if let Some(ref input) = self.minimized_input {
+            write!(f, " (minimized input: `{}`)", input)?;
+        }
+        Ok(())
+    }
+}
+
+/// A location in the Rust source code of a macro function, as reported by `std::panic::Location`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub struct PanicLocation {
+    /// The source file.
+    pub file: String,
+    /// The line number, starting at 1.
+    pub line: u32,
+    /// The column number, starting at 1.
+    pub column: u32,
+}
+
+impl fmt::Display for PanicLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// The kinds of procedural macros.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub enum MacroKind {
+    /// A function-like macro, such as `foo!(...)`.
+    Functionlike,
+    /// A derive macro, such as `#[derive(Foo)]`.
+    Derive,
+    /// An attribute-like macro, such as `#[foo(...)]`.
+    Attribute,
+}
+
+impl fmt::Display for MacroKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            MacroKind::Functionlike => "function-like",
+            MacroKind::Derive => "derive",
+            MacroKind::Attribute => "attribute-like",
+        })
+    }
+}
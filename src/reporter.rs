@@ -0,0 +1,352 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Reporting on a run as it happens, such as by printing a summary for a person to read.
+
+use {
+    report::{ExpansionPanic, ExpansionRecord, ExpansionReport, FileReport},
+    std::{
+        collections::BTreeSet,
+        env,
+        io::{self, IsTerminal},
+        path::Path,
+    },
+    Error,
+};
+
+#[cfg(feature = "serde")]
+use std::io::Write;
+
+/// Something that's told about each step of an [`Expander`]'s run, such as a summary printed for
+/// a person to read. A reporter is given to an expander with [`Expander::reporter`].
+///
+/// Every method does nothing by default, so a reporter only has to implement the ones it needs.
+/// The methods are called in order for each file: [`on_file_start`] before it's scanned, then
+/// [`on_expansion`] for each expansion and [`on_panic`] for each panic, then [`on_file_finish`].
+/// Finally, [`on_finish`] is called once with the result of the whole run. Code blocks from doc
+/// comments and Markdown files are reported as files with the path of the file they're in.
+///
+/// [`Expander`]: struct.Expander.html
+/// [`Expander::reporter`]: struct.Expander.html#method.reporter
+/// [`on_file_start`]: #method.on_file_start
+/// [`on_expansion`]: #method.on_expansion
+/// [`on_panic`]: #method.on_panic
+/// [`on_file_finish`]: #method.on_file_finish
+/// [`on_finish`]: #method.on_finish
+///
+/// # Example
+///
+/// ```
+/// # use runtime_macros::{ExpansionRecord, Expander, Reporter};
+/// # fn custom_assert_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+/// // Prints a line for each expansion.
+/// struct Log;
+///
+/// impl Reporter for Log {
+///     fn on_expansion(&mut self, path: &std::path::Path, record: &ExpansionRecord) {
+///         println!("{}:{}: {}", path.display(), record.start.line, record.macro_path);
+///     }
+/// }
+///
+/// Expander::new()
+///     .functionlike("custom_assert", custom_assert_internal)
+///     .reporter(Log)
+///     # .file(concat!(env!("CARGO_MANIFEST_DIR"), "/src/lib.rs"))
+///     # /*
+///     .file("tests/tests.rs")
+///     # */
+///     .run()
+///     .unwrap();
+/// ```
+pub trait Reporter {
+    /// Called before a file is scanned.
+    fn on_file_start(&mut self, _path: &Path) {}
+
+    /// Called for each expansion in a file, in source order, once the file has been scanned.
+    fn on_expansion(&mut self, _path: &Path, _record: &ExpansionRecord) {}
+
+    /// Called for each invocation in a file whose macro function panicked, once the file has been
+    /// scanned.
+    fn on_panic(&mut self, _panic: &ExpansionPanic) {}
+
+    /// Called after a file has been scanned, with everything that was found in it.
+    fn on_file_finish(&mut self, _file: &FileReport) {}
+
+    /// Called once at the end of the run, with the report if the run succeeded or the error if it
+    /// failed. An error returned from here makes the run fail with an [`Error::IoError`] unless it
+    /// had already failed.
+    ///
+    /// [`Error::IoError`]: enum.Error.html#variant.IoError
+    fn on_finish(&mut self, _result: Result<&ExpansionReport, &Error>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<R: Reporter + ?Sized> Reporter for &mut R {
+    fn on_file_start(&mut self, path: &Path) {
+        (**self).on_file_start(path)
+    }
+
+    fn on_expansion(&mut self, path: &Path, record: &ExpansionRecord) {
+        (**self).on_expansion(path, record)
+    }
+
+    fn on_panic(&mut self, panic: &ExpansionPanic) {
+        (**self).on_panic(panic)
+    }
+
+    fn on_file_finish(&mut self, file: &FileReport) {
+        (**self).on_file_finish(file)
+    }
+
+    fn on_finish(&mut self, result: Result<&ExpansionReport, &Error>) -> io::Result<()> {
+        (**self).on_finish(result)
+    }
+}
+
+// Tells the reporter about everything in a file that was just scanned.
+pub(crate) fn report_file(reporter: &mut dyn Reporter, file: &FileReport) {
+    for record in file.records.iter() {
+        reporter.on_expansion(&file.path, record);
+    }
+    for panic in file.panics.iter() {
+        reporter.on_panic(panic);
+    }
+    reporter.on_file_finish(file);
+}
+
+/// A [`Reporter`] that doesn't report anything. This is what an [`Expander`] does without a
+/// reporter.
+///
+/// [`Reporter`]: trait.Reporter.html
+/// [`Expander`]: struct.Expander.html
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QuietReporter;
+
+impl Reporter for QuietReporter {}
+
+/// A [`Reporter`] that prints to standard error for a person to read: each panic, warning, and
+/// near miss as it's found, and then a summary of the run.
+///
+/// By default, the output is colored if standard error is a terminal and the `NO_COLOR`
+/// environment variable isn't set. Like anything else printed by a test, it's only shown if the
+/// test fails or is run with `--nocapture`.
+///
+/// [`Reporter`]: trait.Reporter.html
+#[derive(Debug, Clone)]
+pub struct ConsoleReporter {
+    color: bool,
+}
+
+impl Default for ConsoleReporter {
+    fn default() -> Self {
+        ConsoleReporter {
+            color: io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none(),
+        }
+    }
+}
+
+impl ConsoleReporter {
+    /// Creates a console reporter that decides whether to use color as described above.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether the output should be colored.
+    pub fn color(&mut self, enabled: bool) -> &mut Self {
+        self.color = enabled;
+        self
+    }
+
+    // Writes a line to stderr, starting with a label in the given ANSI color.
+    fn line(&self, color: &str, label: &str, message: &str) {
+        if self.color {
+            eprintln!("\x1b[1;{}m{}\x1b[0m: {}", color, label, message);
+        } else {
+            eprintln!("{}: {}", label, message);
+        }
+    }
+}
+
+const RED: &str = "31";
+const GREEN: &str = "32";
+const YELLOW: &str = "33";
+
+impl Reporter for ConsoleReporter {
+    fn on_panic(&mut self, panic: &ExpansionPanic) {
+        if !panic.expected {
+            self.line(RED, "panicked", &panic.to_string());
+        }
+    }
+
+    fn on_file_finish(&mut self, file: &FileReport) {
+        let path = file.path.display();
+        for warning in file.warnings.iter() {
+            let at = format!("{}:{}:{}", path, warning.start.line, warning.start.column);
+            self.line(YELLOW, "warning", &format!("{}: {}", at, warning));
+        }
+        for near_miss in file.near_misses.iter() {
+            let start = near_miss.span.start();
+            let at = format!("{}:{}:{}", path, start.line, start.column);
+            self.line(YELLOW, "near miss", &format!("{}: {}", at, near_miss));
+        }
+    }
+
+    fn on_finish(&mut self, result: Result<&ExpansionReport, &Error>) -> io::Result<()> {
+        let report = match result {
+            Ok(report) => report,
+            Err(error) => {
+                self.line(RED, "runtime-macros", &format!("the run failed: {}", error));
+                return Ok(());
+            }
+        };
+        let macros: BTreeSet<_> = report
+            .files
+            .iter()
+            .flat_map(|file| file.invocation_counts().into_keys())
+            .collect();
+        let panics = report
+            .files
+            .iter()
+            .map(|file| file.panics.len())
+            .sum::<usize>();
+        let warnings = report.warnings().count() + report.near_misses().count();
+        let mut summary = format!(
+            "{} expansions of {} macros in {} files",
+            report.expansions(),
+            macros.len(),
+            report.files.len()
+        );
+        if panics > 0 {
+            summary.push_str(&format!(", {} panicked", panics));
+        }
+        if warnings > 0 {
+            summary.push_str(&format!(", {} warnings", warnings));
+        }
+        let color = match (panics, warnings) {
+            (0, 0) => GREEN,
+            (0, _) => YELLOW,
+            _ => RED,
+        };
+        self.line(color, "runtime-macros", &summary);
+        Ok(())
+    }
+}
+
+/// A [`Reporter`] that writes the report as JSON, as in [`ExpansionReport::to_json`], at the end
+/// of a successful run. This requires the `serde` feature.
+///
+/// [`Reporter`]: trait.Reporter.html
+/// [`ExpansionReport::to_json`]: struct.ExpansionReport.html#method.to_json
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub struct JsonReporter<W: Write> {
+    writer: W,
+}
+
+#[cfg(feature = "serde")]
+impl<W: Write> JsonReporter<W> {
+    /// Creates a reporter that writes to the given writer, such as a file.
+    pub fn new(writer: W) -> Self {
+        JsonReporter { writer }
+    }
+
+    /// Returns the writer.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<W: Write> Reporter for JsonReporter<W> {
+    fn on_finish(&mut self, result: Result<&ExpansionReport, &Error>) -> io::Result<()> {
+        match result {
+            Ok(report) => {
+                self.writer.write_all(report.to_json().as_bytes())?;
+                self.writer.flush()
+            }
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{env, fs, process},
+        Expander, PanicPolicy,
+    };
+
+    #[derive(Default)]
+    struct Events(Vec<String>);
+
+    impl Reporter for Events {
+        fn on_file_start(&mut self, path: &Path) {
+            self.0.push(format!(
+                "start {}",
+                path.file_name().unwrap().to_string_lossy()
+            ));
+        }
+
+        fn on_expansion(&mut self, _path: &Path, record: &ExpansionRecord) {
+            self.0.push(format!("expand {}", record.start.line));
+        }
+
+        fn on_panic(&mut self, panic: &ExpansionPanic) {
+            self.0.push(format!("panic {}", panic.message));
+        }
+
+        fn on_file_finish(&mut self, file: &FileReport) {
+            self.0.push(format!("finish {}", file.call_sites.len()));
+        }
+
+        fn on_finish(&mut self, result: Result<&ExpansionReport, &Error>) -> io::Result<()> {
+            self.0
+                .push(format!("done {}", result.unwrap().expansions()));
+            Err(io::Error::other("can't write"))
+        }
+    }
+
+    #[test]
+    fn reports_each_step() {
+        let path = env::temp_dir().join(format!("runtime-macros-reporter-{}.rs", process::id()));
+        fs::write(&path, "ok!(1);\npanics!();\nok!(2);\n").unwrap();
+
+        let mut events = Events::default();
+        let result = Expander::new()
+            .functionlike("ok", |ts| ts)
+            .functionlike("panics", |_| panic!("oops"))
+            .panic_policy(PanicPolicy::Record)
+            .reporter(&mut events)
+            .file(&path)
+            .run();
+        match result {
+            Err(Error::IoError { path: None, .. }) => {}
+            result => panic!("expected an IO error, got {:?}", result.map(|_| ())),
+        }
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        assert_eq!(
+            events.0,
+            [
+                format!("start {}", name),
+                "expand 1".to_owned(),
+                "expand 3".to_owned(),
+                "panic oops".to_owned(),
+                "finish 3".to_owned(),
+                "done 2".to_owned(),
+            ]
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+}
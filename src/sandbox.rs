@@ -0,0 +1,158 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Temporarily changing the process's environment around each call to a macro function, so the
+//! function sees what it would see during compilation.
+//!
+//! Both are shared by the whole process, so every change is made while holding a lock, which keeps
+//! other threads, like the workers of a `ParallelExpander`, from changing them at the same time.
+
+use std::{
+    cell::Cell,
+    env,
+    ffi::{OsStr, OsString},
+    io,
+    path::Path,
+    sync::{Mutex, PoisonError},
+};
+
+static LOCK: Mutex<()> = Mutex::new(());
+
+thread_local! {
+    // Whether this thread is holding the lock, so it can take it again without deadlocking.
+    static HOLDING: Cell<bool> = const { Cell::new(false) };
+}
+
+// Calls the given function while holding the lock that's held while the environment or the working
+// directory is changed. A thread that's already holding it just calls the function.
+pub(crate) fn locked<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    if HOLDING.with(Cell::get) {
+        return f();
+    }
+
+    struct Release;
+    impl Drop for Release {
+        fn drop(&mut self) {
+            HOLDING.with(|holding| holding.set(false));
+        }
+    }
+
+    // A panic while the lock was held can't have left anything half-changed, since the changes
+    // are always undone while unwinding.
+    let _guard = LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    HOLDING.with(|holding| holding.set(true));
+    let _release = Release;
+    f()
+}
+
+// Calls the given function with each of the given environment variables set to its value, or
+// removed if its value is `None`, and then puts back the values they had before, even if the
+// function panics.
+pub(crate) fn with_env<F, R>(vars: &[(OsString, Option<OsString>)], f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    if vars.is_empty() {
+        return f();
+    }
+
+    struct Restore(Vec<(OsString, Option<OsString>)>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            // If a variable was given twice, its first saved value is the original one.
+            for (key, value) in self.0.iter().rev() {
+                set(key, value.as_deref());
+            }
+        }
+    }
+
+    locked(|| {
+        let _restore = Restore(
+            vars.iter()
+                .map(|(key, _)| (key.clone(), env::var_os(key)))
+                .collect(),
+        );
+        for (key, value) in vars.iter() {
+            set(key, value.as_deref());
+        }
+        f()
+    })
+}
+
+// Calls the given function with the given directory as the current working directory, if there is
+// one, and then changes back to the directory that was current before, even if the function
+// panics. Returns an error without calling the function if either directory can't be used.
+pub(crate) fn with_current_dir<F, R>(dir: Option<&Path>, f: F) -> io::Result<R>
+where
+    F: FnOnce() -> R,
+{
+    let dir = match dir {
+        Some(dir) => dir,
+        None => return Ok(f()),
+    };
+
+    struct Restore(std::path::PathBuf);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            // There's nowhere to report this, but it's unlikely, since it was just the current
+            // directory.
+            env::set_current_dir(&self.0).ok();
+        }
+    }
+
+    locked(|| {
+        let _restore = Restore(env::current_dir()?);
+        env::set_current_dir(dir)?;
+        Ok(f())
+    })
+}
+
+fn set(key: &OsStr, value: Option<&OsStr>) {
+    match value {
+        Some(value) => env::set_var(key, value),
+        None => env::remove_var(key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        std::{fs, process, thread, time::Duration},
+    };
+
+    #[test]
+    fn keeps_threads_from_changing_directories_at_once() {
+        let root = env::temp_dir().join(format!("runtime-macros-sandbox-{}", process::id()));
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let dir = root.join(i.to_string());
+                fs::create_dir_all(&dir).unwrap();
+                let dir = dir.canonicalize().unwrap();
+                thread::spawn(move || {
+                    with_current_dir(Some(&dir), || {
+                        thread::sleep(Duration::from_millis(20));
+                        env::current_dir().unwrap() == dir
+                    })
+                    .unwrap()
+                })
+            })
+            .collect();
+        for thread in threads {
+            assert!(thread.join().unwrap());
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,205 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Serializing reports with serde, for the types that don't implement `Serialize` themselves.
+
+use {
+    proc_macro2::{LineColumn, TokenStream},
+    report::{ExpansionOutcome, ExpansionReport, MacroKind},
+    serde::{ser::SerializeStruct, Serialize, Serializer},
+    serde_json,
+    std::{collections::BTreeMap, time::Duration},
+};
+
+impl Serialize for ExpansionReport {
+    // Besides the files, the report has a summary, so a script doesn't have to add it all up.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Summary {
+            files: usize,
+            invocations: usize,
+            expanded: usize,
+            panicked: usize,
+            filtered_out: usize,
+            near_misses: usize,
+            unregistered: usize,
+            warnings: usize,
+            #[serde(serialize_with = "duration")]
+            duration: Duration,
+        }
+
+        let count = |f: fn(&ExpansionOutcome) -> bool| {
+            self.call_sites().filter(|site| f(&site.outcome)).count()
+        };
+        let summary = Summary {
+            files: self.files.len(),
+            invocations: count(ExpansionOutcome::was_called),
+            expanded: count(|outcome| *outcome == ExpansionOutcome::Expanded),
+            panicked: count(|outcome| matches!(*outcome, ExpansionOutcome::Panicked(_))),
+            filtered_out: count(|outcome| *outcome == ExpansionOutcome::FilteredOut),
+            near_misses: self.near_misses().count(),
+            unregistered: self.unregistered().count(),
+            warnings: self.warnings().count(),
+            duration: self.files.iter().map(|file| file.duration).sum(),
+        };
+        let mut report = serializer.serialize_struct("ExpansionReport", 2)?;
+        report.serialize_field("summary", &summary)?;
+        report.serialize_field("files", &self.files)?;
+        report.end()
+    }
+}
+
+impl ExpansionReport {
+    /// Returns the report as pretty-printed JSON, for archiving or for scripts to read. This
+    /// requires the `serde` feature.
+    ///
+    /// Besides the `files`, there's a `summary` with the numbers of files scanned, invocations
+    /// whose macro functions were called and how many of them were expanded or panicked, invocations
+    /// that were filtered out, near misses, invocations of unregistered macros, and warnings, along
+    /// with the total time spent scanning. Tokens are written as strings, locations as objects
+    /// with a `line` and a `column`, and durations as numbers of seconds.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::Expander;
+    /// # fn custom_assert_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+    /// # let dir = std::env::temp_dir();
+    /// let report = Expander::new()
+    ///     .functionlike("custom_assert", custom_assert_internal)
+    ///     # .file(concat!(env!("CARGO_MANIFEST_DIR"), "/src/lib.rs"))
+    ///     # /*
+    ///     .file("tests/tests.rs")
+    ///     # */
+    ///     .run()
+    ///     .unwrap();
+    /// std::fs::write(dir.join("expansions.json"), report.to_json()).unwrap();
+    /// ```
+    pub fn to_json(&self) -> String {
+        // Every map in the report has string keys, so this can't fail.
+        serde_json::to_string_pretty(self).expect("the report should always serialize as JSON")
+    }
+}
+
+pub(crate) fn tokens<S: Serializer>(
+    tokens: &TokenStream,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(tokens)
+}
+
+pub(crate) fn option_tokens<S: Serializer>(
+    tokens: &Option<TokenStream>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    tokens
+        .as_ref()
+        .map(TokenStream::to_string)
+        .serialize(serializer)
+}
+
+pub(crate) fn line_column<S: Serializer>(
+    at: &LineColumn,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut location = serializer.serialize_struct("LineColumn", 2)?;
+    location.serialize_field("line", &at.line)?;
+    location.serialize_field("column", &at.column)?;
+    location.end()
+}
+
+pub(crate) fn option_error<S: Serializer>(
+    error: &Option<syn::Error>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    error
+        .as_ref()
+        .map(syn::Error::to_string)
+        .serialize(serializer)
+}
+
+// The keys are tuples, which JSON can't have, so the counts are written as a list.
+pub(crate) fn invocation_counts<S: Serializer>(
+    counts: &BTreeMap<(MacroKind, String), usize>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    #[derive(Serialize)]
+    struct Count<'c> {
+        kind: MacroKind,
+        macro_path: &'c str,
+        count: usize,
+    }
+
+    serializer.collect_seq(
+        counts
+            .iter()
+            .map(|(&(kind, ref macro_path), &count)| Count {
+                kind,
+                macro_path,
+                count,
+            }),
+    )
+}
+
+pub(crate) fn duration<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use {serde_json::Value, std::path::PathBuf, MacroRegistry};
+
+    #[test]
+    fn serializes_reports() {
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("foo", |ts| ts)
+            .functionlike("bar", |_| panic!("bar"))
+            .panic_policy(::PanicPolicy::Record);
+        let file = registry
+            .expand_source(
+                "foo!(1 + 1); bar!(); a::foo!();\n// runtime-macros: skip\nfoo!(2);",
+                None,
+            )
+            .unwrap()
+            .into_report(PathBuf::from("a.rs"));
+        let report = registry.report(vec![file]).unwrap();
+        let json: Value = serde_json::from_str(&report.to_json()).unwrap();
+
+        let summary = &json["summary"];
+        assert_eq!(summary["files"], 1);
+        assert_eq!(summary["invocations"], 2);
+        assert_eq!(summary["expanded"], 1);
+        assert_eq!(summary["panicked"], 1);
+        assert_eq!(summary["filtered_out"], 1);
+        assert_eq!(summary["near_misses"], 1);
+        assert_eq!(summary["unregistered"], 1);
+        assert!(summary["duration"].is_f64());
+
+        let file = &json["files"][0];
+        assert_eq!(file["path"], "a.rs");
+        assert_eq!(file["records"][0]["kind"], "Functionlike");
+        assert_eq!(file["records"][0]["input"], "1 + 1");
+        assert_eq!(file["records"][0]["start"]["column"], 0);
+        assert_eq!(file["records"][0]["attr"], Value::Null);
+        assert_eq!(file["call_sites"][1]["outcome"]["Panicked"], "bar");
+        assert_eq!(file["call_sites"][2]["outcome"], "SkippedUnmatched");
+        assert_eq!(file["call_sites"][3]["outcome"], "FilteredOut");
+        assert_eq!(file["panics"][0]["message"], "bar");
+        assert_eq!(file["near_misses"][0]["found_path"], "a::foo");
+        assert_eq!(file["unregistered"][0]["path"], "a::foo");
+        assert_eq!(file["unregistered"][0]["start"]["column"], 21);
+    }
+}
@@ -0,0 +1,296 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+
+//! Source files that are read and parsed once, so any number of registries can expand the macros
+//! in them.
+
+use {
+    docs,
+    proc_macro2::TokenStream,
+    report::ExpansionReport,
+    std::path::{Path, PathBuf},
+    Error, MacroRegistry,
+};
+#[cfg(feature = "fs")]
+use {
+    modules,
+    std::{collections::HashSet, fs, io::Read},
+};
+
+/// A Rust source code file that has already been read and parsed.
+///
+/// Expanding macros never changes a `ParsedSource`, so the same one can be scanned by several
+/// registries without being parsed again.
+#[derive(Debug, Clone)]
+pub struct ParsedSource {
+    path: Option<PathBuf>,
+    content: String,
+    ast: syn::File,
+    // The error from syn and the file's tokens, if it was parsed leniently and syn failed.
+    fallback: Option<(syn::Error, TokenStream)>,
+}
+
+impl ParsedSource {
+    /// Reads and parses the file at the given path.
+    #[cfg(feature = "fs")]
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        Self::parse(read(path)?, Some(path))
+    }
+
+    /// Reads and parses the given file. Since a `File` doesn't know its own path, the resulting
+    /// `ParsedSource` doesn't have one.
+    #[cfg(feature = "fs")]
+    pub fn from_file(file: fs::File) -> Result<Self, Error> {
+        Self::parse(read_file(file)?, None)
+    }
+
+    /// Parses the given Rust source code. The path is optional and is only used to tell the macro
+    /// functions and the error messages where the code came from.
+    pub fn parse(content: String, path: Option<&Path>) -> Result<Self, Error> {
+        match syn::parse_file(&content) {
+            Ok(ast) => Ok(ParsedSource {
+                path: path.map(Path::to_owned),
+                content,
+                ast,
+                fallback: None,
+            }),
+            Err(error) => Err(Error::ParseError {
+                path: path.map(Path::to_owned),
+                error,
+                snippet: None,
+            }
+            .annotate(&content)),
+        }
+    }
+
+    /// Parses the given Rust source code like [`parse`], but if syn can't parse it, for instance
+    /// because it uses syntax that's too new, only splits it into tokens. Registries scan those
+    /// tokens for anything that looks like a macro invocation instead of visiting a syntax tree.
+    /// An error is only returned if the code can't even be split into tokens.
+    ///
+    /// [`parse`]: #method.parse
+    pub fn parse_lenient(content: String, path: Option<&Path>) -> Result<Self, Error> {
+        let error = match syn::parse_file(&content) {
+            Ok(_) => return Self::parse(content, path),
+            Err(error) => error,
+        };
+        match content.parse::<TokenStream>() {
+            Ok(tokens) => Ok(ParsedSource {
+                path: path.map(Path::to_owned),
+                content,
+                ast: syn::File {
+                    shebang: None,
+                    attrs: Vec::new(),
+                    items: Vec::new(),
+                },
+                fallback: Some((error, tokens)),
+            }),
+            Err(_) => Err(Error::ParseError {
+                path: path.map(Path::to_owned),
+                error,
+                snippet: None,
+            }
+            .annotate(&content)),
+        }
+    }
+
+    /// Returns the path of the file, if it's known.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Returns the source code.
+    pub fn content(&self) -> &str {
+        &self.content
+    }
+
+    /// Returns the syntax tree. If the code was parsed with [`parse_lenient`] and syn couldn't
+    /// parse it, this is an empty file.
+    ///
+    /// [`parse_lenient`]: #method.parse_lenient
+    pub fn ast(&self) -> &syn::File {
+        &self.ast
+    }
+
+    /// Returns the error that syn reported if the code was parsed with [`parse_lenient`] and only
+    /// its tokens could be kept.
+    ///
+    /// [`parse_lenient`]: #method.parse_lenient
+    pub fn parse_error(&self) -> Option<&syn::Error> {
+        self.fallback.as_ref().map(|(error, _)| error)
+    }
+
+    /// Reads the Markdown file at the given path and parses each of its fenced Rust code blocks,
+    /// such as the examples in a README. A block is Rust if rustdoc would test it as Rust: it
+    /// has no language, or it's marked `rust`, `no_run`, `ignore`, and so on. Lines hidden with
+    /// `# ` are included, and a block that isn't a valid file on its own is wrapped in a `main`
+    /// function, as rustdoc does. Blocks that still can't be parsed are skipped.
+    ///
+    /// Each block is padded with blank lines so that the line numbers in the reports match the
+    /// Markdown file.
+    #[cfg(feature = "fs")]
+    pub fn from_markdown<P: AsRef<Path>>(path: P) -> Result<Vec<Self>, Error> {
+        let path = path.as_ref();
+        let content = read(path)?;
+        Ok(docs::markdown_code_blocks(&content)
+            .into_iter()
+            .filter_map(|block| docs::parse_block(block, |code| Self::parse(code, Some(path))))
+            .collect())
+    }
+
+    /// Parses each fenced Rust code block in the doc comments in this file, in the same way as
+    /// [`from_markdown`]. Consecutive lines of doc comments are treated as one piece of Markdown.
+    ///
+    /// [`from_markdown`]: #method.from_markdown
+    pub fn doc_examples(&self) -> Vec<Self> {
+        docs::doc_code_blocks(self)
+            .into_iter()
+            .filter_map(|block| docs::parse_block(block, |code| Self::parse(code, self.path())))
+            .collect()
+    }
+
+    // Returns the tokens to scan if syn couldn't parse the code.
+    pub(crate) fn tokens(&self) -> Option<&TokenStream> {
+        self.fallback.as_ref().map(|(_, tokens)| tokens)
+    }
+}
+
+// Reads the whole file at the given path.
+#[cfg(feature = "fs")]
+pub(crate) fn read(path: &Path) -> Result<String, Error> {
+    let mut content = String::new();
+    fs::File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut content))
+        .map_err(|error| Error::IoError {
+            path: Some(path.to_owned()),
+            error,
+        })?;
+    Ok(content)
+}
+
+// Reads the whole of the given file, whose path isn't known.
+#[cfg(feature = "fs")]
+pub(crate) fn read_file(mut file: fs::File) -> Result<String, Error> {
+    let mut content = String::new();
+    file.read_to_string(&mut content)
+        .map_err(|error| Error::IoError { path: None, error })?;
+    Ok(content)
+}
+
+/// A set of parsed source files that can be scanned by one registry after another.
+///
+/// # Example
+///
+/// ```
+/// # use runtime_macros::{MacroRegistry, Session};
+/// # fn remove_internal(_: proc_macro2::TokenStream) -> proc_macro2::TokenStream {
+/// #     proc_macro2::TokenStream::new()
+/// # }
+/// let mut session = Session::new();
+/// # /*
+/// session.file("tests/a.rs")?.file("tests/b.rs")?;
+/// # */
+/// # session.file(concat!(env!("CARGO_MANIFEST_DIR"), "/src/lib.rs"))?;
+///
+/// // Both of these scan the same syntax trees, which were only parsed once.
+/// let functionlike = session.run(MacroRegistry::new().functionlike("remove", remove_internal))?;
+/// let derive = session.run(MacroRegistry::new().derive("Remove", remove_internal))?;
+/// # Ok::<(), runtime_macros::Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    sources: Vec<ParsedSource>,
+}
+
+impl Session {
+    /// Creates an empty session.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads and parses the file at the given path and adds it to the session. Files are scanned
+    /// in the order they are added.
+    #[cfg(feature = "fs")]
+    pub fn file<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self, Error> {
+        let source = ParsedSource::from_path(path)?;
+        Ok(self.source(source))
+    }
+
+    /// Reads and parses the file at the given path, which is treated as a crate root, and adds it
+    /// to the session, followed by every file it declares as a module with `mod foo;`, and every
+    /// file those declare, and so on. See [`Expander::follow_modules`] for how the files are
+    /// found. Unlike with an `Expander`, no `#[cfg]` attributes are evaluated, so every declared
+    /// module is added.
+    ///
+    /// [`Expander::follow_modules`]: struct.Expander.html#method.follow_modules
+    #[cfg(feature = "fs")]
+    pub fn file_tree<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self, Error> {
+        let mut pending = vec![(path.as_ref().to_owned(), true)];
+        let mut seen = HashSet::new();
+        while let Some((path, mod_rs)) = pending.pop() {
+            if !seen.insert(modules::normalize(&path)) {
+                continue;
+            }
+            let source = ParsedSource::from_path(&path)?;
+            let modules = modules::declared_modules(&source, mod_rs, None);
+            pending.extend(modules.into_iter().rev());
+            self.sources.push(source);
+        }
+        Ok(self)
+    }
+
+    /// Adds each Rust code block in the Markdown file at the given path to the session. See
+    /// [`ParsedSource::from_markdown`] for which blocks are added.
+    ///
+    /// [`ParsedSource::from_markdown`]: struct.ParsedSource.html#method.from_markdown
+    #[cfg(feature = "fs")]
+    pub fn markdown<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self, Error> {
+        self.sources.extend(ParsedSource::from_markdown(path)?);
+        Ok(self)
+    }
+
+    /// Adds a file that has already been parsed to the session.
+    pub fn source(&mut self, source: ParsedSource) -> &mut Self {
+        self.sources.push(source);
+        self
+    }
+
+    /// Returns every file in the session, in the order they were added.
+    pub fn sources(&self) -> &[ParsedSource] {
+        &self.sources
+    }
+
+    /// Expands the macros in the given registry in every file in the session.
+    ///
+    /// # Returns
+    ///
+    /// An [`ExpansionReport`] with one [`FileReport`] per file on success, or an instance of
+    /// [`Error`] describing the first error that occurred, just like [`Expander::run`].
+    ///
+    /// [`ExpansionReport`]: struct.ExpansionReport.html
+    /// [`FileReport`]: struct.FileReport.html
+    /// [`Error`]: enum.Error.html
+    /// [`Expander::run`]: struct.Expander.html#method.run
+    pub fn run(&self, registry: &MacroRegistry) -> Result<ExpansionReport, Error> {
+        let mut files = Vec::new();
+        for source in self.sources.iter() {
+            files.push(
+                registry
+                    .scan(source)?
+                    .into_report(source.path.clone().unwrap_or_default()),
+            );
+        }
+        registry.report(files)
+    }
+}
@@ -0,0 +1,367 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Comparing the output of each macro invocation with a stored snapshot, so changes to a macro's
+//! expansions are caught along with its coverage.
+
+use {
+    corpus,
+    proc_macro2::LineColumn,
+    report::{ExpansionRecord, ExpansionReport, FileReport, MacroKind},
+    std::{collections::HashMap, fmt, path::PathBuf},
+    tokens::{self, TokenDiff},
+};
+#[cfg(feature = "fs")]
+use {
+    std::{env, fs, path::Path},
+    Error,
+};
+
+/// A directory of snapshots of macro expansions, in the style of `macrotest`.
+///
+/// Each expansion in a report is written out as a file named
+/// `<file>-<line>-<column>-<macro path>.expanded.rs`, where the file is the path of the file
+/// containing the invocation, with every character other than letters, digits, `.`, `-`, and `_`
+/// replaced with `_`. If the snapshot already exists, it has to match the new expansion, ignoring
+/// whitespace and other differences that [`tokens::token_eq`] ignores, or else
+/// [`check`] returns an [`Error::SnapshotMismatch`]. Setting the `UPDATE_EXPANSIONS` environment
+/// variable to anything other than `0` overwrites the snapshots instead. New snapshots are
+/// formatted with [`ExpansionRecord::pretty_output`].
+///
+/// Files that were skipped because of [`Expander::cache_dir`] don't have any expansions, so their
+/// snapshots aren't checked.
+///
+/// [`tokens::token_eq`]: tokens/fn.token_eq.html
+/// [`check`]: #method.check
+/// [`Error::SnapshotMismatch`]: enum.Error.html#variant.SnapshotMismatch
+/// [`ExpansionRecord::pretty_output`]: struct.ExpansionRecord.html#method.pretty_output
+/// [`Expander::cache_dir`]: struct.Expander.html#method.cache_dir
+///
+/// # Example
+///
+/// ```
+/// # use runtime_macros::{Expander, Snapshots};
+/// # fn custom_assert_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+/// # let dir = std::env::temp_dir().join("runtime-macros-snapshots-doctest");
+/// let report = Expander::new()
+///     .functionlike("custom_assert", custom_assert_internal)
+/// #   .file(concat!(env!("CARGO_MANIFEST_DIR"), "/examples/custom_assert/tests/tests.rs"))
+/// # /*
+///     .file("tests/tests.rs")
+/// # */
+///     .run()
+///     .unwrap();
+/// # /*
+/// Snapshots::new("tests/expansions").check(&report).unwrap();
+/// # */
+/// # Snapshots::new(&dir).check(&report).unwrap();
+/// # std::fs::remove_dir_all(&dir).ok();
+/// ```
+#[cfg(feature = "fs")]
+#[derive(Debug, Clone)]
+pub struct Snapshots {
+    dir: PathBuf,
+    update: bool,
+}
+
+#[cfg(feature = "fs")]
+impl Snapshots {
+    /// Creates a set of snapshots stored in the given directory. They are updated instead of
+    /// checked if the `UPDATE_EXPANSIONS` environment variable is set to anything other than `0`.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Snapshots {
+            dir: dir.as_ref().to_owned(),
+            update: env::var_os("UPDATE_EXPANSIONS").is_some_and(|var| var != "0"),
+        }
+    }
+
+    /// Sets whether the snapshots should be overwritten with the new expansions instead of checked,
+    /// regardless of the `UPDATE_EXPANSIONS` environment variable.
+    pub fn update(&mut self, enabled: bool) -> &mut Self {
+        self.update = enabled;
+        self
+    }
+
+    /// Compares every expansion in the report with its snapshot. Missing snapshots are written,
+    /// and so are all the others if the snapshots are being updated.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if every snapshot matched, an [`Error::SnapshotMismatch`] listing every one that
+    /// didn't, or an [`Error::IoError`] if a snapshot couldn't be read or written.
+    ///
+    /// [`Error::SnapshotMismatch`]: enum.Error.html#variant.SnapshotMismatch
+    /// [`Error::IoError`]: enum.Error.html#variant.IoError
+    pub fn check(&self, report: &ExpansionReport) -> Result<(), Error> {
+        let mut mismatches = Vec::new();
+        for (name, file, record) in named_records(report) {
+            let snapshot = self.dir.join(name + ".expanded.rs");
+
+            let actual = record.pretty_output();
+            let io_error = |error| Error::IoError {
+                path: Some(snapshot.clone()),
+                error,
+            };
+            if !self.update && snapshot.exists() {
+                let expected = fs::read_to_string(&snapshot).map_err(io_error)?;
+                if !same_code(&expected, &actual) {
+                    mismatches.push(SnapshotMismatch {
+                        snapshot,
+                        path: file.path.clone(),
+                        kind: record.kind,
+                        macro_path: record.macro_path.clone(),
+                        start: record.start,
+                        expected,
+                        actual,
+                    });
+                }
+            } else {
+                fs::create_dir_all(&self.dir)
+                    .and_then(|()| fs::write(&snapshot, actual))
+                    .map_err(io_error)?;
+            }
+        }
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::SnapshotMismatch(mismatches))
+        }
+    }
+}
+
+// Returns true if a snapshot matches an expansion, either exactly or, if they're both valid tokens,
+// once they're normalized, so a reformatted snapshot still matches.
+#[cfg(feature = "fs")]
+fn same_code(expected: &str, actual: &str) -> bool {
+    if expected == actual {
+        return true;
+    }
+    match (expected.parse(), actual.parse()) {
+        (Ok(expected), Ok(actual)) => tokens::token_eq(&expected, &actual),
+        _ => false,
+    }
+}
+
+/// Returns the name and the formatted output of every expansion in the report, for use with other
+/// snapshot testing tools. The names are the ones that [`Snapshots`] uses, without the extension,
+/// and the outputs are formatted with [`ExpansionRecord::pretty_output`].
+///
+/// [`Snapshots`]: struct.Snapshots.html
+/// [`ExpansionRecord::pretty_output`]: struct.ExpansionRecord.html#method.pretty_output
+pub fn expansion_snapshots(report: &ExpansionReport) -> Vec<(String, String)> {
+    named_records(report)
+        .into_iter()
+        .map(|(name, _, record)| (name, record.pretty_output()))
+        .collect()
+}
+
+// Names every expansion in the report after the location of its invocation and the macro's path.
+fn named_records(report: &ExpansionReport) -> Vec<(String, &FileReport, &ExpansionRecord)> {
+    let mut named = Vec::new();
+    // How many times each name has been used, since an invocation can be expanded more than once,
+    // for instance when its output is rescanned.
+    let mut names = HashMap::new();
+    for file in report.files.iter() {
+        for record in file.records.iter() {
+            let mut name = format!(
+                "{}-{}",
+                corpus::location_name(Some(&file.path), record.start),
+                corpus::sanitize(&record.macro_path)
+            );
+            let count = names.entry(name.clone()).or_insert(0);
+            *count += 1;
+            if *count > 1 {
+                name = format!("{}-{}", name, count);
+            }
+            named.push((name, file, record));
+        }
+    }
+    named
+}
+
+/// Asserts that every expansion in an [`ExpansionReport`] matches its snapshot, using
+/// `insta::assert_snapshot!`, so the snapshots can be reviewed with `cargo insta review`. This
+/// requires the `insta` feature. Each snapshot is named as described in [`expansion_snapshots`],
+/// and it's stored wherever insta would store it for an assertion in the calling module.
+///
+/// [`ExpansionReport`]: struct.ExpansionReport.html
+/// [`expansion_snapshots`]: fn.expansion_snapshots.html
+///
+/// # Example
+///
+/// ```no_run
+/// # #[macro_use] extern crate runtime_macros;
+/// # use runtime_macros::Expander;
+/// # fn custom_assert_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+/// # fn main() {
+/// let report = Expander::new()
+///     .functionlike("custom_assert", custom_assert_internal)
+///     .file("tests/tests.rs")
+///     .run()
+///     .unwrap();
+/// assert_expansion_snapshots!(report);
+/// # }
+/// ```
+#[cfg(feature = "insta")]
+#[macro_export]
+macro_rules! assert_expansion_snapshots {
+    ($report:expr) => {
+        for (name, expansion) in $crate::expansion_snapshots(&$report) {
+            $crate::__insta::assert_snapshot!(name, expansion);
+        }
+    };
+}
+
+/// An expansion that didn't match its snapshot.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct SnapshotMismatch {
+    /// The path of the snapshot.
+    pub snapshot: PathBuf,
+    /// The path of the file containing the invocation.
+    pub path: PathBuf,
+    /// The kind of macro that was invoked.
+    pub kind: MacroKind,
+    /// The path of the macro, as it was registered.
+    pub macro_path: String,
+    /// The line and column in the file where the invocation starts.
+    pub start: LineColumn,
+    /// The contents of the snapshot.
+    pub expected: String,
+    /// The new expansion.
+    pub actual: String,
+}
+
+impl SnapshotMismatch {
+    /// Returns the differences between the snapshot and the new expansion, or `None` if either of
+    /// them isn't a valid token stream.
+    pub fn diff(&self) -> Option<TokenDiff> {
+        match (self.expected.parse(), self.actual.parse()) {
+            (Ok(expected), Ok(actual)) => Some(tokens::diff(&expected, &actual)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SnapshotMismatch {
+    /// Describes the mismatch, followed by the parts of the expansion that changed, as rendered by
+    /// [`TokenDiff::render`], with each line indented.
+    ///
+    /// [`TokenDiff::render`]: tokens/struct.TokenDiff.html#method.render
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: the expansion of {} macro `{}` doesn't match {}",
+            self.path.display(),
+            self.start.line,
+            self.start.column,
+            self.kind,
+            self.macro_path,
+            self.snapshot.display()
+        )?;
+        if let Some(diff) = self.diff() {
+            for line in diff.render(3, false).lines() {
+                write!(f, "\n    {}", line)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "fs"))]
+mod tests {
+    use {super::*, proc_macro2::TokenStream, std::process, MacroRegistry};
+
+    #[test]
+    fn compares_and_updates_snapshots() {
+        let dir = env::temp_dir().join(format!("runtime-macros-snapshots-{}", process::id()));
+        let source = "fn main() { foo!(1); foo!(2); }";
+        let expand = |output: &'static str| {
+            let mut registry = MacroRegistry::new();
+            registry.functionlike("foo", move |ts: TokenStream| {
+                if ts.to_string() == "2" {
+                    output.parse().unwrap()
+                } else {
+                    ts
+                }
+            });
+            let scan = registry.expand_source(source, None).unwrap();
+            ExpansionReport {
+                files: vec![scan.into_report(PathBuf::from("src/main.rs"))],
+            }
+        };
+
+        let mut snapshots = Snapshots::new(&dir);
+        snapshots.update(false);
+        snapshots.check(&expand("2")).unwrap();
+        assert_eq!(
+            expansion_snapshots(&expand("2")),
+            [
+                ("src_main.rs-1-12-foo".to_owned(), "1\n".to_owned()),
+                ("src_main.rs-1-21-foo".to_owned(), "2\n".to_owned())
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("src_main.rs-1-21-foo.expanded.rs")).unwrap(),
+            "2\n"
+        );
+        snapshots.check(&expand("2")).unwrap();
+
+        match snapshots.check(&expand("3")) {
+            Err(Error::SnapshotMismatch(mismatches)) => {
+                assert_eq!(mismatches.len(), 1);
+                assert_eq!(mismatches[0].expected, "2\n");
+                assert_eq!(mismatches[0].actual, "3\n");
+                assert!(mismatches[0].to_string().ends_with("\n    [-2-]{+3+}"));
+            }
+            other => panic!("expected a mismatch, got {:?}", other),
+        }
+
+        let snapshot = dir.join("src_main.rs-1-21-foo.expanded.rs");
+        fs::write(&snapshot, "3 \n\n").unwrap();
+        snapshots.check(&expand("3")).unwrap();
+
+        snapshots.update(true).check(&expand("3")).unwrap();
+        snapshots.update(false).check(&expand("3")).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(feature = "insta")]
+    #[test]
+    fn asserts_insta_snapshots() {
+        use sandbox;
+
+        // Other threads could be reading the environment while it changes.
+        sandbox::locked(|| {
+            let dir = env::temp_dir().join(format!("runtime-macros-insta-{}", process::id()));
+            env::set_var("INSTA_UPDATE", "always");
+            let mut registry = MacroRegistry::new();
+            registry.functionlike("foo", |ts| ts);
+            let scan = registry
+                .expand_source("fn main() { foo!(1); }", None)
+                .unwrap();
+            let report = ExpansionReport {
+                files: vec![scan.into_report(PathBuf::from("src/main.rs"))],
+            };
+
+            let mut settings = ::__insta::Settings::clone_current();
+            settings.set_snapshot_path(&dir);
+            settings.set_prepend_module_to_snapshot(false);
+            settings.bind(|| assert_expansion_snapshots!(report));
+            let snapshot = fs::read_to_string(dir.join("src_main.rs-1-12-foo.snap")).unwrap();
+            assert!(snapshot.ends_with("1\n"));
+
+            fs::remove_dir_all(&dir).unwrap();
+        });
+    }
+}
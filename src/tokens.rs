@@ -0,0 +1,449 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Utilities for comparing token streams by their structure rather than their string forms.
+//!
+//! Two token streams that print differently can still be the same code: their spans may differ,
+//! a group may be wrapped in invisible delimiters, or a punctuation mark may be marked as joined
+//! to a token that can't join with it. [`normalize`] removes those differences, [`token_eq`]
+//! compares two streams after normalizing them, [`contains`] looks for one inside the other, and
+//! [`diff`] finds where they differ. [`compile_errors`] finds the errors that a macro reported
+//! with `compile_error!`.
+//!
+//! [`normalize`]: fn.normalize.html
+//! [`token_eq`]: fn.token_eq.html
+//! [`contains`]: fn.contains.html
+//! [`compile_errors`]: fn.compile_errors.html
+//! [`diff`]: fn.diff.html
+
+use {
+    proc_macro2::{Delimiter, Group, Punct, Spacing, TokenStream, TokenTree},
+    std::fmt,
+};
+
+/// Returns the same tokens with the differences that don't change their meaning removed. Groups
+/// with invisible delimiters, like the ones that `macro_rules!` puts around fragments, are replaced
+/// with their contents, and punctuation is only marked as joint if it's followed by more
+/// punctuation. Spans are left alone, since they never affect [`token_eq`].
+///
+/// [`token_eq`]: fn.token_eq.html
+pub fn normalize(tokens: TokenStream) -> TokenStream {
+    let mut normalized = Vec::new();
+    flatten(tokens, &mut normalized);
+    for i in 0..normalized.len() {
+        let followed_by_punct = matches!(normalized.get(i + 1), Some(TokenTree::Punct(_)));
+        if let TokenTree::Punct(ref mut punct) = normalized[i] {
+            if punct.spacing() == Spacing::Joint && !followed_by_punct {
+                let mut alone = Punct::new(punct.as_char(), Spacing::Alone);
+                alone.set_span(punct.span());
+                *punct = alone;
+            }
+        }
+    }
+    normalized.into_iter().collect()
+}
+
+fn flatten(tokens: TokenStream, normalized: &mut Vec<TokenTree>) {
+    for tt in tokens {
+        match tt {
+            TokenTree::Group(group) if group.delimiter() == Delimiter::None => {
+                flatten(group.stream(), normalized);
+            }
+            TokenTree::Group(group) => {
+                let mut normalized_group = Group::new(group.delimiter(), normalize(group.stream()));
+                normalized_group.set_span(group.span());
+                normalized.push(TokenTree::Group(normalized_group));
+            }
+            tt => normalized.push(tt),
+        }
+    }
+}
+
+/// Returns true if the two token streams are the same code once they're [normalized], ignoring
+/// spans and whitespace.
+///
+/// [normalized]: fn.normalize.html
+///
+/// # Example
+///
+/// ```
+/// # use runtime_macros::tokens::token_eq;
+/// let a: proc_macro2::TokenStream = "foo :: bar(1)".parse().unwrap();
+/// let b: proc_macro2::TokenStream = "foo::bar( 1 )".parse().unwrap();
+/// assert!(token_eq(&a, &b));
+/// ```
+pub fn token_eq(a: &TokenStream, b: &TokenStream) -> bool {
+    atoms(a) == atoms(b)
+}
+
+/// Returns true if the needle appears somewhere in the haystack, as an unbroken run of tokens,
+/// once both are [normalized]. A group in the needle only matches a whole group, but the needle
+/// itself can match part of the inside of a group.
+///
+/// [normalized]: fn.normalize.html
+///
+/// # Example
+///
+/// ```
+/// # use runtime_macros::tokens::contains;
+/// let haystack: proc_macro2::TokenStream = "fn f() { g(1, 2); }".parse().unwrap();
+/// assert!(contains(&haystack, &"g(1, 2)".parse().unwrap()));
+/// assert!(contains(&haystack, &"1, 2".parse().unwrap()));
+/// assert!(!contains(&haystack, &"g(1)".parse().unwrap()));
+/// ```
+pub fn contains(haystack: &TokenStream, needle: &TokenStream) -> bool {
+    let (haystack, needle) = (atoms(haystack), atoms(needle));
+    needle.is_empty()
+        || haystack
+            .windows(needle.len())
+            .any(|window| window == &needle[..])
+}
+
+/// Returns the message of every `compile_error!` invocation in the tokens, including ones nested
+/// inside groups, in order. Invocations with a path, like the `::core::compile_error! { ... }`
+/// that `syn::Error::to_compile_error` produces, are found too. Invocations whose argument isn't a
+/// string literal are skipped.
+///
+/// # Example
+///
+/// ```
+/// # use runtime_macros::tokens::compile_errors;
+/// let error = syn::Error::new(proc_macro2::Span::call_site(), "expected a struct");
+/// let error = error.to_compile_error();
+/// let tokens = quote::quote!(fn f() { #error });
+/// assert_eq!(compile_errors(&tokens), ["expected a struct"]);
+/// ```
+pub fn compile_errors(tokens: &TokenStream) -> Vec<String> {
+    let mut messages = Vec::new();
+    find_compile_errors(tokens.clone(), &mut messages);
+    messages
+}
+
+fn find_compile_errors(tokens: TokenStream, messages: &mut Vec<String>) {
+    let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+    for (i, tt) in tokens.iter().enumerate() {
+        match *tt {
+            TokenTree::Ident(ref ident) if ident == "compile_error" => {
+                if let (Some(TokenTree::Punct(bang)), Some(TokenTree::Group(args))) =
+                    (tokens.get(i + 1), tokens.get(i + 2))
+                {
+                    if bang.as_char() == '!' {
+                        if let Ok(message) = syn::parse2::<syn::LitStr>(args.stream()) {
+                            messages.push(message.value());
+                        }
+                    }
+                }
+            }
+            TokenTree::Group(ref group) => find_compile_errors(group.stream(), messages),
+            _ => {}
+        }
+    }
+}
+
+/// The differences between two token streams, as found by [`diff`].
+///
+/// [`diff`]: fn.diff.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct TokenDiff {
+    /// Every token of both streams, in order, marked with whether it's in one or both of them. The
+    /// delimiters of each group count as separate tokens, so a group can be partly the same.
+    pub changes: Vec<Change>,
+}
+
+impl TokenDiff {
+    /// Returns true if the two token streams were the same.
+    pub fn is_empty(&self) -> bool {
+        self.changes
+            .iter()
+            .all(|change| matches!(change, Change::Same(_)))
+    }
+
+    /// Renders only the parts of the streams that changed, each with up to `context` unchanged
+    /// tokens on either side, one region per line. Removed tokens are written as `[-removed-]`
+    /// and added ones as `{+added+}`, and with `color`, they're also colored red and green for a
+    /// terminal. Unchanged tokens that are left out are written as `...`. If nothing changed, the
+    /// result is empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use runtime_macros::tokens::diff;
+    /// let expected: proc_macro2::TokenStream = "let x = 1 ; let y = 2 ;".parse().unwrap();
+    /// let actual: proc_macro2::TokenStream = "let x = 1 ; let y = 3 ;".parse().unwrap();
+    /// assert_eq!(diff(&expected, &actual).render(2, false), "... y = [-2-]{+3+} ;");
+    /// ```
+    pub fn render(&self, context: usize, color: bool) -> String {
+        if self.is_empty() {
+            return String::new();
+        }
+        // Which changes to show: every changed token and the context around it.
+        let mut shown = vec![false; self.changes.len()];
+        for (i, change) in self.changes.iter().enumerate() {
+            if !matches!(change, Change::Same(_)) {
+                let start = i.saturating_sub(context);
+                let end = (i + context + 1).min(self.changes.len());
+                for show in shown[start..end].iter_mut() {
+                    *show = true;
+                }
+            }
+        }
+
+        let (removed, added, reset) = if color {
+            ("\x1b[31m", "\x1b[32m", "\x1b[0m")
+        } else {
+            ("", "", "")
+        };
+        let mut lines = Vec::new();
+        let mut line = String::new();
+        let mut i = 0;
+        while i < self.changes.len() {
+            if !shown[i] {
+                if !line.is_empty() {
+                    lines.push(line + " ...");
+                    line = String::new();
+                }
+                while i < self.changes.len() && !shown[i] {
+                    i += 1;
+                }
+                line.push_str("...");
+                continue;
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            match self.changes[i] {
+                Change::Same(ref token) => {
+                    line.push_str(token);
+                    i += 1;
+                }
+                _ => {
+                    // Show a whole run of changes as one removal followed by one addition.
+                    let mut old = Vec::new();
+                    let mut new = Vec::new();
+                    while let Some(change) = self.changes.get(i) {
+                        match *change {
+                            Change::Removed(ref token) => old.push(token.as_str()),
+                            Change::Added(ref token) => new.push(token.as_str()),
+                            Change::Same(_) => break,
+                        }
+                        i += 1;
+                    }
+                    if !old.is_empty() {
+                        line.push_str(&format!("{}[-{}-]{}", removed, old.join(" "), reset));
+                    }
+                    if !new.is_empty() {
+                        line.push_str(&format!("{}{{+{}+}}{}", added, new.join(" "), reset));
+                    }
+                }
+            }
+        }
+        if line != "..." {
+            lines.push(line);
+        }
+        lines.join("\n")
+    }
+}
+
+impl fmt::Display for TokenDiff {
+    /// Renders the diff without color, with three tokens of context. See [`render`].
+    ///
+    /// [`render`]: #method.render
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.render(3, false))
+    }
+}
+
+/// One token in a [`TokenDiff`], printed as it would appear in the code.
+///
+/// [`TokenDiff`]: struct.TokenDiff.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Change {
+    /// A token that's in both streams.
+    Same(String),
+    /// A token that's only in the expected stream.
+    Removed(String),
+    /// A token that's only in the actual stream.
+    Added(String),
+}
+
+// Counts the tokens in the stream, including the delimiters of each group and the tokens inside
+// it.
+pub(crate) fn count(tokens: &TokenStream) -> usize {
+    tokens
+        .clone()
+        .into_iter()
+        .map(|tt| match tt {
+            TokenTree::Group(ref group) => 2 + count(&group.stream()),
+            _ => 1,
+        })
+        .sum()
+}
+
+/// Finds the differences between the expected and actual token streams after [normalizing] them,
+/// keeping as many tokens in common as possible.
+///
+/// [normalizing]: fn.normalize.html
+pub fn diff(expected: &TokenStream, actual: &TokenStream) -> TokenDiff {
+    let (expected, actual) = (atoms(expected), atoms(actual));
+    let prefix = expected
+        .iter()
+        .zip(actual.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let suffix = expected[prefix..]
+        .iter()
+        .rev()
+        .zip(actual[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (a, b) = (
+        &expected[prefix..expected.len() - suffix],
+        &actual[prefix..actual.len() - suffix],
+    );
+
+    // The length of the longest common subsequence of `a[i..]` and `b[j..]`.
+    let mut lengths = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i] == b[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes: Vec<Change> = expected[..prefix]
+        .iter()
+        .cloned()
+        .map(Change::Same)
+        .collect();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            changes.push(Change::Same(a[i].clone()));
+            i += 1;
+            j += 1;
+        } else if j == b.len() || (i < a.len() && lengths[i + 1][j] >= lengths[i][j + 1]) {
+            changes.push(Change::Removed(a[i].clone()));
+            i += 1;
+        } else {
+            changes.push(Change::Added(b[j].clone()));
+            j += 1;
+        }
+    }
+    changes.extend(
+        expected[expected.len() - suffix..]
+            .iter()
+            .cloned()
+            .map(Change::Same),
+    );
+    TokenDiff { changes }
+}
+
+// Splits normalized tokens into a flat list of strings, with each group's delimiters separate.
+// Joint punctuation is kept together with the punctuation that follows it, so `::` differs from
+// `: :`.
+fn atoms(tokens: &TokenStream) -> Vec<String> {
+    fn push_atoms(tokens: TokenStream, atoms: &mut Vec<String>) {
+        let mut joint = false;
+        for tt in tokens {
+            match tt {
+                TokenTree::Group(group) => {
+                    let (open, close) = match group.delimiter() {
+                        Delimiter::Parenthesis => ("(", ")"),
+                        Delimiter::Brace => ("{", "}"),
+                        Delimiter::Bracket => ("[", "]"),
+                        Delimiter::None => {
+                            unreachable!("normalized tokens have no invisible groups")
+                        }
+                    };
+                    atoms.push(open.to_owned());
+                    push_atoms(group.stream(), atoms);
+                    atoms.push(close.to_owned());
+                    joint = false;
+                }
+                TokenTree::Punct(punct) => {
+                    match atoms.last_mut() {
+                        Some(last) if joint => last.push(punct.as_char()),
+                        _ => atoms.push(punct.as_char().to_string()),
+                    }
+                    joint = punct.spacing() == Spacing::Joint;
+                }
+                tt => {
+                    atoms.push(tt.to_string());
+                    joint = false;
+                }
+            }
+        }
+    }
+    let mut atoms = Vec::new();
+    push_atoms(normalize(tokens.clone()), &mut atoms);
+    atoms
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(s: &str) -> TokenStream {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn compares_normalized_tokens() {
+        assert!(token_eq(&ts("a::b ( 1 )"), &ts("a :: b(1)")));
+        assert!(!token_eq(&ts("a::b"), &ts("a: :b")));
+        let wrapped: TokenStream =
+            TokenTree::Group(Group::new(Delimiter::None, ts("1 + 1"))).into();
+        assert!(token_eq(&wrapped, &ts("1 + 1")));
+        assert_eq!(normalize(wrapped).to_string(), "1 + 1");
+    }
+
+    #[test]
+    fn diffs_tokens() {
+        let changed = diff(&ts("f(a, b)"), &ts("f(a, c, b)"));
+        assert_eq!(
+            changed.changes,
+            [
+                Change::Same("f".to_owned()),
+                Change::Same("(".to_owned()),
+                Change::Same("a".to_owned()),
+                Change::Same(",".to_owned()),
+                Change::Added("c".to_owned()),
+                Change::Added(",".to_owned()),
+                Change::Same("b".to_owned()),
+                Change::Same(")".to_owned()),
+            ]
+        );
+        assert!(!changed.is_empty());
+        assert!(diff(&ts("x"), &ts("x")).is_empty());
+    }
+
+    #[test]
+    fn renders_changed_regions() {
+        let expected = ts("a b c d e f g h i j k l");
+        let actual = ts("a B c d e f g h i j l m");
+        let changed = diff(&expected, &actual);
+        assert_eq!(
+            changed.render(1, false),
+            "a [-b-]{+B+} c ...\n... j [-k-] l {+m+}"
+        );
+        assert_eq!(
+            changed.render(0, true),
+            "... \x1b[31m[-b-]\x1b[0m\x1b[32m{+B+}\x1b[0m ...\n... \x1b[31m[-k-]\x1b[0m ...\n... \x1b[32m{+m+}\x1b[0m"
+        );
+        assert_eq!(diff(&expected, &expected).to_string(), "");
+    }
+}
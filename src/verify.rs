@@ -0,0 +1,301 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+//! Checking that the expansions compile, by splicing them back into the source code and running
+//! rustc on the result.
+
+use {
+    proc_macro2::{LineColumn, TokenStream},
+    report::{ExpansionRecord, FileReport, MacroKind, MacroPosition},
+    std::{
+        env, fs,
+        path::PathBuf,
+        process::{self, Command},
+        sync::atomic::{AtomicUsize, Ordering},
+    },
+    Error,
+};
+
+/// Compiles each scanned file with its expansions in place of the macro invocations, to check that
+/// the expansions are valid Rust code and not just valid syntax. This requires the
+/// `verify-compile` feature, and it's slow, since rustc runs once per file.
+///
+/// The file is compiled on its own, as a library crate, with `rustc --emit=metadata`, so any other
+/// crates or modules it uses have to be made available with [`arg`], for instance with `--extern`
+/// and `-L`. Warnings are allowed.
+///
+/// Each expansion is spliced in where its invocation was: a function-like macro's output replaces
+/// the call, wrapped in parentheses if it's in an expression or pattern, an attribute-like macro's
+/// output replaces the annotated item, and a derive's output is added after the item. If one
+/// invocation is inside another one's replaced code, only the outer one is spliced in. Derives and
+/// attribute-like macros can only be spliced in if the input they were given still has the spans
+/// from the file, which isn't true of files scanned by a `ParallelExpander` or of the output of
+/// another macro, and those expansions are left out.
+///
+/// [`arg`]: #method.arg
+///
+/// # Example
+///
+/// ```no_run
+/// # use runtime_macros::{CompileCheck, Expander};
+/// # fn custom_assert_internal(ts: proc_macro2::TokenStream) -> proc_macro2::TokenStream { ts }
+/// let mut check = CompileCheck::new();
+/// check.edition("2021");
+/// Expander::new()
+///     .functionlike("custom_assert", custom_assert_internal)
+///     .file("tests/tests.rs")
+///     .verify_compile(check)
+///     .run()
+///     .unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct CompileCheck {
+    rustc: PathBuf,
+    edition: String,
+    args: Vec<String>,
+}
+
+impl Default for CompileCheck {
+    fn default() -> Self {
+        CompileCheck {
+            rustc: env::var_os("RUSTC").map_or_else(|| PathBuf::from("rustc"), PathBuf::from),
+            edition: "2021".to_owned(),
+            args: Vec::new(),
+        }
+    }
+}
+
+impl CompileCheck {
+    /// Creates a check that uses the rustc in the `RUSTC` environment variable, or else the one on
+    /// the `PATH`, with the 2021 edition.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the edition to compile the files with.
+    pub fn edition(&mut self, edition: &str) -> &mut Self {
+        self.edition = edition.to_owned();
+        self
+    }
+
+    /// Adds an argument to pass to rustc, such as `--extern`, `-L`, or `--cfg`.
+    pub fn arg(&mut self, arg: &str) -> &mut Self {
+        self.args.push(arg.to_owned());
+        self
+    }
+
+    /// Compiles the given source code with the expansions in the report spliced in, as described
+    /// above.
+    ///
+    /// # Returns
+    ///
+    /// `Ok(())` if the code compiled, an [`Error::CompileFailed`] with rustc's output if it didn't,
+    /// or an [`Error::IoError`] if rustc couldn't be run.
+    ///
+    /// [`Error::CompileFailed`]: enum.Error.html#variant.CompileFailed
+    /// [`Error::IoError`]: enum.Error.html#variant.IoError
+    pub fn check(&self, content: &str, report: &FileReport) -> Result<(), Error> {
+        // Every check gets its own directory, in case several run at once.
+        static CHECKS: AtomicUsize = AtomicUsize::new(0);
+        let dir = env::temp_dir().join(format!(
+            "runtime-macros-verify-{}-{}",
+            process::id(),
+            CHECKS.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = dir.join("lib.rs");
+        let result = fs::create_dir_all(&dir)
+            .and_then(|()| fs::write(&file, splice(content, report)))
+            .and_then(|()| {
+                Command::new(&self.rustc)
+                    .arg("--edition")
+                    .arg(&self.edition)
+                    .args([
+                        "--crate-type",
+                        "lib",
+                        "--crate-name",
+                        "runtime_macros_verify",
+                    ])
+                    .args(["--emit=metadata", "-A", "warnings", "--out-dir"])
+                    .arg(&dir)
+                    .args(&self.args)
+                    .arg(&file)
+                    .output()
+            });
+        fs::remove_dir_all(&dir).ok();
+        let output = result.map_err(|error| Error::IoError {
+            path: Some(self.rustc.clone()),
+            error,
+        })?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(Error::CompileFailed {
+                path: Some(report.path.clone()),
+                stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+            })
+        }
+    }
+}
+
+// Returns the source code with every expansion in the report spliced in.
+pub(crate) fn splice(content: &str, report: &FileReport) -> String {
+    // The byte offset where each line starts.
+    let mut line_starts = vec![0];
+    line_starts.extend(content.match_indices('\n').map(|(i, _)| i + 1));
+    let offset = |at: LineColumn| -> Option<usize> {
+        let start = *line_starts.get(at.line.checked_sub(1)?)?;
+        let line = &content[start..];
+        Some(
+            start
+                + line
+                    .char_indices()
+                    .nth(at.column)
+                    .map_or(line.len(), |(i, _)| i),
+        )
+    };
+
+    // Each replacement as the range it replaces and the code to put there.
+    let mut replacements = Vec::new();
+    for record in report.records.iter() {
+        replacements.extend(replacements_for(record, &offset, content).unwrap_or_default());
+    }
+    replacements.sort_by_key(|&(start, end, _)| (start, std::cmp::Reverse(end)));
+
+    let mut spliced = String::new();
+    let mut copied = 0;
+    for (start, end, code) in replacements {
+        if start < copied {
+            // This one is inside an earlier replacement, or overlaps it.
+            continue;
+        }
+        spliced.push_str(&content[copied..start]);
+        spliced.push_str(&code);
+        copied = end;
+    }
+    spliced.push_str(&content[copied..]);
+    spliced
+}
+
+// Returns the replacements that splice in one expansion, or `None` if it can't be spliced in.
+fn replacements_for<F>(
+    record: &ExpansionRecord,
+    offset: &F,
+    content: &str,
+) -> Option<Vec<(usize, usize, String)>>
+where
+    F: Fn(LineColumn) -> Option<usize>,
+{
+    let output = record.output.to_string();
+    Some(match record.kind {
+        MacroKind::Functionlike => {
+            let (start, mut end) = (offset(record.start)?, offset(record.end)?);
+            let code = match record.position {
+                Some(MacroPosition::Expression) | Some(MacroPosition::Pattern) => {
+                    format!("({})", output)
+                }
+                Some(MacroPosition::Statement) | Some(MacroPosition::Type) | None => output,
+                Some(_) => {
+                    // An item-like invocation with parentheses or brackets ends with a semicolon,
+                    // which would be left over.
+                    let rest = &content[end..];
+                    if rest.trim_start().starts_with(';') {
+                        end += rest.len() - rest.trim_start().len() + 1;
+                    }
+                    output
+                }
+            };
+            vec![(start, end, code)]
+        }
+        MacroKind::Derive => {
+            // The derive's path is removed from the attribute, along with the comma after it.
+            let (path_start, mut path_end) = (offset(record.start)?, offset(record.end)?);
+            let rest = &content[path_end..];
+            if rest.trim_start().starts_with(',') {
+                path_end += rest.len() - rest.trim_start().len() + 1;
+            }
+            let (_, end) = item_range(&record.input, offset)?;
+            vec![
+                (path_start, path_end, String::new()),
+                (end, end, format!("\n{}\n", output)),
+            ]
+        }
+        MacroKind::Attribute => {
+            let (item_start, end) = item_range(&record.input, offset)?;
+            vec![(item_start.min(offset(record.start)?), end, output)]
+        }
+    })
+}
+
+// Returns the byte range that the tokens came from, if they still have their original spans.
+fn item_range<F>(tokens: &TokenStream, offset: &F) -> Option<(usize, usize)>
+where
+    F: Fn(LineColumn) -> Option<usize>,
+{
+    let first = tokens.clone().into_iter().next()?.span();
+    let last = tokens.clone().into_iter().last()?.span();
+    let (start, end) = (offset(first.start())?, offset(last.end())?);
+    if first.start().line == 0 || start >= end {
+        None
+    } else {
+        Some((start, end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, MacroRegistry};
+
+    #[test]
+    fn splices_and_compiles_expansions() {
+        let source = "
+#[derive(Zero)]
+struct A;
+
+#[double]
+fn f() -> u32 { one!() }
+
+foo!(item);
+";
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("one", |_| "1".parse().unwrap())
+            .functionlike("foo", |_| "fn g() -> u32 { 2 }".parse().unwrap())
+            .derive("Zero", |_| {
+                "impl A { fn zero() -> u32 { 0 } }".parse().unwrap()
+            })
+            .attribute("double", |_, _| "fn f() -> u32 { 2 }".parse().unwrap());
+        let report = registry
+            .expand_source(source, None)
+            .unwrap()
+            .into_report(PathBuf::new());
+        let spliced = splice(source, &report);
+        assert!(spliced.contains("impl A"));
+        assert!(!spliced.contains("!"));
+        assert!(!spliced.contains("Zero"));
+        assert!(!spliced.contains("double"));
+        CompileCheck::new().check(source, &report).unwrap();
+
+        let mut registry = MacroRegistry::new();
+        registry.functionlike("one", |_| "\"one\"".parse().unwrap());
+        let source = "fn f() -> u32 { one!() }";
+        let report = registry
+            .expand_source(source, None)
+            .unwrap()
+            .into_report(PathBuf::new());
+        match CompileCheck::new().check(source, &report) {
+            Err(Error::CompileFailed { stderr, .. }) => {
+                assert!(stderr.contains("mismatched types"))
+            }
+            other => panic!("expected a compile error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,2066 @@
+// Copyright (c) 2018-2022 Jeremy Davis (jeremydavis519@gmail.com)
+//
+// Licensed under the Apache License, Version 2.0 (located at /LICENSE-APACHE
+// or http://www.apache.org/licenses/LICENSE-2.0), or the MIT license
+// (located at /LICENSE-MIT or http://opensource.org/licenses/MIT), at your
+// option. The file may not be copied, modified, or distributed except
+// according to those terms.
+//
+// Unless required by applicable law or agreed to in writing, this software
+// is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR CONDITIONS OF
+// ANY KIND, either express or implied. See the applicable license for the
+// specific language governing permissions and limitations under that license.
+
+//! The syntax tree visitor that finds macro invocations and calls the registered functions to
+//! expand them.
+
+#[cfg(feature = "fuzz")]
+use fuzz;
+use {
+    cfg,
+    compat::{self, AttrArgs},
+    context::ExpansionContext,
+    directives::Directives,
+    driver::ExpansionHandler,
+    hygiene, isolation,
+    matching::{self, MatchMode},
+    memory, minimize, order,
+    panics::{self, PanicPolicy},
+    proc_macro2::{Delimiter, Span, TokenStream, TokenTree},
+    quote::ToTokens,
+    registry::{ContextAttributeFn, ContextFn, HookFn, LintFn, MacroRegistry, Options},
+    report::{
+        CallSite, ExpansionOutcome, ExpansionPanic, ExpansionRecord, ExpansionWarning, FileReport,
+        InvocationInfo, MacroKind, MacroPosition, NearMiss,
+    },
+    sandbox,
+    std::{
+        borrow::Cow,
+        cell::Cell,
+        collections::BTreeMap,
+        io, mem,
+        path::{Path, PathBuf},
+        time::{Duration, Instant},
+    },
+    syn::{
+        parse::{Parse, ParseStream, Parser},
+        punctuated::Punctuated,
+        spanned::Spanned,
+        visit::Visit,
+        Token,
+    },
+    tokens, Error,
+};
+#[cfg(feature = "fs")]
+use {
+    corpus,
+    proc_macro2::LineColumn,
+    session::{self, ParsedSource},
+};
+
+// The results of scanning one file.
+#[derive(Debug)]
+pub(crate) struct Scan {
+    pub(crate) records: Vec<ExpansionRecord>,
+    pub(crate) nested_items: usize,
+    pub(crate) near_misses: Vec<NearMiss>,
+    pub(crate) unregistered: Vec<InvocationInfo>,
+    pub(crate) call_sites: Vec<CallSite>,
+    pub(crate) panics: Vec<ExpansionPanic>,
+    pub(crate) warnings: Vec<ExpansionWarning>,
+    pub(crate) parse_error: Option<syn::Error>,
+    pub(crate) duration: Duration,
+    // The paths and contents of the modules' files that were loaded for attribute-like macros.
+    #[cfg(feature = "fs")]
+    pub(crate) loaded_modules: Vec<(PathBuf, String)>,
+}
+
+impl Scan {
+    pub(crate) fn into_report(self, path: PathBuf) -> FileReport {
+        FileReport {
+            path,
+            records: self.records,
+            nested_items: self.nested_items,
+            near_misses: self.near_misses,
+            unregistered: self.unregistered,
+            call_sites: self.call_sites,
+            panics: self.panics,
+            warnings: self.warnings,
+            parse_error: self.parse_error,
+            cached: false,
+            cached_invocations: BTreeMap::new(),
+            duration: self.duration,
+        }
+    }
+}
+
+// A registration whose path has been parsed so it can be compared with the ones in the source
+// code.
+struct Registered<'r, F: 'r> {
+    path: syn::Path,
+    mode: MatchMode,
+    name: &'r str,
+    proc_macro_fn: &'r F,
+}
+
+impl<'r, F> Registered<'r, F> {
+    fn matches(&self, path: &syn::Path) -> bool {
+        self.mode.matches(&self.path, path)
+    }
+
+    // Returns true if the path didn't match but has the same last segment, ignoring any generic
+    // arguments.
+    fn almost_matches(&self, path: &syn::Path) -> bool {
+        match (self.path.segments.last(), path.segments.last()) {
+            (Some(registered), Some(found)) => registered.ident == found.ident,
+            _ => false,
+        }
+    }
+}
+
+// Returns a warning for each registration that the given path almost matches. This should only be
+// called if the path didn't match any of them.
+fn near_misses<F>(
+    registrations: &[Registered<'_, F>],
+    kind: MacroKind,
+    path: &syn::Path,
+) -> Vec<NearMiss> {
+    registrations
+        .iter()
+        .filter(|registered| registered.almost_matches(path))
+        .map(|registered| NearMiss {
+            kind,
+            found_path: matching::path_to_string(path),
+            registered_path: registered.name.to_owned(),
+            span: path.span(),
+        })
+        .collect()
+}
+
+fn parse_paths<'r, F>(
+    registrations: &'r [(String, F)],
+    kind: MacroKind,
+    options: &Options,
+) -> Result<Vec<Registered<'r, F>>, Error> {
+    registrations
+        .iter()
+        .map(|(name, proc_macro_fn)| {
+            Ok(Registered {
+                path: syn::parse_str(name).map_err(|error| Error::InvalidMacroPath {
+                    kind,
+                    macro_path: name.clone(),
+                    error,
+                })?,
+                mode: options.match_mode(kind, name),
+                name,
+                proc_macro_fn,
+            })
+        })
+        .collect()
+}
+
+// If the tokens start with a macro invocation, such as `foo::bar!(...)`, returns the macro's path
+// and the number of tokens in the invocation.
+fn macro_call_at(tokens: &[TokenTree]) -> Option<(syn::Path, usize)> {
+    let mut len = 0;
+    loop {
+        match tokens.get(len) {
+            Some(TokenTree::Punct(ref colon)) if colon.as_char() == ':' => {
+                match tokens.get(len + 1) {
+                    Some(TokenTree::Punct(ref colon)) if colon.as_char() == ':' => len += 2,
+                    _ => return None,
+                }
+            }
+            _ if len > 0 => return None,
+            _ => {}
+        }
+        match tokens.get(len) {
+            Some(TokenTree::Ident(_)) => len += 1,
+            _ => return None,
+        }
+        match tokens.get(len) {
+            Some(TokenTree::Punct(ref bang)) if bang.as_char() == '!' => break,
+            Some(TokenTree::Punct(ref colon)) if colon.as_char() == ':' => {}
+            _ => return None,
+        }
+    }
+    match tokens.get(len + 1) {
+        Some(TokenTree::Group(_)) => {}
+        _ => return None,
+    }
+    let path = syn::parse2(tokens[..len].iter().cloned().collect()).ok()?;
+    Some((path, len + 2))
+}
+
+// Returns the number of tokens in the outer attributes at the start of the given tokens.
+fn outer_attrs_len(tokens: &[TokenTree]) -> usize {
+    let mut len = 0;
+    while let (Some(TokenTree::Punct(pound)), Some(TokenTree::Group(group))) =
+        (tokens.get(len), tokens.get(len + 1))
+    {
+        if pound.as_char() != '#' || group.delimiter() != Delimiter::Bracket {
+            break;
+        }
+        len += 2;
+    }
+    len
+}
+
+// Guesses the number of tokens in the item at the start of the given tokens: everything up to and
+// including the first semicolon or block that isn't nested inside anything else.
+fn item_len(tokens: &[TokenTree]) -> usize {
+    for (i, token) in tokens.iter().enumerate() {
+        match *token {
+            TokenTree::Punct(ref semi) if semi.as_char() == ';' => return i + 1,
+            TokenTree::Group(ref block) if block.delimiter() == Delimiter::Brace => return i + 1,
+            _ => {}
+        }
+    }
+    tokens.len()
+}
+
+// Returns true if the compiler allows `#[derive]` on the item, which it only does on structs, enums,
+// and unions.
+fn is_derivable(item: &syn::Item) -> bool {
+    matches!(
+        *item,
+        syn::Item::Struct(_) | syn::Item::Enum(_) | syn::Item::Union(_)
+    )
+}
+
+// Does the same thing as `is_derivable` for the tokens after the attributes of an item that syn
+// couldn't parse, by looking at the keyword after its visibility.
+fn is_derivable_tokens(tokens: &TokenStream) -> bool {
+    for token in tokens.clone() {
+        match token {
+            TokenTree::Ident(ref ident) if ident == "pub" => {}
+            TokenTree::Group(ref group) if group.delimiter() == Delimiter::Parenthesis => {}
+            TokenTree::Ident(ref ident) => {
+                return ident == "struct" || ident == "enum" || ident == "union"
+            }
+            _ => return false,
+        }
+    }
+    false
+}
+
+// An item that syn couldn't parse, split into its outer attributes and everything after them.
+#[derive(Clone)]
+struct LooseItem {
+    attrs: Vec<syn::Attribute>,
+    tokens: TokenStream,
+}
+
+impl ToTokens for LooseItem {
+    fn to_tokens(&self, tokens: &mut TokenStream) {
+        for attr in self.attrs.iter() {
+            attr.to_tokens(tokens);
+        }
+        self.tokens.to_tokens(tokens);
+    }
+}
+
+// A syntax tree node that can have attributes, some of which might be macro invocations.
+trait HasAttrs: ToTokens + Clone {
+    // Whether the compiler lets attribute-like macros replace this kind of node.
+    const REPLACEABLE: bool = false;
+
+    fn attrs(&self) -> &[syn::Attribute];
+
+    // Returns `None` if syn doesn't know where this node's attributes are. In that case, `attrs`
+    // returns an empty slice.
+    fn attrs_mut(&mut self) -> Option<&mut Vec<syn::Attribute>>;
+
+    // Parses the output of an attribute-like macro that replaced this node as a sequence of nodes
+    // of the same kind.
+    fn parse_output(_output: TokenStream) -> syn::Result<Vec<Self>> {
+        Ok(Vec::new())
+    }
+
+    // Visits this node and everything inside it.
+    fn visit(&self, _visitor: &mut MacroVisitor) {}
+}
+
+// The number of times an attribute-like macro's output can be scanned again inside another
+// attribute-like macro's output, matching the compiler's default recursion limit.
+const RECURSION_LIMIT: usize = 128;
+
+// Any number of syntax tree nodes of the same kind, one after another.
+struct Sequence<T>(Vec<T>);
+
+impl<T: Parse> Parse for Sequence<T> {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut nodes = Vec::new();
+        while !input.is_empty() {
+            nodes.push(input.parse()?);
+        }
+        Ok(Sequence(nodes))
+    }
+}
+
+// Calls the given function with at least as much stack space as the options ask for.
+#[cfg(feature = "stacker")]
+fn with_stack<F, R>(options: &Options, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    match options.stack_size {
+        Some(bytes) => stacker::maybe_grow(bytes, bytes, f),
+        None => f(),
+    }
+}
+
+#[cfg(not(feature = "stacker"))]
+fn with_stack<F, R>(_options: &Options, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    f()
+}
+
+// Checks that the output of a function-like macro can be parsed in the position where the macro
+// was invoked.
+fn validate(position: MacroPosition, output: TokenStream) -> syn::Result<()> {
+    match position {
+        MacroPosition::Expression => syn::parse2::<syn::Expr>(output).map(drop),
+        MacroPosition::Statement => syn::Block::parse_within.parse2(output).map(drop),
+        MacroPosition::Item => syn::parse2::<Sequence<syn::Item>>(output).map(drop),
+        MacroPosition::ImplItem => syn::parse2::<Sequence<syn::ImplItem>>(output).map(drop),
+        MacroPosition::TraitItem => syn::parse2::<Sequence<syn::TraitItem>>(output).map(drop),
+        MacroPosition::ForeignItem => syn::parse2::<Sequence<syn::ForeignItem>>(output).map(drop),
+        MacroPosition::Type => syn::parse2::<syn::Type>(output).map(drop),
+        MacroPosition::Pattern => compat::parse_pattern(output),
+    }
+}
+
+// Returns the paths in a `#[derive(...)]` attribute, or `None` if it isn't one.
+fn derive_paths(attr: &syn::Attribute) -> Option<syn::Result<Vec<syn::Path>>> {
+    if !compat::attr_path(attr).is_ident("derive") {
+        return None;
+    }
+    match compat::attr_args(attr) {
+        AttrArgs::List(tokens) => Some(
+            (|input: ParseStream| {
+                Punctuated::<syn::Path, Token![,]>::parse_terminated_with(
+                    input,
+                    syn::Path::parse_mod_style,
+                )
+            })
+            .parse2(tokens)
+            .map(|paths| paths.into_iter().collect()),
+        ),
+        _ => None,
+    }
+}
+
+// Implements `HasAttrs` for an enum whose variants hold the actual nodes.
+macro_rules! impl_has_attrs {
+    ( $ty:ident { $($variant:ident),* } => $visit:ident ) => {
+        impl HasAttrs for syn::$ty {
+            const REPLACEABLE: bool = true;
+
+            fn attrs(&self) -> &[syn::Attribute] {
+                match *self {
+                    $(syn::$ty::$variant(ref item) => &item.attrs,)*
+                    _ => &[]
+                }
+            }
+
+            fn attrs_mut(&mut self) -> Option<&mut Vec<syn::Attribute>> {
+                match *self {
+                    $(syn::$ty::$variant(ref mut item) => Some(&mut item.attrs),)*
+                    _ => None
+                }
+            }
+
+            fn parse_output(output: TokenStream) -> syn::Result<Vec<Self>> {
+                Ok(syn::parse2::<Sequence<syn::$ty>>(output)?.0)
+            }
+
+            fn visit(&self, visitor: &mut MacroVisitor) {
+                visitor.$visit(self);
+            }
+        }
+    };
+    ( $($ty:ty),* ) => {
+        $(impl HasAttrs for $ty {
+            fn attrs(&self) -> &[syn::Attribute] {
+                &self.attrs
+            }
+
+            fn attrs_mut(&mut self) -> Option<&mut Vec<syn::Attribute>> {
+                Some(&mut self.attrs)
+            }
+        })*
+    };
+}
+
+impl_has_attrs!(Item {
+    Const,
+    Enum,
+    ExternCrate,
+    Fn,
+    ForeignMod,
+    Impl,
+    Macro,
+    Mod,
+    Static,
+    Struct,
+    Trait,
+    TraitAlias,
+    Type,
+    Union,
+    Use
+} => visit_item);
+#[cfg(feature = "syn2")]
+impl_has_attrs!(ImplItem {
+    Const,
+    Fn,
+    Type,
+    Macro
+} => visit_impl_item);
+#[cfg(not(feature = "syn2"))]
+impl_has_attrs!(ImplItem {
+    Const,
+    Method,
+    Type,
+    Macro
+} => visit_impl_item);
+#[cfg(feature = "syn2")]
+impl_has_attrs!(TraitItem {
+    Const,
+    Fn,
+    Type,
+    Macro
+} => visit_trait_item);
+#[cfg(not(feature = "syn2"))]
+impl_has_attrs!(TraitItem {
+    Const,
+    Method,
+    Type,
+    Macro
+} => visit_trait_item);
+impl_has_attrs!(ForeignItem {
+    Fn,
+    Static,
+    Type,
+    Macro
+} => visit_foreign_item);
+impl_has_attrs!(syn::Field, syn::Variant, LooseItem);
+
+impl HasAttrs for syn::File {
+    // An inner attribute at the top of a file applies to the whole file.
+    const REPLACEABLE: bool = true;
+
+    fn attrs(&self) -> &[syn::Attribute] {
+        &self.attrs
+    }
+
+    fn attrs_mut(&mut self) -> Option<&mut Vec<syn::Attribute>> {
+        Some(&mut self.attrs)
+    }
+
+    fn parse_output(output: TokenStream) -> syn::Result<Vec<Self>> {
+        Ok(vec![syn::parse2(output)?])
+    }
+
+    fn visit(&self, visitor: &mut MacroVisitor) {
+        visitor.visit_file(self);
+    }
+}
+
+pub(crate) struct MacroVisitor<'r, 'a: 'r> {
+    functionlike: Vec<Registered<'r, ContextFn<'a>>>,
+    derive: Vec<Registered<'r, ContextFn<'a>>>,
+    attribute: Vec<Registered<'r, ContextAttributeFn<'a>>>,
+    hooks: &'r [HookFn<'a>],
+    lints: &'r [(String, LintFn<'a>)],
+    // The reference implementations to compare the registered macros' outputs with.
+    references: &'r [(MacroKind, String, ContextAttributeFn<'a>)],
+    options: &'r Options,
+    source_path: Option<&'r Path>,
+
+    item_depth: usize,
+    nested_items: usize,
+    // How many attribute-like macros' outputs are being scanned inside one another.
+    rescan_depth: usize,
+    // The position of the function-like macro invocation about to be visited, if any.
+    position: Option<MacroPosition>,
+    // The helper attributes of the derives on the item being visited.
+    helpers: Vec<&'r str>,
+    // Decides which invocations to expand, if they aren't all expanded.
+    pub(crate) handler: Option<Box<dyn ExpansionHandler + 'r>>,
+    // The directive comments in the file, by the line they apply to.
+    pub(crate) directives: BTreeMap<usize, Directives>,
+    // The files of the modules declared with `mod foo;`, by where their names start, if they
+    // should be given to the attribute-like macros applied to the declarations.
+    #[cfg(feature = "fs")]
+    pub(crate) module_files: BTreeMap<LineColumn, PathBuf>,
+    // The paths and contents of the modules' files that have been loaded.
+    #[cfg(feature = "fs")]
+    loaded_modules: Vec<(PathBuf, String)>,
+    // The working directory for the macro functions, if it should be changed.
+    working_dir: Option<PathBuf>,
+    // How many macro functions have been called so far.
+    calls: usize,
+
+    records: Vec<ExpansionRecord>,
+    near_misses: Vec<NearMiss>,
+    unregistered: Vec<InvocationInfo>,
+    call_sites: Vec<CallSite>,
+    panics: Vec<ExpansionPanic>,
+    warnings: Vec<ExpansionWarning>,
+    // The first error returned by a macro function, along with the macro's kind and path.
+    error: Option<(MacroKind, &'r str, syn::Error)>,
+    // The first part of the file that syn parsed but Rust wouldn't accept, such as a malformed
+    // `#[derive(...)]`.
+    parse_error: Option<syn::Error>,
+    // The first error from recording an input to the corpus, changing the working directory, or
+    // loading a module's file.
+    record_error: Option<Error>,
+}
+
+impl<'r, 'a> MacroVisitor<'r, 'a> {
+    pub(crate) fn new(
+        registry: &'r MacroRegistry<'a>,
+        source_path: Option<&'r Path>,
+    ) -> Result<Self, Error> {
+        isolation::check_timeout(registry.options.timeout)?;
+        Ok(MacroVisitor {
+            functionlike: parse_paths(
+                &registry.functionlike,
+                MacroKind::Functionlike,
+                &registry.options,
+            )?,
+            derive: parse_paths(&registry.derive, MacroKind::Derive, &registry.options)?,
+            attribute: parse_paths(&registry.attribute, MacroKind::Attribute, &registry.options)?,
+            hooks: &registry.hooks,
+            lints: &registry.lints,
+            references: &registry.references,
+            options: &registry.options,
+            source_path,
+            item_depth: 0,
+            nested_items: 0,
+            rescan_depth: 0,
+            position: None,
+            helpers: Vec::new(),
+            handler: None,
+            directives: BTreeMap::new(),
+            #[cfg(feature = "fs")]
+            module_files: BTreeMap::new(),
+            #[cfg(feature = "fs")]
+            loaded_modules: Vec::new(),
+            working_dir: registry.working_dir.as_ref().and_then(|f| f(source_path)),
+            calls: 0,
+            records: Vec::new(),
+            near_misses: Vec::new(),
+            unregistered: Vec::new(),
+            call_sites: Vec::new(),
+            panics: Vec::new(),
+            warnings: Vec::new(),
+            error: None,
+            parse_error: None,
+            record_error: None,
+        })
+    }
+
+    // Forgets the registered macros, so every invocation is found without expanding any of them.
+    pub(crate) fn without_macros(mut self) -> Self {
+        self.functionlike.clear();
+        self.derive.clear();
+        self.attribute.clear();
+        self
+    }
+
+    // Returns the results of the scan, or the first error that a macro function returned.
+    pub(crate) fn finish(self) -> Result<Scan, Error> {
+        if let Some(error) = self.record_error {
+            return Err(error);
+        }
+        let path = self.source_path.map(Path::to_owned);
+        if let Some(error) = self.parse_error {
+            return Err(Error::ParseError {
+                path,
+                error,
+                snippet: None,
+            });
+        }
+        match self.error {
+            Some((kind, macro_path, error)) => Err(Error::MacroFailed {
+                path,
+                kind,
+                macro_path: macro_path.to_owned(),
+                error,
+                snippet: None,
+            }),
+            None => Ok(Scan {
+                records: self.records,
+                nested_items: self.nested_items,
+                near_misses: self.near_misses,
+                unregistered: self.unregistered,
+                call_sites: self.call_sites,
+                panics: self.panics,
+                warnings: self.warnings,
+                parse_error: None,
+                duration: Duration::ZERO,
+                #[cfg(feature = "fs")]
+                loaded_modules: self.loaded_modules,
+            }),
+        }
+    }
+
+    pub(crate) fn source_path(&self) -> Option<&'r Path> {
+        self.source_path
+    }
+
+    // Returns the directives that apply to the invocation.
+    fn directives(&self, context: &ExpansionContext) -> Directives {
+        self.directives
+            .get(&context.span.start().line)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // Returns whether to expand the invocation, according to its directives and the handler, if
+    // there is one. An invocation that isn't expanded is recorded as filtered out.
+    fn allowed<F>(&mut self, context: &ExpansionContext, ask: F) -> bool
+    where
+        F: FnOnce(&mut (dyn ExpansionHandler + 'r)) -> bool,
+    {
+        let allowed = !self.directives(context).skip
+            && match self.handler {
+                Some(ref mut handler) => ask(&mut **handler),
+                None => true,
+            };
+        if !allowed {
+            self.push_call_site(
+                context.kind,
+                context.macro_path,
+                context.span,
+                ExpansionOutcome::FilteredOut,
+            );
+        }
+        allowed
+    }
+
+    // Records an invocation that didn't match any registered macro, along with its near misses.
+    fn skip_unmatched(
+        &mut self,
+        kind: MacroKind,
+        path: &syn::Path,
+        span: Span,
+        misses: Vec<NearMiss>,
+    ) {
+        if !matching::is_built_in(kind, path) {
+            self.unregistered.push(InvocationInfo {
+                kind,
+                path: matching::path_to_string(path),
+                span,
+                start: span.start(),
+                end: span.end(),
+                position: self.position,
+            });
+        }
+        for miss in misses.iter() {
+            self.push_call_site(
+                miss.kind,
+                &miss.registered_path,
+                miss.span,
+                ExpansionOutcome::SkippedUnmatched,
+            );
+        }
+        self.near_misses.extend(misses);
+    }
+
+    fn push_call_site(
+        &mut self,
+        kind: MacroKind,
+        macro_path: &str,
+        span: Span,
+        outcome: ExpansionOutcome,
+    ) {
+        self.call_sites.push(CallSite {
+            kind,
+            macro_path: macro_path.to_owned(),
+            span,
+            start: span.start(),
+            end: span.end(),
+            outcome,
+            position: self.position,
+            order: self.calls,
+        });
+    }
+
+    fn context(&self, kind: MacroKind, macro_path: &'r str, span: Span) -> ExpansionContext<'r> {
+        ExpansionContext {
+            source_path: self.source_path,
+            macro_path,
+            kind,
+            span,
+            depth: self.item_depth,
+        }
+    }
+
+    // Calls a macro function in the environment that the options ask for, catching any panic
+    // unless the panic policy says not to. Returns an error if the working directory couldn't be
+    // changed.
+    fn call<F, R>(
+        &self,
+        expect_panic: bool,
+        proc_macro_fn: F,
+    ) -> io::Result<Result<R, panics::CaughtPanic>>
+    where
+        F: FnOnce() -> R,
+    {
+        let options = self.options;
+        sandbox::with_current_dir(self.working_dir.as_deref(), || {
+            isolation::with_timeout(options.timeout, || {
+                sandbox::with_env(&options.env, || {
+                    with_stack(options, || {
+                        if options.panic_policy == PanicPolicy::Propagate && !expect_panic {
+                            Ok(proc_macro_fn())
+                        } else if expect_panic && options.quiet_expected_panics {
+                            panics::catch_quietly(proc_macro_fn)
+                        } else {
+                            panics::catch(proc_macro_fn)
+                        }
+                    })
+                })
+            })
+        })
+    }
+
+    // Calls a macro function, records the expansion, and tells the hooks about it. If the macro
+    // function returned an error, the error is kept so it can be returned at the end. If it
+    // panicked, the panic is kept instead, and nothing is recorded. Returns the output only if the
+    // function succeeded. The function is given the input, so it can be called with a smaller one
+    // when the input is being minimized.
+    fn expand<F>(
+        &mut self,
+        context: &ExpansionContext<'r>,
+        attr: Option<TokenStream>,
+        input: TokenStream,
+        proc_macro_fn: F,
+    ) -> Option<TokenStream>
+    where
+        F: Fn(TokenStream) -> syn::Result<TokenStream>,
+    {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!(
+            "expand",
+            kind = %context.kind,
+            macro_path = context.macro_path,
+            line = context.span.start().line,
+            column = context.span.start().column,
+        )
+        .entered();
+        #[cfg(feature = "fs")]
+        if let Some(ref dir) = self.options.record_corpus {
+            if let Err(e) = corpus::record(dir, context, attr.as_ref(), &input) {
+                self.record_error.get_or_insert(e);
+            }
+        }
+        let expect_panic = self.directives(context).expect_panic;
+        let call_fn = || proc_macro_fn(input.clone());
+        let peak_memory = Cell::new(None);
+        let start = Instant::now();
+        let crashed = isolation::before_call(self.source_path, context.span.start());
+        #[cfg(feature = "fuzz")]
+        let called = crashed.is_none();
+        let result = match crashed {
+            Some(message) => Err(panics::CaughtPanic {
+                message,
+                location: None,
+                backtrace: None,
+            }),
+            None => match self.call(expect_panic, || {
+                let (output, peak) = memory::track(call_fn);
+                peak_memory.set(peak);
+                output
+            }) {
+                Ok(result) => result,
+                Err(error) => {
+                    self.record_error.get_or_insert(Error::IoError {
+                        path: self.working_dir.clone(),
+                        error,
+                    });
+                    return None;
+                }
+            },
+        };
+        let duration = start.elapsed();
+        #[cfg(feature = "fuzz")]
+        if called {
+            self.fuzz(context, &input, &proc_macro_fn);
+        }
+        #[cfg(feature = "tracing")]
+        {
+            match result {
+                Ok(Ok(_)) => tracing::debug!(?duration, "the macro expanded"),
+                Ok(Err(ref error)) => {
+                    tracing::warn!(?duration, %error, "the macro returned an error")
+                }
+                Err(ref caught) if expect_panic => {
+                    tracing::debug!(?duration, panic = %caught.message, "the macro panicked as expected")
+                }
+                Err(ref caught) => {
+                    tracing::error!(?duration, panic = %caught.message, "the macro panicked")
+                }
+            }
+        }
+        if let Ok(ref first) = result {
+            if self.options.check_determinism {
+                self.compare_outputs(
+                    context,
+                    first,
+                    call_fn,
+                    (
+                        "the macro's output changed when it was expanded again with the same input",
+                        "the macro panicked when it was expanded again with the same input",
+                    ),
+                    true,
+                );
+            }
+            if let Some(reference_fn) = self.reference_fn(context) {
+                let attr = attr.clone().unwrap_or_default();
+                self.compare_outputs(
+                    context,
+                    first,
+                    || reference_fn(context, attr, input.clone()),
+                    (
+                        "the macro's output differs from the reference implementation's",
+                        "the reference implementation panicked",
+                    ),
+                    false,
+                );
+            }
+        }
+        if expect_panic && result.is_ok() {
+            let error = syn::Error::new(
+                context.span,
+                "the macro was expected to panic, but it didn't",
+            );
+            self.error
+                .get_or_insert((context.kind, context.macro_path, error));
+        }
+        let outcome = match result {
+            Ok(_) => ExpansionOutcome::Expanded,
+            Err(ref caught) => ExpansionOutcome::Panicked(caught.message.clone()),
+        };
+        self.push_call_site(context.kind, context.macro_path, context.span, outcome);
+        self.calls += 1;
+        let (output, succeeded) = match result {
+            Ok(Ok(output)) => (output, true),
+            Ok(Err(e)) => {
+                let output = e.to_compile_error();
+                self.error
+                    .get_or_insert((context.kind, context.macro_path, e));
+                (output, false)
+            }
+            Err(caught) => {
+                let minimized_input = if self.options.minimize_panics && !expect_panic {
+                    Some(self.minimize(&input, &caught.message, &proc_macro_fn))
+                } else {
+                    None
+                };
+                self.panics.push(ExpansionPanic {
+                    path: self.source_path.map(Path::to_owned),
+                    kind: context.kind,
+                    macro_path: context.macro_path.to_owned(),
+                    span: context.span,
+                    message: caught.message,
+                    location: caught.location,
+                    backtrace: caught.backtrace,
+                    minimized_input,
+                    expected: expect_panic,
+                });
+                return None;
+            }
+        };
+        if succeeded {
+            self.check_duration(context, duration);
+            self.check_budget(context, &input, &output);
+            self.run_lints(context, &output);
+            if self.options.check_hygiene {
+                self.check_hygiene(context, attr.as_ref(), &input, &output);
+            }
+        }
+        for hook in self.hooks.iter() {
+            hook(context, &output);
+        }
+        self.records.push(ExpansionRecord {
+            kind: context.kind,
+            macro_path: context.macro_path.to_owned(),
+            attr,
+            input,
+            output,
+            span: context.span,
+            start: context.span.start(),
+            end: context.span.end(),
+            position: self.position,
+            order: self.calls - 1,
+            duration,
+            peak_memory: peak_memory.get(),
+        });
+        if succeeded {
+            self.records.last().map(|record| record.output.clone())
+        } else {
+            None
+        }
+    }
+
+    // Calls the macro function with mutations of the input, and keeps a warning for each one that
+    // crashed a child process or timed out in one. Anything else the function does is fine.
+    #[cfg(feature = "fuzz")]
+    fn fuzz<F>(&mut self, context: &ExpansionContext<'r>, input: &TokenStream, proc_macro_fn: F)
+    where
+        F: Fn(TokenStream) -> syn::Result<TokenStream>,
+    {
+        let (mutations, seed) = match self.options.fuzz {
+            Some(fuzz) => fuzz,
+            None => return,
+        };
+        let start = context.span.start();
+        let mut mutator = fuzz::Mutator::new(seed, start);
+        for i in 0..mutations {
+            let (mutated, mutation) = match mutator.mutate(input) {
+                Some(mutated) => mutated,
+                None => return,
+            };
+            if let Some(message) = isolation::before_mutation(self.source_path, start, i) {
+                let message = format!(
+                    "{} when the input was mutated ({}): `{}`",
+                    message, mutation, mutated
+                );
+                self.warn(context, "fuzz", message, context.span);
+                continue;
+            }
+            let result = self.call(true, || {
+                panics::catch_quietly(|| proc_macro_fn(mutated.clone()))
+            });
+            if let Err(error) = result {
+                self.record_error.get_or_insert(Error::IoError {
+                    path: self.working_dir.clone(),
+                    error,
+                });
+                return;
+            }
+        }
+    }
+
+    // Shrinks the input of an invocation whose macro function panicked, keeping each smaller input
+    // that makes it panic with the same message.
+    fn minimize<F>(&self, input: &TokenStream, message: &str, proc_macro_fn: F) -> TokenStream
+    where
+        F: Fn(TokenStream) -> syn::Result<TokenStream>,
+    {
+        minimize::minimize(input.clone(), |candidate| {
+            let result = self.call(true, || {
+                panics::catch_quietly(|| proc_macro_fn(candidate.clone()))
+            });
+            match result {
+                Ok(Ok(Err(caught))) => caught.message == message,
+                _ => false,
+            }
+        })
+    }
+
+    // Visits the items in an order chosen by the seed, then puts everything found in them back in
+    // source order, so only the order indices show that they were shuffled.
+    fn visit_items_shuffled(&mut self, items: &[syn::Item], seed: u64) {
+        let lengths = |visitor: &Self| {
+            [
+                visitor.records.len(),
+                visitor.near_misses.len(),
+                visitor.call_sites.len(),
+                visitor.panics.len(),
+                visitor.warnings.len(),
+                visitor.unregistered.len(),
+            ]
+        };
+        let mut indices: Vec<usize> = (0..items.len()).collect();
+        order::shuffle(&mut indices, seed);
+        let prefix = lengths(self);
+        let mut found = Vec::new();
+        for i in indices {
+            let before = lengths(self);
+            self.visit_item(&items[i]);
+            found.push((i, before, lengths(self)));
+        }
+        found.sort_by_key(|&(i, _, _)| i);
+        let ranges = |n: usize| {
+            found
+                .iter()
+                .map(move |&(_, before, after)| before[n]..after[n])
+        };
+        self.records = order::reorder(mem::take(&mut self.records), prefix[0], ranges(0));
+        self.near_misses = order::reorder(mem::take(&mut self.near_misses), prefix[1], ranges(1));
+        self.call_sites = order::reorder(mem::take(&mut self.call_sites), prefix[2], ranges(2));
+        self.panics = order::reorder(mem::take(&mut self.panics), prefix[3], ranges(3));
+        self.warnings = order::reorder(mem::take(&mut self.warnings), prefix[4], ranges(4));
+        self.unregistered = order::reorder(mem::take(&mut self.unregistered), prefix[5], ranges(5));
+    }
+
+    // Keeps a warning, or an error if the budget says to, if the expansion is over the budget
+    // for its macro.
+    fn check_budget(
+        &mut self,
+        context: &ExpansionContext<'r>,
+        input: &TokenStream,
+        output: &TokenStream,
+    ) {
+        let budget = match self.options.budget(context.kind, context.macro_path) {
+            Some(budget) => budget,
+            None => return,
+        };
+        if let Some(message) = budget.check(input, output) {
+            if budget.denies() {
+                let error = syn::Error::new(context.span, message);
+                self.error
+                    .get_or_insert((context.kind, context.macro_path, error));
+            } else {
+                self.warn(context, "budget", message, context.span);
+            }
+        }
+    }
+
+    // Keeps a warning, or an error if the options say to, if the expansion took longer than the
+    // threshold for slow expansions.
+    fn check_duration(&mut self, context: &ExpansionContext<'r>, duration: Duration) {
+        let threshold = match self.options.slow_threshold {
+            Some(threshold) if duration > threshold => threshold,
+            _ => return,
+        };
+        let message = format!(
+            "the expansion took {:?}, which is over the threshold of {:?}",
+            duration, threshold
+        );
+        if self.options.deny_slow {
+            let error = syn::Error::new(context.span, message);
+            self.error
+                .get_or_insert((context.kind, context.macro_path, error));
+        } else {
+            self.warn(context, "slow", message, context.span);
+        }
+    }
+
+    // Runs every lint on the output of an expansion and keeps a warning for each problem found.
+    fn run_lints(&mut self, context: &ExpansionContext, output: &TokenStream) {
+        let lints = self.lints;
+        for (name, lint) in lints.iter() {
+            let errors = match lint(context, output) {
+                Ok(()) => continue,
+                Err(errors) => errors,
+            };
+            for error in errors {
+                self.warn(context, name, error.to_string(), error.span());
+            }
+        }
+    }
+
+    // Keeps a warning for each local variable in the output that looks like it could capture a
+    // name in the caller's code.
+    fn check_hygiene(
+        &mut self,
+        context: &ExpansionContext,
+        attr: Option<&TokenStream>,
+        input: &TokenStream,
+        output: &TokenStream,
+    ) {
+        let inputs: Vec<&TokenStream> = attr.into_iter().chain(Some(input)).collect();
+        for name in hygiene::unhygienic_bindings(&inputs, output) {
+            let message = format!(
+                "the expansion binds `{}`, which isn't in the input, so it could clash with a \
+                 name in the caller's code; consider giving it `Span::mixed_site()`",
+                name
+            );
+            self.warn(context, "hygiene", message, name.span());
+        }
+    }
+
+    // Keeps a warning about an expansion. If the span isn't in the file, the invocation's span is
+    // used instead.
+    fn warn(&mut self, context: &ExpansionContext, lint: &str, message: String, span: Span) {
+        // Most of the spans in a macro's output don't point into the file, and those can't be
+        // joined with one that does.
+        let span = match span.join(context.span) {
+            Some(_) => span,
+            None => context.span,
+        };
+        self.warnings.push(ExpansionWarning {
+            kind: context.kind,
+            macro_path: context.macro_path.to_owned(),
+            lint: lint.to_owned(),
+            message,
+            span,
+            start: span.start(),
+            end: span.end(),
+        });
+    }
+
+    // If the item is a `mod foo;` declaration with a registered attribute-like macro applied to it,
+    // and its file is known, returns it as an inline module with the file's contents, which is what
+    // the compiler gives the macro.
+    #[cfg(feature = "fs")]
+    fn load_module(&mut self, item: &syn::Item) -> Option<syn::Item> {
+        let item_mod = match *item {
+            syn::Item::Mod(ref item_mod) if item_mod.content.is_none() => item_mod,
+            _ => return None,
+        };
+        let path = self
+            .module_files
+            .get(&item_mod.ident.span().start())?
+            .clone();
+        let invoked = item_mod.attrs.iter().any(|attr| {
+            self.attribute
+                .iter()
+                .any(|registered| registered.matches(compat::attr_path(attr)))
+        });
+        if !invoked {
+            return None;
+        }
+        let loaded_modules = &mut self.loaded_modules;
+        let file = match session::read(&path).and_then(|content| {
+            loaded_modules.push((path.clone(), content.clone()));
+            ParsedSource::parse(content, Some(&path))
+        }) {
+            Ok(source) => source.ast().clone(),
+            Err(error) => {
+                self.record_error.get_or_insert(error);
+                return None;
+            }
+        };
+        let mut item_mod = item_mod.clone();
+        item_mod.attrs.extend(file.attrs);
+        item_mod.content = Some((Default::default(), file.items));
+        item_mod.semi = None;
+        Some(syn::Item::Mod(item_mod))
+    }
+
+    // Returns the reference implementation of the invoked macro, if it has one.
+    fn reference_fn(&self, context: &ExpansionContext) -> Option<&'r ContextAttributeFn<'a>> {
+        self.references
+            .iter()
+            .find(|(kind, path, _)| *kind == context.kind && path == context.macro_path)
+            .map(|(_, _, reference_fn)| reference_fn)
+    }
+
+    // Calls another macro function with the same input as the first call, or the same one a
+    // second time, and keeps an error if it doesn't do the same thing as the first one did. The
+    // messages are the beginnings of the errors for different output and for a panic. The diff in
+    // the error shows how to get from the expected output to the other one.
+    fn compare_outputs<F>(
+        &mut self,
+        context: &ExpansionContext<'r>,
+        first: &syn::Result<TokenStream>,
+        proc_macro_fn: F,
+        (changed, panicked): (&str, &str),
+        first_is_expected: bool,
+    ) where
+        F: FnOnce() -> syn::Result<TokenStream>,
+    {
+        let as_tokens = |result: &syn::Result<TokenStream>| match *result {
+            Ok(ref output) => output.clone(),
+            Err(ref e) => e.to_compile_error(),
+        };
+        let message = match self.call(false, proc_macro_fn) {
+            Ok(Ok(ref second)) => {
+                let (first, second) = (as_tokens(first), as_tokens(second));
+                let diff = if first_is_expected {
+                    tokens::diff(&first, &second)
+                } else {
+                    tokens::diff(&second, &first)
+                };
+                if diff.is_empty() {
+                    return;
+                }
+                format!("{}: {}", changed, diff)
+            }
+            Ok(Err(caught)) => format!("{}: {}", panicked, caught.message),
+            Err(error) => {
+                self.record_error.get_or_insert(Error::IoError {
+                    path: self.working_dir.clone(),
+                    error,
+                });
+                return;
+            }
+        };
+        let error = syn::Error::new(context.span, message);
+        self.error
+            .get_or_insert((context.kind, context.macro_path, error));
+    }
+
+    // Calls an attribute-like macro function on its own output and keeps an error if it fails or
+    // makes the output any bigger.
+    fn check_idempotency<F>(&mut self, context: &ExpansionContext<'r>, output: &TokenStream, f: F)
+    where
+        F: FnOnce() -> syn::Result<TokenStream>,
+    {
+        let message = match self.call(false, f) {
+            Ok(Ok(Ok(ref again))) if tokens::count(again) <= tokens::count(output) => return,
+            Ok(Ok(Ok(again))) => format!(
+                "the macro's output grew from {} to {} tokens when it was applied to its own output",
+                tokens::count(output),
+                tokens::count(&again)
+            ),
+            Ok(Ok(Err(e))) => format!(
+                "the macro returned an error when it was applied to its own output: {}",
+                e
+            ),
+            Ok(Err(caught)) => format!(
+                "the macro panicked when it was applied to its own output: {}",
+                caught.message
+            ),
+            Err(error) => {
+                self.record_error.get_or_insert(Error::IoError {
+                    path: self.working_dir.clone(),
+                    error,
+                });
+                return;
+            }
+        };
+        let error = syn::Error::new(context.span, message);
+        self.error
+            .get_or_insert((context.kind, context.macro_path, error));
+    }
+
+    // Returns true if the node should be skipped because of a `#[cfg]` attribute that is false.
+    fn is_configured_out(&self, attrs: &[syn::Attribute]) -> bool {
+        match self.options.cfgs {
+            Some(ref cfgs) => cfg::is_configured_out(attrs, cfgs),
+            None => false,
+        }
+    }
+
+    // Returns the helper attributes of the registered derives in the given attributes.
+    fn derive_helpers(&self, attrs: &[syn::Attribute]) -> Vec<&'r str> {
+        let mut helpers = Vec::new();
+        if self.options.derive_helpers.is_empty() {
+            return helpers;
+        }
+        let attrs = if self.options.unwrap_cfg_attr {
+            cfg::unwrap_cfg_attrs(attrs, self.options.cfgs.as_ref())
+        } else {
+            Cow::Borrowed(attrs)
+        };
+        // Malformed derives are reported when they're expanded.
+        for paths in attrs.iter().filter_map(derive_paths).filter_map(Result::ok) {
+            for path in paths.iter() {
+                for registered in self.derive.iter() {
+                    if registered.matches(path) {
+                        helpers.extend(
+                            self.options
+                                .derive_helpers
+                                .iter()
+                                .filter(|(path, _)| path == registered.name)
+                                .map(|(_, helper)| &helper[..]),
+                        );
+                    }
+                }
+            }
+        }
+        helpers
+    }
+
+    // Expands every registered attribute-like macro applied to the given node, as well as every
+    // registered derive macro if `derives` is true, which it should only be for a struct, enum, or
+    // union. Otherwise, a registered derive gets a warning, since the compiler wouldn't accept it,
+    // but the rest of the file is still expanded. Returns false without
+    // expanding anything if the node is configured out, in which case its children shouldn't be
+    // visited either.
+    fn expand_attrs_of<T: HasAttrs>(&mut self, node: &T, derives: bool) -> bool {
+        let attrs = if self.options.unwrap_cfg_attr {
+            cfg::unwrap_cfg_attrs(node.attrs(), self.options.cfgs.as_ref())
+        } else {
+            Cow::Borrowed(node.attrs())
+        };
+        if self.is_configured_out(&attrs) {
+            return false;
+        }
+
+        // If any `cfg_attr`s were unwrapped, the macros should see the result.
+        let rewritten;
+        let node = match attrs {
+            Cow::Borrowed(_) => node,
+            Cow::Owned(ref attrs) => {
+                let mut node = node.clone();
+                if let Some(node_attrs) = node.attrs_mut() {
+                    *node_attrs = attrs.clone();
+                }
+                rewritten = node;
+                &rewritten
+            }
+        };
+
+        // The compiler removes the derive attributes before passing the item to its derives.
+        let stripped;
+        let derive_input = if derives && self.options.strip_derive_attributes {
+            let mut item = node.clone();
+            if let Some(attrs) = item.attrs_mut() {
+                attrs.retain(|attr| !compat::attr_path(attr).is_ident("derive"));
+            }
+            stripped = item;
+            &stripped
+        } else {
+            node
+        };
+
+        // Once the attribute-like macros have been chained, the rest of the attributes have been
+        // given to them, so only derives are left to expand.
+        let mut chained = false;
+        for (i, attr) in attrs.iter().enumerate() {
+            if derives {
+                self.expand_derives(derive_input, attr);
+            } else {
+                self.warn_misplaced_derives(attr);
+            }
+            if chained
+                || self
+                    .helpers
+                    .iter()
+                    .any(|&helper| compat::attr_path(attr).is_ident(helper))
+            {
+                continue;
+            }
+            if self.options.rescan_attribute_output && T::REPLACEABLE {
+                if self.replace_with_output(node, i, |visitor, node| node.visit(visitor)) {
+                    return false;
+                }
+            } else if self.options.chain_attributes && T::REPLACEABLE {
+                chained = self.replace_with_output(node, i, Self::chain_attributes);
+            } else if self.options.strip_invoked_attribute
+                && self
+                    .attribute
+                    .iter()
+                    .any(|registered| registered.matches(compat::attr_path(attr)))
+            {
+                // Only the items given to macros need to be copied.
+                let mut item = node.clone();
+                if let Some(attrs) = item.attrs_mut() {
+                    attrs.remove(i);
+                }
+                self.expand_attributes(&item, attr);
+            } else {
+                self.expand_attributes(node, attr);
+            }
+        }
+        true
+    }
+
+    // Expands the node's first registered attribute-like macro, then the first one in each node
+    // of its output, and so on, the way the compiler does.
+    fn chain_attributes<T: HasAttrs>(&mut self, node: &T) {
+        for i in 0..node.attrs().len() {
+            if self.replace_with_output(node, i, Self::chain_attributes) {
+                return;
+            }
+        }
+    }
+
+    // Expands the registered attribute-like macros that match the node's `index`th attribute,
+    // passing them the node without that attribute, and then calls `rescan` on each node in their
+    // output. Returns false if no registered macro matched, in which case the node is untouched.
+    fn replace_with_output<T: HasAttrs>(
+        &mut self,
+        node: &T,
+        index: usize,
+        rescan: fn(&mut Self, &T),
+    ) -> bool {
+        let attr = &node.attrs()[index];
+        let mut item = node.clone();
+        if let Some(attrs) = item.attrs_mut() {
+            attrs.remove(index);
+        }
+        let (matched, outputs) = self.expand_attributes(&item, attr);
+        if !matched {
+            return false;
+        }
+
+        for (name, output) in outputs {
+            if self.rescan_depth >= RECURSION_LIMIT {
+                let error = syn::Error::new(
+                    attr.span(),
+                    format!("recursion limit reached while expanding `#[{}]`", name),
+                );
+                self.error
+                    .get_or_insert((MacroKind::Attribute, name, error));
+                break;
+            }
+            match T::parse_output(output) {
+                Ok(nodes) => {
+                    self.rescan_depth += 1;
+                    for node in nodes.iter() {
+                        rescan(self, node);
+                    }
+                    self.rescan_depth -= 1;
+                }
+                Err(error) => {
+                    self.error
+                        .get_or_insert((MacroKind::Attribute, name, error));
+                }
+            }
+        }
+        true
+    }
+
+    fn expand_derives<T: ToTokens>(&mut self, item: &T, attr: &syn::Attribute) {
+        let paths = match derive_paths(attr) {
+            Some(Ok(paths)) => paths,
+            // A malformed derive only stops the scan if it might have been meant for a registered
+            // macro.
+            Some(Err(e)) => {
+                if !self.derive.is_empty() {
+                    self.parse_error.get_or_insert(e);
+                }
+                return;
+            }
+            None => return,
+        };
+
+        let mut matches = Vec::new();
+        for path in paths.iter() {
+            let matched = matches.len();
+            for (i, registered) in self.derive.iter().enumerate() {
+                if registered.matches(path) {
+                    matches.push((i, path.span()));
+                }
+            }
+            if matches.len() == matched {
+                let misses = near_misses(&self.derive, MacroKind::Derive, path);
+                self.skip_unmatched(MacroKind::Derive, path, path.span(), misses);
+            }
+        }
+
+        for (i, span) in matches {
+            let (name, proc_macro_fn) = (self.derive[i].name, self.derive[i].proc_macro_fn);
+            let context = self.context(MacroKind::Derive, name, span);
+            let input = item.to_token_stream();
+            if !self.allowed(&context, |handler| handler.on_derive(&context, &input)) {
+                continue;
+            }
+            self.expand(&context, None, input.clone(), |input| {
+                proc_macro_fn(&context, input)
+            });
+        }
+    }
+
+    // Keeps a warning for each registered derive macro in a derive attribute that's applied to
+    // something other than a struct, enum, or union.
+    fn warn_misplaced_derives(&mut self, attr: &syn::Attribute) {
+        let paths = match derive_paths(attr) {
+            Some(Ok(paths)) => paths,
+            _ => return,
+        };
+        for path in paths.iter() {
+            for registered in self
+                .derive
+                .iter()
+                .filter(|registered| registered.matches(path))
+            {
+                let span = path.span();
+                self.warnings.push(ExpansionWarning {
+                    kind: MacroKind::Derive,
+                    macro_path: registered.name.to_owned(),
+                    lint: "misplaced_derive".to_owned(),
+                    message: "`derive` may only be applied to `struct`s, `enum`s and `union`s"
+                        .to_owned(),
+                    span,
+                    start: span.start(),
+                    end: span.end(),
+                });
+            }
+        }
+    }
+
+    // Expands every registered function-like macro that matches the given path. Returns whether
+    // any of them matched.
+    fn expand_functionlike(&mut self, path: &syn::Path, tokens: &TokenStream, span: Span) -> bool {
+        let position = self.position;
+        let mut matched = false;
+        for i in 0..self.functionlike.len() {
+            if self.functionlike[i].matches(path) {
+                matched = true;
+                let (name, proc_macro_fn) = (
+                    self.functionlike[i].name,
+                    self.functionlike[i].proc_macro_fn,
+                );
+                let context = self.context(MacroKind::Functionlike, name, span);
+                if !self.allowed(&context, |handler| {
+                    handler.on_functionlike(&context, tokens)
+                }) {
+                    continue;
+                }
+                let input = tokens.clone();
+                let output = self.expand(&context, None, input.clone(), |input| {
+                    proc_macro_fn(&context, input)
+                });
+                let output = match output {
+                    Some(output) => output,
+                    None => continue,
+                };
+                if let (true, Some(position)) = (self.options.validate_output, position) {
+                    if let Err(e) = validate(position, output.clone()) {
+                        let message = format!("the expansion isn't valid as {}: {}", position, e);
+                        self.error.get_or_insert((
+                            context.kind,
+                            name,
+                            syn::Error::new(span, message),
+                        ));
+                    }
+                }
+                if self.options.rescan_functionlike_output {
+                    self.rescan_functionlike_output(name, span, position, output);
+                }
+            }
+        }
+        if !matched {
+            let misses = near_misses(&self.functionlike, MacroKind::Functionlike, path);
+            self.skip_unmatched(MacroKind::Functionlike, path, span, misses);
+        }
+        matched
+    }
+
+    // Scans the output of a function-like macro as whatever its position calls for, and then puts
+    // the position back the way it was.
+    fn rescan_functionlike_output(
+        &mut self,
+        name: &'r str,
+        span: Span,
+        position: Option<MacroPosition>,
+        output: TokenStream,
+    ) {
+        if self.rescan_depth >= RECURSION_LIMIT {
+            let error = syn::Error::new(
+                span,
+                format!("recursion limit reached while expanding `{}!`", name),
+            );
+            self.error
+                .get_or_insert((MacroKind::Functionlike, name, error));
+            return;
+        }
+        self.rescan_depth += 1;
+        let result = match position {
+            Some(MacroPosition::Expression) => {
+                syn::parse2::<syn::Expr>(output).map(|expr| self.visit_expr(&expr))
+            }
+            Some(MacroPosition::Statement) => {
+                syn::Block::parse_within.parse2(output).map(|stmts| {
+                    for stmt in stmts.iter() {
+                        self.visit_stmt(stmt);
+                    }
+                })
+            }
+            Some(MacroPosition::Item) => syn::parse2::<Sequence<syn::Item>>(output).map(|items| {
+                for item in items.0.iter() {
+                    self.visit_item(item);
+                }
+            }),
+            Some(MacroPosition::ImplItem) => {
+                syn::parse2::<Sequence<syn::ImplItem>>(output).map(|items| {
+                    for item in items.0.iter() {
+                        self.visit_impl_item(item);
+                    }
+                })
+            }
+            Some(MacroPosition::TraitItem) => {
+                syn::parse2::<Sequence<syn::TraitItem>>(output).map(|items| {
+                    for item in items.0.iter() {
+                        self.visit_trait_item(item);
+                    }
+                })
+            }
+            Some(MacroPosition::ForeignItem) => syn::parse2::<Sequence<syn::ForeignItem>>(output)
+                .map(|items| {
+                    for item in items.0.iter() {
+                        self.visit_foreign_item(item);
+                    }
+                }),
+            Some(MacroPosition::Type) => {
+                syn::parse2::<syn::Type>(output).map(|ty| self.visit_type(&ty))
+            }
+            // Patterns can only contain function-like macros, which don't need a syntax tree
+            // to be found.
+            Some(MacroPosition::Pattern) => {
+                self.scan_tokens(output, false);
+                Ok(())
+            }
+            None => {
+                self.scan_tokens(output, true);
+                Ok(())
+            }
+        };
+        self.rescan_depth -= 1;
+        self.position = position;
+        if let Err(error) = result {
+            self.error
+                .get_or_insert((MacroKind::Functionlike, name, error));
+        }
+    }
+
+    // Passes the given tokens straight to every registered function-like macro, as if each one
+    // had been invoked with them.
+    #[cfg(feature = "fs")]
+    pub(crate) fn expand_input(&mut self, tokens: TokenStream) {
+        let span = tokens
+            .clone()
+            .into_iter()
+            .next()
+            .map_or_else(Span::call_site, |tt| tt.span());
+        for i in 0..self.functionlike.len() {
+            let (name, proc_macro_fn) = (
+                self.functionlike[i].name,
+                self.functionlike[i].proc_macro_fn,
+            );
+            let context = self.context(MacroKind::Functionlike, name, span);
+            if !self.allowed(&context, |handler| {
+                handler.on_functionlike(&context, &tokens)
+            }) {
+                continue;
+            }
+            let input = tokens.clone();
+            self.expand(&context, None, input.clone(), |input| {
+                proc_macro_fn(&context, input)
+            });
+        }
+    }
+
+    // Looks for invocations of registered function-like macros in tokens that syn didn't parse,
+    // such as the `foo!(x)` in `assert!(foo!(x))`. The tokens inside the invocations that match
+    // are left alone, as they would be in the syntax tree. If `items` is true, the tokens are
+    // assumed to contain items, and the derives and attribute-like macros in the outer
+    // attributes before each one are expanded too.
+    pub(crate) fn scan_tokens(&mut self, tokens: TokenStream, items: bool) {
+        let tokens: Vec<TokenTree> = tokens.into_iter().collect();
+        let mut i = 0;
+        while i < tokens.len() {
+            if items {
+                let attrs_len = outer_attrs_len(&tokens[i..]);
+                if attrs_len > 0 {
+                    let item_len = item_len(&tokens[i + attrs_len..]);
+                    let attrs = syn::Attribute::parse_outer
+                        .parse2(tokens[i..i + attrs_len].iter().cloned().collect());
+                    if let Ok(attrs) = attrs {
+                        let item = LooseItem {
+                            attrs,
+                            tokens: tokens[i + attrs_len..i + attrs_len + item_len]
+                                .iter()
+                                .cloned()
+                                .collect(),
+                        };
+                        let derivable = is_derivable_tokens(&item.tokens);
+                        let helpers = if derivable {
+                            self.derive_helpers(&item.attrs)
+                        } else {
+                            Vec::new()
+                        };
+                        let outer = mem::replace(&mut self.helpers, helpers);
+                        let configured = self.expand_attrs_of(&item, derivable);
+                        self.helpers = outer;
+                        if !configured {
+                            i += attrs_len + item_len;
+                            continue;
+                        }
+                    }
+                    i += attrs_len;
+                    continue;
+                }
+            }
+            if let Some((path, len)) = macro_call_at(&tokens[i..]) {
+                if let TokenTree::Group(ref group) = tokens[i + len - 1] {
+                    let span = tokens[i]
+                        .span()
+                        .join(group.span())
+                        .unwrap_or(tokens[i].span());
+                    if !self.expand_functionlike(&path, &group.stream(), span) {
+                        self.scan_tokens(group.stream(), items);
+                    }
+                }
+                i += len;
+                continue;
+            }
+            if let TokenTree::Group(ref group) = tokens[i] {
+                self.scan_tokens(group.stream(), items);
+            }
+            i += 1;
+        }
+    }
+
+    // Looks for invocations of registered function-like macros in the body of a `macro_rules!`
+    // definition. Only the transcriber of each rule, after the `=>`, is searched, since the
+    // matchers are patterns rather than code.
+    fn scan_transcribers(&mut self, rules: TokenStream) {
+        let tokens: Vec<TokenTree> = rules.into_iter().collect();
+        for window in tokens.windows(3) {
+            match (&window[0], &window[1], &window[2]) {
+                (TokenTree::Punct(eq), TokenTree::Punct(gt), TokenTree::Group(transcriber))
+                    if eq.as_char() == '=' && gt.as_char() == '>' =>
+                {
+                    self.scan_tokens(transcriber.stream(), false);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Expands every registered attribute-like macro that matches the given attribute. Returns
+    // whether any of them matched, along with the outputs of the ones that succeeded.
+    fn expand_attributes<T: ToTokens>(
+        &mut self,
+        item: &T,
+        attr: &syn::Attribute,
+    ) -> (bool, Vec<(&'r str, TokenStream)>) {
+        // An attribute-like macro can be called with or without arguments.
+        let path = compat::attr_path(attr);
+        let tokens = match compat::attr_args(attr) {
+            AttrArgs::Empty => TokenStream::new(),
+            AttrArgs::List(tokens) => tokens,
+            AttrArgs::Value => return (false, Vec::new()),
+        };
+
+        // A macro the handler skips still matched, so it isn't a near miss.
+        let mut found = false;
+        let mut matched = false;
+        let mut outputs = Vec::new();
+        for i in 0..self.attribute.len() {
+            if self.attribute[i].matches(path) {
+                found = true;
+                let (name, proc_macro_fn) =
+                    (self.attribute[i].name, self.attribute[i].proc_macro_fn);
+                let context = self.context(MacroKind::Attribute, name, attr.span());
+                let input = item.to_token_stream();
+                if !self.allowed(&context, |handler| {
+                    handler.on_attribute(&context, &tokens, &input)
+                }) {
+                    continue;
+                }
+                matched = true;
+                let output = self.expand(&context, Some(tokens.clone()), input.clone(), |input| {
+                    proc_macro_fn(&context, tokens.clone(), input)
+                });
+                if let (true, Some(output)) = (self.options.check_idempotency, output.as_ref()) {
+                    self.check_idempotency(&context, output, || {
+                        proc_macro_fn(&context, tokens.clone(), output.clone())
+                    });
+                }
+                outputs.extend(output.map(|output| (name, output)));
+            }
+        }
+        if !found {
+            let misses = near_misses(&self.attribute, MacroKind::Attribute, path);
+            self.skip_unmatched(MacroKind::Attribute, path, attr.span(), misses);
+        }
+        (matched, outputs)
+    }
+}
+
+impl<'r, 'a, 'ast> syn::visit::Visit<'ast> for MacroVisitor<'r, 'a> {
+    fn visit_file(&mut self, file: &'ast syn::File) {
+        // Inner attributes at the top of the file apply to the whole file. (Those inside inline
+        // modules are included in the modules' own attributes.)
+        if self.expand_attrs_of(file, false) {
+            match self.options.shuffle {
+                Some(seed) => {
+                    for attr in file.attrs.iter() {
+                        self.visit_attribute(attr);
+                    }
+                    self.visit_items_shuffled(&file.items, seed);
+                }
+                None => syn::visit::visit_file(self, file),
+            }
+        }
+    }
+
+    fn visit_macro(&mut self, macro_item: &'ast syn::Macro) {
+        let expanded =
+            self.expand_functionlike(&macro_item.path, &macro_item.tokens, macro_item.span());
+        // Any macros found by scanning this one's tokens are in an unknown position.
+        self.position = None;
+        if expanded {
+            return;
+        }
+        if macro_item.path.is_ident("macro_rules") {
+            if self.options.scan_macro_rules {
+                self.scan_transcribers(macro_item.tokens.clone());
+            }
+        } else if self.options.scan_macro_tokens {
+            self.scan_tokens(macro_item.tokens.clone(), false);
+        }
+    }
+
+    fn visit_expr_macro(&mut self, expr: &'ast syn::ExprMacro) {
+        // Macros in patterns are also `ExprMacro`s, so the position might already be set.
+        self.position.get_or_insert(MacroPosition::Expression);
+        syn::visit::visit_expr_macro(self, expr);
+    }
+
+    fn visit_pat(&mut self, pat: &'ast syn::Pat) {
+        if let syn::Pat::Macro(_) = *pat {
+            self.position = Some(MacroPosition::Pattern);
+        }
+        syn::visit::visit_pat(self, pat);
+    }
+
+    fn visit_type_macro(&mut self, ty: &'ast syn::TypeMacro) {
+        self.position = Some(MacroPosition::Type);
+        syn::visit::visit_type_macro(self, ty);
+    }
+
+    #[cfg(feature = "syn2")]
+    fn visit_stmt_macro(&mut self, stmt: &'ast syn::StmtMacro) {
+        self.position = Some(MacroPosition::Statement);
+        syn::visit::visit_stmt_macro(self, stmt);
+    }
+
+    fn visit_item_macro(&mut self, item: &'ast syn::ItemMacro) {
+        self.position = Some(MacroPosition::Item);
+        syn::visit::visit_item_macro(self, item);
+    }
+
+    fn visit_impl_item_macro(&mut self, item: &'ast syn::ImplItemMacro) {
+        self.position = Some(MacroPosition::ImplItem);
+        syn::visit::visit_impl_item_macro(self, item);
+    }
+
+    fn visit_trait_item_macro(&mut self, item: &'ast syn::TraitItemMacro) {
+        self.position = Some(MacroPosition::TraitItem);
+        syn::visit::visit_trait_item_macro(self, item);
+    }
+
+    fn visit_foreign_item_macro(&mut self, item: &'ast syn::ForeignItemMacro) {
+        self.position = Some(MacroPosition::ForeignItem);
+        syn::visit::visit_foreign_item_macro(self, item);
+    }
+
+    fn visit_item(&mut self, item: &'ast syn::Item) {
+        // Helper attributes are only in scope on the item that derives their macros.
+        let derivable = is_derivable(item);
+        let helpers = if derivable {
+            self.derive_helpers(item.attrs())
+        } else {
+            Vec::new()
+        };
+        let outer = mem::replace(&mut self.helpers, helpers);
+        let entered = match self.handler {
+            Some(ref mut handler) => handler.enter_item(item),
+            None => true,
+        };
+        if !entered {
+            self.helpers = outer;
+            return;
+        }
+        // The module's own file is scanned separately, so only the macros see its contents.
+        #[cfg(feature = "fs")]
+        let loaded = self.load_module(item);
+        #[cfg(feature = "fs")]
+        let configured = self.expand_attrs_of(loaded.as_ref().unwrap_or(item), derivable);
+        #[cfg(not(feature = "fs"))]
+        let configured = self.expand_attrs_of(item, derivable);
+        if configured {
+            if self.item_depth > 0 {
+                self.nested_items += 1;
+            }
+
+            // A macro invocation in item position doesn't count as being nested inside itself.
+            let nested = match *item {
+                syn::Item::Macro(_) => 0,
+                _ => 1,
+            };
+            self.item_depth += nested;
+            match *item {
+                // syn couldn't fully parse the item, so its tokens, including its attributes, are
+                // scanned instead.
+                syn::Item::Verbatim(ref tokens) => self.scan_tokens(tokens.clone(), true),
+                _ => syn::visit::visit_item(self, item),
+            }
+            self.item_depth -= nested;
+        }
+        if let Some(ref mut handler) = self.handler {
+            handler.leave_item(item);
+        }
+        self.helpers = outer;
+    }
+
+    fn visit_impl_item(&mut self, item: &'ast syn::ImplItem) {
+        match *item {
+            syn::ImplItem::Verbatim(ref tokens) => self.scan_tokens(tokens.clone(), true),
+            _ => {
+                if self.expand_attrs_of(item, false) {
+                    syn::visit::visit_impl_item(self, item);
+                }
+            }
+        }
+    }
+
+    fn visit_trait_item(&mut self, item: &'ast syn::TraitItem) {
+        match *item {
+            syn::TraitItem::Verbatim(ref tokens) => self.scan_tokens(tokens.clone(), true),
+            _ => {
+                if self.expand_attrs_of(item, false) {
+                    syn::visit::visit_trait_item(self, item);
+                }
+            }
+        }
+    }
+
+    fn visit_field(&mut self, field: &'ast syn::Field) {
+        if self.options.field_attributes {
+            if !self.expand_attrs_of(field, false) {
+                return;
+            }
+        } else if self.is_configured_out(&field.attrs) {
+            return;
+        }
+        syn::visit::visit_field(self, field);
+    }
+
+    fn visit_variant(&mut self, variant: &'ast syn::Variant) {
+        if self.options.field_attributes {
+            if !self.expand_attrs_of(variant, false) {
+                return;
+            }
+        } else if self.is_configured_out(&variant.attrs) {
+            return;
+        }
+        syn::visit::visit_variant(self, variant);
+    }
+
+    fn visit_stmt(&mut self, stmt: &'ast syn::Stmt) {
+        let attrs = match *stmt {
+            syn::Stmt::Local(ref local) => &local.attrs[..],
+            _ => compat::stmt_macro_attrs(stmt).unwrap_or(&[]),
+        };
+        if self.is_configured_out(attrs) {
+            return;
+        }
+        // syn 1 doesn't have a separate kind of statement for macros, so they're told apart here.
+        #[cfg(not(feature = "syn2"))]
+        match *stmt {
+            syn::Stmt::Item(syn::Item::Macro(ref item)) if item.ident.is_none() => {
+                self.position = Some(MacroPosition::Statement);
+                return syn::visit::visit_item_macro(self, item);
+            }
+            syn::Stmt::Semi(syn::Expr::Macro(ref expr), _) => {
+                self.position = Some(MacroPosition::Statement);
+                return syn::visit::visit_expr_macro(self, expr);
+            }
+            _ => {}
+        }
+        syn::visit::visit_stmt(self, stmt);
+    }
+
+    fn visit_foreign_item(&mut self, item: &'ast syn::ForeignItem) {
+        match *item {
+            syn::ForeignItem::Verbatim(ref tokens) => self.scan_tokens(tokens.clone(), true),
+            _ => {
+                if self.expand_attrs_of(item, false) {
+                    syn::visit::visit_foreign_item(self, item);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, syn::visit::Visit};
+
+    // Scans the code with a visitor directly, without a file.
+    fn scan(registry: &MacroRegistry, code: &str) -> Scan {
+        let file = syn::parse_file(code).unwrap();
+        let mut visitor = MacroVisitor::new(registry, None).unwrap();
+        visitor.visit_file(&file);
+        visitor.finish().unwrap()
+    }
+
+    fn tokens(code: &str) -> Vec<TokenTree> {
+        code.parse::<TokenStream>().unwrap().into_iter().collect()
+    }
+
+    #[test]
+    fn finds_the_invocations_in_the_examples() {
+        let mut registry = MacroRegistry::new();
+        registry
+            .functionlike("custom_assert", |ts| ts)
+            .derive("Pod", |_| TokenStream::new())
+            .attribute("reference_counted", |_, item| item);
+        let examples = [
+            include_str!("../examples/custom_assert/tests/tests.rs"),
+            include_str!("../examples/plain_old_data/tests/tests.rs"),
+            include_str!("../examples/reference_counting/tests/tests.rs"),
+        ];
+        let records: Vec<(MacroKind, String, usize)> = examples
+            .iter()
+            .flat_map(|code| scan(&registry, code).records)
+            .map(|record| (record.kind, record.macro_path, record.start.line))
+            .collect();
+        assert_eq!(
+            records,
+            [
+                (MacroKind::Functionlike, "custom_assert".to_owned(), 7),
+                (MacroKind::Derive, "Pod".to_owned(), 9),
+                (MacroKind::Attribute, "reference_counted".to_owned(), 8),
+            ]
+        );
+    }
+
+    #[test]
+    fn records_the_position_of_each_invocation() {
+        let mut registry = MacroRegistry::new();
+        registry.functionlike("m", |_| TokenStream::new());
+        let scan = scan(
+            &registry,
+            "m!();
+            fn f() { m!(); let x = m!(); let m!() = x; let y: m!() = x; }
+            impl S { m!(); }
+            trait T { m!(); }
+            extern \"C\" { m!(); }",
+        );
+        let positions: Vec<Option<MacroPosition>> =
+            scan.call_sites.iter().map(|site| site.position).collect();
+        assert_eq!(
+            positions,
+            [
+                Some(MacroPosition::Item),
+                Some(MacroPosition::Statement),
+                Some(MacroPosition::Expression),
+                Some(MacroPosition::Pattern),
+                Some(MacroPosition::Type),
+                Some(MacroPosition::ImplItem),
+                Some(MacroPosition::TraitItem),
+                Some(MacroPosition::ForeignItem),
+            ]
+        );
+    }
+
+    #[test]
+    fn splits_loose_tokens() {
+        let (path, len) = macro_call_at(&tokens("a::b!(x); c")).unwrap();
+        assert_eq!((path.segments.len(), len), (2, 6));
+        assert!(macro_call_at(&tokens("a::b(x);")).is_none());
+        assert!(macro_call_at(&tokens("a!;")).is_none());
+        assert!(macro_call_at(&tokens("a:b!(x);")).is_none());
+
+        assert_eq!(outer_attrs_len(&tokens("#[a] #[b(c)] fn f() {}")), 4);
+        assert_eq!(outer_attrs_len(&tokens("#![a] fn f() {}")), 0);
+        assert_eq!(item_len(&tokens("struct S(u8); struct T;")), 4);
+        assert_eq!(item_len(&tokens("fn f() { g(); } fn h() {}")), 4);
+        assert_eq!(item_len(&tokens("struct S")), 2);
+    }
+
+    #[test]
+    fn reads_the_paths_in_derives() {
+        let paths: Vec<String> =
+            derive_paths(&syn::parse_quote!(#[derive(Debug, serde::Serialize)]))
+                .unwrap()
+                .unwrap()
+                .iter()
+                .map(|path| path.to_token_stream().to_string())
+                .collect();
+        assert_eq!(paths, ["Debug", "serde :: Serialize"]);
+        assert!(derive_paths(&syn::parse_quote!(#[inline])).is_none());
+        assert!(derive_paths(&syn::parse_quote!(#[derive = "Debug"])).is_none());
+        assert!(derive_paths(&syn::parse_quote!(#[derive(Debug, 1)]))
+            .unwrap()
+            .is_err());
+    }
+}